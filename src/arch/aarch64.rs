@@ -0,0 +1,79 @@
+use std::mem;
+
+use anyhow::{anyhow, Result};
+use nix::errno::Errno;
+use nix::unistd::Pid;
+
+pub type Regs = libc::user_regs_struct;
+
+// `svc #0`
+pub const SYSCALL_INSTRUCTION: u64 = 0xd400_0001;
+
+pub const REPLACER_CODES: bool = false;
+
+// `AUDIT_ARCH_AARCH64`, which the seccomp filter checks the syscalls against
+pub const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// aarch64 only has the `*at` syscalls
+pub const LEGACY_SYSCALLS: &[libc::c_long] = &[];
+
+// the general registers are only read and written as a register set on aarch64
+pub fn getregs(pid: Pid) -> Result<Regs> {
+    let mut regs: Regs = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: &mut regs as *mut Regs as *mut libc::c_void,
+        iov_len: mem::size_of::<Regs>(),
+    };
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_GETREGSET,
+            pid.as_raw(),
+            libc::NT_PRSTATUS,
+            &mut iov as *mut libc::iovec,
+        )
+    };
+    Errno::result(ret)?;
+
+    Ok(regs)
+}
+
+pub fn setregs(pid: Pid, mut regs: Regs) -> Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: &mut regs as *mut Regs as *mut libc::c_void,
+        iov_len: mem::size_of::<Regs>(),
+    };
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_SETREGSET,
+            pid.as_raw(),
+            libc::NT_PRSTATUS,
+            &mut iov as *mut libc::iovec,
+        )
+    };
+    Errno::result(ret)?;
+
+    Ok(())
+}
+
+pub fn instruction_pointer(regs: &Regs) -> u64 {
+    regs.pc
+}
+
+pub fn set_instruction_pointer(regs: &mut Regs, addr: u64) {
+    regs.pc = addr;
+}
+
+// set_syscall puts the number of a syscall into x8, and the arguments into x0-x5
+pub fn set_syscall(regs: &mut Regs, id: u64, args: &[u64]) -> Result<()> {
+    if args.len() > 6 {
+        return Err(anyhow!("too many arguments for a syscall"));
+    }
+    regs.regs[8] = id;
+    regs.regs[..args.len()].copy_from_slice(args);
+
+    Ok(())
+}
+
+pub fn syscall_result(regs: &Regs) -> u64 {
+    regs.regs[0]
+}
@@ -0,0 +1,52 @@
+// The parts of tracing the processes which depend on the architecture: the registers, and how a
+// syscall is made in a traced process. The codes injected by the replacers are only assembled for
+// x86_64, so the other architectures could serve the FUSE, but only with `--replacer none`.
+
+use anyhow::{anyhow, Result};
+use nix::sys::utsname::uname;
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::*;
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "aarch64")]
+pub use self::aarch64::*;
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("toda only supports x86_64 and aarch64");
+
+// the architecture toda is built for, in the format of `uname -m`
+pub const ARCH: &str = std::env::consts::ARCH;
+
+// check fails if toda is running on another architecture than the one it's built for, like an
+// x86_64 image emulated on an aarch64 node, where the registers of the traced processes are not
+// the ones toda knows
+pub fn check() -> Result<()> {
+    let machine = uname().machine().to_owned();
+    if machine != ARCH {
+        return Err(anyhow!(
+            "toda is built for {}, but the node is {}, use the image for {}",
+            ARCH,
+            machine,
+            machine
+        ));
+    }
+
+    Ok(())
+}
+
+// check_replacer fails if the codes injected by the replacers are not built for the architecture
+pub fn check_replacer() -> Result<()> {
+    if !REPLACER_CODES {
+        return Err(anyhow!(
+            "the replacer codes are not available on {}, inject before the workload starts with \
+             --replacer none",
+            ARCH
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use anyhow::{anyhow, Result};
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+
+pub type Regs = libc::user_regs_struct;
+
+// `syscall`
+pub const SYSCALL_INSTRUCTION: u64 = 0x050f;
+
+pub const REPLACER_CODES: bool = true;
+
+// `AUDIT_ARCH_X86_64`, which the seccomp filter checks the syscalls against
+pub const AUDIT_ARCH: u32 = 0xc000_003e;
+
+// the syscalls which only exist on x86_64, replaced by the `*at` ones on the newer architectures,
+// but still made by the libc
+pub const LEGACY_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_poll,
+    libc::SYS_pipe,
+    libc::SYS_dup2,
+    libc::SYS_rename,
+    libc::SYS_unlink,
+    libc::SYS_mkdir,
+    libc::SYS_rmdir,
+    libc::SYS_readlink,
+    libc::SYS_link,
+    libc::SYS_symlink,
+    libc::SYS_mknod,
+    libc::SYS_chmod,
+    libc::SYS_chown,
+    libc::SYS_lchown,
+    libc::SYS_getdents,
+    libc::SYS_epoll_wait,
+    libc::SYS_arch_prctl,
+];
+
+pub fn getregs(pid: Pid) -> Result<Regs> {
+    Ok(ptrace::getregs(pid)?)
+}
+
+pub fn setregs(pid: Pid, regs: Regs) -> Result<()> {
+    Ok(ptrace::setregs(pid, regs)?)
+}
+
+pub fn instruction_pointer(regs: &Regs) -> u64 {
+    regs.rip
+}
+
+pub fn set_instruction_pointer(regs: &mut Regs, addr: u64) {
+    regs.rip = addr;
+}
+
+// set_syscall puts the number and the arguments of a syscall into the registers
+pub fn set_syscall(regs: &mut Regs, id: u64, args: &[u64]) -> Result<()> {
+    regs.rax = id;
+    for (index, arg) in args.iter().enumerate() {
+        match index {
+            0 => regs.rdi = *arg,
+            1 => regs.rsi = *arg,
+            2 => regs.rdx = *arg,
+            3 => regs.r10 = *arg,
+            4 => regs.r8 = *arg,
+            5 => regs.r9 = *arg,
+            _ => return Err(anyhow!("too many arguments for a syscall")),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn syscall_result(regs: &Regs) -> u64 {
+    regs.rax
+}
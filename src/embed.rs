@@ -0,0 +1,425 @@
+// The API to embed toda in other tools and tests, instead of running the binary with flags. The
+// lifecycle is the same as the binary:
+//
+// ```ignore
+// let handle = MountInjectorBuilder::new("/mnt/data")
+//     .with_config(serde_json::from_str(config)?)
+//     .mount()?;
+// handle.update(another_config).await?;
+// handle.disable().await;
+// handle.recover().await?;
+// ```
+//
+// The FUSE is served by the runtime of toda, which is shut down once the last injection in the
+// process is unmounted, and built again for the next one.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use futures::channel::oneshot;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::exec_gate::ExecGate;
+use crate::hookfs::ownership::OwnershipOptions;
+use crate::hookfs::HookFs;
+use crate::injector::{InjectorConfig, MultiInjector};
+use crate::mount_injector::{
+    MountInjectionGuard, MountInjector, MountMode, PermissionCheck, RecoverOptions,
+};
+use crate::replacer::{
+    self, ParallelReplacer, Replacer, ReplacerOptions, ReplacerStats, ReplacerWatcher,
+};
+use crate::{fuse_device, snapshot};
+
+// Stage is the step of the injection at which an error occurs, which is attached to the error as
+// its context, and could be found with `err.downcast_ref::<Stage>()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Stage {
+    // the path or the injectors are invalid
+    Config,
+    // the FUSE is not mounted, or its session is lost
+    Mount,
+    // the processes are not moved onto the FUSE by the replacer
+    Ptrace,
+    // the original mount is not restored
+    Recovery,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stage = match self {
+            Stage::Config => "invalid config",
+            Stage::Mount => "fail to mount",
+            Stage::Ptrace => "fail to replace the processes",
+            Stage::Recovery => "fail to recover",
+        };
+        f.write_str(stage)
+    }
+}
+
+// MountInjectorBuilder configures the injection on a path, which is mounted by `mount`
+#[derive(Debug, Clone)]
+pub struct MountInjectorBuilder {
+    path: PathBuf,
+    mount_mode: MountMode,
+    permission_check: PermissionCheck,
+    ownership: OwnershipOptions,
+    config: Vec<InjectorConfig>,
+    replacer: Option<ReplacerOptions>,
+    lazy_umount: bool,
+    enable_injection: bool,
+    trace: Option<PathBuf>,
+    shadow_dir: Option<PathBuf>,
+    staging_dir: Option<PathBuf>,
+    snapshot_dir: Option<PathBuf>,
+    restore: bool,
+    wait_for_exec: Option<PathBuf>,
+}
+
+impl MountInjectorBuilder {
+    // new starts the configuration of the injection on the path, without any injector, in which
+    // the processes using the path are not moved onto the FUSE
+    pub fn new<P: AsRef<Path>>(path: P) -> MountInjectorBuilder {
+        MountInjectorBuilder {
+            path: path.as_ref().to_owned(),
+            mount_mode: MountMode::default(),
+            permission_check: PermissionCheck::default(),
+            ownership: OwnershipOptions::default(),
+            config: Vec::new(),
+            replacer: None,
+            lazy_umount: false,
+            enable_injection: true,
+            trace: None,
+            shadow_dir: None,
+            staging_dir: None,
+            snapshot_dir: None,
+            restore: false,
+            wait_for_exec: None,
+        }
+    }
+
+    pub fn with_mount_mode(mut self, mount_mode: MountMode) -> MountInjectorBuilder {
+        self.mount_mode = mount_mode;
+        self
+    }
+
+    pub fn with_permission_check(
+        mut self,
+        permission_check: PermissionCheck,
+    ) -> MountInjectorBuilder {
+        self.permission_check = permission_check;
+        self
+    }
+
+    pub fn with_ownership(mut self, ownership: OwnershipOptions) -> MountInjectorBuilder {
+        self.ownership = ownership;
+        self
+    }
+
+    pub fn with_config(mut self, config: Vec<InjectorConfig>) -> MountInjectorBuilder {
+        self.config = config;
+        self
+    }
+
+    // with_replacer moves the fds, cwds and mmaps of the processes using the path onto the FUSE
+    // after mounting, and back before recovering
+    pub fn with_replacer(mut self, replacer: Option<ReplacerOptions>) -> MountInjectorBuilder {
+        self.replacer = replacer;
+        self
+    }
+
+    pub fn with_lazy_umount(mut self, lazy_umount: bool) -> MountInjectorBuilder {
+        self.lazy_umount = lazy_umount;
+        self
+    }
+
+    // with_injection_enabled decides whether the injection is enabled once mounted, or later with
+    // `InjectionHandle::enable`
+    pub fn with_injection_enabled(mut self, enable: bool) -> MountInjectorBuilder {
+        self.enable_injection = enable;
+        self
+    }
+
+    pub fn with_trace(mut self, trace: Option<PathBuf>) -> MountInjectorBuilder {
+        self.trace = trace;
+        self
+    }
+
+    pub fn with_shadow_dir(mut self, shadow_dir: Option<PathBuf>) -> MountInjectorBuilder {
+        self.shadow_dir = shadow_dir;
+        self
+    }
+
+    // with_staging_dir keeps the original path in the directory during the injection, where the
+    // workload doesn't see it
+    pub fn with_staging_dir(mut self, staging_dir: Option<PathBuf>) -> MountInjectorBuilder {
+        self.staging_dir = staging_dir;
+        self
+    }
+
+    // with_snapshot_dir snapshots the path before mounting, which is restored after recovering
+    // if `restore` is set
+    pub fn with_snapshot_dir(
+        mut self,
+        snapshot_dir: Option<PathBuf>,
+        restore: bool,
+    ) -> MountInjectorBuilder {
+        self.snapshot_dir = snapshot_dir;
+        self.restore = restore;
+        self
+    }
+
+    // with_wait_for_exec holds the execs of the files on the mount of the path until the FUSE is
+    // mounted, so that the workload started along with toda runs on the FUSE without the replacer
+    pub fn with_wait_for_exec(mut self, wait_for_exec: Option<PathBuf>) -> MountInjectorBuilder {
+        self.wait_for_exec = wait_for_exec;
+        self
+    }
+
+    // mount mounts the FUSE over the path and moves the processes onto it. It blocks until the
+    // FUSE is up, and should be called in the mount namespace of the path.
+    pub fn mount(self) -> Result<InjectionHandle> {
+        info!("canonicalizing path {}", self.path.display());
+        let path = self.path.canonicalize().context(Stage::Config)?;
+        // the execs are released once the FUSE is mounted, or fails to be mounted
+        let gate = match &self.wait_for_exec {
+            Some(wait_for_exec) => Some(ExecGate::hold(wait_for_exec).context(Stage::Config)?),
+            None => None,
+        };
+        // the injectors are built again once mounted, but they're checked before the original
+        // mount is moved
+        MultiInjector::build(self.config.clone()).context(Stage::Config)?;
+
+        if let Some(snapshot_dir) = &self.snapshot_dir {
+            let stats = snapshot::take(&path, snapshot_dir).context(Stage::Mount)?;
+            info!("snapshot taken: {:?}", stats);
+        }
+
+        let replacer = match &self.replacer {
+            Some(options) => {
+                Some(ParallelReplacer::prepare(&path, &path, options).context(Stage::Ptrace)?)
+            }
+            None => None,
+        };
+
+        if let Err(err) = fuse_device::mkfuse_node() {
+            info!("fail to make /dev/fuse node: {}", err)
+        }
+
+        let mut injection = MountInjector::create_injection(
+            &path,
+            self.mount_mode,
+            self.permission_check,
+            self.config,
+        )
+        .context(Stage::Mount)?
+        .with_ownership(self.ownership)
+        .with_trace(self.trace)
+        .with_shadow_dir(self.shadow_dir)
+        .with_staging_dir(self.staging_dir)
+        .context(Stage::Config)?;
+        let guard = injection.mount().context(Stage::Mount)?;
+        info!("mount successfully");
+        drop(gate);
+
+        let mut replacer_stats = ReplacerStats::default();
+        if let Some(mut replacer) = replacer {
+            replacer.run().context(Stage::Ptrace)?;
+            replacer_stats = replacer.stats();
+            drop(replacer);
+            info!("replacer detached");
+        }
+        if let Some(options) = &self.replacer {
+            replacer_stats =
+                verify_replacer(&path, &guard, options, replacer_stats).context(Stage::Ptrace)?;
+        }
+
+        let mut watcher = None;
+        let watched = self
+            .replacer
+            .as_ref()
+            .filter(|options| options.watch_interval_ms > 0);
+        if let Some(options) = watched {
+            if guard.mount_mode() == MountMode::Move {
+                info!("start replacer watcher");
+                watcher = Some(
+                    ReplacerWatcher::start(
+                        guard.hidden_path(),
+                        &path,
+                        options.clone(),
+                        Duration::from_millis(options.watch_interval_ms),
+                    )
+                    .context(Stage::Ptrace)?,
+                );
+            } else {
+                warn!("replacer watcher only works with the move mount mode");
+            }
+        }
+
+        if self.enable_injection {
+            info!("enable injection");
+            guard.enable_injection();
+        }
+
+        Ok(InjectionHandle {
+            hookfs: guard.hookfs.clone(),
+            recovery: Recovery {
+                guard: Arc::new(Mutex::new(guard)),
+                watcher: Arc::new(Mutex::new(watcher)),
+                options: RecoverOptions {
+                    replacer: self.replacer,
+                    cwds: replacer_stats.cwds.clone(),
+                    lazy_umount: self.lazy_umount,
+                },
+                path,
+                snapshot_dir: self.snapshot_dir.filter(|_| self.restore),
+            },
+            replacer_stats,
+        })
+    }
+}
+
+// verify_replacer checks that the replacer has moved the processes onto the FUSE, replaces the
+// ones left again for `verify_retries` times, and records the references still left in the stats
+fn verify_replacer(
+    path: &Path,
+    guard: &MountInjectionGuard,
+    options: &ReplacerOptions,
+    mut stats: ReplacerStats,
+) -> Result<ReplacerStats> {
+    // the original directory is only at another path with the move mount mode
+    let detect_path = match guard.mount_mode() {
+        MountMode::Move => guard.hidden_path(),
+        _ => path,
+    };
+
+    let mut remaining = replacer::verify(path, guard.hidden_path(), options, &stats)?;
+    for retry in 0..options.verify_retries {
+        if remaining.is_empty() {
+            break;
+        }
+
+        let mut pids: Vec<i32> = remaining.iter().map(|case| case.pid).collect();
+        pids.dedup();
+        info!("replace processes {:?} again, retry {}", pids, retry + 1);
+        let mut options = options.clone();
+        options.include_pid = pids;
+        options.include_name.clear();
+
+        let mut replacer = ParallelReplacer::prepare(detect_path, path, &options)?;
+        let result = replacer.run();
+        stats.merge(replacer.stats());
+        drop(replacer);
+        result?;
+
+        remaining = replacer::verify(path, guard.hidden_path(), &options, &stats)?;
+    }
+
+    stats.remaining = remaining;
+    Ok(stats)
+}
+
+// Recovery recovers the mount, and restores the snapshot after it. It could be run again after a
+// failure, as every step is skipped once done.
+#[derive(Clone)]
+struct Recovery {
+    guard: Arc<Mutex<MountInjectionGuard>>,
+    // the watcher is stopped before the recovery moves the processes back, but kept for its stats
+    watcher: Arc<Mutex<Option<ReplacerWatcher>>>,
+    options: RecoverOptions,
+    path: PathBuf,
+    // the snapshot to restore after recovering
+    snapshot_dir: Option<PathBuf>,
+}
+
+impl Recovery {
+    fn run(&self) -> Result<()> {
+        if let Some(watcher) = self.watcher.lock().unwrap().as_mut() {
+            info!("stop replacer watcher");
+            watcher.stop();
+        }
+
+        // the cwds replaced by the watcher are restored as well
+        let mut options = self.options.clone();
+        if let Some(watcher) = self.watcher.lock().unwrap().as_ref() {
+            options.cwds.extend(watcher.stats().cwds);
+        }
+
+        let mut guard = self.guard.lock().unwrap();
+        info!("disable injection");
+        guard.disable_injection();
+
+        info!("recovering mount");
+        guard.recover_mount(options).context(Stage::Recovery)?;
+        info!("recover successfully");
+
+        if let Some(snapshot_dir) = &self.snapshot_dir {
+            let stats = snapshot::restore(snapshot_dir, &self.path).context(Stage::Recovery)?;
+            info!("snapshot restored: {:?}", stats);
+        }
+        Ok(())
+    }
+}
+
+// InjectionHandle controls a mounted injection, until it's recovered
+pub struct InjectionHandle {
+    hookfs: Arc<HookFs>,
+    recovery: Recovery,
+    replacer_stats: ReplacerStats,
+}
+
+impl InjectionHandle {
+    pub fn hookfs(&self) -> &Arc<HookFs> {
+        &self.hookfs
+    }
+
+    // replacer_stats returns what the replacer has moved onto the FUSE after mounting, and the
+    // watcher since then
+    pub fn replacer_stats(&self) -> ReplacerStats {
+        let mut stats = self.replacer_stats.clone();
+        if let Some(watcher) = self.recovery.watcher.lock().unwrap().as_ref() {
+            stats.merge(watcher.stats());
+        }
+        stats
+    }
+
+    pub async fn enable(&self) {
+        self.hookfs.enable_injection();
+    }
+
+    // disable stops the injection, and interrupts the requests blocked by the injectors
+    pub async fn disable(&self) {
+        self.hookfs.disable_injection();
+    }
+
+    // update replaces the injectors with the config, and interrupts the requests blocked by the
+    // former ones
+    pub async fn update(&self, config: Vec<InjectorConfig>) -> Result<()> {
+        self.hookfs.set_injector(MultiInjector::build(config)?);
+        Ok(())
+    }
+
+    // recover disables the injection, moves the processes back and restores the original mount.
+    // It's run on a thread of its own, as the umount is retried for seconds, so that it doesn't
+    // block the runtime of the caller. If it fails, it could be called again, and continues from
+    // the step which failed.
+    pub async fn recover(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        let recovery = self.recovery.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send(recovery.run());
+        });
+        rx.await
+            .map_err(|_| anyhow!("recover thread exits unexpectedly"))?
+    }
+
+    // recover_blocking is `recover` for the callers without an async runtime
+    pub fn recover_blocking(&self) -> Result<()> {
+        self.recovery.run()
+    }
+}
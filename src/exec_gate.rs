@@ -0,0 +1,192 @@
+use std::ffi::CString;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use anyhow::{anyhow, Result};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::{close, read, write};
+use procfs::process::Process;
+use tracing::{info, trace, warn};
+
+// the fanotify API, which is not exposed by libc yet. The permission events on exec are supported
+// since Linux 5.0.
+const FAN_CLOEXEC: libc::c_uint = 0x0000_0001;
+const FAN_CLASS_CONTENT: libc::c_uint = 0x0000_0004;
+const FAN_MARK_ADD: libc::c_uint = 0x0000_0001;
+const FAN_MARK_MOUNT: libc::c_uint = 0x0000_0010;
+const FAN_OPEN_EXEC_PERM: u64 = 0x0004_0000;
+const FAN_ALLOW: u32 = 0x01;
+
+// how often the responder checks whether the gate is dropped, in milliseconds
+const RESPOND_INTERVAL: libc::c_int = 100;
+
+// the layout of `struct fanotify_event_metadata`
+#[repr(C)]
+struct EventMetadata {
+    event_len: u32,
+    vers: u8,
+    reserved: u8,
+    metadata_len: u16,
+    mask: u64,
+    fd: i32,
+    pid: i32,
+}
+
+// the layout of `struct fanotify_response`
+#[repr(C)]
+struct Response {
+    fd: i32,
+    response: u32,
+}
+
+// ExecGate holds the execs of the files on the mount of a path with the fanotify permission
+// events, until it's dropped. Without the replacer, the processes started before the FUSE is
+// mounted keep using the original directory, so the workload started along with toda waits for
+// the FUSE at its exec instead. The execs of toda and its children are let through at once.
+pub struct ExecGate {
+    fd: RawFd,
+    stop: Arc<AtomicBool>,
+    responder: Option<JoinHandle<()>>,
+}
+
+impl ExecGate {
+    pub fn hold<P: AsRef<Path>>(path: P) -> Result<ExecGate> {
+        let path = path.as_ref();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_fanotify_init,
+                FAN_CLASS_CONTENT | FAN_CLOEXEC,
+                libc::O_RDONLY | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(anyhow!("fanotify_init: {}", Errno::last()));
+        }
+        let fd = fd as RawFd;
+
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let marked = unsafe {
+            libc::syscall(
+                libc::SYS_fanotify_mark,
+                fd,
+                FAN_MARK_ADD | FAN_MARK_MOUNT,
+                FAN_OPEN_EXEC_PERM,
+                libc::AT_FDCWD,
+                cpath.as_ptr(),
+            )
+        };
+        if marked < 0 {
+            let errno = Errno::last();
+            close(fd)?;
+            return Err(anyhow!(
+                "fail to hold the execs on the mount of {}: {}",
+                path.display(),
+                errno
+            ));
+        }
+        info!("hold the execs on the mount of {}", path.display());
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let responder = {
+            let stop = stop.clone();
+            std::thread::spawn(move || respond(fd, &stop))
+        };
+
+        Ok(ExecGate {
+            fd,
+            stop,
+            responder: Some(responder),
+        })
+    }
+}
+
+impl Drop for ExecGate {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(responder) = self.responder.take() {
+            responder.join().ok();
+        }
+
+        // the kernel allows all the execs being held once the group is closed
+        if let Err(err) = close(self.fd) {
+            warn!("fail to close fanotify: {}", err);
+        }
+        info!("release the execs");
+    }
+}
+
+// respond reads the exec events until the gate is dropped. The ones of toda and its children are
+// allowed, which would never go on otherwise, e.g. a helper run while mounting. The others are left
+// pending, and their fds are closed, as they're only needed to respond.
+fn respond(fd: RawFd, stop: &AtomicBool) {
+    let mut buffer = [0u8; 4096];
+    while !stop.load(Ordering::SeqCst) {
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        match poll(&mut fds, RESPOND_INTERVAL) {
+            Ok(0) | Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Ok(_) => {}
+            Err(err) => {
+                warn!("fail to poll fanotify: {}", err);
+                return;
+            }
+        }
+
+        let len = match read(fd, &mut buffer) {
+            Ok(len) => len,
+            Err(nix::Error::Sys(Errno::EINTR)) | Err(nix::Error::Sys(Errno::EAGAIN)) => continue,
+            Err(err) => {
+                warn!("fail to read fanotify: {}", err);
+                return;
+            }
+        };
+
+        let mut offset = 0;
+        while offset + size_of::<EventMetadata>() <= len {
+            let event = unsafe {
+                std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const EventMetadata)
+            };
+            if (event.event_len as usize) < size_of::<EventMetadata>() {
+                break;
+            }
+            offset += event.event_len as usize;
+            if event.fd < 0 {
+                continue;
+            }
+
+            if is_own(event.pid) {
+                trace!("allow the exec of process {}", event.pid);
+                let response = Response {
+                    fd: event.fd,
+                    response: FAN_ALLOW,
+                };
+                let response = unsafe {
+                    std::slice::from_raw_parts(
+                        &response as *const Response as *const u8,
+                        size_of::<Response>(),
+                    )
+                };
+                if let Err(err) = write(fd, response) {
+                    warn!("fail to allow the exec of process {}: {}", event.pid, err);
+                }
+            } else {
+                trace!("hold the exec of process {}", event.pid);
+            }
+            close(event.fd).ok();
+        }
+    }
+}
+
+// is_own returns whether the process is toda, or started by it
+fn is_own(pid: i32) -> bool {
+    let own = std::process::id() as i32;
+    pid == own
+        || Process::new(pid)
+            .map(|process| process.stat.ppid == own)
+            .unwrap_or(false)
+}
@@ -6,31 +6,101 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use fuser::*;
+use nix::errno::Errno;
 use tracing::trace_span;
 use tracing_futures::Instrument;
 
-use super::errors::Result;
+use super::buffer_pool::BUFFER_POOL;
+use super::errors::{HookFsError as Error, Result};
+use super::op_stats::OP_STATS;
+use super::permission::{Requester, REQUESTER};
 use super::reply::*;
-use super::runtime::spawn;
+use super::runtime::{admit, spawn};
+use crate::injector::{IoRange, Method, IO_RANGE};
 
-pub fn spawn_reply<F, R, V>(id: u64, reply: R, f: F)
+pub fn spawn_reply<T, F, R, V>(fs: &Arc<T>, req: &Request, method: Method, reply: R, f: F)
 where
+    T: AsyncFileSystemImpl + 'static,
     F: Future<Output = Result<V>> + Send + 'static,
     R: FsReply<V> + Send + 'static,
-    V: Debug,
+    V: Debug + Send + 'static,
 {
+    spawn_reply_with(fs, req, method, reply, move |reply| async move {
+        (reply, f.await)
+    })
+}
+
+// spawn_reply_with is like `spawn_reply`, but the request is handed the reply, and gives it back
+// along with the result. It's for the requests which fill the reply themselves, e.g. readdir.
+pub fn spawn_reply_with<T, F, U, R, V>(fs: &Arc<T>, req: &Request, method: Method, reply: R, f: F)
+where
+    T: AsyncFileSystemImpl + 'static,
+    F: FnOnce(R) -> U + Send + 'static,
+    U: Future<Output = (R, Result<V>)> + Send + 'static,
+    R: FsReply<V> + Send + 'static,
+    V: Debug + Send + 'static,
+{
+    let permit = match admit(is_critical(method)) {
+        Some(permit) => permit,
+        None => {
+            OP_STATS.record_op(method, false);
+            reply.reply(Err(Error::Sys(Errno::EAGAIN)));
+            return;
+        }
+    };
+
+    let fs = fs.clone();
+    let id = req.unique();
+    let requester = Requester {
+        uid: req.uid(),
+        gid: req.gid(),
+        pid: req.pid(),
+    };
     spawn(async move {
-        let result = f.instrument(trace_span!("request", id)).await;
+        let (reply, result) = match fs.available(method) {
+            Ok(()) => {
+                REQUESTER
+                    .scope(requester, f(reply).instrument(trace_span!("request", id)))
+                    .await
+            }
+            Err(err) => (reply, Err(err)),
+        };
+        if let Err(err) = &result {
+            fs.inspect_error(err).await;
+        }
+        OP_STATS.record_op(method, result.is_ok());
         reply.reply(result);
+        drop(permit);
     });
 }
 
+// is_critical returns true for the requests which release the resources of the workload or
+// persist its data, which are never shed
+fn is_critical(method: Method) -> bool {
+    matches!(
+        method,
+        Method::FORGET
+            | Method::RELEASE
+            | Method::RELEASEDIR
+            | Method::FLUSH
+            | Method::FSYNC
+            | Method::FSYNCDIR
+    )
+}
+
 #[async_trait]
 pub trait AsyncFileSystemImpl: Send + Sync {
-    fn init(&self) -> Result<()>;
+    fn init(&self, config: &mut KernelConfig) -> Result<()>;
 
     fn destroy(&self);
 
+    // available fails the request at once, before it reaches the injectors and the backend, if
+    // the filesystem is no longer able to serve it
+    fn available(&self, method: Method) -> Result<()>;
+
+    // inspect_error is called with the error of every failed request
+    async fn inspect_error(&self, err: &Error);
+
     async fn lookup(&self, parent: u64, name: OsString) -> Result<Entry>;
 
     async fn forget(&self, ino: u64, nlookup: u64);
@@ -205,6 +275,10 @@ pub trait AsyncFileSystemImpl: Send + Sync {
     ) -> Result<()>;
 
     async fn bmap(&self, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap);
+
+    async fn poll(&self, ino: u64, fh: u64, kh: u64, events: u32, flags: u32) -> Result<Poll>;
+
+    async fn lseek(&self, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<Lseek>;
 }
 
 pub struct AsyncFileSystem<T>(Arc<T>);
@@ -225,9 +299,9 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     fn init(
         &mut self,
         _req: &fuser::Request,
-        _config: &mut fuser::KernelConfig,
+        config: &mut fuser::KernelConfig,
     ) -> std::result::Result<(), nix::libc::c_int> {
-        self.0.init().map_err(|err| err.into())
+        self.0.init(config).map_err(|err| err.into())
     }
 
     fn destroy(&mut self, _req: &fuser::Request) {
@@ -237,27 +311,28 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     fn lookup(&mut self, req: &Request, parent: u64, name: &std::ffi::OsStr, reply: ReplyEntry) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::LOOKUP, reply, async move {
             async_impl.lookup(parent, name).await
         });
     }
 
     fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
         let async_impl = self.0.clone();
+        let permit = admit(true);
 
         // TODO: union the spawn function for request without reply
         spawn(async move {
             async_impl.forget(ino, nlookup).await;
+            OP_STATS.record_op(Method::FORGET, true);
+            drop(permit);
         });
     }
 
     fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.getattr(ino).await },
-        );
+        spawn_reply(&self.0, req, Method::GETATTR, reply, async move {
+            async_impl.getattr(ino).await
+        });
     }
 
     fn setattr(
@@ -279,7 +354,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyAttr,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::SETATTR, reply, async move {
             async_impl
                 .setattr(
                     ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
@@ -291,7 +366,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
 
     fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::READLINK, reply, async move {
             async_impl.readlink(ino).await
         });
     }
@@ -309,7 +384,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let name = name.to_owned();
         let uid = req.uid();
         let gid = req.gid();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::MKNOD, reply, async move {
             async_impl
                 .mknod(parent, name, mode, umask, rdev, uid, gid)
                 .await
@@ -329,21 +404,21 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
 
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::MKDIR, reply, async move {
             async_impl.mkdir(parent, name, mode, umask, uid, gid).await
         });
     }
     fn unlink(&mut self, req: &Request, parent: u64, name: &std::ffi::OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::UNLINK, reply, async move {
             async_impl.unlink(parent, name).await
         });
     }
     fn rmdir(&mut self, req: &Request, parent: u64, name: &std::ffi::OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::RMDIR, reply, async move {
             async_impl.rmdir(parent, name).await
         });
     }
@@ -360,7 +435,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let link = link.to_owned();
         let uid = req.uid();
         let gid = req.gid();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::SYMLINK, reply, async move {
             async_impl.symlink(parent, name, link, uid, gid).await
         });
     }
@@ -377,7 +452,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let async_impl = self.0.clone();
         let name = name.to_owned();
         let newname = newname.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::RENAME, reply, async move {
             async_impl
                 .rename(parent, name, newparent, newname, flags)
                 .await
@@ -393,13 +468,13 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     ) {
         let async_impl = self.0.clone();
         let newname = newname.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::LINK, reply, async move {
             async_impl.link(ino, newparent, newname).await
         });
     }
     fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::OPEN, reply, async move {
             async_impl.open(ino, flags).await
         });
     }
@@ -415,11 +490,22 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyData,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        let range = IoRange {
+            offset: offset as u64,
+            size: size as u64,
+        };
+        let read = async move {
             async_impl
                 .read(ino, fh, offset, size, flags, lock_owner)
                 .await
-        });
+        };
+        spawn_reply(
+            &self.0,
+            req,
+            Method::READ,
+            reply,
+            IO_RANGE.scope(range, read),
+        );
     }
     fn write(
         &mut self,
@@ -434,16 +520,28 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyWrite,
     ) {
         let async_impl = self.0.clone();
-        let data = data.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        let mut buffer = BUFFER_POOL.checkout(data.len());
+        buffer.extend_from_slice(data);
+        let range = IoRange {
+            offset: offset as u64,
+            size: data.len() as u64,
+        };
+        let write = async move {
             async_impl
-                .write(ino, fh, offset, data, write_flags, flags, lock_owner)
+                .write(ino, fh, offset, buffer, write_flags, flags, lock_owner)
                 .await
-        });
+        };
+        spawn_reply(
+            &self.0,
+            req,
+            Method::WRITE,
+            reply,
+            IO_RANGE.scope(range, write),
+        );
     }
     fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::FLUSH, reply, async move {
             async_impl.flush(ino, fh, lock_owner).await
         });
     }
@@ -458,57 +556,52 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::RELEASE, reply, async move {
             async_impl.release(ino, fh, flags, lock_owner, flush).await
         });
     }
     fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::FSYNC, reply, async move {
             async_impl.fsync(ino, fh, datasync).await
         });
     }
     fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::OPENDIR, reply, async move {
             async_impl.opendir(ino, flags).await
         });
     }
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
         let async_impl = self.0.clone();
-        spawn(async move {
-            match async_impl.readdir(ino, fh, offset, &mut reply).await {
-                Ok(_) => reply.ok(),
-                Err(err) => reply.error(err.into()),
-            }
-        });
+        spawn_reply_with(
+            &self.0,
+            req,
+            Method::READDIR,
+            reply,
+            move |mut reply| async move {
+                let result = async_impl.readdir(ino, fh, offset, &mut reply).await;
+                (reply, result)
+            },
+        );
     }
     fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::RELEASEDIR, reply, async move {
             async_impl.releasedir(ino, fh, flags).await
         });
     }
     fn fsyncdir(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::FSYNCDIR, reply, async move {
             async_impl.fsyncdir(ino, fh, datasync).await
         });
     }
     fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.statfs(ino).await },
-        );
+        spawn_reply(&self.0, req, Method::STATFS, reply, async move {
+            async_impl.statfs(ino).await
+        });
     }
     fn setxattr(
         &mut self,
@@ -523,7 +616,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let async_impl = self.0.clone();
         let name = name.to_owned();
         let value = value.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::SETXATTR, reply, async move {
             async_impl.setxattr(ino, name, value, flags, position).await
         });
     }
@@ -537,26 +630,26 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     ) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::GETXATTR, reply, async move {
             async_impl.getxattr(ino, name, size).await
         });
     }
     fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::LISTXATTR, reply, async move {
             async_impl.listxattr(ino, size).await
         });
     }
     fn removexattr(&mut self, req: &Request, ino: u64, name: &std::ffi::OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::REMOVEXATTR, reply, async move {
             async_impl.removexattr(ino, name).await
         });
     }
     fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::ACCESS, reply, async move {
             async_impl.access(ino, mask).await
         });
     }
@@ -575,7 +668,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
 
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::CREATE, reply, async move {
             async_impl
                 .create(parent, name, mode, umask, flags, uid, gid)
                 .await
@@ -594,7 +687,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyLock,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::GETLK, reply, async move {
             async_impl
                 .getlk(ino, fh, lock_owner, start, end, typ, pid)
                 .await
@@ -614,7 +707,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(&self.0, req, Method::SETLK, reply, async move {
             async_impl
                 .setlk(ino, fh, lock_owner, start, end, typ, pid, sleep)
                 .await
@@ -626,4 +719,64 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
             async_impl.bmap(ino, blocksize, idx, reply).await;
         });
     }
+
+    fn ioctl(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _cmd: u32,
+        _in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        OP_STATS.record_unsupported("ioctl");
+        reply.error(nix::libc::ENOSYS);
+    }
+
+    fn fallocate(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _length: i64,
+        _mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        OP_STATS.record_unsupported("fallocate");
+        reply.error(nix::libc::ENOSYS);
+    }
+
+    fn poll(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        kh: u64,
+        events: u32,
+        flags: u32,
+        reply: ReplyPoll,
+    ) {
+        let async_impl = self.0.clone();
+        spawn_reply(&self.0, req, Method::POLL, reply, async move {
+            async_impl.poll(ino, fh, kh, events, flags).await
+        });
+    }
+
+    fn lseek(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        let async_impl = self.0.clone();
+        spawn_reply(&self.0, req, Method::LSEEK, reply, async move {
+            async_impl.lseek(ino, fh, offset, whence).await
+        });
+    }
 }
@@ -0,0 +1,474 @@
+use std::ffi::{CString, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use nix::dir;
+use nix::errno::Errno;
+use nix::fcntl::{openat, readlink, readlinkat, renameat, AtFlags, OFlag};
+use nix::sys::stat;
+use nix::unistd::{
+    close, fchownat, fsync, ftruncate, linkat, symlinkat, unlinkat, FchownatFlags, Gid,
+    LinkatFlags, Uid, UnlinkatFlags,
+};
+use tracing::{error, trace};
+
+use super::errors::{HookFsError as Error, Result};
+use super::runtime::spawn_blocking;
+use super::utils::check_normal;
+
+// openat2 is supported since Linux 5.6, and is not exposed by libc yet
+const SYS_OPENAT2: libc::c_long = 437;
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+const RESOLVE_BENEATH: u64 = 0x08;
+
+// the layout of `struct open_how`
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+// whether the kernel doesn't support openat2, in which case the files are opened with openat
+static OPENAT2_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// resolve_beneath returns the path of the file opened as `fd` relative to the root. The file found
+// by its handle could have been deleted, which is still open somewhere, or moved out of the root,
+// in which case it's not found.
+fn resolve_beneath(root: RawFd, fd: RawFd) -> Result<PathBuf> {
+    let (root_stat, stat) = (stat::fstat(root)?, stat::fstat(fd)?);
+    if stat.st_nlink == 0 || stat.st_dev != root_stat.st_dev {
+        return Err(Error::Sys(Errno::ENOENT));
+    }
+
+    let root_path = readlink(format!("/proc/self/fd/{}", root).as_str())?;
+    let path = readlink(format!("/proc/self/fd/{}", fd).as_str())?;
+    if path.as_bytes().ends_with(b" (deleted)") {
+        return Err(Error::Sys(Errno::ENOENT));
+    }
+
+    // the root is compared as it's seen now, which could have been moved since it's opened
+    match Path::new(&path).strip_prefix(&root_path) {
+        Ok(relative) => Ok(relative.to_owned()),
+        Err(_) => Err(Error::Sys(Errno::ENOENT)),
+    }
+}
+
+// open_beneath opens the path relative to the root without following the symlinks out of it,
+// which could be created on the backend after the path is looked up. The last component is never
+// followed, as the kernel resolves the symlinks of the FUSE itself.
+fn open_beneath(root: RawFd, path: &Path, flags: OFlag, mode: stat::Mode) -> Result<RawFd> {
+    let flags = flags | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    if !OPENAT2_UNSUPPORTED.load(Ordering::Relaxed) {
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        // the mode must be zero unless a file is created
+        let mode = if flags.intersects(OFlag::O_CREAT | OFlag::O_TMPFILE) {
+            mode.bits() as u64
+        } else {
+            0
+        };
+        let how = OpenHow {
+            flags: flags.bits() as u64,
+            mode,
+            resolve: RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                SYS_OPENAT2,
+                root,
+                cpath.as_ptr(),
+                &how as *const OpenHow,
+                std::mem::size_of::<OpenHow>(),
+            )
+        };
+        if fd >= 0 {
+            return Ok(fd as RawFd);
+        }
+
+        let errno = Errno::last();
+        if errno != Errno::ENOSYS {
+            return Err(Error::Sys(errno));
+        }
+        OPENAT2_UNSUPPORTED.store(true, Ordering::Relaxed);
+    }
+
+    Ok(openat(root, path, flags, mode)?)
+}
+
+// the maximum size of a file handle, which is `MAX_HANDLE_SZ` in the kernel
+const MAX_HANDLE_SIZE: usize = 128;
+
+// the layout of `struct file_handle`
+#[repr(C)]
+struct RawFileHandle {
+    handle_bytes: u32,
+    handle_type: i32,
+    f_handle: [u8; MAX_HANDLE_SIZE],
+}
+
+// FileHandle identifies a file on the backend through `name_to_handle_at`, and keeps working
+// after the file is renamed
+#[derive(Debug, Clone)]
+pub struct FileHandle {
+    handle_type: i32,
+    handle: Vec<u8>,
+}
+
+// Backend accesses the original filesystem relative to an `O_PATH` fd of its root, instead of
+// resolving every absolute path from `/` again. The paths passed to it are the absolute paths
+// under the root, which are kept in the inode map.
+#[derive(Debug)]
+pub struct Backend {
+    root: RawFd,
+    root_path: PathBuf,
+
+    // the backend is lost once its device is detached, or its root is unmounted, after which it's
+    // never accessed again
+    lost: AtomicBool,
+}
+
+impl Backend {
+    pub fn open<P: AsRef<Path>>(root_path: P) -> Result<Backend> {
+        let root = nix::fcntl::open(
+            root_path.as_ref(),
+            OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            stat::Mode::empty(),
+        )?;
+
+        Ok(Backend {
+            root,
+            root_path: root_path.as_ref().to_owned(),
+            lost: AtomicBool::new(false),
+        })
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::SeqCst)
+    }
+
+    // check_lost checks whether the error of a request means the backend is lost. ENODEV is only
+    // returned after the device is gone, while ESTALE is also returned for a single removed file,
+    // so the root is checked again, which is stale, or replaced by another directory, after the
+    // backend is unmounted.
+    pub async fn check_lost(&self, err: &Error) -> bool {
+        if self.is_lost() {
+            return true;
+        }
+
+        let lost = match err.errno() {
+            Errno::ENODEV => true,
+            Errno::ESTALE => {
+                let (root, root_path) = (self.root, self.root_path.clone());
+                spawn_blocking(move || {
+                    let opened = stat::fstatat(root, ".", AtFlags::empty());
+                    let current = stat::stat(&root_path);
+                    match (opened, current) {
+                        (Ok(opened), Ok(current)) => {
+                            opened.st_dev != current.st_dev || opened.st_ino != current.st_ino
+                        }
+                        _ => true,
+                    }
+                })
+                .await
+                .unwrap_or(false)
+            }
+            _ => false,
+        };
+        if lost && !self.lost.swap(true, Ordering::SeqCst) {
+            error!(
+                "backend {} is lost after {}, failing all the requests",
+                self.root_path.display(),
+                err
+            );
+        }
+
+        lost
+    }
+
+    // relative returns the path relative to the root, which is "." for the root itself. The path
+    // is rejected if it could escape the root.
+    fn relative(&self, path: &Path) -> Result<PathBuf> {
+        let path = path.strip_prefix(&self.root_path)?;
+        check_normal(path)?;
+        if path.as_os_str().is_empty() {
+            Ok(PathBuf::from("."))
+        } else {
+            Ok(path.to_owned())
+        }
+    }
+
+    fn relative_cstring(&self, path: &Path) -> Result<CString> {
+        Ok(CString::new(self.relative(path)?.as_os_str().as_bytes())?)
+    }
+
+    pub async fn stat(&self, path: &Path) -> Result<stat::FileStat> {
+        trace!("async read stat from path {}", path.display());
+        let (root, path) = (self.root, self.relative(path)?);
+        let stat = spawn_blocking(move || stat::fstatat(root, &path, AtFlags::AT_SYMLINK_NOFOLLOW))
+            .await??;
+        Ok(stat)
+    }
+
+    pub async fn lchown(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        let (root, path) = (self.root, self.relative(path)?);
+        spawn_blocking(move || {
+            fchownat(
+                Some(root),
+                &path,
+                uid.map(Uid::from_raw),
+                gid.map(Gid::from_raw),
+                FchownatFlags::NoFollowSymlink,
+            )
+        })
+        .await??;
+        Ok(())
+    }
+
+    pub async fn chmod(&self, path: &Path, mode: u32) -> Result<()> {
+        let (root, path) = (self.root, self.relative(path)?);
+        spawn_blocking(move || {
+            stat::fchmodat(
+                Some(root),
+                &path,
+                stat::Mode::from_bits_truncate(mode),
+                stat::FchmodatFlags::FollowSymlink,
+            )
+        })
+        .await??;
+        Ok(())
+    }
+
+    pub async fn truncate(&self, path: &Path, len: i64) -> Result<()> {
+        let (root, path) = (self.root, self.relative(path)?);
+        spawn_blocking(move || -> Result<()> {
+            let fd = open_beneath(root, &path, OFlag::O_WRONLY, stat::Mode::empty())?;
+            let result = ftruncate(fd, len);
+            close(fd)?;
+
+            Ok(result?)
+        })
+        .await??;
+        Ok(())
+    }
+
+    pub async fn utimens(&self, path: &Path, times: [libc::timespec; 2]) -> Result<()> {
+        let (root, path) = (self.root, self.relative_cstring(path)?);
+        spawn_blocking(move || {
+            let ret = unsafe {
+                libc::utimensat(
+                    root,
+                    path.as_ptr(),
+                    &times as *const [libc::timespec; 2] as *const libc::timespec,
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+
+            if ret != 0 {
+                Err(Error::last())
+            } else {
+                Ok(())
+            }
+        })
+        .await?
+    }
+
+    pub async fn readlink(&self, path: &Path) -> Result<OsString> {
+        let (root, path) = (self.root, self.relative(path)?);
+        Ok(spawn_blocking(move || readlinkat(root, &path)).await??)
+    }
+
+    pub async fn mknod(&self, path: &Path, mode: u32, rdev: u64) -> Result<()> {
+        let (root, path) = (self.root, self.relative_cstring(path)?);
+        spawn_blocking(move || {
+            let ret = unsafe { libc::mknodat(root, path.as_ptr(), mode, rdev) };
+
+            if ret != 0 {
+                Err(Error::last())
+            } else {
+                Ok(())
+            }
+        })
+        .await?
+    }
+
+    pub async fn mkdir(&self, path: &Path, mode: stat::Mode) -> Result<()> {
+        let (root, path) = (self.root, self.relative(path)?);
+        spawn_blocking(move || stat::mkdirat(root, &path, mode)).await??;
+        Ok(())
+    }
+
+    pub async fn unlink(&self, path: &Path) -> Result<()> {
+        let (root, path) = (self.root, self.relative(path)?);
+        spawn_blocking(move || unlinkat(Some(root), &path, UnlinkatFlags::NoRemoveDir)).await??;
+        Ok(())
+    }
+
+    pub async fn rmdir(&self, path: &Path) -> Result<()> {
+        let (root, path) = (self.root, self.relative(path)?);
+        spawn_blocking(move || unlinkat(Some(root), &path, UnlinkatFlags::RemoveDir)).await??;
+        Ok(())
+    }
+
+    pub async fn symlink(&self, target: PathBuf, path: &Path) -> Result<()> {
+        let (root, path) = (self.root, self.relative(path)?);
+        spawn_blocking(move || symlinkat(&target, Some(root), &path)).await??;
+        Ok(())
+    }
+
+    pub async fn rename(&self, old_path: &Path, new_path: &Path) -> Result<()> {
+        let root = self.root;
+        let old_path = self.relative(old_path)?;
+        let new_path = self.relative(new_path)?;
+        spawn_blocking(move || renameat(Some(root), &old_path, Some(root), &new_path)).await??;
+        Ok(())
+    }
+
+    pub async fn link(&self, old_path: &Path, new_path: &Path) -> Result<()> {
+        let root = self.root;
+        let old_path = self.relative(old_path)?;
+        let new_path = self.relative(new_path)?;
+        spawn_blocking(move || {
+            linkat(
+                Some(root),
+                &old_path,
+                Some(root),
+                &new_path,
+                LinkatFlags::NoSymlinkFollow,
+            )
+        })
+        .await??;
+        Ok(())
+    }
+
+    pub async fn open(&self, path: &Path, flags: OFlag, mode: stat::Mode) -> Result<RawFd> {
+        let (root, path) = (self.root, self.relative(path)?);
+        spawn_blocking(move || open_beneath(root, &path, flags, mode)).await?
+    }
+
+    pub async fn opendir(&self, path: &Path, flags: OFlag) -> Result<dir::Dir> {
+        trace!("opening directory {}", path.display());
+        let (root, path) = (self.root, self.relative(path)?);
+        spawn_blocking(move || -> Result<dir::Dir> {
+            let fd = open_beneath(root, &path, flags | OFlag::O_DIRECTORY, stat::Mode::empty())?;
+            match dir::Dir::from_fd(fd) {
+                Ok(dir) => Ok(dir),
+                Err(err) => {
+                    close(fd)?;
+                    Err(err.into())
+                }
+            }
+        })
+        .await?
+    }
+
+    pub async fn access(&self, path: &Path, mask: i32) -> Result<()> {
+        let (root, path) = (self.root, self.relative_cstring(path)?);
+        spawn_blocking(move || {
+            let ret = unsafe { libc::faccessat(root, path.as_ptr(), mask, 0) };
+
+            if ret != 0 {
+                Err(Error::last())
+            } else {
+                Ok(())
+            }
+        })
+        .await?
+    }
+
+    // handle returns the file handle of the path, or `None` if the backend doesn't support it
+    pub async fn handle(&self, path: &Path) -> Option<FileHandle> {
+        let (root, path) = (self.root, self.relative_cstring(path).ok()?);
+        spawn_blocking(move || {
+            let mut raw = RawFileHandle {
+                handle_bytes: MAX_HANDLE_SIZE as u32,
+                handle_type: 0,
+                f_handle: [0; MAX_HANDLE_SIZE],
+            };
+            let mut mount_id: libc::c_int = 0;
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_name_to_handle_at,
+                    root,
+                    path.as_ptr(),
+                    &mut raw as *mut RawFileHandle,
+                    &mut mount_id as *mut libc::c_int,
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if ret != 0 {
+                trace!("fail to get the handle of {:?}: {}", path, Error::last());
+                return None;
+            }
+
+            Some(FileHandle {
+                handle_type: raw.handle_type,
+                handle: raw.f_handle[..raw.handle_bytes as usize].to_vec(),
+            })
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    // resolve returns the current path of the file identified by the handle
+    pub async fn resolve(&self, handle: &FileHandle) -> Result<PathBuf> {
+        let mut raw = RawFileHandle {
+            handle_bytes: handle.handle.len() as u32,
+            handle_type: handle.handle_type,
+            f_handle: [0; MAX_HANDLE_SIZE],
+        };
+        raw.f_handle[..handle.handle.len()].copy_from_slice(&handle.handle);
+
+        let root = self.root;
+        let relative = spawn_blocking(move || -> Result<PathBuf> {
+            let fd = unsafe {
+                libc::syscall(
+                    libc::SYS_open_by_handle_at,
+                    root,
+                    &raw as *const RawFileHandle,
+                    libc::O_PATH | libc::O_CLOEXEC,
+                )
+            };
+            if fd < 0 {
+                return Err(Error::last());
+            }
+
+            let fd = fd as RawFd;
+            let relative = resolve_beneath(root, fd);
+            close(fd)?;
+
+            relative
+        })
+        .await??;
+
+        Ok(self.root_path.join(relative))
+    }
+
+    pub async fn fsync_dir(&self, path: &Path) -> Result<()> {
+        let (root, path) = (self.root, self.relative(path)?);
+        spawn_blocking(move || -> Result<()> {
+            let fd = open_beneath(
+                root,
+                &path,
+                OFlag::O_RDONLY | OFlag::O_DIRECTORY,
+                stat::Mode::empty(),
+            )?;
+            let result = fsync(fd);
+            close(fd)?;
+
+            Ok(result?)
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        if let Err(err) = close(self.root) {
+            error!("fail to close the backend root: {:?}", err);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+// the size of the pooled buffers, which is the default `max_read` and `max_write` of the kernel,
+// so that most of the requests can be served by the pool
+const BUFFER_SIZE: usize = 128 * 1024;
+
+// the maximum count of idle buffers kept in the pool
+const POOL_CAPACITY: usize = 256;
+
+pub static BUFFER_POOL: Lazy<BufferPool> =
+    Lazy::new(|| BufferPool::new(BUFFER_SIZE, POOL_CAPACITY));
+
+// BufferPool keeps the buffers of the finished reads and writes, so that the following requests
+// can reuse them instead of allocating new ones
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    buffer_size: usize,
+    capacity: usize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, capacity: usize) -> BufferPool {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(capacity)),
+            buffer_size,
+            capacity,
+        }
+    }
+
+    // checkout returns an empty buffer with at least `size` bytes of capacity. A buffer larger
+    // than the pooled ones is allocated directly
+    pub fn checkout(&self, size: usize) -> Vec<u8> {
+        if size > self.buffer_size {
+            return Vec::with_capacity(size);
+        }
+
+        self.buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.buffer_size))
+    }
+
+    // put returns the buffer to the pool. Buffers which are not checked out from the pool, or
+    // exceed the capacity of the pool are dropped
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() != self.buffer_size {
+            return;
+        }
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.capacity {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::{Path, PathBuf};
+
 use nix::errno::Errno;
 use nix::Error;
 use thiserror::Error;
@@ -25,6 +27,16 @@ pub enum HookFsError {
 
     #[error("unknown error")]
     UnknownError,
+
+    #[error("backend is lost")]
+    BackendLost,
+
+    #[error("{operation} {}: {source}", path.display())]
+    Context {
+        operation: &'static str,
+        path: PathBuf,
+        source: Box<HookFsError>,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, HookFsError>;
@@ -33,17 +45,51 @@ impl HookFsError {
     pub fn last() -> HookFsError {
         HookFsError::from(nix::Error::last())
     }
+
+    // errno returns the errno replied to the kernel, which is the original one for the
+    // failures of syscalls
+    pub fn errno(&self) -> Errno {
+        use HookFsError::*;
+
+        match self {
+            Sys(errno) => *errno,
+            InodeNotFound { .. } => Errno::EFAULT,
+            FhNotFound { .. } => Errno::EFAULT,
+            UnknownFileType => Errno::EINVAL,
+            InvalidStr => Errno::EINVAL,
+            // the same as a FUSE whose daemon is gone
+            BackendLost => Errno::ENOTCONN,
+            Context { source, .. } => source.errno(),
+            _ => Errno::EFAULT,
+        }
+    }
+
+    // context records the operation and the path which failed, for logging
+    pub fn context<P: AsRef<Path>>(self, operation: &'static str, path: P) -> HookFsError {
+        HookFsError::Context {
+            operation,
+            path: path.as_ref().to_owned(),
+            source: Box::new(self),
+        }
+    }
+}
+
+pub trait ErrorContext<T> {
+    fn context<P: AsRef<Path>>(self, operation: &'static str, path: P) -> Result<T>;
+}
+
+impl<T, E: Into<HookFsError>> ErrorContext<T> for std::result::Result<T, E> {
+    fn context<P: AsRef<Path>>(self, operation: &'static str, path: P) -> Result<T> {
+        self.map_err(|err| err.into().context(operation, path))
+    }
 }
 
 impl From<nix::Error> for HookFsError {
     fn from(err: Error) -> HookFsError {
-        // TODO: match more error types
         match err {
             Error::Sys(errno) => HookFsError::Sys(errno),
-            _ => {
-                error!("unknown error {:?}", err);
-                HookFsError::UnknownError
-            }
+            Error::InvalidPath | Error::InvalidUtf8 => HookFsError::InvalidStr,
+            Error::UnsupportedOperation => HookFsError::Sys(Errno::ENOTSUP),
         }
     }
 }
@@ -56,8 +102,13 @@ impl From<std::ffi::NulError> for HookFsError {
 
 impl From<std::io::Error> for HookFsError {
     fn from(err: std::io::Error) -> HookFsError {
-        error!("unknown error {:?}", err);
-        HookFsError::UnknownError
+        match err.raw_os_error() {
+            Some(errno) => HookFsError::Sys(Errno::from_i32(errno)),
+            None => {
+                error!("unknown error {:?}", err);
+                HookFsError::UnknownError
+            }
+        }
     }
 }
 
@@ -70,15 +121,6 @@ impl From<tokio::task::JoinError> for HookFsError {
 
 impl From<HookFsError> for libc::c_int {
     fn from(err: HookFsError) -> libc::c_int {
-        use HookFsError::*;
-
-        match err {
-            Sys(errno) => errno as i32,
-            InodeNotFound { inode: _ } => libc::EFAULT,
-            FhNotFound { fh: _ } => libc::EFAULT,
-            UnknownFileType => libc::EINVAL,
-            InvalidStr => libc::EINVAL,
-            _ => libc::EFAULT,
-        }
+        err.errno() as libc::c_int
     }
 }
@@ -0,0 +1,102 @@
+use std::fs::read_to_string;
+
+use anyhow::{anyhow, Result};
+
+// the id of the unmapped users and groups, which is `/proc/sys/kernel/overflowuid` by default
+const OVERFLOW_ID: u32 = 65534;
+
+// Extent is a line of `/proc/<pid>/uid_map`, which maps `count` ids starting at `inside` in the
+// user namespace of the process to the ids starting at `outside` in the namespace of toda
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    inside: u32,
+    outside: u32,
+    count: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+struct IdTable {
+    extents: Vec<Extent>,
+}
+
+impl IdTable {
+    fn parse(content: &str) -> Result<IdTable> {
+        let extents = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let fields: Vec<_> = line.split_whitespace().collect();
+                match fields.as_slice() {
+                    [inside, outside, count] => Ok(Extent {
+                        inside: inside.parse()?,
+                        outside: outside.parse()?,
+                        count: count.parse()?,
+                    }),
+                    _ => Err(anyhow!("invalid id map line: {}", line)),
+                }
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(IdTable { extents })
+    }
+
+    // the ids shifted beyond u32::MAX by a corrupted map are unmapped, instead of wrapping to
+    // another user
+    fn to_outside(&self, id: u32) -> u32 {
+        self.extents
+            .iter()
+            .find(|extent| id.wrapping_sub(extent.inside) < extent.count)
+            .and_then(|extent| extent.outside.checked_add(id - extent.inside))
+            .unwrap_or(OVERFLOW_ID)
+    }
+
+    fn to_inside(&self, id: u32) -> u32 {
+        self.extents
+            .iter()
+            .find(|extent| id.wrapping_sub(extent.outside) < extent.count)
+            .and_then(|extent| extent.inside.checked_add(id - extent.outside))
+            .unwrap_or(OVERFLOW_ID)
+    }
+}
+
+// IdMap shifts the ids between the user namespace of the workload and the namespace of toda, in
+// which the files are owned on the backend. The requests and the replies of the FUSE are shifted
+// by the kernel, so it only shifts the ids given in the namespace of the workload.
+#[derive(Debug, Clone, Default)]
+pub struct IdMap {
+    uid: IdTable,
+    gid: IdTable,
+}
+
+impl IdMap {
+    // read reads the id maps of the user namespace of the process
+    pub fn read(pid: i32) -> Result<IdMap> {
+        Ok(IdMap {
+            uid: IdTable::parse(&read_to_string(format!("/proc/{}/uid_map", pid))?)?,
+            gid: IdTable::parse(&read_to_string(format!("/proc/{}/gid_map", pid))?)?,
+        })
+    }
+
+    pub fn parse(uid_map: &str, gid_map: &str) -> Result<IdMap> {
+        Ok(IdMap {
+            uid: IdTable::parse(uid_map)?,
+            gid: IdTable::parse(gid_map)?,
+        })
+    }
+
+    pub fn uid_to_backend(&self, uid: u32) -> u32 {
+        self.uid.to_outside(uid)
+    }
+
+    pub fn gid_to_backend(&self, gid: u32) -> u32 {
+        self.gid.to_outside(gid)
+    }
+
+    pub fn uid_to_workload(&self, uid: u32) -> u32 {
+        self.uid.to_inside(uid)
+    }
+
+    pub fn gid_to_workload(&self, gid: u32) -> u32 {
+        self.gid.to_inside(gid)
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+// the maximum count of paths tracked by IoStats
+const IO_STATS_CAPACITY: usize = 1024;
+
+#[derive(Debug, Default, Clone)]
+struct PathStats {
+    reads: u64,
+    writes: u64,
+    read_bytes: u64,
+    write_bytes: u64,
+    latency: Duration,
+
+    // the value of the clock of IoStats when the path was accessed last time
+    last_access: u64,
+}
+
+// HotFile is the I/O statistics of a path reported through the control API
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HotFile {
+    pub path: PathBuf,
+    pub reads: u64,
+    pub writes: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub mean_latency_us: u64,
+}
+
+#[derive(Debug, Default)]
+struct IoStatsInner {
+    paths: HashMap<PathBuf, PathStats>,
+    // the paths ordered by their last access, so that the least recently accessed one is evicted
+    // without scanning all of them
+    access_order: BTreeMap<u64, PathBuf>,
+    clock: u64,
+}
+
+// IoStats accounts the reads and writes of every path. It only keeps the recently accessed
+// paths, and evicts the least recently accessed one when it's full.
+#[derive(Debug, Default)]
+pub struct IoStats {
+    inner: Mutex<IoStatsInner>,
+}
+
+impl IoStats {
+    pub fn record_read(&self, path: &Path, bytes: usize, latency: Duration) {
+        self.record(path, latency, |stats| {
+            stats.reads += 1;
+            stats.read_bytes += bytes as u64;
+        })
+    }
+
+    pub fn record_write(&self, path: &Path, bytes: usize, latency: Duration) {
+        self.record(path, latency, |stats| {
+            stats.writes += 1;
+            stats.write_bytes += bytes as u64;
+        })
+    }
+
+    fn record<F: FnOnce(&mut PathStats)>(&self, path: &Path, latency: Duration, f: F) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        match inner.paths.get(path).map(|stats| stats.last_access) {
+            Some(last_access) => {
+                inner.access_order.remove(&last_access);
+            }
+            None if inner.paths.len() >= IO_STATS_CAPACITY => {
+                let oldest = inner.access_order.keys().next().copied();
+                if let Some(oldest) = oldest.and_then(|oldest| inner.access_order.remove(&oldest)) {
+                    inner.paths.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+        inner.access_order.insert(clock, path.to_owned());
+
+        let stats = inner.paths.entry(path.to_owned()).or_default();
+        stats.latency += latency;
+        stats.last_access = clock;
+        f(stats);
+    }
+
+    // top returns the `n` paths with the most operations
+    pub fn top(&self, n: usize) -> Vec<HotFile> {
+        let inner = self.inner.lock().unwrap();
+        let mut files: Vec<_> = inner
+            .paths
+            .iter()
+            .map(|(path, stats)| {
+                let ops = stats.reads + stats.writes;
+                HotFile {
+                    path: path.clone(),
+                    reads: stats.reads,
+                    writes: stats.writes,
+                    read_bytes: stats.read_bytes,
+                    write_bytes: stats.write_bytes,
+                    mean_latency_us: (stats.latency.as_micros() / ops.max(1) as u128) as u64,
+                }
+            })
+            .collect();
+
+        files.sort_by_key(|file| std::cmp::Reverse(file.reads + file.writes));
+        files.truncate(n);
+        files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn us(us: u64) -> Duration {
+        Duration::from_micros(us)
+    }
+
+    fn find<'a>(files: &'a [HotFile], path: &str) -> Option<&'a HotFile> {
+        files.iter().find(|file| file.path == Path::new(path))
+    }
+
+    #[test]
+    fn counts_bytes_and_ops() {
+        let stats = IoStats::default();
+        stats.record_read(Path::new("/a"), 100, us(1));
+        stats.record_read(Path::new("/a"), 20, us(1));
+        stats.record_write(Path::new("/a"), 3, us(1));
+        stats.record_write(Path::new("/b"), 7, us(1));
+
+        let files = stats.top(10);
+        assert_eq!(files.len(), 2);
+        let a = find(&files, "/a").unwrap();
+        assert_eq!((a.reads, a.writes), (2, 1));
+        assert_eq!((a.read_bytes, a.write_bytes), (120, 3));
+        let b = find(&files, "/b").unwrap();
+        assert_eq!((b.reads, b.writes), (0, 1));
+        assert_eq!((b.read_bytes, b.write_bytes), (0, 7));
+    }
+
+    #[test]
+    fn mean_latency() {
+        let stats = IoStats::default();
+        stats.record_read(Path::new("/a"), 1, us(10));
+        stats.record_write(Path::new("/a"), 1, us(30));
+        stats.record_read(Path::new("/a"), 1, us(50));
+
+        assert_eq!(stats.top(1)[0].mean_latency_us, 30);
+    }
+
+    #[test]
+    fn top_orders_by_ops() {
+        let stats = IoStats::default();
+        for (path, ops) in &[("/one", 1), ("/three", 3), ("/two", 2), ("/four", 4)] {
+            for _ in 0..*ops {
+                stats.record_read(Path::new(path), 1, us(1));
+            }
+        }
+
+        let paths: Vec<_> = stats.top(3).into_iter().map(|file| file.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("/four"),
+                PathBuf::from("/three"),
+                PathBuf::from("/two")
+            ]
+        );
+        assert!(stats.top(0).is_empty());
+        assert_eq!(stats.top(10).len(), 4);
+    }
+
+    #[test]
+    fn evicts_least_recently_accessed() {
+        let stats = IoStats::default();
+        for i in 0..IO_STATS_CAPACITY {
+            stats.record_read(Path::new(&format!("/{}", i)), 1, us(1));
+        }
+        // the first path is accessed again, so the second one is the least recently accessed
+        stats.record_write(Path::new("/0"), 1, us(1));
+        stats.record_read(Path::new("/new"), 1, us(1));
+
+        let files = stats.top(usize::MAX);
+        assert_eq!(files.len(), IO_STATS_CAPACITY);
+        assert!(find(&files, "/0").is_some());
+        assert!(find(&files, "/1").is_none());
+        assert!(find(&files, "/new").is_some());
+
+        let inner = stats.inner.lock().unwrap();
+        assert_eq!(inner.access_order.len(), IO_STATS_CAPACITY);
+    }
+}
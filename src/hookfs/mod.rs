@@ -1,113 +1,164 @@
 mod async_fs;
+mod backend;
+mod buffer_pool;
 mod errors;
+pub mod idmap;
+mod io_stats;
+mod op_stats;
+pub mod ownership;
+pub mod permission;
 mod reply;
 pub mod runtime;
+// the helpers of the integration tests, which are not used by the binary
+#[allow(dead_code)]
+pub mod testing;
 mod utils;
 
-use std::collections::{HashMap, LinkedList};
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet, HashMap, LinkedList};
 use std::ffi::{CString, OsStr, OsString};
+use std::future::Future;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
 pub use async_fs::{AsyncFileSystem, AsyncFileSystemImpl};
 use async_trait::async_trait;
+use backend::{Backend, FileHandle};
+use buffer_pool::BUFFER_POOL;
 use derive_more::{Deref, DerefMut, From};
+use errors::ErrorContext;
 pub use errors::{HookFsError as Error, Result};
 use fuser::*;
+use idmap::IdMap;
+pub use io_stats::HotFile;
+use io_stats::IoStats;
 use libc::{c_void, lgetxattr, llistxattr, lremovexattr, lsetxattr};
 use nix::dir;
 use nix::errno::Errno;
-use nix::fcntl::{open, readlink, renameat, OFlag};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::{stat, statfs};
-use nix::unistd::{
-    close, fchownat, fsync, linkat, mkdir, symlinkat, truncate, unlink, AccessFlags, FchownatFlags,
-    Gid, LinkatFlags, Uid,
-};
-pub use reply::Reply;
+use nix::unistd::{self, close, fsync};
+use op_stats::OP_STATS;
+use ownership::{Owner, OwnershipOptions};
+use permission::Acl;
+pub use permission::Requester;
 use reply::*;
+pub use reply::{Entry, Open, Poll, Reply, StatFs};
 use runtime::spawn_blocking;
+use serde::Serialize;
 use slab::Slab;
+use tokio::select;
 use tokio::sync::RwLock;
-use tracing::{debug, error, instrument, trace};
+use tokio::time::{delay_for, timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, instrument, trace, warn};
 use utils::*;
 
 use crate::injector::{Injector, Method, MultiInjector};
+use crate::recorder::{Operation, Recorder};
+use crate::shadow::Shadow;
 
-// use fuse::consts::FOPEN_DIRECT_IO;
+// the interval to retry opening the writing end of a FIFO until it's opened for reading, and to
+// poll an opened FIFO until it's ready
+const FIFO_RETRY_MIN: Duration = Duration::from_millis(1);
+const FIFO_RETRY_MAX: Duration = Duration::from_millis(100);
 
 macro_rules! inject {
     ($self:ident, $method:ident, $path:expr) => {
-        if $self.enable_injection.load(Ordering::SeqCst) {
-            $self
-                .injector
-                .read()
-                .await
-                .inject(&Method::$method, $self.rebuild_path($path)?.as_path())
-                .await?;
+        if $self.should_inject(Method::$method) {
+            let path = $self.rebuild_path($path)?;
+            trace!("injecting on {}", path.display());
+            // the injector is kept across the await, as it could be swapped in the meantime
+            let injector = $self.injector.load_full();
+            let start = Instant::now();
+            let result = guard_injection(
+                Method::$method,
+                &path,
+                &$self.shutdown,
+                injector.inject(&Method::$method, path.as_path()),
+            )
+            .await;
+            OP_STATS.record_injection(Method::$method, start.elapsed());
+            result?;
         }
     };
 }
 
 macro_rules! inject_with_ino {
     ($self:ident, $method:ident, $ino:ident) => {{
-        let inode_map = $self.inode_map.read().await;
-        if let Ok(path) = inode_map.get_path($ino) {
-            let path = path.to_owned();
-            trace!("getting attr from path {}", path.display());
-            drop(inode_map);
-            inject!($self, $method, &path);
+        if $self.should_inject(Method::$method) {
+            let inode_map = $self.inode_map.read().await;
+            if let Ok(path) = inode_map.get_path($ino) {
+                let path = path.to_owned();
+                trace!("getting attr from path {}", path.display());
+                drop(inode_map);
+                inject!($self, $method, &path);
+            }
         }
     }};
 }
 
 macro_rules! inject_with_fh {
     ($self:ident, $method:ident, $fh:ident) => {{
-        let opened_files = $self.opened_files.read().await;
-        if let Ok(file) = opened_files.get($fh as usize) {
-            let path = file.original_path().to_owned();
-            drop(opened_files);
-            inject!($self, $method, &path);
+        if $self.should_inject(Method::$method) {
+            let opened_files = $self.opened_files.shard($fh).read().await;
+            if let Ok(file) = opened_files.get($fh) {
+                let path = file.original_path().to_owned();
+                drop(opened_files);
+                inject!($self, $method, &path);
+            }
         }
     }};
 }
 
 macro_rules! inject_write_data {
     ($self:ident, $fh:ident, $data:ident) => {{
-        let opened_files = $self.opened_files.read().await;
-        if let Ok(file) = opened_files.get($fh as usize) {
-            let path = file.original_path().to_owned();
-            trace!("Write data before inject {:?}", $data);
-            $self
-                .injector
-                .read()
-                .await
-                .inject_write_data($self.rebuild_path(path)?.as_path(), &mut $data)?;
-            trace!("Write data after inject {:?}", $data);
+        if $self.may_inject(Method::WRITE) {
+            let opened_files = $self.opened_files.shard($fh).read().await;
+            if let Ok(file) = opened_files.get($fh) {
+                let path = file.original_path().to_owned();
+                trace!("Write data before inject {:?}", $data);
+                $self
+                    .injector
+                    .load()
+                    .inject_write_data($self.rebuild_path(path)?.as_path(), &mut $data)?;
+                trace!("Write data after inject {:?}", $data);
+            }
         }
     }};
 }
 
 macro_rules! inject_with_dir_fh {
     ($self:ident, $method:ident, $fh:ident) => {{
-        let opened_dirs = $self.opened_dirs.read().await;
-        if let Ok(dir) = opened_dirs.get($fh as usize) {
-            let path = dir.original_path().to_owned();
-            drop(opened_dirs);
-            inject!($self, $method, &path);
+        if $self.should_inject(Method::$method) {
+            let opened_dirs = $self.opened_dirs.shard($fh).read().await;
+            if let Ok(dir) = opened_dirs.get($fh) {
+                let path = dir.original_path().to_owned();
+                drop(opened_dirs);
+                inject!($self, $method, &path);
+            }
         }
     }};
 }
 
 macro_rules! inject_with_parent_and_name {
     ($self:ident, $method:ident, $parent:ident, $name:expr) => {{
-        let inode_map = $self.inode_map.read().await;
-        if let Ok(parent_path) = inode_map.get_path($parent) {
-            let old_path = parent_path.join($name);
-            trace!("get path: {}", old_path.display());
-            drop(inode_map);
-            inject!($self, $method, old_path.as_path());
+        if $self.should_inject(Method::$method) {
+            let inode_map = $self.inode_map.read().await;
+            let path = inode_map
+                .get_path($parent)
+                .and_then(|parent_path| join_name(parent_path, $name));
+            if let Ok(old_path) = path {
+                trace!("get path: {}", old_path.display());
+                drop(inode_map);
+                inject!($self, $method, old_path.as_path());
+            }
         }
     }};
 }
@@ -115,20 +166,63 @@ macro_rules! inject_with_parent_and_name {
 macro_rules! inject_attr {
     ($self:ident, $attr:ident, $path:expr) => {
         if $self.enable_injection.load(Ordering::SeqCst) {
-            $self
-                .injector
-                .read()
-                .await
-                .inject_attr(&mut $attr, $self.rebuild_path($path)?.as_path());
+            let injector = $self.injector.load();
+            if injector.override_attr() {
+                injector.inject_attr(&mut $attr, $self.rebuild_path($path)?.as_path());
+            }
         }
     };
 }
 
+// inject_after injects after the operation has been done on the backend, e.g. to delay its
+// reply. The locks should be released before, as the injection could take a long time.
+macro_rules! inject_after {
+    ($self:ident, $method:ident, $path:expr) => {
+        if $self.should_inject(Method::$method) {
+            let path = $self.rebuild_path($path)?;
+            let injector = $self.injector.load_full();
+            guard_injection(
+                Method::$method,
+                &path,
+                &$self.shutdown,
+                injector.inject_after(&Method::$method, path.as_path()),
+            )
+            .await?;
+        }
+    };
+}
+
+macro_rules! inject_after_with_ino {
+    ($self:ident, $method:ident, $ino:ident) => {{
+        if $self.should_inject(Method::$method) {
+            let inode_map = $self.inode_map.read().await;
+            if let Ok(path) = inode_map.get_path($ino) {
+                let path = path.to_owned();
+                drop(inode_map);
+                inject_after!($self, $method, &path);
+            }
+        }
+    }};
+}
+
+macro_rules! inject_after_with_fh {
+    ($self:ident, $method:ident, $fh:ident) => {{
+        if $self.should_inject(Method::$method) {
+            let opened_files = $self.opened_files.shard($fh).read().await;
+            if let Ok(file) = opened_files.get($fh) {
+                let path = file.original_path().to_owned();
+                drop(opened_files);
+                inject_after!($self, $method, &path);
+            }
+        }
+    }};
+}
+
 macro_rules! inject_reply {
     ($self:ident, $method:ident, $path:expr, $reply:ident, $reply_typ:ident) => {
-        if $self.enable_injection.load(Ordering::SeqCst) {
+        if $self.should_inject(Method::$method) {
             trace!("before inject {:?}", $reply);
-            $self.injector.read().await.inject_reply(
+            $self.injector.load().inject_reply(
                 &Method::$method,
                 $self.rebuild_path($path)?.as_path(),
                 &mut Reply::$reply_typ(&mut $reply),
@@ -138,6 +232,66 @@ macro_rules! inject_reply {
     };
 }
 
+// the requests closing and syncing the files, which should be replied promptly to quiesce the
+// FUSE before it's unmounted
+const QUIESCING_METHODS: Method = Method::from_bits_truncate(
+    Method::FLUSH.bits()
+        | Method::RELEASE.bits()
+        | Method::RELEASEDIR.bits()
+        | Method::FSYNC.bits()
+        | Method::FSYNCDIR.bits(),
+);
+
+// guard_injection awaits the injection for at most the --injection-timeout, so that an injector
+// which never resolves couldn't hang the request, and the umount after it. The injection timed
+// out is dropped, and the request goes on, or fails with EIO. The injection of a quiescing
+// request is also dropped once the FUSE is shutting down.
+async fn guard_injection<F>(
+    method: Method,
+    path: &Path,
+    shutdown: &CancellationToken,
+    injection: F,
+) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    let injection = async {
+        if !QUIESCING_METHODS.intersects(method) {
+            return injection.await;
+        }
+        select! {
+            result = injection => result,
+            _ = shutdown.cancelled() => {
+                debug!("injection of {:?} on {} bypassed for shutdown", method, path.display());
+                Ok(())
+            }
+        }
+    };
+
+    let options = runtime::options();
+    let limit = match options.injection_timeout() {
+        Some(limit) => limit,
+        None => return injection.await,
+    };
+
+    match timeout(limit, injection).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!(
+                "injection of {:?} on {} timed out after {:?}",
+                method,
+                path.display(),
+                limit
+            );
+            if options.fail_on_injection_timeout {
+                Err(Error::Sys(Errno::EIO))
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct HookFs {
     mount_path: PathBuf,
@@ -145,14 +299,52 @@ pub struct HookFs {
 
     enable_injection: AtomicBool,
 
-    opened_files: RwLock<FhMap<File>>,
+    // cancelled once the FUSE is about to be unmounted, after which the injection is never
+    // enabled again, and the quiescing requests are never injected
+    shutdown: CancellationToken,
+
+    // cancelled once the FUSE session has ended, with the error if it's ended without being
+    // unmounted by toda
+    session_ended: CancellationToken,
+    session_error: std::sync::Mutex<Option<String>>,
+
+    // check the permissions in the daemon instead of the kernel, which honors the POSIX ACLs
+    check_permissions: AtomicBool,
+
+    opened_files: ShardedFhMap<File>,
 
-    opened_dirs: RwLock<FhMap<Dir>>,
+    opened_dirs: ShardedFhMap<Dir>,
 
-    pub injector: RwLock<MultiInjector>,
+    // the injectors are read by every request, and rarely replaced, so they are swapped
+    // atomically instead of being locked
+    injector: ArcSwap<MultiInjector>,
 
     // map from inode to real path
     inode_map: RwLock<InodeMap>,
+
+    io_stats: IoStats,
+
+    // decides the owner of the created files
+    ownership: OwnershipOptions,
+
+    // shifts the owners given by the ownership options, when the workload runs in another user
+    // namespace. The ids of the requests and the replies are shifted by the kernel, as the FUSE is
+    // mounted from the user namespace of toda.
+    id_map: Option<IdMap>,
+
+    // records the operations to a trace, which could be replayed later
+    recorder: Option<Recorder>,
+
+    // keeps the data intended to be written, before it's corrupted by the injectors
+    shadow: Option<Shadow>,
+
+    backend: Backend,
+}
+
+impl Drop for HookFs {
+    fn drop(&mut self) {
+        futures::executor::block_on(self.teardown());
+    }
 }
 
 #[derive(Debug, Default)]
@@ -160,6 +352,10 @@ struct Node {
     pub ref_count: u64,
     // TODO: optimize paths with a combination data structure
     paths: LinkedList<PathBuf>,
+    // the handle finds the inode again after all its paths are renamed on the backend
+    handle: Option<FileHandle>,
+    // the value of the clock of InodeMap when the inode was looked up last time
+    last_access: u64,
 }
 
 impl Node {
@@ -182,40 +378,107 @@ impl Node {
     }
 }
 
-#[derive(Debug, Deref, DerefMut, From)]
-struct InodeMap(HashMap<u64, Node>);
+// the inode of the mount point
+const ROOT_INODE: u64 = 1;
+
+// InodeMap records the paths of the inodes referenced by the kernel. Without a capacity, they are
+// removed once the kernel forgets them. With a capacity, the forgotten inodes are kept together
+// with the ones recorded without being looked up (e.g. the target of a rename), and are evicted
+// from the least recently used one when the map grows over its capacity.
+#[derive(Debug, Default)]
+struct InodeMap {
+    nodes: HashMap<u64, Node>,
+    // the inodes which are not referenced by the kernel, except the root, ordered by their last
+    // access, so that the eviction never goes through the referenced ones
+    unreferenced: BTreeSet<(u64, u64)>,
+    clock: u64,
+    capacity: Option<usize>,
+    evicted: u64,
+}
 
 impl InodeMap {
+    fn new(capacity: Option<usize>) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
     fn get_path(&self, inode: u64) -> Result<&Path> {
-        self.0
+        self.nodes
             .get(&inode)
             .and_then(|item| item.get_path())
             .ok_or(Error::InodeNotFound { inode })
     }
 
     fn increase_ref(&mut self, inode: u64) {
-        if let Some(node) = self.0.get_mut(&inode) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(node) = self.nodes.get_mut(&inode) {
+            if node.ref_count == 0 {
+                self.unreferenced.remove(&(node.last_access, inode));
+            }
             node.ref_count += 1;
+            node.last_access = clock;
         }
     }
 
     fn decrease_ref(&mut self, inode: u64, nlookup: u64) {
-        if let Some(node) = self.0.get_mut(&inode) {
-            if node.ref_count <= nlookup {
-                self.0.remove(&inode);
+        let node = match self.nodes.get_mut(&inode) {
+            Some(node) => node,
+            None => return,
+        };
+        if node.ref_count > nlookup {
+            node.ref_count -= nlookup;
+            return;
+        }
+
+        let referenced = node.ref_count > 0;
+        node.ref_count = 0;
+        let key = (node.last_access, inode);
+        if self.capacity.is_some() {
+            if referenced && inode != ROOT_INODE {
+                self.unreferenced.insert(key);
             }
+            self.evict();
+        } else {
+            self.nodes.remove(&inode);
+            self.unreferenced.remove(&key);
         }
     }
 
     fn insert_path<P: AsRef<Path>>(&mut self, inode: u64, path: P) {
-        self.0
-            .entry(inode)
-            .or_default()
-            .insert(path.as_ref().to_owned());
+        self.clock += 1;
+        let clock = self.clock;
+        let node = self.nodes.entry(inode).or_default();
+        node.insert(path.as_ref().to_owned());
+        if node.ref_count == 0 && inode != ROOT_INODE {
+            self.unreferenced.remove(&(node.last_access, inode));
+            self.unreferenced.insert((clock, inode));
+        }
+        node.last_access = clock;
+    }
+
+    fn get_handle(&self, inode: u64) -> Option<&FileHandle> {
+        self.nodes.get(&inode).and_then(|node| node.handle.as_ref())
+    }
+
+    fn set_handle(&mut self, inode: u64, handle: FileHandle) {
+        if let Some(node) = self.nodes.get_mut(&inode) {
+            node.handle = Some(handle);
+        }
+    }
+
+    // replace_paths replaces all recorded paths of the inode with the current one
+    fn replace_paths(&mut self, inode: u64, path: PathBuf) {
+        if let Some(node) = self.nodes.get_mut(&inode) {
+            node.paths.clear();
+            node.paths.push_back(path);
+        }
     }
 
     fn remove_path<P: AsRef<Path>>(&mut self, inode: u64, path: P) {
-        match self.0.get_mut(&inode) {
+        match self.nodes.get_mut(&inode) {
             Some(set) => {
                 set.remove(path.as_ref());
             }
@@ -224,19 +487,206 @@ impl InodeMap {
             }
         }
     }
+
+    // evict removes the least recently used inodes which are not referenced by the kernel, until
+    // the map fits in its capacity. The referenced ones and the root are always kept, as the
+    // kernel could send requests on them at any time.
+    fn evict(&mut self) {
+        let capacity = match self.capacity {
+            Some(capacity) if self.nodes.len() > capacity => capacity,
+            _ => return,
+        };
+
+        let mut count = 0;
+        while self.nodes.len() > capacity {
+            let key = match self.unreferenced.iter().next() {
+                Some(key) => *key,
+                None => break,
+            };
+            self.unreferenced.remove(&key);
+            self.nodes.remove(&key.1);
+            count += 1;
+        }
+        self.evicted += count;
+        trace!("evict {} unreferenced inodes", count);
+    }
+
+    // stats counts the inodes and their paths, together with the `n` directories containing the
+    // most inodes
+    fn stats(&self, n: usize) -> InodeStats {
+        let mut directories: HashMap<&Path, usize> = HashMap::new();
+        let mut paths = 0;
+        for node in self.nodes.values() {
+            paths += node.paths.len();
+            for parent in node.paths.iter().filter_map(|path| path.parent()) {
+                *directories.entry(parent).or_default() += 1;
+            }
+        }
+
+        let mut top_directories: Vec<_> = directories
+            .into_iter()
+            .map(|(path, inodes)| DirectoryInodes {
+                path: path.to_owned(),
+                inodes,
+            })
+            .collect();
+        top_directories.sort_by_key(|dir| std::cmp::Reverse(dir.inodes));
+        top_directories.truncate(n);
+
+        InodeStats {
+            inodes: self.nodes.len(),
+            paths,
+            unreferenced: self.unreferenced.len(),
+            evicted: self.evicted,
+            capacity: self.capacity,
+            top_directories,
+        }
+    }
+}
+
+// InodeStats is the size of the inode map reported through the control API
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InodeStats {
+    pub inodes: usize,
+    pub paths: usize,
+    // the inodes recorded without being referenced by the kernel, which could be evicted
+    pub unreferenced: usize,
+    pub evicted: u64,
+    pub capacity: Option<usize>,
+    pub top_directories: Vec<DirectoryInodes>,
+}
+
+// HandleStats is the count of the files and the directories opened on the backend, reported
+// through the control API
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HandleStats {
+    pub files: usize,
+    pub dirs: usize,
+    pub max_files: Option<usize>,
+    pub max_dirs: Option<usize>,
+    // the opens failed with EMFILE by the limits
+    pub rejected: u64,
+}
+
+// DirectoryInodes is the count of the inodes recorded under a directory
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectoryInodes {
+    pub path: PathBuf,
+    pub inodes: usize,
+}
+
+// the count of the shards of the opened files and directories
+const FH_SHARDS: usize = 16;
+
+// ShardedFhMap spreads the opened handles over several locks, so that the requests on unrelated
+// handles don't contend on a single lock. The shard of a handle is encoded in its lowest bits.
+#[derive(Debug)]
+struct ShardedFhMap<T> {
+    shards: Vec<RwLock<FhMap<T>>>,
+    next: AtomicUsize,
+    // the count of the handles, including the ones being opened, and the opens rejected by the
+    // limit
+    len: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+// FhReservation counts a handle being opened on the backend, until it's inserted into the map, or
+// dropped after the open failed
+struct FhReservation<'a, T> {
+    map: &'a ShardedFhMap<T>,
+    inserted: bool,
+}
+
+impl<'a, T> FhReservation<'a, T> {
+    async fn insert(mut self, item: T) -> u64 {
+        self.inserted = true;
+        self.map.insert(item).await
+    }
+}
+
+impl<'a, T> Drop for FhReservation<'a, T> {
+    fn drop(&mut self) {
+        if !self.inserted {
+            self.map.closed();
+        }
+    }
+}
+
+impl<T> ShardedFhMap<T> {
+    fn new() -> Self {
+        Self {
+            shards: (0..FH_SHARDS)
+                .map(|_| RwLock::new(FhMap::from(Slab::new())))
+                .collect(),
+            next: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    // reserve counts a handle before it's opened on the backend, or fails with EMFILE if there are
+    // already `limit` handles, so that toda never runs out of fds itself
+    fn reserve(&self, limit: Option<usize>) -> Result<FhReservation<'_, T>> {
+        let len = self.len.fetch_add(1, Ordering::SeqCst);
+        if limit.map_or(false, |limit| len >= limit) || runtime::fd_pressure() {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::Sys(Errno::EMFILE));
+        }
+
+        Ok(FhReservation {
+            map: self,
+            inserted: false,
+        })
+    }
+
+    // closed uncounts a handle removed from its shard
+    fn closed(&self) {
+        self.len.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    // shard returns the shard which contains the handle
+    fn shard(&self, fh: u64) -> &RwLock<FhMap<T>> {
+        &self.shards[fh as usize % FH_SHARDS]
+    }
+
+    async fn insert(&self, item: T) -> u64 {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % FH_SHARDS;
+        let key = self.shards[index].write().await.insert(item);
+
+        (key * FH_SHARDS + index) as u64
+    }
 }
 
 #[derive(Debug, Deref, DerefMut, From)]
 struct FhMap<T>(Slab<T>);
 
 impl<T> FhMap<T> {
-    fn get(&self, key: usize) -> Result<&T> {
-        self.0.get(key).ok_or(Error::FhNotFound { fh: key as u64 })
+    fn get(&self, fh: u64) -> Result<&T> {
+        self.0
+            .get(fh as usize / FH_SHARDS)
+            .ok_or(Error::FhNotFound { fh })
     }
-    fn get_mut(&mut self, key: usize) -> Result<&mut T> {
+    fn get_mut(&mut self, fh: u64) -> Result<&mut T> {
         self.0
-            .get_mut(key)
-            .ok_or(Error::FhNotFound { fh: key as u64 })
+            .get_mut(fh as usize / FH_SHARDS)
+            .ok_or(Error::FhNotFound { fh })
+    }
+    // remove returns `None` if the handle has gone, e.g. it's released after the teardown
+    fn remove(&mut self, fh: u64) -> Option<T> {
+        let key = fh as usize / FH_SHARDS;
+        if self.0.contains(key) {
+            Some(self.0.remove(key))
+        } else {
+            None
+        }
     }
 }
 
@@ -276,6 +726,8 @@ impl std::ops::DerefMut for Dir {
 pub struct File {
     pub fd: RawFd,
     original_path: PathBuf,
+    // whether the file is a FIFO, which is read and written without offsets
+    stream: bool,
 }
 
 impl File {
@@ -283,6 +735,13 @@ impl File {
         File {
             fd,
             original_path: path.as_ref().to_owned(),
+            stream: false,
+        }
+    }
+    fn new_stream<P: AsRef<Path>>(fd: RawFd, path: P) -> File {
+        File {
+            stream: true,
+            ..File::new(fd, path)
         }
     }
     fn original_path(&self) -> &Path {
@@ -290,6 +749,92 @@ impl File {
     }
 }
 
+// Stream is a duplicate of the fd of an opened FIFO, through which it's read and written without
+// holding the shard of its handle, as it could wait for the other end for an unbounded time. The
+// fd never blocks, and a blocking caller polls it with a backoff until it's ready, instead of
+// holding a blocking thread.
+struct Stream {
+    fd: RawFd,
+}
+
+impl Stream {
+    fn dup(fd: RawFd) -> Result<Stream> {
+        let fd = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(0))?;
+        Ok(Stream { fd })
+    }
+
+    // wait returns once the fd is ready for the events, or hung up. It fails with EINTR once toda
+    // is shutting down.
+    async fn wait(&self, events: PollFlags, shutdown: &CancellationToken) -> Result<()> {
+        let mut delay = FIFO_RETRY_MIN;
+        loop {
+            let mut fds = [PollFd::new(self.fd, events)];
+            if poll(&mut fds, 0)? > 0 {
+                return Ok(());
+            }
+            if shutdown.is_cancelled() {
+                return Err(Error::Sys(Errno::EINTR));
+            }
+
+            delay_for(delay).await;
+            delay = min(delay * 2, FIFO_RETRY_MAX);
+        }
+    }
+
+    // read reads from the FIFO. A FIFO without any writer reads as EOF at once, so a blocking
+    // reader waits for a writer first, until there is data, or all the writers have closed it.
+    async fn read(
+        &self,
+        count: usize,
+        nonblock: bool,
+        shutdown: &CancellationToken,
+    ) -> Result<Vec<u8>> {
+        let mut buf = vec![0; count];
+        loop {
+            if !nonblock {
+                self.wait(PollFlags::POLLIN, shutdown).await?;
+            }
+            match unistd::read(self.fd, &mut buf) {
+                Err(nix::Error::Sys(Errno::EAGAIN)) if !nonblock => continue,
+                result => {
+                    buf.truncate(result?);
+                    return Ok(buf);
+                }
+            }
+        }
+    }
+
+    // write writes to the FIFO. A blocking writer writes all the data, as a blocking write does.
+    async fn write(
+        &self,
+        data: &[u8],
+        nonblock: bool,
+        shutdown: &CancellationToken,
+    ) -> Result<isize> {
+        if nonblock {
+            return Ok(unistd::write(self.fd, data)? as isize);
+        }
+
+        let mut written = 0;
+        while written < data.len() {
+            self.wait(PollFlags::POLLOUT, shutdown).await?;
+            match unistd::write(self.fd, &data[written..]) {
+                Err(nix::Error::Sys(Errno::EAGAIN)) => {}
+                result => written += result?,
+            }
+        }
+        Ok(written as isize)
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Err(err) = close(self.fd) {
+            warn!("fail to close the duplicated fifo: {}", err);
+        }
+    }
+}
+
 unsafe impl Send for Dir {}
 unsafe impl Sync for Dir {}
 
@@ -298,51 +843,413 @@ impl HookFs {
         mount_path: P1,
         original_path: P2,
         injector: MultiInjector,
-    ) -> HookFs {
-        let mut inode_map = InodeMap::from(HashMap::new());
-        inode_map.insert_path(1, original_path.as_ref());
+    ) -> Result<HookFs> {
+        let mut inode_map = InodeMap::new(runtime::options().max_inodes);
+        inode_map.insert_path(ROOT_INODE, original_path.as_ref());
 
         let inode_map = RwLock::new(inode_map);
 
-        HookFs {
+        let backend = Backend::open(original_path.as_ref())?;
+
+        Ok(HookFs {
             mount_path: mount_path.as_ref().to_owned(),
             original_path: original_path.as_ref().to_owned(),
-            opened_files: RwLock::new(FhMap::from(Slab::new())),
-            opened_dirs: RwLock::new(FhMap::from(Slab::new())),
-            injector: RwLock::new(injector),
+            opened_files: ShardedFhMap::new(),
+            opened_dirs: ShardedFhMap::new(),
+            injector: ArcSwap::from_pointee(injector),
             inode_map,
             enable_injection: AtomicBool::from(false),
-        }
+            shutdown: CancellationToken::new(),
+            session_ended: CancellationToken::new(),
+            session_error: std::sync::Mutex::new(None),
+            check_permissions: AtomicBool::from(false),
+            io_stats: IoStats::default(),
+            ownership: OwnershipOptions::default(),
+            id_map: None,
+            recorder: None,
+            shadow: None,
+            backend,
+        })
+    }
+
+    pub fn with_ownership(mut self, ownership: OwnershipOptions) -> HookFs {
+        self.ownership = ownership;
+        self
+    }
+
+    pub fn with_id_map(mut self, id_map: IdMap) -> HookFs {
+        self.id_map = Some(id_map);
+        self
+    }
+
+    pub fn with_recorder(mut self, recorder: Recorder) -> HookFs {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub fn with_shadow(mut self, shadow: Shadow) -> HookFs {
+        self.shadow = Some(shadow);
+        self
     }
 
     pub fn enable_injection(&self) {
+        if self.shutdown.is_cancelled() {
+            warn!("FUSE is shutting down, the injection is not enabled again");
+            return;
+        }
         self.enable_injection.store(true, Ordering::SeqCst);
     }
 
     pub fn disable_injection(&self) {
         self.enable_injection.store(false, Ordering::SeqCst);
+        self.injector.load().interrupt();
+    }
+
+    // shutdown disables the injection for good before the FUSE is unmounted. The flushes, the
+    // releases and the fsyncs which are being injected are let through at once, even if their
+    // injectors ignore the interruption, so that the umount is not kept busy by them.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+        self.disable_injection();
+    }
+
+    // end_session records how the FUSE session has ended. Unless the FUSE is being unmounted by
+    // toda, the session is lost, e.g. the connection is aborted by the kernel or it's unmounted by
+    // others, and the injection is disabled for good.
+    pub fn end_session(&self, result: &std::io::Result<()>) {
+        if !self.shutdown.is_cancelled() {
+            let err = match result {
+                Ok(_) => "FUSE is unmounted out of toda".to_owned(),
+                Err(err) => err.to_string(),
+            };
+            error!("FUSE session is lost: {}", err);
+            *self.session_error.lock().unwrap() = Some(err);
+            self.shutdown();
+        }
+        self.session_ended.cancel();
+    }
+
+    // session_ended waits until the FUSE session has ended, however it's ended
+    pub async fn session_ended(&self) {
+        self.session_ended.cancelled().await
+    }
+
+    // session_error returns why the FUSE session is lost, if it's ended without being unmounted by
+    // toda
+    pub fn session_error(&self) -> Option<String> {
+        self.session_error.lock().unwrap().clone()
+    }
 
-        // TODO: create a standalone runtime only for interrupt is too ugly.
-        //       this RWLock is actually redundant, and the injector is rarely written.
-        let mut rt  = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(async {
-            let injector = self.injector.read().await;
-            injector.interrupt();
-        });
+    pub fn injection_enabled(&self) -> bool {
+        self.enable_injection.load(Ordering::SeqCst)
+    }
+
+    // enable_permission_check makes the daemon check the permissions of the requests, which should
+    // be enabled when the FUSE is mounted without `default_permissions`
+    pub fn enable_permission_check(&self) {
+        self.check_permissions.store(true, Ordering::SeqCst);
+    }
+
+    // set_injector replaces the current injectors. The requests still blocked by the former ones
+    // are interrupted, as they would never be cancelled otherwise.
+    pub fn set_injector(&self, injector: MultiInjector) {
+        self.injector.swap(Arc::new(injector)).interrupt();
+    }
+
+    // should_inject returns whether the injection is enabled, and any injector could affect the
+    // method
+    fn should_inject(&self, method: Method) -> bool {
+        self.enable_injection.load(Ordering::SeqCst) && self.may_inject(method)
+    }
+
+    fn may_inject(&self, method: Method) -> bool {
+        self.injector.load().methods().intersects(method)
+    }
+
+    // hot_files returns the I/O statistics of the `n` most frequently accessed paths
+    pub fn hot_files(&self, n: usize) -> Vec<HotFile> {
+        self.io_stats.top(n)
+    }
+
+    // teardown closes the files and the directories left open on the backend, which are never
+    // released by the kernel once the FUSE session has ended, e.g. after the FUSE is aborted. It
+    // waits for the requests still using them.
+    pub async fn teardown(&self) {
+        let mut files = 0;
+        for shard in self.opened_files.shards.iter() {
+            for (_, file) in shard.write().await.drain() {
+                self.opened_files.closed();
+                if let Err(err) = close(file.fd) {
+                    warn!(
+                        "fail to close {} on the backend: {}",
+                        file.original_path().display(),
+                        err
+                    );
+                }
+                files += 1;
+            }
+        }
+
+        let mut dirs = 0;
+        for shard in self.opened_dirs.shards.iter() {
+            // the directories are closed when they're dropped
+            for _ in shard.write().await.drain() {
+                self.opened_dirs.closed();
+                dirs += 1;
+            }
+        }
+
+        if files > 0 || dirs > 0 {
+            warn!(
+                "closed {} files and {} directories left open on the backend",
+                files, dirs
+            );
+        }
+    }
+
+    // unsupported_ops returns the count of the requests of every operation toda doesn't implement,
+    // since the mount
+    pub fn unsupported_ops(&self) -> BTreeMap<String, u64> {
+        OP_STATS.unsupported()
+    }
+
+    // backend_lost returns whether the backend has gone away, after which all the requests fail
+    pub fn backend_lost(&self) -> bool {
+        self.backend.is_lost()
+    }
+
+    // handle_stats returns the count of the handles opened on the backend, and their limits
+    pub fn handle_stats(&self) -> HandleStats {
+        let options = runtime::options();
+        HandleStats {
+            files: self.opened_files.len(),
+            dirs: self.opened_dirs.len(),
+            max_files: options.max_open_files,
+            max_dirs: options.max_open_dirs,
+            rejected: self.opened_files.rejected.load(Ordering::Relaxed)
+                + self.opened_dirs.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    // inode_stats returns the size of the inode map, and the `n` directories under the mount point
+    // containing the most inodes
+    pub async fn inode_stats(&self, n: usize) -> InodeStats {
+        let mut stats = self.inode_map.read().await.stats(n);
+        for dir in stats.top_directories.iter_mut() {
+            if let Ok(path) = self.rebuild_path(&dir.path) {
+                dir.path = path;
+            }
+        }
+        stats
     }
 
     pub fn rebuild_path<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
         let path_tail = path.as_ref().strip_prefix(self.original_path.as_path())?;
+        check_normal(path_tail)?;
         let path = self.mount_path.join(path_tail);
 
         Ok(path)
     }
+
+    // public_link_target rewrites an absolute symlink target under the original path, which is
+    // hidden during the injection, to the same file under the mount path. The links created while
+    // the original path is moved would otherwise leak the hidden path to the workload, and break
+    // once it's moved back.
+    fn public_link_target(&self, target: PathBuf) -> PathBuf {
+        match target.strip_prefix(&self.original_path) {
+            Ok(tail) => {
+                let rewritten = self.mount_path.join(tail);
+                trace!(
+                    "rewrite link target {} => {}",
+                    target.display(),
+                    rewritten.display()
+                );
+                rewritten
+            }
+            Err(_) => target,
+        }
+    }
 }
 
 impl HookFs {
+    // record appends the operation received at `received` to the trace, if it's being recorded
+    fn record(&self, operation: Operation, received: Instant) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(operation, received);
+        }
+    }
+
+    // insert_inode records the path of a looked up inode, together with its file handle
+    async fn insert_inode(&self, inode_map: &mut InodeMap, inode: u64, path: PathBuf) {
+        inode_map.insert_path(inode, path.clone());
+        inode_map.increase_ref(inode);
+        if inode_map.get_handle(inode).is_none() {
+            if let Some(handle) = self.backend.handle(&path).await {
+                inode_map.set_handle(inode, handle);
+            }
+        }
+        inode_map.evict();
+    }
+
+    // refresh_path finds the current path of an inode through its file handle, after its
+    // recorded paths have been renamed or removed on the backend directly
+    async fn refresh_path(&self, inode: u64) -> Result<PathBuf> {
+        let mut inode_map = self.inode_map.write().await;
+        let handle = inode_map
+            .get_handle(inode)
+            .cloned()
+            .ok_or(Error::Sys(Errno::ENOENT))?;
+        let path = self.backend.resolve(&handle).await?;
+        trace!("inode {} is found at {}", inode, path.display());
+        inode_map.replace_paths(inode, path.clone());
+
+        Ok(path)
+    }
+
+    // requester returns the caller of the current request if the daemon checks the permissions
+    fn requester(&self) -> Option<Requester> {
+        if self.check_permissions.load(Ordering::SeqCst) {
+            Requester::current()
+        } else {
+            None
+        }
+    }
+
+    // set_owner sets the owner of a created file, which is the caller unless it's overridden by
+    // the ownership options. The caller is already in the ids of toda, while the overriding owner
+    // is given in the ids of the workload if there is an id map.
+    async fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> Result<()> {
+        let owner = match self.ownership.configured_owner(&self.rebuild_path(path)?) {
+            Some(owner) => match &self.id_map {
+                Some(id_map) => Owner {
+                    uid: id_map.uid_to_backend(owner.uid),
+                    gid: id_map.gid_to_backend(owner.gid),
+                },
+                None => owner,
+            },
+            None => Owner { uid, gid },
+        };
+        trace!("setting owner {}:{}", owner.uid, owner.gid);
+
+        self.backend
+            .lchown(path, Some(owner.uid), Some(owner.gid))
+            .await
+            .context("lchown", path)
+    }
+
+    // check_permission runs the check with the attributes and the access ACL of the path, if the
+    // daemon checks the permissions. The attributes are the injected ones, which are also seen by
+    // the kernel, so that the decisions are consistent with `default_permissions`.
+    async fn check_permission<F>(&self, path: &Path, check: F) -> Result<()>
+    where
+        F: FnOnce(&Requester, &FileAttr, Option<&Acl>) -> Result<()>,
+    {
+        let requester = match self.requester() {
+            Some(requester) => requester,
+            None => return Ok(()),
+        };
+
+        let attr = self.get_file_attr(path).await?;
+        let acl = self.get_acl(path).await?;
+
+        check(&requester, &attr, acl.as_ref())
+    }
+
+    async fn check_access(&self, path: &Path, mask: u32) -> Result<()> {
+        self.check_permission(path, |requester, attr, acl| {
+            permission::check_access(requester, attr, acl, mask)
+        })
+        .await
+    }
+
+    // check_parent checks whether the requester is permitted to add or remove entries in the
+    // parent directory of the path
+    async fn check_parent(&self, path: &Path) -> Result<()> {
+        let parent = path.parent().unwrap_or(path);
+        self.check_access(parent, (libc::W_OK | libc::X_OK) as u32)
+            .await
+    }
+
+    // check_remove checks whether the requester is permitted to remove the path from its parent
+    // directory, which also respects the sticky bit of the directory
+    async fn check_remove(&self, path: &Path) -> Result<()> {
+        let requester = match self.requester() {
+            Some(requester) => requester,
+            None => return Ok(()),
+        };
+
+        self.check_parent(path).await?;
+        let parent = path.parent().unwrap_or(path);
+        let dir = self.get_file_attr(parent).await?;
+        let attr = self.get_file_attr(path).await?;
+
+        permission::check_sticky(&requester, &dir, &attr)
+    }
+
+    // check_xattr checks whether the requester is permitted to set or remove the xattr
+    async fn check_xattr(&self, path: &Path, name: &OsStr) -> Result<()> {
+        let name = name.to_str().ok_or(Error::InvalidStr)?;
+        self.check_permission(path, |requester, attr, acl| {
+            permission::check_xattr(requester, attr, acl, name)
+        })
+        .await
+    }
+
+    // get_acl returns the access ACL of the path, or `None` if it doesn't have one
+    async fn get_acl(&self, path: &Path) -> Result<Option<Acl>> {
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let name = CString::new(permission::ACL_ACCESS_XATTR)?;
+
+        let size = match async_getxattr(cpath.clone(), name.clone(), 0).await {
+            Ok(data) => data.len(),
+            Err(err) if matches!(err.errno(), Errno::ENODATA | Errno::EOPNOTSUPP) => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.context("getxattr", path)),
+        };
+        let data = async_getxattr(cpath, name, size)
+            .await
+            .context("getxattr", path)?;
+
+        Ok(Some(Acl::parse(&data)?))
+    }
+
+    // open_file opens the file on the backend, and returns whether it's a FIFO. A blocking open of
+    // a FIFO waits until the other end is opened, and would hold a blocking thread for an unbounded
+    // time, so it's opened without blocking instead. The writing end fails with ENXIO until there
+    // is a reader, which is retried here. The reading end is opened at once, and its blocking reads
+    // wait for a writer instead. The fd is kept non-blocking, see `Stream`.
+    async fn open_file(&self, path: &Path, flags: OFlag) -> Result<(RawFd, bool)> {
+        let mode = stat::Mode::S_IRWXU;
+        let stat = self.backend.stat(path).await.context("stat", path)?;
+        if stat.st_mode & libc::S_IFMT != libc::S_IFIFO {
+            let fd = self.backend.open(path, flags, mode).await;
+            return Ok((fd.context("open", path)?, false));
+        }
+
+        let nonblock = flags.contains(OFlag::O_NONBLOCK);
+        let fifo_flags = flags | OFlag::O_NONBLOCK;
+        let mut delay = FIFO_RETRY_MIN;
+        let fd = loop {
+            match self.backend.open(path, fifo_flags, mode).await {
+                Err(err) if err.errno() == Errno::ENXIO && !nonblock => {
+                    trace!("wait for a reader of fifo {}", path.display());
+                    delay_for(delay).await;
+                    delay = min(delay * 2, FIFO_RETRY_MAX);
+                }
+                result => break result.context("open", path)?,
+            }
+        };
+
+        Ok((fd, true))
+    }
+
     async fn get_file_attr(&self, path: &Path) -> Result<FileAttr> {
-        let mut attr = async_stat(path)
+        let mut attr = self
+            .backend
+            .stat(path)
             .await
+            .context("stat", path)
             .map(convert_libc_stat_to_fuse_stat)??;
 
         trace!("before inject attr {:?}", &attr);
@@ -355,35 +1262,90 @@ impl HookFs {
 
 #[async_trait]
 impl AsyncFileSystemImpl for HookFs {
-    fn init(&self) -> Result<()> {
+    fn init(&self, config: &mut KernelConfig) -> Result<()> {
         trace!("init");
 
         stat::umask(stat::Mode::from_bits_truncate(0));
 
+        // fuser only requests the capabilities it implements, so the kernel never enables the
+        // features toda doesn't serve. The operations the kernel sends regardless, like ioctl and
+        // fallocate, are replied with ENOSYS and counted in `unsupported_ops`, after which the
+        // kernel stops sending most of them.
+
+        // the kernel keeps its defaults if the values are not accepted, which are replied back
+        let options = runtime::options();
+        if let Some(max_background) = options.max_background {
+            if let Err(nearest) = config.set_max_background(max_background) {
+                warn!(
+                    "max_background {} is not accepted, the nearest one is {}",
+                    max_background, nearest
+                );
+            }
+        }
+        if let Some(threshold) = options.congestion_threshold {
+            if let Err(nearest) = config.set_congestion_threshold(threshold) {
+                warn!(
+                    "congestion_threshold {} is not accepted, the nearest one is {}",
+                    threshold, nearest
+                );
+            }
+        }
+
+        runtime::spawn(runtime::watch_resources());
+        if let Some(interval) = options.report_interval() {
+            runtime::spawn(async move {
+                loop {
+                    delay_for(interval).await;
+                    OP_STATS.report(interval);
+                }
+            });
+        }
+
         Ok(())
     }
 
     fn destroy(&self) {
         trace!("destroy");
+        if let Some(recorder) = &self.recorder {
+            recorder.flush();
+        }
+        if let Some(shadow) = &self.shadow {
+            shadow.save();
+        }
+    }
+
+    fn available(&self, method: Method) -> Result<()> {
+        // the handles are still released, so that the lost backend is not kept busy by them
+        if self.backend.is_lost() && !(Method::RELEASE | Method::RELEASEDIR).contains(method) {
+            return Err(Error::BackendLost);
+        }
+        Ok(())
+    }
+
+    async fn inspect_error(&self, err: &Error) {
+        self.backend.check_lost(err).await;
     }
 
     #[instrument(skip(self))]
     async fn lookup(&self, parent: u64, name: OsString) -> Result<Entry> {
         trace!("lookup");
+        let received = Instant::now();
         inject_with_parent_and_name!(self, LOOKUP, parent, &name);
 
         let mut inode_map = self.inode_map.write().await;
         let path = {
             let parent_path = inode_map.get_path(parent)?;
-            parent_path.join(name)
+            join_name(parent_path, &name)?
         };
         trace!("lookup in {}", path.display());
 
+        self.check_access(path.parent().unwrap_or(&path), libc::X_OK as u32)
+            .await?;
         let stat = self.get_file_attr(&path).await?;
 
         trace!("insert ({}, {}) into inode_map", stat.ino, path.display());
-        inode_map.insert_path(stat.ino, path.clone());
-        inode_map.increase_ref(stat.ino);
+        self.insert_inode(&mut inode_map, stat.ino, path.clone())
+            .await;
         // TODO: support generation number
         // this can be implemented with ioctl FS_IOC_GETVERSION
         trace!("return with {:?}", stat);
@@ -391,31 +1353,56 @@ impl AsyncFileSystemImpl for HookFs {
         let mut reply = Entry::new(stat, 0);
         inject_reply!(self, LOOKUP, path.as_path(), reply, Entry);
 
+        self.record(Operation::new(Method::LOOKUP, &path), received);
+
+        // the kernel never forgets a negative entry, so it's not referenced
+        if reply.stat.ino == 0 {
+            inode_map.decrease_ref(stat.ino, 1);
+        }
+
         Ok(reply)
     }
 
     #[instrument(skip(self))]
     async fn forget(&self, ino: u64, nlookup: u64) {
         trace!("forget");
+        // a forget is never replied, so only the latency applies
+        let injected: Result<()> = async {
+            inject_with_ino!(self, FORGET, ino);
+            Ok(())
+        }
+        .await;
+        if let Err(err) = injected {
+            debug!("ignore the fault injected into forget: {}", err);
+        }
+
         self.inode_map.write().await.decrease_ref(ino, nlookup)
     }
 
     #[instrument(skip(self))]
     async fn getattr(&self, ino: u64) -> Result<Attr> {
         trace!("getattr");
+        let received = Instant::now();
 
         inject_with_ino!(self, GETATTR, ino);
 
-        let inode_map = self.inode_map.read().await;
-        let path = inode_map.get_path(ino)?;
+        let path = self.inode_map.read().await.get_path(ino)?.to_owned();
         trace!("getting attr from path {}", path.display());
-        let stat = self.get_file_attr(path).await?;
+        let (path, stat) = match self.get_file_attr(&path).await {
+            Err(err) if err.errno() == Errno::ENOENT => {
+                let path = self.refresh_path(ino).await?;
+                let stat = self.get_file_attr(&path).await?;
+                (path, stat)
+            }
+            result => (path, result?),
+        };
 
         trace!("return with {:?}", stat);
 
         let mut reply = Attr::new(stat);
         inject_reply!(self, GETATTR, path, reply, Attr);
 
+        self.record(Operation::new(Method::GETATTR, &path), received);
         Ok(reply)
     }
 
@@ -437,6 +1424,7 @@ impl AsyncFileSystemImpl for HookFs {
         _flags: Option<u32>,
     ) -> Result<Attr> {
         trace!("setattr");
+        let received = Instant::now();
         inject_with_ino!(self, SETATTR, ino);
 
         // TODO: support setattr with fh
@@ -444,25 +1432,68 @@ impl AsyncFileSystemImpl for HookFs {
         let inode_map = self.inode_map.read().await;
         let path = inode_map.get_path(ino)?;
 
-        async_lchown(path, uid, gid).await?;
+        if mode.is_some() {
+            self.check_permission(path, |requester, attr, _| {
+                permission::check_owner(requester, attr)
+            })
+            .await?;
+        }
+        if uid.is_some() || gid.is_some() {
+            self.check_permission(path, |requester, attr, _| {
+                permission::check_chown(requester, attr, uid, gid)
+            })
+            .await?;
+        }
+        if size.is_some() {
+            self.check_access(path, libc::W_OK as u32).await?;
+        }
+        if atime.is_some() || mtime.is_some() {
+            self.check_permission(path, |requester, attr, acl| {
+                permission::check_owner(requester, attr)
+                    .or_else(|_| permission::check_access(requester, attr, acl, libc::W_OK as u32))
+            })
+            .await?;
+        }
+
+        self.backend
+            .lchown(path, uid, gid)
+            .await
+            .context("lchown", path)?;
 
         if let Some(mode) = mode {
-            async_fchmodat(path, mode).await?;
+            self.backend
+                .chmod(path, mode)
+                .await
+                .context("chmod", path)?;
         }
 
         if let Some(size) = size {
-            async_truncate(path, size as i64).await?;
+            self.backend
+                .truncate(path, size as i64)
+                .await
+                .context("truncate", path)?;
+            if let Some(shadow) = &self.shadow {
+                shadow.truncate(path, size);
+            }
         }
 
         let times = [convert_time(atime), convert_time(mtime)];
-        let cpath = CString::new(path.as_os_str().as_bytes())?;
-        async_utimensat(cpath, times).await?;
+        self.backend
+            .utimens(path, times)
+            .await
+            .context("utimens", path)?;
 
         let stat = self.get_file_attr(path).await?;
         trace!("return with {:?}", stat);
         let mut reply = Attr::new(stat);
         inject_reply!(self, GETATTR, path, reply, Attr);
 
+        if let Some(size) = size {
+            self.record(Operation::new(Method::SETATTR, path).size(size), received);
+        }
+
+        drop(inode_map);
+        inject_after_with_ino!(self, SETATTR, ino);
         Ok(reply)
     }
 
@@ -474,7 +1505,12 @@ impl AsyncFileSystemImpl for HookFs {
         let inode_map = self.inode_map.read().await;
         let link_path = inode_map.get_path(ino)?;
 
-        let path = async_readlink(link_path).await?;
+        let path = self
+            .backend
+            .readlink(link_path)
+            .await
+            .context("readlink", link_path)?;
+        let path = self.public_link_target(path);
 
         let path = CString::new(path.as_os_str().as_bytes())?;
 
@@ -499,25 +1535,33 @@ impl AsyncFileSystemImpl for HookFs {
         gid: u32,
     ) -> Result<Entry> {
         trace!("mknod");
+        let received = Instant::now();
         inject_with_parent_and_name!(self, MKNOD, parent, &name);
 
         let mut inode_map = self.inode_map.write().await;
         let parent_path = inode_map.get_path(parent)?;
-        let path = parent_path.join(&name);
+        let path = join_name(parent_path, &name)?;
         inject!(self, MKNOD, path.as_path());
-        let cpath = CString::new(path.as_os_str().as_bytes())?;
 
-        trace!("mknod for {:?}", cpath);
+        trace!("mknod for {}", path.display());
+        self.check_parent(&path).await?;
 
-        async_mknod(cpath, mode, rdev as u64).await?;
-        async_lchown(&path, Some(uid), Some(gid)).await?;
+        self.backend
+            .mknod(&path, mode, rdev as u64)
+            .await
+            .context("mknod", &path)?;
+        self.set_owner(&path, uid, gid).await?;
 
         let stat = self.get_file_attr(&path).await?;
-        inode_map.insert_path(stat.ino, path.clone());
-        inode_map.increase_ref(stat.ino);
+        self.insert_inode(&mut inode_map, stat.ino, path.clone())
+            .await;
         let mut reply = Entry::new(stat, 0);
-        inject_reply!(self, LOOKUP, path.as_path(), reply, Entry);
+        inject_reply!(self, MKNOD, path.as_path(), reply, Entry);
+
+        self.record(Operation::new(Method::MKNOD, &path), received);
 
+        drop(inode_map);
+        inject_after!(self, MKNOD, &path);
         Ok(reply)
     }
 
@@ -532,70 +1576,94 @@ impl AsyncFileSystemImpl for HookFs {
         gid: u32,
     ) -> Result<Entry> {
         trace!("mkdir");
+        let received = Instant::now();
         inject_with_parent_and_name!(self, MKDIR, parent, &name);
 
         let mut inode_map = self.inode_map.write().await;
         let path = {
             let parent_path = inode_map.get_path(parent)?;
-            parent_path.join(&name)
+            join_name(parent_path, &name)?
         };
 
+        self.check_parent(&path).await?;
+
         let mode = stat::Mode::from_bits_truncate(mode);
         trace!("create directory with mode: {:?}", mode);
-        async_mkdir(&path, mode).await?;
-        trace!("setting owner {}:{}", uid, gid);
-        async_lchown(&path, Some(uid), Some(gid)).await?;
+        self.backend
+            .mkdir(&path, mode)
+            .await
+            .context("mkdir", &path)?;
+        self.set_owner(&path, uid, gid).await?;
 
         let stat = self.get_file_attr(&path).await?;
-        inode_map.insert_path(stat.ino, path.clone());
-        inode_map.increase_ref(stat.ino);
+        self.insert_inode(&mut inode_map, stat.ino, path.clone())
+            .await;
         let mut reply = Entry::new(stat, 0);
-        inject_reply!(self, LOOKUP, path.as_path(), reply, Entry);
+        inject_reply!(self, MKDIR, path.as_path(), reply, Entry);
+
+        self.record(Operation::new(Method::MKDIR, &path), received);
 
+        drop(inode_map);
+        inject_after!(self, MKDIR, &path);
         Ok(reply)
     }
 
     #[instrument(skip(self))]
     async fn unlink(&self, parent: u64, name: OsString) -> Result<()> {
         trace!("unlink");
+        let received = Instant::now();
         inject_with_parent_and_name!(self, UNLINK, parent, &name);
 
         let mut inode_map = self.inode_map.write().await;
         let path = {
             let parent_path = inode_map.get_path(parent)?;
-            parent_path.join(name)
+            join_name(parent_path, &name)?
         };
 
+        self.check_remove(&path).await?;
         let stat = self.get_file_attr(&path).await?;
 
         trace!("unlinking {}", path.display());
-        async_unlink(&path).await?;
+        self.backend.unlink(&path).await.context("unlink", &path)?;
+        // the other links of the file are not followed by the shadow
+        if let Some(shadow) = &self.shadow {
+            shadow.remove(&path);
+        }
 
         trace!("remove {:x} from inode_map", &stat.ino);
         inode_map.remove_path(stat.ino, &path);
 
+        self.record(Operation::new(Method::UNLINK, &path), received);
+
+        drop(inode_map);
+        inject_after!(self, UNLINK, &path);
         Ok(())
     }
 
     #[instrument(skip(self))]
     async fn rmdir(&self, parent: u64, name: OsString) -> Result<()> {
         trace!("rmdir");
+        let received = Instant::now();
         inject_with_parent_and_name!(self, RMDIR, parent, &name);
 
         let mut inode_map = self.inode_map.write().await;
         let path = {
             let parent_path = inode_map.get_path(parent)?;
-            parent_path.join(name)
+            join_name(parent_path, &name)?
         };
 
+        self.check_remove(&path).await?;
         let stat = self.get_file_attr(&path).await?;
 
-        let cpath = CString::new(path.as_os_str().as_bytes())?;
-        async_rmdir(cpath).await?;
+        self.backend.rmdir(&path).await.context("rmdir", &path)?;
 
         trace!("remove {:x} from inode_map", &stat.ino);
         inode_map.remove_path(stat.ino, &path);
 
+        self.record(Operation::new(Method::RMDIR, &path), received);
+
+        drop(inode_map);
+        inject_after!(self, RMDIR, &path);
         Ok(())
     }
 
@@ -614,23 +1682,30 @@ impl AsyncFileSystemImpl for HookFs {
         let mut inode_map = self.inode_map.write().await;
         let path = {
             let parent_path = inode_map.get_path(parent)?;
-            parent_path.join(&name)
+            join_name(parent_path, &name)?
         };
 
+        // the link is kept on the original path after the recovery, where the hidden path is
+        // gone, so it's always stored with the mount path
+        let link = self.public_link_target(link);
         trace!("create symlink: {} => {}", path.display(), link.display());
+        self.check_parent(&path).await?;
 
-        let path_clone = path.clone();
-        spawn_blocking(move || symlinkat(&link, None, &path_clone)).await??;
+        self.backend
+            .symlink(link, &path)
+            .await
+            .context("symlink", &path)?;
 
-        trace!("setting owner {}:{}", uid, gid);
-        async_lchown(&path, Some(uid), Some(gid)).await?;
+        self.set_owner(&path, uid, gid).await?;
 
         let stat = self.get_file_attr(&path).await?;
-        inode_map.insert_path(stat.ino, path.clone());
-        inode_map.increase_ref(stat.ino);
+        self.insert_inode(&mut inode_map, stat.ino, path.clone())
+            .await;
         let mut reply = Entry::new(stat, 0);
-        inject_reply!(self, LOOKUP, path.as_path(), reply, Entry);
+        inject_reply!(self, SYMLINK, path.as_path(), reply, Entry);
 
+        drop(inode_map);
+        inject_after!(self, SYMLINK, &path);
         Ok(reply)
     }
 
@@ -644,20 +1719,21 @@ impl AsyncFileSystemImpl for HookFs {
         _flags: u32,
     ) -> Result<()> {
         trace!("rename");
+        let received = Instant::now();
         inject_with_parent_and_name!(self, RENAME, parent, &name);
 
         let mut inode_map = self.inode_map.write().await;
         let old_path = {
             let parent_path = inode_map.get_path(parent)?;
-            parent_path.join(&name)
+            join_name(parent_path, &name)?
         };
         trace!("get original path: {}", old_path.display());
 
         let parent_path = inode_map.get_path(parent)?;
-        let old_path = parent_path.join(&name);
+        let old_path = join_name(parent_path, &name)?;
 
         let new_parent_path = inode_map.get_path(newparent)?;
-        let new_path = new_parent_path.join(&newname);
+        let new_path = join_name(new_parent_path, &newname)?;
 
         trace!("get new path: {}", new_path.display());
         trace!(
@@ -666,16 +1742,28 @@ impl AsyncFileSystemImpl for HookFs {
             new_path.display()
         );
 
-        let new_path_clone = new_path.clone();
-        let old_path_clone = old_path.clone();
-        spawn_blocking(move || renameat(None, &old_path_clone, None, &new_path_clone)).await??;
+        self.check_remove(&old_path).await?;
+        self.check_parent(&new_path).await?;
+        self.backend
+            .rename(&old_path, &new_path)
+            .await
+            .context("rename", &old_path)?;
+        if let Some(shadow) = &self.shadow {
+            shadow.rename(&old_path, &new_path);
+        }
 
         let stat = self.get_file_attr(&new_path).await?;
         trace!("remove ({:x}, {})", stat.ino, old_path.display());
         inode_map.remove_path(stat.ino, &old_path);
         trace!("insert ({:x}, {})", stat.ino, new_path.display());
         inode_map.insert_path(stat.ino, &new_path);
+        inode_map.evict();
+
+        let operation = Operation::new(Method::RENAME, &old_path).new_path(&new_path);
+        self.record(operation, received);
 
+        drop(inode_map);
+        inject_after!(self, RENAME, &old_path);
         Ok(())
     }
 
@@ -687,7 +1775,7 @@ impl AsyncFileSystemImpl for HookFs {
         let mut inode_map = self.inode_map.write().await;
         let original_path = inode_map.get_path(ino)?.to_owned();
         let new_parent_path = inode_map.get_path(newparent)?.to_owned();
-        let new_path = new_parent_path.join(&newname);
+        let new_path = join_name(&new_parent_path, &newname)?;
 
         trace!(
             "link from {} to {}",
@@ -695,54 +1783,84 @@ impl AsyncFileSystemImpl for HookFs {
             original_path.display()
         );
 
-        let new_path_clone = new_path.clone();
-        spawn_blocking(move || {
-            linkat(
-                None,
-                &original_path,
-                None,
-                &new_path_clone,
-                LinkatFlags::NoSymlinkFollow,
-            )
-        })
-        .await??;
+        self.check_parent(&new_path).await?;
+        self.backend
+            .link(&original_path, &new_path)
+            .await
+            .context("link", &original_path)?;
 
         let stat = self.get_file_attr(&new_path).await?;
-        inode_map.insert_path(stat.ino, new_path.clone());
-        inode_map.increase_ref(stat.ino);
+        self.insert_inode(&mut inode_map, stat.ino, new_path.clone())
+            .await;
         let mut reply = Entry::new(stat, 0);
-        inject_reply!(self, LOOKUP, new_path.as_path(), reply, Entry);
+        inject_reply!(self, LINK, new_path.as_path(), reply, Entry);
 
+        drop(inode_map);
+        inject_after!(self, LINK, &original_path);
         Ok(reply)
     }
 
     #[instrument(skip(self))]
     async fn open(&self, ino: u64, flags: i32) -> Result<Open> {
         trace!("open");
+        let received = Instant::now();
         inject_with_ino!(self, OPEN, ino);
 
-        // TODO: support direct io
-        if flags & libc::O_DIRECT != 0 {
-            debug!("direct io flag is ignored directly")
-        }
         // filter out append. The kernel layer will translate the
         // offsets for us appropriately.
+        // The O_DIRECT of the caller is handled by the kernel, which bypasses the page cache, while
+        // the requests through the FUSE don't meet its alignment on the backend. The cacheMode
+        // injector bypasses the page cache for the matching paths, whatever the flags.
         let filtered_flags = flags & (!libc::O_APPEND) & (!libc::O_DIRECT);
         let filtered_flags = OFlag::from_bits_truncate(filtered_flags as i32);
 
-        let inode_map = self.inode_map.read().await;
-        let path = inode_map.get_path(ino)?;
+        let path = self.inode_map.read().await.get_path(ino)?.to_owned();
+        let reservation = self
+            .opened_files
+            .reserve(runtime::options().max_open_files)?;
 
         trace!("open with flags: {:?}", filtered_flags);
 
-        let fd = async_open(path, filtered_flags, stat::Mode::S_IRWXU).await?;
-        let fh = self.opened_files.write().await.insert(File::new(fd, path)) as u64;
+        let mask = permission::open_mask(flags);
+        let path = match self.check_access(&path, mask).await {
+            Err(err) if err.errno() == Errno::ENOENT => {
+                let path = self.refresh_path(ino).await?;
+                self.check_access(&path, mask).await?;
+                path
+            }
+            result => result.map(|_| path)?,
+        };
+
+        let (path, (fd, stream)) = match self.open_file(&path, filtered_flags).await {
+            Err(err) if err.errno() == Errno::ENOENT => {
+                let path = self.refresh_path(ino).await?;
+                let opened = self.open_file(&path, filtered_flags).await?;
+                (path, opened)
+            }
+            result => (path, result?),
+        };
+        if flags & libc::O_TRUNC != 0 {
+            if let Some(shadow) = &self.shadow {
+                shadow.truncate(&path, 0);
+            }
+        }
+        let (file, flags) = if stream {
+            // the page cache and the offsets are meaningless for a FIFO
+            let flags = consts::FOPEN_DIRECT_IO | consts::FOPEN_NONSEEKABLE;
+            (File::new_stream(fd, &path), flags as i32)
+        } else {
+            (File::new(fd, &path), 0)
+        };
+        let fh = reservation.insert(file).await;
 
-        trace!("return with fh: {}, flags: {}", fh, 0);
+        trace!("return with fh: {}, flags: {}", fh, flags);
 
-        let mut reply = Open::new(fh, 0);
-        inject_reply!(self, OPEN, path, reply, Open);
-        // TODO: force DIRECT_IO is not a great option
+        let mut reply = Open::new(fh, flags);
+        inject_reply!(self, OPEN, &path, reply, Open);
+        // a stream is never cached, whatever the injectors
+        reply.flags |= flags;
+        self.record(Operation::new(Method::OPEN, &path), received);
+        inject_after!(self, OPEN, &path);
         Ok(reply)
     }
 
@@ -753,18 +1871,44 @@ impl AsyncFileSystemImpl for HookFs {
         fh: u64,
         offset: i64,
         size: u32,
-        _flags: i32,
+        flags: i32,
         _lock_owner: Option<u64>,
     ) -> Result<Data> {
         trace!("read");
+        let received = Instant::now();
         inject_with_fh!(self, READ, fh);
 
-        let opened_files = self.opened_files.read().await;
-        let file = opened_files.get(fh as usize)?;
-        let buf = async_read(file.fd, size as usize, offset).await?;
+        let opened_files = self.opened_files.shard(fh).read().await;
+        let file = opened_files.get(fh)?;
+        let path = file.original_path().to_owned();
+        let start = Instant::now();
+        // the shard is held until the reply is injected, which may look into the backing fd
+        let (buf, source, opened_files) = if file.stream {
+            let stream = Stream::dup(file.fd)?;
+            drop(opened_files);
+            let nonblock = flags & libc::O_NONBLOCK != 0;
+            let buf = stream.read(size as usize, nonblock, &self.shutdown).await?;
+            (buf, None, None)
+        } else {
+            let fd = file.fd;
+            let buf = async_read(fd, size as usize, offset).await?;
+            (buf, Some(fd), Some(opened_files))
+        };
+        let elapsed = start.elapsed();
+        trace!("read {} bytes from backend in {:?}", buf.len(), elapsed);
+        self.io_stats
+            .record_read(&self.rebuild_path(&path)?, buf.len(), elapsed);
+
+        let mut reply = match source {
+            Some(fd) => Data::with_source(buf, fd, offset),
+            None => Data::new(buf),
+        };
+        inject_reply!(self, READ, &path, reply, Data);
+        let operation = Operation::new(Method::READ, &path);
+        self.record(operation.io(offset, reply.data.len() as u64), received);
 
-        let mut reply = Data::new(buf);
-        inject_reply!(self, READ, &file.original_path(), reply, Data);
+        drop(opened_files);
+        inject_after_with_fh!(self, READ, fh);
         Ok(reply)
     }
 
@@ -776,18 +1920,47 @@ impl AsyncFileSystemImpl for HookFs {
         offset: i64,
         mut data: Vec<u8>,
         _write_flags: u32,
-        _flags: i32,
+        flags: i32,
         _lock_owner: Option<u64>,
     ) -> Result<Write> {
         trace!("write");
+        let received = Instant::now();
         inject_with_fh!(self, WRITE, fh);
+        let intended = self.shadow.as_ref().map(|_| data.clone());
         inject_write_data!(self, fh, data);
-        let opened_files = self.opened_files.read().await;
-        let file = opened_files.get(fh as usize)?;
+        let opened_files = self.opened_files.shard(fh).read().await;
+        let file = opened_files.get(fh)?;
+        let (path, stream) = (file.original_path().to_owned(), file.stream);
 
-        let size = async_write(file.fd, data, offset).await?;
+        let start = Instant::now();
+        let (size, opened_files) = if stream {
+            let stream = Stream::dup(file.fd)?;
+            drop(opened_files);
+            let nonblock = flags & libc::O_NONBLOCK != 0;
+            let size = stream.write(&data, nonblock, &self.shutdown).await?;
+            (size, None)
+        } else {
+            let size = async_write(file.fd, data, offset).await?;
+            (size, Some(opened_files))
+        };
+        let elapsed = start.elapsed();
+        trace!("wrote {} bytes to backend in {:?}", size, elapsed);
+        self.io_stats
+            .record_write(&self.rebuild_path(&path)?, size as usize, elapsed);
+        if let (Some(shadow), Some(intended)) = (&self.shadow, intended) {
+            // a stream has no offsets to duplicate the data at
+            if !stream {
+                let written = min(size as usize, intended.len());
+                shadow.write(&path, offset as u64, &intended[..written]);
+            }
+        }
         let mut reply = Write::new(size as u32);
-        inject_reply!(self, WRITE, file.original_path(), reply, Write);
+        inject_reply!(self, WRITE, &path, reply, Write);
+        let operation = Operation::new(Method::WRITE, &path);
+        self.record(operation.io(offset, size as u64), received);
+
+        drop(opened_files);
+        inject_after_with_fh!(self, WRITE, fh);
         Ok(reply)
     }
 
@@ -797,12 +1970,15 @@ impl AsyncFileSystemImpl for HookFs {
         inject_with_fh!(self, FLUSH, fh);
 
         // flush is implemented with fsync. Is it the correct way?
-        let opened_files = self.opened_files.read().await;
+        let opened_files = self.opened_files.shard(fh).read().await;
         let fd: RawFd = {
-            let file = opened_files.get(fh as usize)?;
+            let file = opened_files.get(fh)?;
             file.fd
         };
         spawn_blocking(move || fsync(fd)).await??;
+
+        drop(opened_files);
+        inject_after_with_fh!(self, FLUSH, fh);
         Ok(())
     }
 
@@ -816,28 +1992,39 @@ impl AsyncFileSystemImpl for HookFs {
         _flush: bool,
     ) -> Result<()> {
         trace!("release");
+        // the file is always closed on the backend, even if a fault is injected, as the kernel
+        // forgets the handle anyway
+        let injected: Result<()> = async {
+            inject_with_fh!(self, RELEASE, fh);
+            Ok(())
+        }
+        .await;
 
-        let mut opened_files = self.opened_files.write().await;
-        if let Ok(file) = opened_files.get(fh as usize) {
+        let mut opened_files = self.opened_files.shard(fh).write().await;
+        if let Some(file) = opened_files.remove(fh) {
+            self.opened_files.closed();
             async_close(file.fd).await?;
         }
-        opened_files.remove(fh as usize);
-        Ok(())
+        injected
     }
 
     #[instrument(skip(self))]
     async fn fsync(&self, _ino: u64, fh: u64, _datasync: bool) -> Result<()> {
         trace!("fsync");
+        let received = Instant::now();
         inject_with_fh!(self, FSYNC, fh);
 
-        let opened_files = self.opened_files.read().await;
-        let fd: RawFd = {
-            let file = opened_files.get(fh as usize)?;
-            file.fd
+        let opened_files = self.opened_files.shard(fh).read().await;
+        let (fd, path) = {
+            let file = opened_files.get(fh)?;
+            (file.fd, file.original_path().to_owned())
         };
 
         spawn_blocking(move || fsync(fd)).await??;
+        self.record(Operation::new(Method::FSYNC, &path), received);
 
+        drop(opened_files);
+        inject_after_with_fh!(self, FSYNC, fh);
         Ok(())
     }
 
@@ -851,14 +2038,15 @@ impl AsyncFileSystemImpl for HookFs {
         let filtered_flags = flags & (!libc::O_APPEND);
         let filtered_flags = OFlag::from_bits_truncate(filtered_flags as i32);
 
-        let path_clone = path.clone();
-        let dir = spawn_blocking(move || {
-            trace!("opening directory {}", path_clone.display());
-            dir::Dir::open(&path_clone, filtered_flags, stat::Mode::S_IRWXU)
-        })
-        .await??;
+        self.check_access(&path, libc::R_OK as u32).await?;
+        let reservation = self.opened_dirs.reserve(runtime::options().max_open_dirs)?;
+        let dir = self
+            .backend
+            .opendir(&path, filtered_flags)
+            .await
+            .context("opendir", &path)?;
         trace!("directory {} opened", path.display());
-        let fh = self.opened_dirs.write().await.insert(Dir::new(dir, &path)) as u64;
+        let fh = reservation.insert(Dir::new(dir, &path)).await;
         trace!("return with fh: {}, flags: {}", fh, flags);
 
         let mut reply = Open::new(fh, flags);
@@ -875,16 +2063,22 @@ impl AsyncFileSystemImpl for HookFs {
         reply: &mut ReplyDirectory,
     ) -> Result<()> {
         trace!("readdir");
+        let received = Instant::now();
         inject_with_dir_fh!(self, READDIR, fh);
 
         let offset = offset as usize;
-        let mut opened_dirs = self.opened_dirs.write().await;
+        let mut opened_dirs = self.opened_dirs.shard(fh).write().await;
         // TODO: optimize the implementation
-        let all_entries: Vec<_> = {
-            let dir = opened_dirs.get_mut(fh as usize)?;
+        let (all_entries, path): (Vec<_>, _) = {
+            let dir = opened_dirs.get_mut(fh)?;
 
-            dir.iter().collect()
+            let path = dir.original_path().to_owned();
+            (dir.iter().collect(), path)
         };
+        // the directory is read by several requests, while it's only listed once on replay
+        if offset == 0 {
+            self.record(Operation::new(Method::READDIR, &path), received);
+        }
         if offset >= all_entries.len() {
             trace!("empty reply");
             return Ok(());
@@ -912,23 +2106,33 @@ impl AsyncFileSystemImpl for HookFs {
     #[instrument(skip(self))]
     async fn releasedir(&self, _ino: u64, fh: u64, _flags: i32) -> Result<()> {
         trace!("releasedir");
+        let injected: Result<()> = async {
+            inject_with_dir_fh!(self, RELEASEDIR, fh);
+            Ok(())
+        }
+        .await;
 
-        self.opened_dirs.write().await.remove(fh as usize);
-        Ok(())
+        let dir = self.opened_dirs.shard(fh).write().await.remove(fh);
+        if dir.is_some() {
+            self.opened_dirs.closed();
+        }
+        injected
     }
 
     #[instrument(skip(self))]
     async fn fsyncdir(&self, ino: u64, _fh: u64, _datasync: bool) -> Result<()> {
-        // TODO: inject
+        trace!("fsyncdir");
+        inject_with_ino!(self, FSYNCDIR, ino);
 
         let inode_map = self.inode_map.read().await;
-        let path = inode_map.get_path(ino)?.to_owned();
-        spawn_blocking(move || -> Result<_> {
-            std::fs::File::open(path)?.sync_all()?;
+        let path = inode_map.get_path(ino)?;
+        self.backend
+            .fsync_dir(path)
+            .await
+            .context("fsync_dir", path)?;
 
-            Ok(())
-        })
-        .await??;
+        drop(inode_map);
+        inject_after_with_ino!(self, FSYNCDIR, ino);
         Ok(())
     }
 
@@ -972,6 +2176,7 @@ impl AsyncFileSystemImpl for HookFs {
 
         let inode_map = self.inode_map.read().await;
         let path = inode_map.get_path(ino)?.to_owned();
+        self.check_xattr(&path, &name).await?;
         let path = CString::new(path.as_os_str().as_bytes())?;
         let name = CString::new(name.as_bytes())?;
 
@@ -1050,6 +2255,7 @@ impl AsyncFileSystemImpl for HookFs {
 
         let inode_map = self.inode_map.read().await;
         let path = inode_map.get_path(ino)?.to_owned();
+        self.check_xattr(&path, &name).await?;
         let path = CString::new(path.as_os_str().as_bytes())?;
         let name = CString::new(name.as_bytes())?;
 
@@ -1072,11 +2278,17 @@ impl AsyncFileSystemImpl for HookFs {
         inject_with_ino!(self, ACCESS, ino);
 
         let inode_map = self.inode_map.read().await;
-        let path = inode_map.get_path(ino)?.to_owned();
-        let mask = AccessFlags::from_bits_truncate(mask as i32);
-        let path_clone = path.to_path_buf();
-
-        spawn_blocking(move || nix::unistd::access(&path_clone, mask)).await??;
+        let path = inode_map.get_path(ino)?;
+        // the backend is always accessible for the daemon, so the access is checked against the
+        // requester instead
+        if self.requester().is_some() {
+            self.check_access(path, mask as u32).await?;
+        } else {
+            self.backend
+                .access(path, mask)
+                .await
+                .context("access", path)?;
+        }
 
         Ok(())
     }
@@ -1093,12 +2305,13 @@ impl AsyncFileSystemImpl for HookFs {
         gid: u32,
     ) -> Result<Create> {
         trace!("create");
+        let received = Instant::now();
         inject_with_parent_and_name!(self, CREATE, parent, &name);
 
         let mut inode_map = self.inode_map.write().await;
         let path = {
             let parent_path = inode_map.get_path(parent)?;
-            parent_path.join(name)
+            join_name(parent_path, &name)?
         };
 
         let filtered_flags = flags & (!libc::O_APPEND);
@@ -1106,20 +2319,35 @@ impl AsyncFileSystemImpl for HookFs {
         let mode = stat::Mode::from_bits_truncate(mode);
 
         trace!("create with flags: {:?}, mode: {:?}", filtered_flags, mode);
-        let fd = async_open(&path, filtered_flags, mode).await?;
-        trace!("setting owner {}:{} for file", uid, gid);
-        async_lchown(&path, Some(uid), Some(gid)).await?;
+        self.check_parent(&path).await?;
+        let reservation = self
+            .opened_files
+            .reserve(runtime::options().max_open_files)?;
+        let fd = self
+            .backend
+            .open(&path, filtered_flags, mode)
+            .await
+            .context("open", &path)?;
+        self.set_owner(&path, uid, gid).await?;
+        if let Some(shadow) = &self.shadow {
+            shadow.truncate(&path, 0);
+        }
 
         let stat = self.get_file_attr(&path).await?;
-        let fh = self.opened_files.write().await.insert(File::new(fd, &path));
+        let fh = reservation.insert(File::new(fd, &path)).await;
 
         // TODO: support generation number
         // this can be implemented with ioctl FS_IOC_GETVERSION
         trace!("return with stat: {:?} fh: {}", stat, fh);
-        inode_map.insert_path(stat.ino, path.clone());
-        inode_map.increase_ref(stat.ino);
-        let mut reply = Create::new(stat, 0, fh as u64, flags);
+        self.insert_inode(&mut inode_map, stat.ino, path.clone())
+            .await;
+        let mut reply = Create::new(stat, 0, fh, 0);
         inject_reply!(self, CREATE, path.as_path(), reply, Create);
+
+        self.record(Operation::new(Method::CREATE, &path), received);
+
+        drop(inode_map);
+        inject_after!(self, CREATE, &path);
         Ok(reply)
     }
 
@@ -1157,16 +2385,53 @@ impl AsyncFileSystemImpl for HookFs {
 
     #[instrument(skip(self))]
     async fn bmap(&self, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
-        error!("unimplemented");
+        OP_STATS.record_unsupported("bmap");
         reply.error(nix::libc::ENOSYS);
     }
+
+    #[instrument(skip(self))]
+    async fn poll(&self, _ino: u64, fh: u64, _kh: u64, events: u32, _flags: u32) -> Result<Poll> {
+        trace!("poll");
+        inject_with_fh!(self, POLL, fh);
+
+        let opened_files = self.opened_files.shard(fh).read().await;
+        let path = opened_files.get(fh)?.original_path().to_owned();
+        drop(opened_files);
+
+        // the files on the backend never block, so they are always ready, and the kernel is never
+        // notified later
+        let ready = (libc::POLLIN | libc::POLLOUT | libc::POLLRDNORM | libc::POLLWRNORM) as u32;
+        let mut reply = Poll::new(events & ready);
+        inject_reply!(self, POLL, &path, reply, Poll);
+
+        Ok(reply)
+    }
+
+    #[instrument(skip(self))]
+    async fn lseek(&self, _ino: u64, fh: u64, offset: i64, whence: i32) -> Result<Lseek> {
+        trace!("lseek");
+        inject_with_fh!(self, LSEEK, fh);
+
+        // the kernel only asks for SEEK_DATA and SEEK_HOLE, and seeks the others itself. The offset
+        // of the backing fd is moved, which is fine as it's only read and written with `pread`
+        // and `pwrite`
+        let opened_files = self.opened_files.shard(fh).read().await;
+        let fd = opened_files.get(fh)?.fd;
+        let offset =
+            spawn_blocking(move || Errno::result(unsafe { libc::lseek(fd, offset, whence) }))
+                .await??;
+
+        drop(opened_files);
+        inject_after_with_fh!(self, LSEEK, fh);
+        Ok(Lseek::new(offset))
+    }
 }
 
 async fn async_setxattr(path: CString, name: CString, data: Vec<u8>, flags: i32) -> Result<()> {
     spawn_blocking(move || {
         let path_ptr = &path.as_bytes_with_nul()[0] as *const u8 as *const libc::c_char;
         let name_ptr = &name.as_bytes_with_nul()[0] as *const u8 as *const libc::c_char;
-        let data_ptr = &data[0] as *const u8 as *const libc::c_void;
+        let data_ptr = data.as_ptr() as *const libc::c_void;
         let ret = unsafe { lsetxattr(path_ptr, name_ptr, data_ptr, data.len(), flags) };
 
         if ret == -1 {
@@ -1199,14 +2464,17 @@ async fn async_getxattr(path: CString, name: CString, size: usize) -> Result<Vec
 }
 
 async fn async_read(fd: RawFd, count: usize, offset: i64) -> Result<Vec<u8>> {
+    // the reads are never zero-copy, as fuser supports neither splice nor the passthrough mode, and
+    // the reply is always written to the FUSE device from a buffer. The data is read directly into
+    // the uninitialized capacity without zeroing it first, which saves a pass over the memory for
+    // large reads. The buffer is returned to the pool when the reply is dropped
     spawn_blocking(move || unsafe {
-        let mut buf = Vec::new();
-        buf.resize(count, 0);
-        let ret = libc::pread(fd, buf.as_ptr() as *mut c_void, count, offset);
+        let mut buf = BUFFER_POOL.checkout(count);
+        let ret = libc::pread(fd, buf.as_mut_ptr() as *mut c_void, count, offset);
         if ret == -1 {
             Err(Error::last())
         } else {
-            buf.resize(ret as usize, 0);
+            buf.set_len(ret as usize);
             Ok(buf)
         }
     })
@@ -1216,6 +2484,7 @@ async fn async_read(fd: RawFd, count: usize, offset: i64) -> Result<Vec<u8>> {
 async fn async_write(fd: RawFd, data: Vec<u8>, offset: i64) -> Result<isize> {
     spawn_blocking(move || unsafe {
         let ret = libc::pwrite(fd, data.as_ptr() as *const c_void, data.len(), offset);
+        BUFFER_POOL.put(data);
         if ret == -1 {
             Err(Error::last())
         } else {
@@ -1225,118 +2494,6 @@ async fn async_write(fd: RawFd, data: Vec<u8>, offset: i64) -> Result<isize> {
     .await?
 }
 
-async fn async_stat(path: &Path) -> Result<stat::FileStat> {
-    let path_clone = path.to_path_buf();
-    trace!("async read stat from path {}", path_clone.display());
-    Ok(spawn_blocking(move || stat::lstat(&path_clone)).await??)
-}
-
-async fn async_lchown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
-    let path_clone = path.to_path_buf();
-    spawn_blocking(move || {
-        fchownat(
-            None,
-            &path_clone,
-            uid.map(Uid::from_raw),
-            gid.map(Gid::from_raw),
-            FchownatFlags::NoFollowSymlink,
-        )
-    })
-    .await??;
-    Ok(())
-}
-
-async fn async_fchmodat(path: &Path, mode: u32) -> Result<()> {
-    let path_clone = path.to_path_buf();
-    spawn_blocking(move || {
-        stat::fchmodat(
-            None,
-            &path_clone,
-            stat::Mode::from_bits_truncate(mode),
-            stat::FchmodatFlags::FollowSymlink,
-        )
-    })
-    .await??;
-    Ok(())
-}
-
-async fn async_truncate(path: &Path, len: i64) -> Result<()> {
-    let path_clone = path.to_path_buf();
-    spawn_blocking(move || truncate(&path_clone, len)).await??;
-    Ok(())
-}
-
-async fn async_utimensat(path: CString, times: [libc::timespec; 2]) -> Result<()> {
-    spawn_blocking(move || unsafe {
-        let path_ptr = &path.as_bytes_with_nul()[0] as *const u8 as *mut i8;
-        let ret = libc::utimensat(
-            0,
-            path_ptr,
-            &times as *const [libc::timespec; 2] as *const libc::timespec,
-            libc::AT_SYMLINK_NOFOLLOW,
-        );
-
-        if ret != 0 {
-            Err(Error::last())
-        } else {
-            Ok(())
-        }
-    })
-    .await??;
-    Ok(())
-}
-
-async fn async_readlink(path: &Path) -> Result<OsString> {
-    let path_clone = path.to_path_buf();
-    Ok(spawn_blocking(move || readlink(&path_clone)).await??)
-}
-
-async fn async_mknod(path: CString, mode: u32, rdev: u64) -> Result<()> {
-    spawn_blocking(move || {
-        let path_ptr = &path.as_bytes_with_nul()[0] as *const u8 as *mut i8;
-        let ret = unsafe { libc::mknod(path_ptr, mode, rdev) };
-
-        if ret != 0 {
-            Err(Error::last())
-        } else {
-            Ok(())
-        }
-    })
-    .await?
-}
-
-async fn async_mkdir(path: &Path, mode: stat::Mode) -> Result<()> {
-    let path_clone = path.to_path_buf();
-    spawn_blocking(move || mkdir(&path_clone, mode)).await??;
-    Ok(())
-}
-
-async fn async_unlink(path: &Path) -> Result<()> {
-    let path_clone = path.to_path_buf();
-    spawn_blocking(move || unlink(&path_clone)).await??;
-    Ok(())
-}
-
-async fn async_rmdir(path: CString) -> Result<()> {
-    spawn_blocking(move || {
-        let path_ptr = &path.as_bytes_with_nul()[0] as *const u8 as *mut i8;
-        let ret = unsafe { libc::rmdir(path_ptr) };
-
-        if ret != 0 {
-            Err(Error::last())
-        } else {
-            Ok(())
-        }
-    })
-    .await?
-}
-
-async fn async_open(path: &Path, filtered_flags: OFlag, mode: stat::Mode) -> Result<RawFd> {
-    let path_clone = path.to_path_buf();
-    let fd = spawn_blocking(move || open(&path_clone, filtered_flags, mode)).await??;
-    Ok(fd)
-}
-
 async fn async_close(fd: RawFd) -> Result<()> {
     Ok(spawn_blocking(move || close(fd)).await??)
 }
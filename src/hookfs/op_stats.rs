@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use tracing::{info, warn};
+
+use super::runtime;
+use crate::injector::Method;
+
+// the count of the methods, each of which is a bit of Method
+const METHOD_COUNT: usize = 64;
+
+pub static OP_STATS: Lazy<OpStats> = Lazy::new(OpStats::default);
+
+#[derive(Debug, Default)]
+struct Counter {
+    ops: AtomicU64,
+    errors: AtomicU64,
+    injections: AtomicU64,
+    injection_latency_ns: AtomicU64,
+}
+
+// OpStats counts the requests of every method, and the latency added by the injectors to them,
+// since the last report
+#[derive(Debug)]
+pub struct OpStats {
+    counters: Vec<Counter>,
+    // the requests of the operations toda doesn't implement, which are replied with ENOSYS. The
+    // kernel stops sending most of them after the first one, so they're counted since the mount
+    // instead of being reset by the reports
+    unsupported: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Default for OpStats {
+    fn default() -> Self {
+        OpStats {
+            counters: (0..METHOD_COUNT).map(|_| Counter::default()).collect(),
+            unsupported: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl OpStats {
+    fn counter(&self, method: Method) -> Option<&Counter> {
+        self.counters.get(method.bits().trailing_zeros() as usize)
+    }
+
+    pub fn record_op(&self, method: Method, ok: bool) {
+        if let Some(counter) = self.counter(method) {
+            counter.ops.fetch_add(1, Ordering::Relaxed);
+            if !ok {
+                counter.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn record_injection(&self, method: Method, latency: Duration) {
+        if let Some(counter) = self.counter(method) {
+            counter.injections.fetch_add(1, Ordering::Relaxed);
+            counter
+                .injection_latency_ns
+                .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_unsupported(&self, op: &'static str) {
+        let mut unsupported = self.unsupported.lock().unwrap();
+        let count = unsupported.entry(op).or_insert(0);
+        if *count == 0 {
+            warn!(
+                "{} is not supported by toda, the workload gets ENOSYS or falls back",
+                op
+            );
+        }
+        *count += 1;
+    }
+
+    // unsupported returns the count of the requests of every operation toda doesn't implement
+    pub fn unsupported(&self) -> BTreeMap<String, u64> {
+        self.unsupported
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(op, count)| (op.to_string(), *count))
+            .collect()
+    }
+
+    // report logs the requests since the last report in a single line, and resets the counters
+    pub fn report(&self, interval: Duration) {
+        let secs = interval.as_secs_f64().max(f64::EPSILON);
+        let mut summary = Vec::new();
+        for (index, counter) in self.counters.iter().enumerate() {
+            let ops = counter.ops.swap(0, Ordering::Relaxed);
+            let errors = counter.errors.swap(0, Ordering::Relaxed);
+            let injections = counter.injections.swap(0, Ordering::Relaxed);
+            let latency_ns = counter.injection_latency_ns.swap(0, Ordering::Relaxed);
+            if ops == 0 {
+                continue;
+            }
+
+            let method = Method::from_bits_truncate(1 << index);
+            let mut entry = format!(
+                "{} {:.1}/s",
+                format!("{:?}", method).to_lowercase(),
+                ops as f64 / secs
+            );
+            if errors > 0 {
+                entry += &format!(" {} errors", errors);
+            }
+            if injections > 0 {
+                entry += &format!(" +{}us", latency_ns / injections / 1000);
+            }
+            summary.push(entry);
+        }
+
+        if summary.is_empty() {
+            info!("no requests in the last {:?}", interval);
+        } else {
+            info!(
+                "requests in the last {:?}: {}",
+                interval,
+                summary.join(", ")
+            );
+        }
+
+        if let Some(queue) = runtime::queue_stats() {
+            info!(
+                "pending requests: {}/{}, peak {}, {} shed in the last {:?}",
+                queue.pending, queue.limit, queue.peak, queue.shed, interval
+            );
+        }
+    }
+}
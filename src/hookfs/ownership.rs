@@ -0,0 +1,92 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use glob::Pattern;
+use structopt::StructOpt;
+
+// Owner is a pair of uid and gid, in the form of `uid:gid`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl FromStr for Owner {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, ':');
+        let uid = parts.next().unwrap_or_default();
+        let gid = parts
+            .next()
+            .ok_or(anyhow!("owner should be uid:gid, got {}", s))?;
+
+        Ok(Owner {
+            uid: uid.parse()?,
+            gid: gid.parse()?,
+        })
+    }
+}
+
+// OwnerMapping forces the owner of the files created under the paths matching the pattern, in
+// the form of `pattern=uid:gid`
+#[derive(Debug, Clone)]
+pub struct OwnerMapping {
+    pub pattern: Pattern,
+    pub owner: Owner,
+}
+
+impl FromStr for OwnerMapping {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let index = s.rfind('=').ok_or(anyhow!(
+            "owner mapping should be pattern=uid:gid, got {}",
+            s
+        ))?;
+
+        Ok(OwnerMapping {
+            pattern: Pattern::new(&s[..index])?,
+            owner: s[index + 1..].parse()?,
+        })
+    }
+}
+
+// OwnershipOptions decides the owner of the files created through the FUSE. By default, they are
+// owned by the caller, which could be meaningless when toda runs in a different user namespace
+// than the workload. The squashed and mapped owners are the ids on the backend, or the ids in the
+// user namespace of --id-map-pid if it's given.
+#[derive(StructOpt, Debug, Clone, Default)]
+pub struct OwnershipOptions {
+    /// the owner of all the files created through the FUSE, in the form of uid:gid
+    #[structopt(long = "squash")]
+    pub squash: Option<Owner>,
+
+    /// the owner of the files created under the paths matching the pattern, in the form of
+    /// pattern=uid:gid, which takes precedence over --squash
+    #[structopt(long = "owner-map", number_of_values = 1)]
+    pub mappings: Vec<OwnerMapping>,
+
+    /// shift the owners given by --squash and --owner-map from the user namespace of this process
+    /// into the ids on the backend, with its uid_map and gid_map
+    #[structopt(long = "id-map-pid")]
+    pub id_map_pid: Option<i32>,
+}
+
+impl OwnershipOptions {
+    // owner returns the owner of the file created at the path (which is the path under the mount
+    // point) by the caller
+    pub fn owner(&self, path: &Path, caller: Owner) -> Owner {
+        self.configured_owner(path).unwrap_or(caller)
+    }
+
+    // configured_owner returns the owner given by the options for the path, if any
+    pub fn configured_owner(&self, path: &Path) -> Option<Owner> {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.pattern.matches_path(path))
+            .map(|mapping| mapping.owner)
+            .or(self.squash)
+    }
+}
@@ -0,0 +1,282 @@
+use std::fs::read_to_string;
+
+use fuser::{FileAttr, FileType};
+use nix::errno::Errno;
+use tracing::trace;
+
+use super::errors::{HookFsError as Error, Result};
+
+// the name of the xattr which keeps the access ACL of a file
+pub const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+
+// the layout of the ACL xattr, which is defined in `include/uapi/linux/posix_acl_xattr.h`
+const ACL_XATTR_VERSION: u32 = 0x0002;
+const ACL_HEADER_SIZE: usize = 4;
+const ACL_ENTRY_SIZE: usize = 8;
+
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+tokio::task_local! {
+    // the caller of the FUSE request which is being handled by the task
+    pub static REQUESTER: Requester;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Requester {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+impl Requester {
+    // current returns the caller of the FUSE request handled by the current task
+    pub fn current() -> Option<Requester> {
+        REQUESTER.try_with(|requester| *requester).ok()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    // in_group returns whether the caller belongs to the group, including its supplementary
+    // groups, which are read from the status of the process
+    fn in_group(&self, gid: u32, groups: &[u32]) -> bool {
+        self.gid == gid || groups.contains(&gid)
+    }
+
+    fn groups(&self) -> Vec<u32> {
+        let status = match read_to_string(format!("/proc/{}/status", self.pid)) {
+            Ok(status) => status,
+            Err(err) => {
+                trace!("fail to read the groups of process {}: {}", self.pid, err);
+                return Vec::new();
+            }
+        };
+
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("Groups:"))
+            .map(|groups| {
+                groups
+                    .split_whitespace()
+                    .filter_map(|gid| gid.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AclEntry {
+    tag: u16,
+    perm: u32,
+    id: u32,
+}
+
+// Acl is the parsed access ACL of a file
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    pub fn parse(data: &[u8]) -> Result<Acl> {
+        if data.len() < ACL_HEADER_SIZE || (data.len() - ACL_HEADER_SIZE) % ACL_ENTRY_SIZE != 0 {
+            return Err(Error::Sys(Errno::EINVAL));
+        }
+
+        let version = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        if version != ACL_XATTR_VERSION {
+            return Err(Error::Sys(Errno::EOPNOTSUPP));
+        }
+
+        let entries = data[ACL_HEADER_SIZE..]
+            .chunks(ACL_ENTRY_SIZE)
+            .map(|entry| AclEntry {
+                tag: u16::from_le_bytes([entry[0], entry[1]]),
+                perm: u16::from_le_bytes([entry[2], entry[3]]) as u32,
+                id: u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+            })
+            .collect();
+
+        Ok(Acl { entries })
+    }
+
+    fn find(&self, tag: u16) -> Option<&AclEntry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    // the permissions of the named users and groups, and the owning group, are limited by the
+    // mask entry
+    fn masked(&self, perm: u32) -> u32 {
+        match self.find(ACL_MASK) {
+            Some(mask) => perm & mask.perm,
+            None => perm,
+        }
+    }
+}
+
+// check_access checks whether the requester is permitted to access the file with the mask, which
+// is a combination of `R_OK`, `W_OK` and `X_OK`, following the access check algorithm of POSIX
+// ACLs. The mode bits are used when the file has no ACL.
+pub fn check_access(
+    requester: &Requester,
+    attr: &FileAttr,
+    acl: Option<&Acl>,
+    mask: u32,
+) -> Result<()> {
+    let mask = mask & 0o7;
+    if mask == 0 {
+        return Ok(());
+    }
+
+    if requester.is_root() {
+        // root could only execute a file if it is executable for anyone
+        let is_dir = attr.kind == FileType::Directory;
+        if mask & libc::X_OK as u32 == 0 || is_dir || attr.perm & 0o111 != 0 {
+            return Ok(());
+        }
+        return Err(Error::Sys(Errno::EACCES));
+    }
+
+    let mode = attr.perm as u32;
+    let granted = if requester.uid == attr.uid {
+        (mode >> 6) & 0o7
+    } else {
+        let acl = acl.filter(|acl| !acl.entries.is_empty());
+        let groups = requester.groups();
+        match acl {
+            Some(acl) => {
+                let user = acl
+                    .entries
+                    .iter()
+                    .find(|entry| entry.tag == ACL_USER && entry.id == requester.uid);
+                if let Some(user) = user {
+                    acl.masked(user.perm)
+                } else {
+                    let mut matched = None;
+                    for entry in acl.entries.iter() {
+                        let in_group = match entry.tag {
+                            ACL_GROUP_OBJ => requester.in_group(attr.gid, &groups),
+                            ACL_GROUP => requester.in_group(entry.id, &groups),
+                            _ => false,
+                        };
+                        if in_group {
+                            let perm = acl.masked(entry.perm);
+                            // any matched group entry which grants the permissions is enough
+                            if perm & mask == mask {
+                                matched = Some(perm);
+                                break;
+                            }
+                            matched.get_or_insert(perm);
+                        }
+                    }
+                    match matched {
+                        Some(perm) => perm,
+                        None => acl.find(ACL_OTHER).map(|entry| entry.perm).unwrap_or(0),
+                    }
+                }
+            }
+            None if requester.in_group(attr.gid, &groups) => (mode >> 3) & 0o7,
+            None => mode & 0o7,
+        }
+    };
+
+    if granted & mask == mask {
+        Ok(())
+    } else {
+        trace!(
+            "deny {:?} with mask {:o}, granted {:o}",
+            requester,
+            mask,
+            granted
+        );
+        Err(Error::Sys(Errno::EACCES))
+    }
+}
+
+// check_owner checks whether the requester owns the file, which is required to change its mode,
+// owner, or ACL
+pub fn check_owner(requester: &Requester, attr: &FileAttr) -> Result<()> {
+    if requester.is_root() || requester.uid == attr.uid {
+        Ok(())
+    } else {
+        Err(Error::Sys(Errno::EPERM))
+    }
+}
+
+// check_chown checks whether the requester is permitted to change the owner of the file. Only root
+// could give a file away, and the owner could only change the group to one of its groups.
+pub fn check_chown(
+    requester: &Requester,
+    attr: &FileAttr,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<()> {
+    if requester.is_root() {
+        return Ok(());
+    }
+
+    let uid_changed = uid.map_or(false, |uid| uid != attr.uid);
+    let gid_changed = gid.map_or(false, |gid| gid != attr.gid);
+    if uid_changed || (gid_changed && requester.uid != attr.uid) {
+        return Err(Error::Sys(Errno::EPERM));
+    }
+    if let Some(gid) = gid.filter(|_| gid_changed) {
+        if !requester.in_group(gid, &requester.groups()) {
+            return Err(Error::Sys(Errno::EPERM));
+        }
+    }
+
+    Ok(())
+}
+
+// check_sticky checks whether the requester is permitted to remove or rename the file in the
+// directory. The entries of a sticky directory could only be removed by their owners, or the
+// owner of the directory.
+pub fn check_sticky(requester: &Requester, dir: &FileAttr, attr: &FileAttr) -> Result<()> {
+    let sticky = dir.perm as u32 & libc::S_ISVTX != 0;
+    if !sticky || requester.is_root() || requester.uid == dir.uid || requester.uid == attr.uid {
+        Ok(())
+    } else {
+        Err(Error::Sys(Errno::EPERM))
+    }
+}
+
+// check_xattr checks whether the requester is permitted to modify the xattr of the file
+pub fn check_xattr(
+    requester: &Requester,
+    attr: &FileAttr,
+    acl: Option<&Acl>,
+    name: &str,
+) -> Result<()> {
+    if name.starts_with("system.posix_acl_") {
+        check_owner(requester, attr)
+    } else if name.starts_with("security.") || name.starts_with("trusted.") {
+        if requester.is_root() {
+            Ok(())
+        } else {
+            Err(Error::Sys(Errno::EPERM))
+        }
+    } else {
+        check_access(requester, attr, acl, libc::W_OK as u32)
+    }
+}
+
+// open_mask returns the access mask required to open a file with the flags
+pub fn open_mask(flags: i32) -> u32 {
+    let mut mask = match flags & libc::O_ACCMODE {
+        libc::O_WRONLY => libc::W_OK,
+        libc::O_RDWR => libc::R_OK | libc::W_OK,
+        _ => libc::R_OK,
+    };
+    if flags & libc::O_TRUNC != 0 {
+        mask |= libc::W_OK;
+    }
+
+    mask as u32
+}
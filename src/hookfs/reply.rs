@@ -1,12 +1,15 @@
 use std::fmt::Debug;
+use std::ops::Range;
+use std::os::unix::io::RawFd;
 use std::time::Duration;
 
 use fuser::*;
 use tracing::{debug, error, trace};
 
+use super::buffer_pool::BUFFER_POOL;
 use super::errors::Result;
-
-const TTL: Duration = Duration::from_secs(0);
+use super::runtime;
+use super::utils::find_holes;
 
 #[derive(Debug)]
 pub enum Reply<'a> {
@@ -19,16 +22,24 @@ pub enum Reply<'a> {
     Create(&'a mut Create),
     _Lock(&'a mut Lock),
     Xattr(&'a mut Xattr),
+    Poll(&'a mut Poll),
 }
 
 #[derive(Debug)]
 pub struct Entry {
     pub stat: FileAttr,
     pub generation: u64,
+    // how long the kernel caches the entry, which is a negative one if the ino is 0. The
+    // attributes in the entry are cached for the same time
+    pub ttl: Duration,
 }
 impl Entry {
     pub fn new(stat: FileAttr, generation: u64) -> Self {
-        Self { stat, generation }
+        Self {
+            stat,
+            generation,
+            ttl: runtime::options().entry_timeout(),
+        }
     }
 }
 
@@ -46,20 +57,50 @@ impl Open {
 #[derive(Debug)]
 pub struct Attr {
     pub attr: FileAttr,
+    // how long the kernel caches the attributes
+    pub ttl: Duration,
 }
 impl Attr {
     pub fn new(attr: FileAttr) -> Self {
-        Self { attr }
+        Self {
+            attr,
+            ttl: runtime::options().attr_timeout(),
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct Data {
     pub data: Vec<u8>,
+    // the backing fd and the offset the data was read from, if any
+    source: Option<(RawFd, i64)>,
 }
 impl Data {
     pub fn new(data: Vec<u8>) -> Self {
-        Self { data }
+        Self { data, source: None }
+    }
+
+    pub fn with_source(data: Vec<u8>, fd: RawFd, offset: i64) -> Self {
+        Self {
+            data,
+            source: Some((fd, offset)),
+        }
+    }
+
+    // holes returns the ranges of the data which are read from the holes of a sparse file. They
+    // are found lazily with `SEEK_HOLE` on the backing fd, so that the replies which are never
+    // injected don't pay for it.
+    pub fn holes(&self) -> Vec<Range<usize>> {
+        match self.source {
+            Some((fd, offset)) => find_holes(fd, offset, self.data.len()),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Drop for Data {
+    fn drop(&mut self) {
+        BUFFER_POOL.put(std::mem::take(&mut self.data));
     }
 }
 
@@ -114,6 +155,8 @@ pub struct Create {
     pub generation: u64,
     pub fh: u64,
     pub flags: i32,
+    // how long the kernel caches the entry
+    pub ttl: Duration,
 }
 impl Create {
     pub fn new(attr: FileAttr, generation: u64, fh: u64, flags: i32) -> Self {
@@ -122,6 +165,7 @@ impl Create {
             generation,
             fh,
             flags,
+            ttl: runtime::options().entry_timeout(),
         }
     }
 }
@@ -145,6 +189,17 @@ impl Lock {
     }
 }
 
+#[derive(Debug)]
+pub struct Poll {
+    pub revents: u32,
+}
+
+impl Poll {
+    pub fn new(revents: u32) -> Self {
+        Self { revents }
+    }
+}
+
 #[derive(Debug)]
 pub enum Xattr {
     Data { data: Vec<u8> },
@@ -159,6 +214,17 @@ impl Xattr {
     }
 }
 
+#[derive(Debug)]
+pub struct Lseek {
+    pub offset: i64,
+}
+
+impl Lseek {
+    pub fn new(offset: i64) -> Self {
+        Self { offset }
+    }
+}
+
 pub trait FsReply<T: Debug>: Sized {
     fn reply_ok(self, item: T);
     fn reply_err(self, err: libc::c_int);
@@ -184,7 +250,7 @@ pub trait FsReply<T: Debug>: Sized {
 
 impl FsReply<Entry> for ReplyEntry {
     fn reply_ok(self, item: Entry) {
-        self.entry(&TTL, &item.stat, item.generation);
+        self.entry(&item.ttl, &item.stat, item.generation);
     }
     fn reply_err(self, err: libc::c_int) {
         self.error(err);
@@ -202,7 +268,7 @@ impl FsReply<Open> for ReplyOpen {
 
 impl FsReply<Attr> for ReplyAttr {
     fn reply_ok(self, item: Attr) {
-        self.attr(&TTL, &item.attr);
+        self.attr(&item.ttl, &item.attr);
     }
     fn reply_err(self, err: libc::c_int) {
         self.error(err);
@@ -248,7 +314,7 @@ impl FsReply<Write> for ReplyWrite {
 impl FsReply<Create> for ReplyCreate {
     fn reply_ok(self, item: Create) {
         self.created(
-            &TTL,
+            &item.ttl,
             &item.attr,
             item.generation,
             item.fh,
@@ -282,6 +348,33 @@ impl FsReply<Xattr> for ReplyXattr {
     }
 }
 
+impl FsReply<Poll> for ReplyPoll {
+    fn reply_ok(self, item: Poll) {
+        self.poll(item.revents);
+    }
+    fn reply_err(self, err: libc::c_int) {
+        self.error(err);
+    }
+}
+
+impl FsReply<Lseek> for ReplyLseek {
+    fn reply_ok(self, item: Lseek) {
+        self.offset(item.offset);
+    }
+    fn reply_err(self, err: libc::c_int) {
+        self.error(err);
+    }
+}
+
+impl FsReply<()> for ReplyDirectory {
+    fn reply_ok(self, _: ()) {
+        self.ok();
+    }
+    fn reply_err(self, err: libc::c_int) {
+        self.error(err);
+    }
+}
+
 impl FsReply<()> for ReplyEmpty {
     fn reply_ok(self, _: ()) {
         self.ok();
@@ -1,23 +1,261 @@
+use std::fs;
 use std::future::Future;
-use std::sync::RwLock;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, RwLock};
+use std::time::Duration;
 
-use once_cell::sync::Lazy;
+use anyhow::anyhow;
+use nix::errno::Errno;
+use once_cell::sync::{Lazy, OnceCell};
+use structopt::StructOpt;
 use tokio::runtime::Runtime;
-use tokio::task::JoinHandle;
-use tracing::trace;
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::delay_for;
+use tracing::{info, trace, warn};
 
-pub static RUNTIME: Lazy<RwLock<Option<Runtime>>> = Lazy::new(|| {
+// the default maximum count of the blocking threads of tokio
+const DEFAULT_BLOCKING_THREADS: usize = 512;
+
+// the share of --max-memory or --max-fds beyond which toda sheds the load, before the allocations
+// or the opens fail
+const PRESSURE_RATIO: f64 = 0.9;
+
+// how often the memory and the fds of toda are measured against their limits
+const PRESSURE_INTERVAL: Duration = Duration::from_secs(1);
+
+// OverloadPolicy decides what happens to a request beyond --max-pending-requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    // block the FUSE dispatch loop until a pending request is replied, so that the requests queue
+    // up in the kernel and the workload is throttled
+    Block,
+    // reply EAGAIN at once, except to the requests which release the resources or persist the
+    // data, which still wait
+    Shed,
+}
+
+impl Default for OverloadPolicy {
+    fn default() -> Self {
+        OverloadPolicy::Block
+    }
+}
+
+impl FromStr for OverloadPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "block" => Ok(OverloadPolicy::Block),
+            "shed" => Ok(OverloadPolicy::Shed),
+            _ => Err(anyhow!("unknown overload policy: {}", s)),
+        }
+    }
+}
+
+// RuntimeOptions configures the runtime which serves the FUSE requests
+#[derive(StructOpt, Debug, Clone, Default)]
+pub struct RuntimeOptions {
+    /// the count of the threads serving the FUSE requests, defaults to the count of CPUs
+    #[structopt(long = "fuse-workers")]
+    pub fuse_workers: Option<usize>,
+
+    /// the maximum count of the backend syscalls running concurrently, each of them blocks a
+    /// thread
+    #[structopt(long = "blocking-threads", default_value = "512")]
+    pub blocking_threads: usize,
+
+    /// the maximum count of the background requests (reads ahead, asynchronous direct I/O, etc.)
+    /// the kernel sends to the FUSE concurrently, which is 12 by default. It should be raised
+    /// together with --fuse-workers for volumes serving many I/Os in parallel
+    #[structopt(long = "max-background")]
+    pub max_background: Option<u16>,
+
+    /// the count of the background requests at which the kernel considers the FUSE congested,
+    /// which is 3/4 of --max-background by default
+    #[structopt(long = "congestion-threshold")]
+    pub congestion_threshold: Option<u16>,
+
+    /// log a summary of the requests per second of every method, the errors, and the latency
+    /// added by the injectors, every this many seconds
+    #[structopt(long = "report-interval")]
+    pub report_interval_secs: Option<u64>,
+
+    /// how long the kernel caches the entries looked up, and the attributes in them, in
+    /// milliseconds. They are not cached by default, so that the changes on the backend and the
+    /// injected attributes are seen at once
+    #[structopt(long = "entry-timeout", default_value = "0")]
+    pub entry_timeout_ms: u64,
+
+    /// how long the kernel caches the attributes of the files, in milliseconds
+    #[structopt(long = "attr-timeout", default_value = "0")]
+    pub attr_timeout_ms: u64,
+
+    /// the maximum count of the inodes recorded by the daemon. The inodes forgotten by the kernel
+    /// are kept, with their handles, until the count grows beyond it, when the ones which are not
+    /// referenced by the kernel are evicted from the least recently looked up one
+    #[structopt(long = "max-inodes")]
+    pub max_inodes: Option<usize>,
+
+    /// the longest time the injectors could hold a request, in milliseconds. Beyond it, the
+    /// request goes on without the injection, so that a stuck injector never hangs the FUSE.
+    /// It's not limited by default
+    #[structopt(long = "injection-timeout")]
+    pub injection_timeout_ms: Option<u64>,
+
+    /// fail the requests whose injection timed out with EIO, instead of going on
+    #[structopt(long = "fail-on-injection-timeout")]
+    pub fail_on_injection_timeout: bool,
+
+    /// the maximum count of the files opened on the backend at once. Beyond it, the opens fail
+    /// with EMFILE, instead of running out of the fds of toda. It's not limited by default
+    #[structopt(long = "max-open-files")]
+    pub max_open_files: Option<usize>,
+
+    /// the maximum count of the directories opened on the backend at once, beyond which the
+    /// opens fail with EMFILE
+    #[structopt(long = "max-open-dirs")]
+    pub max_open_dirs: Option<usize>,
+
+    /// the maximum address space of toda in MiB, set as its RLIMIT_AS. Beyond 90% of it, the
+    /// requests are shed with EAGAIN, except the ones releasing the resources, so that a runaway
+    /// experiment never takes the memory of the node. It's not limited by default
+    #[structopt(long = "max-memory")]
+    pub max_memory_mb: Option<u64>,
+
+    /// the maximum count of the fds of toda, set as its RLIMIT_NOFILE. Beyond 90% of it, the opens
+    /// through the FUSE fail with EMFILE
+    #[structopt(long = "max-fds")]
+    pub max_fds: Option<u64>,
+
+    /// the maximum count of the requests being served at once, each of which holds its buffers
+    /// until it's replied. Beyond it, the requests are handled as --overload decides. It's not
+    /// limited by default
+    #[structopt(long = "max-pending-requests")]
+    pub max_pending_requests: Option<usize>,
+
+    /// what to do with a request beyond --max-pending-requests: "block" stops reading the
+    /// requests from the kernel until a pending one is replied, "shed" replies EAGAIN unless the
+    /// request releases a file or persists the data
+    #[structopt(long = "overload", default_value = "block", possible_values = &["block", "shed"])]
+    pub overload: OverloadPolicy,
+}
+
+impl RuntimeOptions {
+    fn blocking_threads(&self) -> usize {
+        match self.blocking_threads {
+            0 => DEFAULT_BLOCKING_THREADS,
+            threads => threads,
+        }
+    }
+
+    pub fn entry_timeout(&self) -> Duration {
+        Duration::from_millis(self.entry_timeout_ms)
+    }
+
+    pub fn attr_timeout(&self) -> Duration {
+        Duration::from_millis(self.attr_timeout_ms)
+    }
+
+    pub fn injection_timeout(&self) -> Option<Duration> {
+        self.injection_timeout_ms
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis)
+    }
+
+    pub fn max_memory(&self) -> Option<u64> {
+        self.max_memory_mb.filter(|mb| *mb > 0).map(|mb| mb << 20)
+    }
+
+    pub fn max_fds(&self) -> Option<u64> {
+        self.max_fds.filter(|fds| *fds > 0)
+    }
+
+    pub fn report_interval(&self) -> Option<Duration> {
+        self.report_interval_secs
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+}
+
+static OPTIONS: OnceCell<RuntimeOptions> = OnceCell::new();
+
+// configure sets the options of the runtime. It should be called before the runtime is used,
+// or the default options are used
+pub fn configure(options: RuntimeOptions) {
+    if OPTIONS.set(options).is_err() {
+        trace!("runtime has already been configured");
+    }
+}
+
+pub fn options() -> &'static RuntimeOptions {
+    OPTIONS.get_or_init(RuntimeOptions::default)
+}
+
+pub static RUNTIME: Lazy<RwLock<Option<Runtime>>> =
+    Lazy::new(|| RwLock::new(Some(build_runtime())));
+
+fn build_runtime() -> Runtime {
     trace!("build tokio runtime");
 
-    RwLock::new(Some(
-        tokio::runtime::Builder::new()
-            .threaded_scheduler()
-            .thread_name("toda")
-            .enable_all()
-            .build()
-            .unwrap(),
-    ))
-});
+    let options = options();
+    let workers = options.fuse_workers.unwrap_or_else(online_cpus).max(1);
+
+    tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .thread_name("toda")
+        .core_threads(workers)
+        .max_threads(workers + options.blocking_threads())
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn online_cpus() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } {
+        cpus if cpus > 0 => cpus as usize,
+        _ => 1,
+    }
+}
+
+// limits the backend syscalls running concurrently, so that a burst of slow requests (e.g.
+// fsync) queues up instead of spawning a thread for each of them
+static BLOCKING_PERMITS: Lazy<Semaphore> =
+    Lazy::new(|| Semaphore::new(options().blocking_threads()));
+
+// the count of the sessions served by the runtime. The runtime is built again for the first
+// session after it has been shut down, and shut down once the last session ends, so that the
+// injections could be mounted one after another in a process
+static SESSIONS: Lazy<Mutex<usize>> = Lazy::new(|| Mutex::new(0));
+
+// SessionRuntime keeps the runtime up for the session of an injection, until it's dropped
+pub struct SessionRuntime(());
+
+impl SessionRuntime {
+    pub fn acquire() -> SessionRuntime {
+        let mut sessions = SESSIONS.lock().unwrap();
+        let mut runtime = RUNTIME.write().unwrap();
+        if runtime.is_none() {
+            *runtime = Some(build_runtime());
+        }
+        *sessions += 1;
+        SessionRuntime(())
+    }
+}
+
+impl Drop for SessionRuntime {
+    fn drop(&mut self) {
+        let mut sessions = SESSIONS.lock().unwrap();
+        *sessions -= 1;
+        if *sessions == 0 {
+            // the sessions lock is held until the runtime is shut down, so the next session
+            // doesn't pick it up in the meantime
+            let runtime = RUNTIME.write().unwrap().take();
+            drop(runtime);
+        }
+    }
+}
 
 pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
 where
@@ -30,13 +268,193 @@ where
     unreachable!()
 }
 
-pub fn spawn_blocking<F, R>(func: F) -> JoinHandle<R>
+pub async fn spawn_blocking<F, R>(func: F) -> Result<R, JoinError>
 where
     R: Send + 'static,
     F: FnOnce() -> R + Send + 'static,
 {
-    if let Some(runtime) = &*RUNTIME.read().unwrap() {
-        return runtime.handle().spawn_blocking(func);
+    let _permit = BLOCKING_PERMITS.acquire().await;
+
+    let handle = match &*RUNTIME.read().unwrap() {
+        Some(runtime) => runtime.handle().spawn_blocking(func),
+        None => unreachable!(),
+    };
+    handle.await
+}
+
+// Admission bounds the count of the requests being served, which are otherwise spawned as soon
+// as they're read from the kernel
+struct Admission {
+    limit: usize,
+    pending: Mutex<usize>,
+    replied: Condvar,
+    // the most requests pending at once, and the requests shed, since the last report
+    peak: AtomicUsize,
+    shed: AtomicU64,
+}
+
+static ADMISSION: Lazy<Option<Admission>> = Lazy::new(|| {
+    options()
+        .max_pending_requests
+        .filter(|limit| *limit > 0)
+        .map(|limit| Admission {
+            limit,
+            pending: Mutex::new(0),
+            replied: Condvar::new(),
+            peak: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+        })
+});
+
+// AdmissionPermit is held by a request until it's replied
+pub struct AdmissionPermit {
+    admission: Option<&'static Admission>,
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        if let Some(admission) = self.admission {
+            *admission.pending.lock().unwrap() -= 1;
+            admission.replied.notify_one();
+        }
+    }
+}
+
+// admit waits until the request could be served, or returns `None` if it should be replied with
+// EAGAIN. The `critical` requests are never shed. It blocks the calling thread, which is the
+// FUSE dispatch loop, so it must not be called in the runtime.
+pub fn admit(critical: bool) -> Option<AdmissionPermit> {
+    if !critical && MEMORY_PRESSURE.load(Ordering::Relaxed) {
+        SHED_UNDER_PRESSURE.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+
+    let admission = match &*ADMISSION {
+        Some(admission) => admission,
+        None => return Some(AdmissionPermit { admission: None }),
+    };
+
+    let mut pending = admission.pending.lock().unwrap();
+    while *pending >= admission.limit {
+        if options().overload == OverloadPolicy::Shed && !critical {
+            admission.shed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        pending = admission.replied.wait(pending).unwrap();
+    }
+    *pending += 1;
+    admission.peak.fetch_max(*pending, Ordering::Relaxed);
+
+    Some(AdmissionPermit {
+        admission: Some(admission),
+    })
+}
+
+// QueueStats is the depth of the pending requests, and the requests shed since the last report
+#[derive(Debug, Clone, Copy)]
+pub struct QueueStats {
+    pub limit: usize,
+    pub pending: usize,
+    pub peak: usize,
+    pub shed: u64,
+}
+
+// queue_stats returns the stats of the pending requests if they're limited, and resets the peak
+// and the shed count
+pub fn queue_stats() -> Option<QueueStats> {
+    let admission = ADMISSION.as_ref()?;
+    let pending = *admission.pending.lock().unwrap();
+
+    Some(QueueStats {
+        limit: admission.limit,
+        pending,
+        peak: admission.peak.swap(pending, Ordering::Relaxed),
+        shed: admission.shed.swap(0, Ordering::Relaxed),
+    })
+}
+
+// set when the memory or the fds of toda are beyond PRESSURE_RATIO of their limits
+static MEMORY_PRESSURE: AtomicBool = AtomicBool::new(false);
+static FD_PRESSURE: AtomicBool = AtomicBool::new(false);
+// the requests shed under the memory pressure, reported once it's relieved
+static SHED_UNDER_PRESSURE: AtomicU64 = AtomicU64::new(0);
+
+fn set_rlimit(resource: libc::c_int, limit: u64) -> anyhow::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: limit,
+        rlim_max: limit,
+    };
+    let ret = unsafe { libc::setrlimit(resource as _, &limit) };
+    Errno::result(ret)?;
+    Ok(())
+}
+
+// apply_limits sets --max-memory and --max-fds as the rlimits of toda. It should be called after
+// the runtime is configured.
+pub fn apply_limits() -> anyhow::Result<()> {
+    let options = options();
+    if let Some(max_memory) = options.max_memory() {
+        info!("limit the address space to {} bytes", max_memory);
+        set_rlimit(libc::RLIMIT_AS as _, max_memory)?;
+    }
+    if let Some(max_fds) = options.max_fds() {
+        info!("limit the fds to {}", max_fds);
+        set_rlimit(libc::RLIMIT_NOFILE as _, max_fds)?;
+    }
+    Ok(())
+}
+
+// fd_pressure returns true if the fds of toda are near --max-fds, beyond which the opens should
+// fail instead of taking more fds
+pub fn fd_pressure() -> bool {
+    FD_PRESSURE.load(Ordering::Relaxed)
+}
+
+// watch_resources measures the address space and the fds of toda against their limits, and sets
+// the pressure flags, until the runtime is shut down
+pub async fn watch_resources() {
+    let options = options();
+    let (max_memory, max_fds) = (options.max_memory(), options.max_fds());
+    if max_memory.is_none() && max_fds.is_none() {
+        return;
+    }
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let near = |used: u64, limit: Option<u64>| {
+        limit.map_or(false, |limit| used as f64 >= limit as f64 * PRESSURE_RATIO)
+    };
+    loop {
+        // the first field of statm is the size of the address space, in pages
+        let memory = fs::read_to_string("/proc/self/statm")
+            .ok()
+            .and_then(|statm| statm.split_whitespace().next()?.parse::<u64>().ok())
+            .map_or(0, |pages| pages * page_size);
+        let fds = fs::read_dir("/proc/self/fd").map_or(0, |fds| fds.count() as u64);
+
+        let pressure = near(memory, max_memory);
+        if MEMORY_PRESSURE.swap(pressure, Ordering::Relaxed) != pressure {
+            if pressure {
+                warn!(
+                    "address space {} bytes is near the limit, shed the requests",
+                    memory
+                );
+            } else {
+                let shed = SHED_UNDER_PRESSURE.swap(0, Ordering::Relaxed);
+                info!(
+                    "address space is relieved, {} requests have been shed",
+                    shed
+                );
+            }
+        }
+        let pressure = near(fds, max_fds);
+        if FD_PRESSURE.swap(pressure, Ordering::Relaxed) != pressure {
+            if pressure {
+                warn!("{} fds are near the limit, fail the opens", fds);
+            } else {
+                info!("fds are relieved");
+            }
+        }
+
+        delay_for(PRESSURE_INTERVAL).await;
     }
-    unreachable!()
 }
@@ -0,0 +1,82 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use fuser::BackgroundSession;
+
+use super::{AsyncFileSystem, HookFs};
+use crate::injector::{InjectorConfig, MultiInjector};
+use crate::mount::wait_for_fuse_mount;
+
+// TestMount mounts a HookFs under /tmp for the integration tests, with the injectors of the config
+// enabled. The FUSE is unmounted when it's dropped.
+pub struct TestMount {
+    // the mount point, through which the files are injected
+    pub path: PathBuf,
+    // the backend, where the files are kept without any injection
+    pub backend: PathBuf,
+    pub hookfs: Arc<HookFs>,
+    _session: BackgroundSession,
+}
+
+impl TestMount {
+    // mount mounts the FUSE at `/tmp/test_mnt/<name>` over `/tmp/test_mnt_backend/<name>`, which
+    // are recreated empty, with the injectors in the JSON config
+    pub fn mount(name: &str, config: &str) -> Result<TestMount> {
+        TestMount::mount_with(name, config, |hookfs| hookfs)
+    }
+
+    // mount_with mounts the FUSE like `mount`, with the HookFs configured by `build` first. The
+    // FUSE is mounted without `default_permissions` if `build` makes the daemon check them.
+    pub fn mount_with<F>(name: &str, config: &str, build: F) -> Result<TestMount>
+    where
+        F: FnOnce(HookFs) -> HookFs,
+    {
+        let config: Vec<InjectorConfig> = serde_json::from_str(config)?;
+        let path: PathBuf = ["/tmp/test_mnt", name].iter().collect();
+        let backend: PathBuf = ["/tmp/test_mnt_backend", name].iter().collect();
+
+        for dir in [&path, &backend].iter() {
+            fs::remove_dir_all(dir).ok();
+            fs::create_dir_all(dir)?;
+        }
+
+        let hookfs = Arc::new(build(HookFs::new(
+            &path,
+            &backend,
+            MultiInjector::build(config)?,
+        )?));
+        hookfs.enable_injection();
+
+        let mut args = vec!["allow_other", "nonempty", "fsname=toda"];
+        if !hookfs.check_permissions.load(Ordering::SeqCst) {
+            args.push("default_permissions");
+        }
+        let flags: Vec<_> = args
+            .iter()
+            .flat_map(|item| vec![OsStr::new("-o"), OsStr::new(item)])
+            .collect();
+
+        let fs = AsyncFileSystem::from(hookfs.clone());
+        let session = fuser::spawn_mount(fs, &path, &flags)?;
+        wait_for_fuse_mount(&path, Duration::from_secs(10))?;
+
+        Ok(TestMount {
+            path,
+            backend,
+            hookfs,
+            _session: session,
+        })
+    }
+
+    // set_config replaces the injectors of the mounted FUSE
+    pub fn set_config(&self, config: &str) -> Result<()> {
+        let config: Vec<InjectorConfig> = serde_json::from_str(config)?;
+        self.hookfs.set_injector(MultiInjector::build(config)?);
+        Ok(())
+    }
+}
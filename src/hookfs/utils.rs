@@ -1,6 +1,14 @@
+use std::cmp::min;
+use std::ffi::OsStr;
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Component, Path, PathBuf};
+
 use fuser::{FileAttr, FileType, TimeOrNow};
 use libc::{UTIME_NOW, UTIME_OMIT};
 use nix::dir;
+use nix::errno::Errno;
 
 use super::{Error, Result};
 
@@ -76,3 +84,56 @@ pub fn convert_time(t: Option<TimeOrNow>) -> libc::timespec {
         },
     }
 }
+
+// join_name joins a name of a directory entry to the path of its parent. The name must be a single
+// component, or the joined path could escape the parent, e.g. with `..` or an absolute name.
+pub fn join_name(parent: &Path, name: &OsStr) -> Result<PathBuf> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() || bytes == b"." || bytes == b".." || bytes.contains(&b'/') {
+        return Err(Error::Sys(Errno::EINVAL));
+    }
+
+    Ok(parent.join(name))
+}
+
+// check_normal checks that all components of the path are normal names, so that it could not
+// point outside of the directory it's relative to
+pub fn check_normal(path: &Path) -> Result<()> {
+    if path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        Ok(())
+    } else {
+        Err(Error::Sys(Errno::EXDEV))
+    }
+}
+
+// find_holes returns the holes in `[offset, offset + len)` of the file, relative to the offset. The
+// file is seeked with `SEEK_DATA` and `SEEK_HOLE`, which is fine as it's only read and written with
+// `pread` and `pwrite`. The file is regarded as fully allocated if the seek is not supported.
+pub fn find_holes(fd: RawFd, offset: i64, len: usize) -> Vec<Range<usize>> {
+    let end = offset + len as i64;
+    let mut holes = Vec::new();
+    let mut pos = offset;
+    while pos < end {
+        let data = match unsafe { libc::lseek(fd, pos, libc::SEEK_DATA) } {
+            -1 if Errno::last() == Errno::ENXIO => end,
+            -1 => break,
+            data => min(data, end),
+        };
+        if data > pos {
+            holes.push((pos - offset) as usize..(data - offset) as usize);
+        }
+        if data >= end {
+            break;
+        }
+
+        pos = match unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) } {
+            -1 => break,
+            hole => hole,
+        };
+    }
+
+    holes
+}
@@ -32,6 +32,11 @@ impl Injector for AttrOverrideInjector {
         Ok(())
     }
 
+    // the attributes are overridden through `inject_attr`, without any method
+    fn methods(&self) -> filter::Method {
+        filter::Method::empty()
+    }
+
     fn inject_attr(&self, attr: &mut FileAttr, path: &Path) {
         // AttrOverrideInjector should always pass method filter
         if !self.filter.filter(&filter::Method::LOOKUP, path) {
@@ -97,6 +102,8 @@ impl AttrOverrideInjector {
             path: Some(conf.path),
             methods: None,
             percent: conf.percent,
+            seed: conf.seed,
+            offset_range: None,
         })?;
 
         let atime = conf.atime;
@@ -0,0 +1,67 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use fuser::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
+use tracing::{debug, trace};
+
+use super::injector_config::CacheModeConfig;
+use super::{filter, Injector};
+use crate::hookfs::{Reply, Result};
+
+// CacheModeInjector decides whether the kernel caches the pages of the matching files, e.g. to
+// make the WAL of a database bypass the page cache, while its other files are cached. The files
+// opened with direct I/O could not be mapped shared on the kernels before 5.16.
+#[derive(Debug)]
+pub struct CacheModeInjector {
+    filter: filter::Filter,
+    direct_io: Option<bool>,
+    keep_cache: Option<bool>,
+}
+
+#[async_trait]
+impl Injector for CacheModeInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+        let flags = match reply {
+            Reply::Open(open) => &mut open.flags,
+            Reply::Create(create) => &mut create.flags,
+            _ => return Ok(()),
+        };
+
+        if self.filter.filter(method, path) {
+            debug!(
+                "CMI:Setting direct io {:?}, keep cache {:?}",
+                self.direct_io, self.keep_cache
+            );
+            set_flag(flags, FOPEN_DIRECT_IO, self.direct_io);
+            set_flag(flags, FOPEN_KEEP_CACHE, self.keep_cache);
+        }
+        Ok(())
+    }
+
+    fn methods(&self) -> filter::Method {
+        self.filter.methods() & (filter::Method::OPEN | filter::Method::CREATE)
+    }
+}
+
+fn set_flag(flags: &mut i32, flag: u32, value: Option<bool>) {
+    match value {
+        Some(true) => *flags |= flag as i32,
+        Some(false) => *flags &= !(flag as i32),
+        None => {}
+    }
+}
+
+impl CacheModeInjector {
+    pub fn build(conf: CacheModeConfig) -> anyhow::Result<Self> {
+        trace!("build cache mode injector");
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            direct_io: conf.direct_io,
+            keep_cache: conf.keep_cache,
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::Path;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tracing::{debug, trace};
+
+use super::injector_config::CacheTimeoutConfig;
+use super::{filter, Injector};
+use crate::hookfs::{Reply, Result};
+
+// CacheTimeoutInjector overrides how long the kernel caches the entries and attributes of the
+// matching paths, instead of the global --entry-timeout and --attr-timeout. The attributes in an
+// entry are cached as long as the entry.
+#[derive(Debug)]
+pub struct CacheTimeoutInjector {
+    filter: filter::Filter,
+    entry_timeout: Option<Duration>,
+    attr_timeout: Option<Duration>,
+}
+
+#[async_trait]
+impl Injector for CacheTimeoutInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+        let (ttl, timeout) = match reply {
+            Reply::Entry(entry) => (&mut entry.ttl, self.entry_timeout),
+            Reply::Create(create) => (&mut create.ttl, self.entry_timeout),
+            Reply::Attr(attr) => (&mut attr.ttl, self.attr_timeout),
+            _ => return Ok(()),
+        };
+
+        if let Some(timeout) = timeout {
+            if self.filter.filter(method, path) {
+                debug!("CTI:Setting cache timeout {:?}", timeout);
+                *ttl = timeout;
+            }
+        }
+        Ok(())
+    }
+
+    fn methods(&self) -> filter::Method {
+        use filter::Method;
+
+        let methods = Method::LOOKUP
+            | Method::GETATTR
+            | Method::MKNOD
+            | Method::MKDIR
+            | Method::SYMLINK
+            | Method::LINK
+            | Method::CREATE;
+        self.filter.methods() & methods
+    }
+}
+
+impl CacheTimeoutInjector {
+    pub fn build(conf: CacheTimeoutConfig) -> anyhow::Result<Self> {
+        trace!("build cache timeout injector");
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            entry_timeout: conf.entry_timeout,
+            attr_timeout: conf.attr_timeout,
+        })
+    }
+}
@@ -0,0 +1,145 @@
+use std::convert::TryFrom;
+use std::path::Path;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use fuser::FileAttr;
+use glob::{MatchOptions, Pattern};
+use tracing::{debug, trace};
+
+use super::injector_config::{CompositeConfig, ConditionConfig};
+use super::interrupter::Interrupter;
+use super::multi_injector::MultiInjector;
+use super::{filter, Injector};
+use crate::hookfs::{Reply, Requester, Result};
+
+// Condition is a boolean combination of the predicates on a request
+#[derive(Debug)]
+enum Condition {
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+    Path(Pattern),
+    Methods(filter::Method),
+    Pids(Vec<u32>),
+    Uids(Vec<u32>),
+}
+
+impl Condition {
+    fn build(conf: ConditionConfig) -> anyhow::Result<Self> {
+        let build_all = |conditions: Vec<ConditionConfig>| -> anyhow::Result<Vec<Condition>> {
+            conditions.into_iter().map(Condition::build).collect()
+        };
+
+        Ok(match conf {
+            ConditionConfig::All(conditions) => Condition::All(build_all(conditions)?),
+            ConditionConfig::Any(conditions) => Condition::Any(build_all(conditions)?),
+            ConditionConfig::Not(condition) => Condition::Not(box Condition::build(*condition)?),
+            ConditionConfig::Path(path) => Condition::Path(Pattern::new(&path)?),
+            ConditionConfig::Methods(methods) => {
+                let methods = methods
+                    .iter()
+                    .map(|method| {
+                        filter::Method::try_from(method.as_str())
+                            .map_err(|_| anyhow!("unknown method {}", method))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Condition::Methods(
+                    methods
+                        .into_iter()
+                        .fold(filter::Method::empty(), |methods, method| methods | method),
+                )
+            }
+            ConditionConfig::Pids(pids) => Condition::Pids(pids),
+            ConditionConfig::Uids(uids) => Condition::Uids(uids),
+        })
+    }
+
+    // matches evaluates the condition on the request. The conditions on the caller never match
+    // the requests without one.
+    fn matches(&self, method: filter::Method, path: &Path) -> bool {
+        match self {
+            Condition::All(conditions) => conditions.iter().all(|c| c.matches(method, path)),
+            Condition::Any(conditions) => conditions.iter().any(|c| c.matches(method, path)),
+            Condition::Not(condition) => !condition.matches(method, path),
+            Condition::Path(pattern) => pattern.matches_path_with(
+                path,
+                MatchOptions {
+                    case_sensitive: true,
+                    require_literal_separator: true,
+                    require_literal_leading_dot: false,
+                },
+            ),
+            Condition::Methods(methods) => methods.intersects(method),
+            Condition::Pids(pids) => Requester::current().map_or(false, |r| pids.contains(&r.pid)),
+            Condition::Uids(uids) => Requester::current().map_or(false, |r| uids.contains(&r.uid)),
+        }
+    }
+}
+
+// CompositeInjector runs its injectors only on the requests matching the condition, which
+// combines the predicates on the path, method, and caller with `all`, `any` and `not`. The
+// filters of the injectors still apply, and could be left matching everything.
+#[derive(Debug)]
+pub struct CompositeInjector {
+    condition: Condition,
+    injectors: MultiInjector,
+}
+
+#[async_trait]
+impl Injector for CompositeInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if self.condition.matches(*method, path) {
+            debug!("CI:Injecting");
+            self.injectors.inject(method, path).await?;
+        }
+        Ok(())
+    }
+
+    async fn inject_after(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if self.condition.matches(*method, path) {
+            self.injectors.inject_after(method, path).await?;
+        }
+        Ok(())
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+        if self.condition.matches(*method, path) {
+            self.injectors.inject_reply(method, path, reply)?;
+        }
+        Ok(())
+    }
+
+    fn inject_write_data(&self, path: &Path, data: &mut Vec<u8>) -> Result<()> {
+        if self.condition.matches(filter::Method::WRITE, path) {
+            self.injectors.inject_write_data(path, data)?;
+        }
+        Ok(())
+    }
+
+    // the attributes are overridden in the replies of both lookup and getattr
+    fn inject_attr(&self, attr: &mut FileAttr, path: &Path) {
+        let method = filter::Method::LOOKUP | filter::Method::GETATTR;
+        if self.condition.matches(method, path) {
+            self.injectors.inject_attr(attr, path);
+        }
+    }
+
+    fn methods(&self) -> filter::Method {
+        self.injectors.methods()
+    }
+}
+
+impl CompositeInjector {
+    pub fn build(conf: CompositeConfig, interrupter: Interrupter) -> anyhow::Result<Self> {
+        trace!("build composite injector");
+        Ok(Self {
+            condition: Condition::build(conf.when)?,
+            injectors: MultiInjector::build_with(conf.injectors, interrupter)?,
+        })
+    }
+
+    pub fn override_attr(&self) -> bool {
+        self.injectors.override_attr()
+    }
+}
@@ -24,8 +24,7 @@ impl Injector for FaultInjector {
         debug!("test filter");
         if self.filter.filter(method, path) {
             debug!("inject io fault");
-            let mut rng = rand::thread_rng();
-            let attempt: f64 = rng.gen();
+            let attempt: f64 = self.filter.rng().with(|rng| rng.gen());
             let mut attempt = (attempt * (self.sum as f64)) as i32;
 
             for (err, p) in self.errnos.iter() {
@@ -40,6 +39,10 @@ impl Injector for FaultInjector {
 
         Ok(())
     }
+
+    fn methods(&self) -> filter::Method {
+        self.filter.methods()
+    }
 }
 
 impl FaultInjector {
@@ -7,10 +7,11 @@ use glob::{MatchOptions, Pattern};
 use rand::Rng;
 use tracing::{info, trace};
 
-use super::injector_config::FilterConfig;
+use super::injector_config::{FilterConfig, OffsetRange};
+use super::rng::InjectorRng;
 
 bitflags! {
-    pub struct Method: u32 {
+    pub struct Method: u64 {
         const LOOKUP = 1;
         const FORGET = 1<<1;
         const GETATTR = 1<<2;
@@ -43,6 +44,8 @@ bitflags! {
         const GETLK = 1<<29;
         const SETLK = 1<<30;
         const BMAP = 1<<31;
+        const POLL = 1<<32;
+        const LSEEK = 1<<33;
     }
 }
 
@@ -81,6 +84,8 @@ impl TryFrom<&str> for Method {
             "getlk" => Ok(Method::GETLK),
             "setlk" => Ok(Method::SETLK),
             "bmap" => Ok(Method::BMAP),
+            "poll" => Ok(Method::POLL),
+            "lseek" => Ok(Method::LSEEK),
             _ => Err(anyhow!("")),
         }
     }
@@ -92,6 +97,29 @@ pub struct Filter {
     path_filter: Option<Pattern>,
     methods: Method,
     probability: f64,
+    rng: InjectorRng,
+    offset_range: Option<OffsetRange>,
+}
+
+tokio::task_local! {
+    // the range of the file read or written by the request which is being handled by the task
+    pub static IO_RANGE: IoRange;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoRange {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl IoRange {
+    pub fn current() -> Option<IoRange> {
+        IO_RANGE.try_with(|range| *range).ok()
+    }
+
+    fn overlaps(&self, range: &OffsetRange) -> bool {
+        self.offset < range.end && range.start < self.offset + self.size
+    }
 }
 
 impl Filter {
@@ -106,7 +134,9 @@ impl Filter {
                     .filter_map(|method| Method::try_from(method.as_str()).ok())
                     .fold(Method::empty(), |methods, method| methods | method)
             })
-            .unwrap_or(Method::all());
+            // a poll is only injected when it is listed, as a file which is never ready hangs its
+            // pollers forever
+            .unwrap_or(Method::all() - Method::POLL);
 
         let path_filter = conf
             .path.and_then(|path| -> Option<Pattern> {
@@ -120,12 +150,27 @@ impl Filter {
             path_filter,
             methods,
             probability: conf.percent as f64 / 100f64,
+            rng: InjectorRng::new(conf.seed),
+            offset_range: conf.offset_range,
         })
     }
 
+    // methods returns the methods which could pass this filter
+    pub fn methods(&self) -> Method {
+        if self.probability > 0f64 {
+            self.methods
+        } else {
+            Method::empty()
+        }
+    }
+
+    // rng returns the random generator of the injector, which is shared with the filter
+    pub fn rng(&self) -> &InjectorRng {
+        &self.rng
+    }
+
     pub fn filter(&self, method: &Method, path: &Path) -> bool {
-        let mut rng = rand::thread_rng();
-        let p: f64 = rng.gen();
+        let p: f64 = self.rng.with(|rng| rng.gen());
 
         let match_path = match &self.path_filter {
             Some(filter) => filter.matches_path_with(
@@ -139,11 +184,17 @@ impl Filter {
             None => true,
         };
         let match_method = !(self.methods & *method).is_empty();
+        // the requests other than reads and writes never match an offset range
+        let match_offset = match &self.offset_range {
+            Some(range) => IoRange::current().map_or(false, |io| io.overlaps(range)),
+            None => true,
+        };
         let match_probability = p < self.probability;
         trace!("path filter: {}", match_path);
         trace!("method filter: {}", match_method);
+        trace!("offset filter: {}", match_offset);
         trace!("probability: {}", match_probability);
 
-        match_path && match_method && match_probability
+        match_path && match_method && match_offset && match_probability
     }
 }
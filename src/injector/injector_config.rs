@@ -10,20 +10,160 @@ pub enum InjectorConfig {
     Fault(FaultsConfig),
     AttrOverride(AttrOverrideConfig),
     Mistake(MistakesConfig),
+    Composite(CompositeConfig),
+    StatFsOverride(StatFsOverrideConfig),
+    NotFound(NotFoundConfig),
+    CacheTimeout(CacheTimeoutConfig),
+    CacheMode(CacheModeConfig),
+    Protected(ProtectedConfig),
+}
+
+impl InjectorConfig {
+    pub fn order(&self) -> OrderConfig {
+        match self {
+            InjectorConfig::Latency(config) => config.order,
+            InjectorConfig::Fault(config) => config.order,
+            InjectorConfig::AttrOverride(config) => config.order,
+            InjectorConfig::Mistake(config) => config.order,
+            InjectorConfig::Composite(config) => config.order,
+            InjectorConfig::StatFsOverride(config) => config.order,
+            InjectorConfig::NotFound(config) => config.order,
+            InjectorConfig::CacheTimeout(config) => config.order,
+            InjectorConfig::CacheMode(config) => config.order,
+            InjectorConfig::Protected(_) => OrderConfig::default(),
+        }
+    }
+}
+
+// OrderConfig decides when the injector runs among the others. The injectors run from the highest
+// priority to the lowest, and in the order of the config if they have the same priority.
+// The first fault is returned, while the following injectors still run, e.g. to apply their
+// latency, unless the faulting injector doesn't continue on fault.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderConfig {
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default = "continue_on_fault")]
+    pub continue_on_fault: bool,
+}
+
+fn continue_on_fault() -> bool {
+    true
+}
+
+impl Default for OrderConfig {
+    fn default() -> Self {
+        OrderConfig {
+            priority: 0,
+            continue_on_fault: continue_on_fault(),
+        }
+    }
+}
+
+// ProtectedConfig lists the globs of the paths which are never injected by the injectors built
+// along with it, whatever their filters, e.g. the binary of the application, the files checked by
+// the liveness probe, and the lock files
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtectedConfig {
+    pub paths: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CompositeConfig {
+    #[serde(flatten)]
+    pub order: OrderConfig,
+
+    pub when: ConditionConfig,
+    pub injectors: Vec<InjectorConfig>,
+}
+
+// ConditionConfig is a predicate on the requests, e.g.
+// `{"all": [{"path": "/mnt/data/**"}, {"pids": [42]}, {"methods": ["write"]}]}`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ConditionConfig {
+    All(Vec<ConditionConfig>),
+    Any(Vec<ConditionConfig>),
+    Not(Box<ConditionConfig>),
+    Path(String),
+    Methods(Vec<String>),
+    Pids(Vec<u32>),
+    Uids(Vec<u32>),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct LatencyConfig {
+    #[serde(flatten)]
+    pub order: OrderConfig,
+
     #[serde(flatten)]
     pub filter: FilterConfig,
-    #[serde(with = "humantime_serde")]
+    #[serde(default, with = "humantime_serde")]
     pub latency: Duration,
+    // the transfer rate of a slow device, in bytes per second. The reads and writes are delayed by
+    // the time to transfer their data in chunks at this rate, in addition to the latency, so that
+    // the large ones are proportionally slower
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    // the size of the chunks transferred at the bandwidth, of which a partial one takes as long as
+    // a full one
+    #[serde(default = "chunk_size")]
+    pub chunk_size: u64,
+    // the maximum count of the delayed operations in flight, like the queue depth of a device
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    // what happens to the operations beyond max_concurrent
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    // whether the operations are delayed before or after they are done on the backend
+    #[serde(default)]
+    pub phase: LatencyPhase,
+}
+
+fn chunk_size() -> u64 {
+    4096
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum LatencyPhase {
+    Before,
+    // the reply is delayed after the operation is done, e.g. a slow acknowledgement of a write
+    After,
+    Both,
+}
+
+impl Default for LatencyPhase {
+    fn default() -> Self {
+        LatencyPhase::Before
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum OverflowPolicy {
+    // wait for an operation in flight to finish before being delayed
+    Queue,
+    // pass through without any delay
+    Pass,
+}
+
+impl Default for OverflowPolicy {
+    fn default() -> Self {
+        OverflowPolicy::Queue
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct FaultsConfig {
+    #[serde(flatten)]
+    pub order: OrderConfig,
+
     #[serde(flatten)]
     pub filter: FilterConfig,
 
@@ -36,6 +176,20 @@ pub struct FilterConfig {
     pub path: Option<String>,
     pub methods: Option<Vec<String>>,
     pub percent: i32,
+    // seeds the random decisions of the injector, so that they are reproducible
+    #[serde(default)]
+    pub seed: Option<u64>,
+    // only the reads and writes overlapping this range of the file are affected
+    #[serde(default)]
+    pub offset_range: Option<OffsetRange>,
+}
+
+// OffsetRange is the range of bytes `[start, end)` of a file
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OffsetRange {
+    pub start: u64,
+    pub end: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -48,8 +202,13 @@ pub struct FaultConfig {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct AttrOverrideConfig {
+    #[serde(flatten)]
+    pub order: OrderConfig,
+
     pub path: String,
     pub percent: i32,
+    #[serde(default)]
+    pub seed: Option<u64>,
 
     pub ino: Option<u64>,
     pub size: Option<u64>,
@@ -65,6 +224,78 @@ pub struct AttrOverrideConfig {
     pub rdev: Option<u32>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NotFoundConfig {
+    #[serde(flatten)]
+    pub order: OrderConfig,
+
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // how long the kernel caches the fake negative entry, which is not cached if it's not set
+    #[serde(default, with = "humantime_serde")]
+    pub entry_timeout: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheTimeoutConfig {
+    #[serde(flatten)]
+    pub order: OrderConfig,
+
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    #[serde(default, with = "humantime_serde")]
+    pub entry_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub attr_timeout: Option<Duration>,
+}
+
+// CacheModeConfig sets or clears the flags of the files opened or created at the matching paths,
+// and keeps the flags decided by toda if unset
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheModeConfig {
+    #[serde(flatten)]
+    pub order: OrderConfig,
+
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // bypass the page cache, so that every read and write reaches toda
+    #[serde(default)]
+    pub direct_io: Option<bool>,
+    // keep the pages cached before the file is opened, instead of invalidating them
+    #[serde(default)]
+    pub keep_cache: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StatFsOverrideConfig {
+    #[serde(flatten)]
+    pub order: OrderConfig,
+
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    pub blocks: Option<StatFsValue>,
+    pub bfree: Option<StatFsValue>,
+    pub bavail: Option<StatFsValue>,
+    pub files: Option<StatFsValue>,
+    pub ffree: Option<StatFsValue>,
+}
+
+// StatFsValue is an absolute count, or a percentage like "5%"
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum StatFsValue {
+    Absolute(u64),
+    Relative(String),
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum FileType {
@@ -96,12 +327,24 @@ pub enum MistakeType {
 pub struct MistakeConfig {
     pub filling: MistakeType,
     pub max_length: usize,
+    #[serde(default)]
     pub max_occurrences: usize,
+    // the expected count of mistakes per KiB of data, instead of up to max_occurrences in every
+    // request. The data is split into KiB chunks aligned to the offset in the file, so that the
+    // corruption is as dense whatever the size of the reads, which depends on the readahead.
+    #[serde(default)]
+    pub density: Option<f64>,
+    // keep the holes of sparse files untouched while corrupting the data read from them
+    #[serde(default)]
+    pub preserve_holes: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct MistakesConfig {
+    #[serde(flatten)]
+    pub order: OrderConfig,
+
     pub mistake: MistakeConfig,
     #[serde(flatten)]
     pub filter: FilterConfig,
@@ -0,0 +1,27 @@
+use std::sync::{Arc, Mutex};
+
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+// Interrupter is shared by a MultiInjector with all the injectors built under it, including the
+// ones in the composite injectors. The injectors which block a request (e.g. the latency) wait
+// on its token, so that all of them are cancelled at once when the injection is disabled, or the
+// injectors are replaced.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupter(Arc<Mutex<CancellationToken>>);
+
+impl Interrupter {
+    // token returns the token the blocking injectors should wait on, which is cancelled by the
+    // next interrupt
+    pub fn token(&self) -> CancellationToken {
+        self.0.lock().unwrap().clone()
+    }
+
+    // interrupt cancels the requests blocked by the injectors. The token is replaced by a new
+    // one, so that the requests after the injection is enabled again are still injected.
+    pub fn interrupt(&self) {
+        debug!("interrupt injectors");
+        let token = std::mem::replace(&mut *self.0.lock().unwrap(), CancellationToken::new());
+        token.cancel();
+    }
+}
@@ -2,29 +2,76 @@ use std::path::Path;
 use std::time::Duration;
 
 use async_trait::async_trait;
-use tokio::time::delay_for;
 use tokio::select;
-use tokio_util::sync::CancellationToken;
+use tokio::sync::Semaphore;
+use tokio::time::delay_for;
 use tracing::{debug, trace};
 
-use super::injector_config::LatencyConfig;
+use super::filter::IoRange;
+use super::injector_config::{LatencyConfig, LatencyPhase, OverflowPolicy};
+use super::interrupter::Interrupter;
 use super::{filter, Injector};
 use crate::hookfs::Result;
 
 #[derive(Debug)]
 pub struct LatencyInjector {
     latency: Duration,
+    bandwidth: Option<u64>,
+    chunk_size: u64,
     filter: filter::Filter,
-    cancel_token: CancellationToken,
+    interrupter: Interrupter,
+
+    // limits the delayed operations in flight, if max_concurrent is set
+    slots: Option<Semaphore>,
+    overflow: OverflowPolicy,
+    phase: LatencyPhase,
 }
 
 #[async_trait]
 impl Injector for LatencyInjector {
     async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if self.phase != LatencyPhase::After {
+            self.delay(method, path).await;
+        }
+        Ok(())
+    }
+
+    async fn inject_after(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if self.phase != LatencyPhase::Before {
+            self.delay(method, path).await;
+        }
+        Ok(())
+    }
+
+    fn methods(&self) -> filter::Method {
+        self.filter.methods()
+    }
+}
+
+impl LatencyInjector {
+    async fn delay(&self, method: &filter::Method, path: &Path) {
         trace!("test for filter");
         if self.filter.filter(method, path) {
-            let token = self.cancel_token.clone();
-            let latency = self.latency;
+            let token = self.interrupter.token();
+            let latency = self.latency + self.transfer_time();
+
+            let _permit = match &self.slots {
+                Some(slots) if self.overflow == OverflowPolicy::Pass => match slots.try_acquire() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        debug!("too many delayed operations, pass through");
+                        return;
+                    }
+                },
+                Some(slots) => select! {
+                    permit = slots.acquire() => Some(permit),
+                    _ = token.cancelled() => {
+                        debug!("cancelled while queueing");
+                        return;
+                    }
+                },
+                None => None,
+            };
             debug!("inject io delay {:?}", latency);
 
             select! {
@@ -36,24 +83,35 @@ impl Injector for LatencyInjector {
 
             debug!("latency finished");
         }
-
-        Ok(())
     }
 
-    fn interrupt(&self) {
-        debug!("interrupt latency");
-        self.cancel_token.cancel();
+    // transfer_time returns the time to transfer the data of the read or write at the bandwidth,
+    // which is zero for the other requests
+    fn transfer_time(&self) -> Duration {
+        match (self.bandwidth, IoRange::current()) {
+            (Some(bandwidth), Some(io)) => {
+                let chunks = (io.size + self.chunk_size - 1) / self.chunk_size;
+                Duration::from_secs_f64((chunks * self.chunk_size) as f64 / bandwidth as f64)
+            }
+            _ => Duration::default(),
+        }
     }
-}
 
-impl LatencyInjector {
-    pub fn build(conf: LatencyConfig) -> anyhow::Result<Self> {
+    pub fn build(conf: LatencyConfig, interrupter: Interrupter) -> anyhow::Result<Self> {
         trace!("build latency injector");
 
         Ok(Self {
             latency: conf.latency,
+            bandwidth: conf.bandwidth.filter(|bandwidth| *bandwidth > 0),
+            chunk_size: conf.chunk_size.max(1),
             filter: filter::Filter::build(conf.filter)?,
-            cancel_token: CancellationToken::new(),
+            interrupter,
+            slots: conf
+                .max_concurrent
+                .filter(|max| *max > 0)
+                .map(Semaphore::new),
+            overflow: conf.overflow,
+            phase: conf.phase,
         })
     }
 }
@@ -1,14 +1,19 @@
 use std::cmp::{max, min};
+use std::ops::Range;
 use std::path::Path;
 
 use async_trait::async_trait;
-use rand::Rng;
+use rand::{Rng, RngCore};
 use tracing::{debug, trace};
 
+use super::filter::IoRange;
 use super::injector_config::{MistakeConfig, MistakeType, MistakesConfig};
 use super::{filter, Injector};
 use crate::hookfs::{Reply, Result};
 
+// the size of the chunks, in which the mistakes are placed with the configured density
+const DENSITY_CHUNK: usize = 1024;
+
 #[derive(Debug)]
 pub struct MistakeInjector {
     mistake: MistakeConfig,
@@ -25,9 +30,18 @@ impl Injector for MistakeInjector {
     fn inject_reply(&self, method: &super::Method, path: &Path, reply: &mut Reply) -> Result<()> {
         if self.filter.filter(method, path) {
             debug!("MI:Injecting reply");
-            if let Reply::Data(data) = reply {
-                let data = &mut data.data;
-                self.handle(data)?;
+            match reply {
+                Reply::Data(data) => {
+                    let holes = if self.mistake.preserve_holes {
+                        data.holes()
+                    } else {
+                        Vec::new()
+                    };
+                    self.handle_with_holes(&mut data.data, &holes)?
+                }
+                // a mistaken poll never reports the file as ready
+                Reply::Poll(poll) => poll.revents = 0,
+                _ => {}
             }
         }
         Ok(())
@@ -40,6 +54,10 @@ impl Injector for MistakeInjector {
         }
         Ok(())
     }
+
+    fn methods(&self) -> super::Method {
+        self.filter.methods()
+    }
 }
 
 impl MistakeInjector {
@@ -51,35 +69,113 @@ impl MistakeInjector {
         })
     }
     pub fn handle(&self, data: &mut Vec<u8>) -> Result<()> {
+        self.handle_with_holes(data, &[])
+    }
+
+    // handle_with_holes sabotages the data, except the ranges in holes, which are read from the
+    // holes of a sparse file and are kept as zero
+    pub fn handle_with_holes(&self, data: &mut Vec<u8>, holes: &[Range<usize>]) -> Result<()> {
         trace!("sabotage data");
-        let mut rng = rand::thread_rng();
-        let data_length = data.len();
-        let mistake = &self.mistake;
-        let occurrence = match mistake.max_occurrences {
+        self.filter
+            .rng()
+            .with(|rng| self.sabotage(rng, data, holes));
+        Ok(())
+    }
+
+    fn sabotage(&self, rng: &mut dyn RngCore, data: &mut Vec<u8>, holes: &[Range<usize>]) {
+        if let Some(density) = self.mistake.density {
+            return self.sabotage_chunks(rng, data, holes, density);
+        }
+
+        let occurrence = match self.mistake.max_occurrences {
             0 => 0,
             mo => rng.gen_range(1, mo + 1),
         };
         for _ in 0..occurrence {
-            let pos = rng.gen_range(0, max(data_length, 1));
-            let length = match min(mistake.max_length, data_length - pos) {
-                0 => 0,
-                l => rng.gen_range(1, l + 1),
-            };
-            debug!(
-                "Setting index [{},{}) to {:?}",
-                pos,
-                pos + length,
-                mistake.filling
+            let pos = rng.gen_range(0, max(data.len(), 1));
+            self.mistake_at(rng, data, holes, pos);
+        }
+    }
+
+    // sabotage_chunks splits the data into the chunks aligned to the offset in the file, and
+    // places the expected count of mistakes in each of them, in proportion to its length
+    fn sabotage_chunks(
+        &self,
+        rng: &mut dyn RngCore,
+        data: &mut Vec<u8>,
+        holes: &[Range<usize>],
+        density: f64,
+    ) {
+        let offset = IoRange::current().map_or(0, |io| io.offset as usize);
+        let mut start = 0;
+        while start < data.len() {
+            let end = min(
+                data.len(),
+                (offset + start) / DENSITY_CHUNK * DENSITY_CHUNK + DENSITY_CHUNK - offset,
             );
+            let expected = density * (end - start) as f64 / DENSITY_CHUNK as f64;
+            let occurrence =
+                expected.trunc() as usize + (rng.gen::<f64>() < expected.fract()) as usize;
+            for _ in 0..occurrence {
+                let pos = rng.gen_range(start, end);
+                self.mistake_at(rng, data, holes, pos);
+            }
+            start = end;
+        }
+    }
+
+    // mistake_at fills a random length of the data from the position, out of the holes
+    fn mistake_at(
+        &self,
+        rng: &mut dyn RngCore,
+        data: &mut Vec<u8>,
+        holes: &[Range<usize>],
+        pos: usize,
+    ) {
+        let mistake = &self.mistake;
+        let length = match min(mistake.max_length, data.len() - pos) {
+            0 => 0,
+            l => rng.gen_range(1, l + 1),
+        };
+        debug!(
+            "Setting index [{},{}) to {:?}",
+            pos,
+            pos + length,
+            mistake.filling
+        );
+        for range in subtract_holes(pos..pos + length, holes) {
             match mistake.filling {
                 MistakeType::Zero => {
-                    for item in data.iter_mut().skip(pos).take(length) {
+                    for item in data[range].iter_mut() {
                         *item = 0;
                     }
                 }
-                MistakeType::Random => rng.fill(&mut data[pos..pos + length]),
+                MistakeType::Random => rng.fill(&mut data[range]),
             }
         }
-        Ok(())
     }
 }
+
+// subtract_holes splits the range into the parts which are not covered by the holes, which are
+// sorted and don't overlap with each other
+fn subtract_holes(range: Range<usize>, holes: &[Range<usize>]) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = range.start;
+    for hole in holes {
+        if hole.end <= start {
+            continue;
+        }
+        if hole.start >= range.end {
+            break;
+        }
+        if hole.start > start {
+            ranges.push(start..hole.start);
+        }
+        start = hole.end;
+    }
+    if start < range.end {
+        ranges.push(start..range.end);
+    }
+
+    ranges
+}
@@ -1,18 +1,27 @@
 mod attr_override_injector;
+mod cache_mode_injector;
+mod cache_timeout_injector;
+mod composite_injector;
 mod fault_injector;
 mod filter;
 mod injector_config;
+mod interrupter;
 mod latency_injector;
 mod mistake_injector;
 mod multi_injector;
+mod not_found_injector;
+mod preset;
+mod rng;
+mod statfs_override_injector;
 
 use std::path::Path;
 
 use async_trait::async_trait;
-pub use filter::Method;
+pub use filter::{IoRange, Method, IO_RANGE};
 use fuser::FileAttr;
-pub use injector_config::InjectorConfig;
+pub use injector_config::{InjectorConfig, ProtectedConfig};
 pub use multi_injector::MultiInjector;
+pub use preset::{Preset, PRESETS};
 
 use crate::hookfs::{Reply, Result};
 
@@ -20,6 +29,11 @@ use crate::hookfs::{Reply, Result};
 pub trait Injector: Send + Sync + std::fmt::Debug {
     async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()>;
 
+    // inject_after is called after the operation has been done on the backend, before replying
+    async fn inject_after(&self, _method: &filter::Method, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
     fn inject_reply(
         &self,
         _method: &filter::Method,
@@ -34,5 +48,11 @@ pub trait Injector: Send + Sync + std::fmt::Debug {
 
     fn inject_attr(&self, _attr: &mut FileAttr, _path: &Path) {}
 
+    // methods returns the methods which could be affected by this injector
+    fn methods(&self) -> filter::Method {
+        filter::Method::all()
+    }
+
+    // interrupt cancels the requests blocked by the injector, e.g. when the injection is disabled
     fn interrupt(&self) {}
 }
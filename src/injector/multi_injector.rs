@@ -2,82 +2,241 @@ use std::path::Path;
 
 use async_trait::async_trait;
 use fuser::FileAttr;
+use glob::{MatchOptions, Pattern};
 use tracing::trace;
 
 use super::attr_override_injector::AttrOverrideInjector;
+use super::cache_mode_injector::CacheModeInjector;
+use super::cache_timeout_injector::CacheTimeoutInjector;
+use super::composite_injector::CompositeInjector;
 use super::fault_injector::FaultInjector;
 use super::injector_config::InjectorConfig;
+use super::interrupter::Interrupter;
 use super::latency_injector::LatencyInjector;
 use super::mistake_injector::MistakeInjector;
+use super::not_found_injector::NotFoundInjector;
+use super::statfs_override_injector::StatFsOverrideInjector;
 use super::{filter, Injector};
-use crate::hookfs::{Reply, Result};
+use crate::hookfs::{Error, Reply, Result};
 
+#[derive(Debug)]
+struct Chained {
+    injector: Box<dyn Injector>,
+    continue_on_fault: bool,
+}
+
+impl Chained {
+    // fail keeps the first fault of the chain, and returns whether the following injectors
+    // should still run
+    fn fail(&self, fault: &mut Option<Error>, err: Error) -> bool {
+        fault.get_or_insert(err);
+        self.continue_on_fault
+    }
+}
+
+// MultiInjector runs the injectors in the order of their priorities. Every injector runs on the
+// request, so that all the latencies are applied, and the first fault is returned, unless the
+// faulting injector doesn't continue on fault and the following ones are skipped.
 #[derive(Debug)]
 pub struct MultiInjector {
-    injectors: Vec<Box<dyn Injector>>,
+    injectors: Vec<Chained>,
+    interrupter: Interrupter,
+    // the paths which are never injected, which are consulted before any injector
+    protected: Vec<Pattern>,
+
+    // the union of the methods of all injectors, and whether any of them overrides attributes
+    methods: filter::Method,
+    override_attr: bool,
 }
 
 impl MultiInjector {
     pub fn build(conf: Vec<InjectorConfig>) -> anyhow::Result<Self> {
+        Self::build_with(conf, Interrupter::default())
+    }
+
+    // build_with builds the injectors, which are interrupted by the interrupter
+    pub(super) fn build_with(
+        conf: Vec<InjectorConfig>,
+        interrupter: Interrupter,
+    ) -> anyhow::Result<Self> {
         trace!("build multiinjectors");
         let mut injectors = Vec::new();
+        let mut override_attr = false;
+        let mut protected = Vec::new();
 
         for injector in conf.into_iter() {
+            let order = injector.order();
             let injector = match injector {
+                InjectorConfig::Protected(config) => {
+                    for path in config.paths.iter() {
+                        protected.push(Pattern::new(path)?);
+                    }
+                    continue;
+                }
                 InjectorConfig::Fault(faults) => {
                     (box FaultInjector::build(faults)?) as Box<dyn Injector>
                 }
                 InjectorConfig::Latency(latency) => {
-                    (box LatencyInjector::build(latency)?) as Box<dyn Injector>
+                    let latency = LatencyInjector::build(latency, interrupter.clone())?;
+                    (box latency) as Box<dyn Injector>
                 }
                 InjectorConfig::AttrOverride(attr_override) => {
+                    override_attr = true;
                     (box AttrOverrideInjector::build(attr_override)?) as Box<dyn Injector>
                 }
                 InjectorConfig::Mistake(mistakes) => {
                     (box MistakeInjector::build(mistakes)?) as Box<dyn Injector>
                 }
+                InjectorConfig::StatFsOverride(statfs) => {
+                    (box StatFsOverrideInjector::build(statfs)?) as Box<dyn Injector>
+                }
+                InjectorConfig::NotFound(not_found) => {
+                    (box NotFoundInjector::build(not_found)?) as Box<dyn Injector>
+                }
+                InjectorConfig::CacheTimeout(cache_timeout) => {
+                    (box CacheTimeoutInjector::build(cache_timeout)?) as Box<dyn Injector>
+                }
+                InjectorConfig::CacheMode(cache_mode) => {
+                    (box CacheModeInjector::build(cache_mode)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Composite(composite) => {
+                    let composite = CompositeInjector::build(composite, interrupter.clone())?;
+                    override_attr |= composite.override_attr();
+                    (box composite) as Box<dyn Injector>
+                }
             };
-            injectors.push(injector)
+            injectors.push((order, injector))
         }
 
-        Ok(Self { injectors })
+        // the sort is stable, so the injectors of the same priority keep the order of the config
+        injectors.sort_by_key(|(order, _)| std::cmp::Reverse(order.priority));
+        let injectors: Vec<_> = injectors
+            .into_iter()
+            .map(|(order, injector)| Chained {
+                injector,
+                continue_on_fault: order.continue_on_fault,
+            })
+            .collect();
+
+        let methods = injectors
+            .iter()
+            .fold(filter::Method::empty(), |methods, chained| {
+                methods | chained.injector.methods()
+            });
+
+        Ok(Self {
+            injectors,
+            interrupter,
+            protected,
+            methods,
+            override_attr,
+        })
+    }
+
+    // override_attr returns whether any injector could override the attributes
+    pub fn override_attr(&self) -> bool {
+        self.override_attr
+    }
+
+    fn is_protected(&self, path: &Path) -> bool {
+        let protected = self.protected.iter().any(|pattern| {
+            pattern.matches_path_with(
+                path,
+                MatchOptions {
+                    case_sensitive: true,
+                    require_literal_separator: true,
+                    require_literal_leading_dot: false,
+                },
+            )
+        });
+        if protected {
+            trace!("skip the protected path {}", path.display());
+        }
+        protected
     }
 }
 
 #[async_trait]
 impl Injector for MultiInjector {
     async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
-        for injector in self.injectors.iter() {
-            injector.inject(method, path).await?
+        if self.is_protected(path) {
+            return Ok(());
+        }
+        let mut fault = None;
+        for chained in self.injectors.iter() {
+            if let Err(err) = chained.injector.inject(method, path).await {
+                if !chained.fail(&mut fault, err) {
+                    break;
+                }
+            }
+        }
+
+        fault.map_or(Ok(()), Err)
+    }
+
+    async fn inject_after(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if self.is_protected(path) {
+            return Ok(());
+        }
+        let mut fault = None;
+        for chained in self.injectors.iter() {
+            if let Err(err) = chained.injector.inject_after(method, path).await {
+                if !chained.fail(&mut fault, err) {
+                    break;
+                }
+            }
         }
 
-        Ok(())
+        fault.map_or(Ok(()), Err)
     }
 
     fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
-        for injector in self.injectors.iter() {
-            injector.inject_reply(method, path, reply)?
+        if self.is_protected(path) {
+            return Ok(());
+        }
+        let mut fault = None;
+        for chained in self.injectors.iter() {
+            if let Err(err) = chained.injector.inject_reply(method, path, reply) {
+                if !chained.fail(&mut fault, err) {
+                    break;
+                }
+            }
         }
 
-        Ok(())
+        fault.map_or(Ok(()), Err)
     }
 
     fn inject_attr(&self, attr: &mut FileAttr, path: &Path) {
-        for injector in self.injectors.iter() {
-            injector.inject_attr(attr, path)
+        if self.is_protected(path) {
+            return;
+        }
+        for chained in self.injectors.iter() {
+            chained.injector.inject_attr(attr, path)
         }
     }
 
     fn inject_write_data(&self, path: &Path, data: &mut Vec<u8>) -> Result<()> {
-        for injector in self.injectors.iter() {
-            injector.inject_write_data(path, data)?;
+        if self.is_protected(path) {
+            return Ok(());
+        }
+        let mut fault = None;
+        for chained in self.injectors.iter() {
+            if let Err(err) = chained.injector.inject_write_data(path, data) {
+                if !chained.fail(&mut fault, err) {
+                    break;
+                }
+            }
         }
-        Ok(())
+
+        fault.map_or(Ok(()), Err)
     }
 
+    // interrupt cancels the requests blocked by any of the injectors, which share the interrupter
     fn interrupt(&self) {
-        for injector in self.injectors.iter() {
-            injector.interrupt();
-        }
+        self.interrupter.interrupt();
+    }
+
+    fn methods(&self) -> filter::Method {
+        self.methods
     }
 }
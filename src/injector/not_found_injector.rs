@@ -0,0 +1,57 @@
+use std::path::Path;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use nix::errno::Errno;
+use tracing::{debug, trace};
+
+use super::injector_config::NotFoundConfig;
+use super::{filter, Injector};
+use crate::hookfs::{Error, Reply, Result};
+
+// NotFoundInjector makes the lookups of the existing paths fail with ENOENT. The fake result is
+// cached by the kernel as a negative entry for the entry timeout, if it's set. Otherwise, the
+// lookup fails before touching the backend, and the kernel looks it up again next time.
+#[derive(Debug)]
+pub struct NotFoundInjector {
+    filter: filter::Filter,
+    entry_timeout: Option<Duration>,
+}
+
+#[async_trait]
+impl Injector for NotFoundInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if self.entry_timeout.is_none() && self.filter.filter(method, path) {
+            debug!("NFI:Injecting ENOENT");
+            return Err(Error::Sys(Errno::ENOENT));
+        }
+        Ok(())
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+        if let (Some(timeout), Reply::Entry(entry)) = (self.entry_timeout, reply) {
+            if self.filter.filter(method, path) {
+                debug!("NFI:Injecting negative entry");
+                entry.stat.ino = 0;
+                entry.ttl = timeout;
+            }
+        }
+        Ok(())
+    }
+
+    fn methods(&self) -> filter::Method {
+        self.filter.methods() & filter::Method::LOOKUP
+    }
+}
+
+impl NotFoundInjector {
+    pub fn build(conf: NotFoundConfig) -> anyhow::Result<Self> {
+        trace!("build not found injector");
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            entry_timeout: conf
+                .entry_timeout
+                .filter(|timeout| *timeout > Duration::ZERO),
+        })
+    }
+}
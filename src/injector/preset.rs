@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+
+use super::InjectorConfig;
+
+// Preset is a curated experiment built into toda, which could be applied without writing the
+// config by hand. The configs are written in JSON, in the same form as the `update` requests.
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    config: fn() -> Value,
+}
+
+impl Preset {
+    pub fn find(name: &str) -> Result<&'static Preset> {
+        PRESETS
+            .iter()
+            .find(|preset| preset.name == name)
+            .ok_or(anyhow!("unknown preset: {}", name))
+    }
+
+    pub fn config(&self) -> Result<Vec<InjectorConfig>> {
+        Ok(serde_json::from_value((self.config)())?)
+    }
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "slow-disk",
+        description: "delay every read, write and fsync by 100ms",
+        config: slow_disk,
+    },
+    Preset {
+        name: "flaky-disk",
+        description: "fail 10% of the reads and writes with EIO",
+        config: flaky_disk,
+    },
+    Preset {
+        name: "disk-full",
+        description: "fail the writes and creations with ENOSPC, and report no free space",
+        config: disk_full,
+    },
+    Preset {
+        name: "bit-rot",
+        description: "corrupt a random byte in every 10MiB read on average",
+        config: bit_rot,
+    },
+    Preset {
+        name: "fsync-lies",
+        description: "acknowledge 10% of the writes, but zero a part of the data on the backend",
+        config: fsync_lies,
+    },
+];
+
+fn slow_disk() -> Value {
+    json!([{
+        "type": "latency",
+        "methods": ["read", "write", "fsync"],
+        "percent": 100,
+        "latency": "100ms",
+    }])
+}
+
+fn flaky_disk() -> Value {
+    json!([{
+        "type": "fault",
+        "methods": ["read", "write"],
+        "percent": 10,
+        "faults": [{"errno": libc::EIO, "weight": 1}],
+    }])
+}
+
+fn disk_full() -> Value {
+    json!([
+        {
+            "type": "fault",
+            "methods": ["write", "create", "mknod", "mkdir", "symlink", "link", "setxattr"],
+            "percent": 100,
+            "faults": [{"errno": libc::ENOSPC, "weight": 1}],
+        },
+        {
+            "type": "statFsOverride",
+            "percent": 100,
+            "bfree": 0,
+            "bavail": 0,
+        },
+    ])
+}
+
+fn bit_rot() -> Value {
+    json!([{
+        "type": "mistake",
+        "methods": ["read"],
+        "percent": 100,
+        "mistake": {
+            "filling": "random",
+            "maxLength": 1,
+            "density": 0.0001,
+        },
+    }])
+}
+
+// the FUSE could not skip the fsync on the backend, so the lie is shown as its outcome after a
+// power loss: the acknowledged data is not what is read back
+fn fsync_lies() -> Value {
+    json!([{
+        "type": "mistake",
+        "methods": ["write"],
+        "percent": 10,
+        "mistake": {
+            "filling": "zero",
+            "maxLength": 4096,
+            "maxOccurrences": 1,
+        },
+    }])
+}
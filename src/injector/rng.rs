@@ -0,0 +1,27 @@
+use std::sync::Mutex;
+
+use rand::rngs::SmallRng;
+use rand::{RngCore, SeedableRng};
+
+// InjectorRng is the source of the random decisions of an injector. It's seeded when the injector
+// is configured with a seed, so that the same operations make the same decisions in every run.
+// Otherwise, the thread local generator is used.
+#[derive(Debug, Default)]
+pub struct InjectorRng {
+    seeded: Option<Mutex<SmallRng>>,
+}
+
+impl InjectorRng {
+    pub fn new(seed: Option<u64>) -> Self {
+        InjectorRng {
+            seeded: seed.map(|seed| Mutex::new(SmallRng::seed_from_u64(seed))),
+        }
+    }
+
+    pub fn with<T, F: FnOnce(&mut dyn RngCore) -> T>(&self, f: F) -> T {
+        match &self.seeded {
+            Some(rng) => f(&mut *rng.lock().unwrap()),
+            None => f(&mut rand::thread_rng()),
+        }
+    }
+}
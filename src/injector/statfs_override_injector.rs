@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use tracing::{debug, trace};
+
+use super::injector_config::{StatFsOverrideConfig, StatFsValue};
+use super::{filter, Injector};
+use crate::hookfs::{Reply, Result};
+
+// Value is the overridden value of a field, which is absolute, or a percentage of a total
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    Absolute(u64),
+    Percent(f64),
+}
+
+impl Value {
+    fn build(value: Option<StatFsValue>) -> anyhow::Result<Option<Value>> {
+        let value = match value {
+            Some(StatFsValue::Absolute(value)) => Value::Absolute(value),
+            Some(StatFsValue::Relative(value)) => {
+                let percent: f64 = value
+                    .strip_suffix('%')
+                    .ok_or(anyhow!("{} should be a percentage like 5%", value))?
+                    .trim()
+                    .parse()?;
+                if !(0.0..=100.0).contains(&percent) {
+                    return Err(anyhow!("{} is out of range", value));
+                }
+                Value::Percent(percent)
+            }
+            None => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    fn apply(&self, total: u64) -> u64 {
+        match self {
+            Value::Absolute(value) => *value,
+            Value::Percent(percent) => (total as f64 * percent / 100.0) as u64,
+        }
+    }
+}
+
+// StatFsOverrideInjector overrides the usage of the filesystem in the statfs replies, e.g. to make
+// it look nearly full. The percentages of blocks and files are of the original ones, and the
+// percentages of the free and available blocks, and the free files, are of the (overridden) total.
+#[derive(Debug)]
+pub struct StatFsOverrideInjector {
+    filter: filter::Filter,
+
+    blocks: Option<Value>,
+    bfree: Option<Value>,
+    bavail: Option<Value>,
+    files: Option<Value>,
+    ffree: Option<Value>,
+}
+
+#[async_trait]
+impl Injector for StatFsOverrideInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+        if let Reply::StatFs(statfs) = reply {
+            if !self.filter.filter(method, path) {
+                return Ok(());
+            }
+            debug!("SOI:Injecting reply");
+
+            if let Some(blocks) = self.blocks {
+                statfs.blocks = blocks.apply(statfs.blocks);
+            }
+            if let Some(files) = self.files {
+                statfs.files = files.apply(statfs.files);
+            }
+            if let Some(bfree) = self.bfree {
+                statfs.bfree = bfree.apply(statfs.blocks);
+            }
+            if let Some(bavail) = self.bavail {
+                statfs.bavail = bavail.apply(statfs.blocks);
+            }
+            if let Some(ffree) = self.ffree {
+                statfs.ffree = ffree.apply(statfs.files);
+            }
+
+            // the free counts never exceed the totals
+            statfs.bfree = statfs.bfree.min(statfs.blocks);
+            statfs.bavail = statfs.bavail.min(statfs.bfree);
+            statfs.ffree = statfs.ffree.min(statfs.files);
+        }
+        Ok(())
+    }
+
+    fn methods(&self) -> filter::Method {
+        self.filter.methods() & filter::Method::STATFS
+    }
+}
+
+impl StatFsOverrideInjector {
+    pub fn build(conf: StatFsOverrideConfig) -> anyhow::Result<Self> {
+        trace!("build statfs override injector");
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            blocks: Value::build(conf.blocks)?,
+            bfree: Value::build(conf.bfree)?,
+            bavail: Value::build(conf.bavail)?,
+            files: Value::build(conf.files)?,
+            ffree: Value::build(conf.ffree)?,
+        })
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::mount::MountsInfo;
+use crate::utils::staging_location;
+
+// the id of this toda, which tells its staging directory apart from the ones left by the previous
+// runs on the same path
+static INSTANCE_ID: Lazy<String> = Lazy::new(|| format!("{:08x}", rand::random::<u32>()));
+
+pub fn instance_id() -> &'static str {
+    &INSTANCE_ID
+}
+
+// the staging directories created before the instance ids have no id
+fn is_instance_id(id: &str) -> bool {
+    id.is_empty() || (id.len() == 8 && id.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// StaleInstance is a staging directory of the path left by another toda, which has crashed or
+// exited without recovering
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StaleInstance {
+    pub path: PathBuf,
+    pub id: String,
+    // the original directory is still mounted on it
+    pub mounted: bool,
+}
+
+// find_stale_instances lists the staging directories of the path, in the staging directory or next
+// to the path, except the one of this toda
+pub fn find_stale_instances<P: AsRef<Path>>(
+    path: P,
+    staging_dir: Option<&Path>,
+) -> Result<Vec<StaleInstance>> {
+    let (dir, prefix) = staging_location(path, staging_dir)?;
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mounts = MountsInfo::parse_mounts()?;
+    let mut instances = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let name = entry?.file_name();
+        let id = match name.to_str().and_then(|name| name.strip_prefix(&prefix)) {
+            Some(id) if is_instance_id(id) && id != instance_id() => id,
+            _ => continue,
+        };
+
+        let path = dir.join(&name);
+        instances.push(StaleInstance {
+            mounted: mounts.is_mount_point(&path),
+            path,
+            id: id.to_owned(),
+        });
+    }
+    Ok(instances)
+}
+
+// clean_stale_instances puts the original directories kept in the stale staging directories back
+// to the path, and removes the staging directories. The FUSE left by a crashed toda is detached
+// first, while a FUSE still served by a running toda fails the cleanup.
+pub fn clean_stale_instances<P: AsRef<Path>>(
+    path: P,
+    staging_dir: Option<&Path>,
+) -> Result<Vec<StaleInstance>> {
+    let path = path.as_ref();
+    let mounts = MountsInfo::parse_mounts()?;
+    if mounts.is_toda_mount(path) {
+        match fs::metadata(path) {
+            Err(err) if err.raw_os_error() == Some(libc::ENOTCONN) => {
+                warn!("detach the FUSE left on {}", path.display());
+                mounts.detach_mount(path)?;
+            }
+            _ => {
+                return Err(anyhow!(
+                    "{} is still injected by a running toda",
+                    path.display()
+                ))
+            }
+        }
+    }
+
+    let instances = find_stale_instances(path, staging_dir)?;
+    for instance in instances.iter() {
+        info!("clean the staging directory {}", instance.path.display());
+        if instance.mounted {
+            let mounts = MountsInfo::parse_mounts()?;
+            let (original, staged) = (fs::metadata(path)?, fs::metadata(&instance.path)?);
+            // a bind mount is detached, as the original directory is still in place, while a
+            // moved mount is moved back
+            if (original.dev(), original.ino()) == (staged.dev(), staged.ino()) {
+                mounts.detach_mount(&instance.path)?;
+            } else {
+                mounts.move_mount(&instance.path, path)?;
+            }
+        }
+        fs::remove_dir(&instance.path)?;
+    }
+    Ok(instances)
+}
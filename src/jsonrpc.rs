@@ -5,8 +5,10 @@ use jsonrpc_stdio_server::jsonrpc_core::*;
 use jsonrpc_stdio_server::ServerBuilder;
 use tracing::{info, trace};
 
-use crate::hookfs::HookFs;
+use crate::hookfs::{HookFs, HotFile, InodeStats};
 use crate::injector::{InjectorConfig, MultiInjector};
+use crate::replacer::ReplacerStats;
+use crate::telemetry::LogReloader;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Comm {
@@ -35,16 +37,42 @@ pub fn new_handler(config: RpcImpl) -> IoHandler {
 
 #[rpc]
 pub trait Rpc {
+    // get_status returns "ok", "backend is lost" if the original filesystem has gone away, "session
+    // is lost" with the error if the FUSE session has ended unexpectedly, or the error of the
+    // injection, or the statistics of the replacers in JSON if `inst` is "replacer",
+    // the count of the requests of every unsupported operation in JSON if it's "unsupported", or
+    // the count of the opened files and directories in JSON (null if the FUSE is not mounted) if
+    // it's "handles"
     #[rpc(name = "get_status")]
     fn get_status(&self, inst: String) -> Result<String>;
     #[rpc(name = "update")]
     fn update(&self, config: Vec<InjectorConfig>) -> Result<String>;
+    // hot_files returns the I/O statistics of the `n` most frequently accessed files
+    #[rpc(name = "hot_files")]
+    fn hot_files(&self, n: usize) -> Result<Vec<HotFile>>;
+    // inodes returns the size of the inode map, and the `n` directories containing the most
+    // inodes, or null if the FUSE is not mounted
+    #[rpc(name = "inodes")]
+    fn inodes(&self, n: usize) -> Result<Option<InodeStats>>;
+    // pause disables the injection until it's resumed, while the FUSE and the replaced fds are
+    // kept, which is the same as sending SIGUSR1 to toda
+    #[rpc(name = "pause")]
+    fn pause(&self) -> Result<String>;
+    #[rpc(name = "resume")]
+    fn resume(&self) -> Result<String>;
+    // set_log_level replaces the log filter with `level`, which is in the form of `RUST_LOG`
+    #[rpc(name = "set_log_level")]
+    fn set_log_level(&self, level: String) -> Result<String>;
 }
 
 pub struct RpcImpl {
     status: Mutex<anyhow::Result<()>>,
     tx: Mutex<mpsc::Sender<Comm>>,
     hookfs: Option<Arc<HookFs>>,
+    replacer_stats: ReplacerStats,
+    log_reloader: Option<LogReloader>,
+    // the protected paths given on the command line, which are kept in every update
+    protected: Option<InjectorConfig>,
 }
 
 impl RpcImpl {
@@ -53,7 +81,29 @@ impl RpcImpl {
         tx: Mutex<mpsc::Sender<Comm>>,
         hookfs: Option<Arc<HookFs>>,
     ) -> Self {
-        Self { status, tx, hookfs }
+        Self {
+            status,
+            tx,
+            hookfs,
+            replacer_stats: ReplacerStats::default(),
+            log_reloader: None,
+            protected: None,
+        }
+    }
+
+    pub fn with_replacer_stats(mut self, stats: ReplacerStats) -> Self {
+        self.replacer_stats = stats;
+        self
+    }
+
+    pub fn with_log_reloader(mut self, reloader: LogReloader) -> Self {
+        self.log_reloader = Some(reloader);
+        self
+    }
+
+    pub fn with_protected(mut self, protected: Option<InjectorConfig>) -> Self {
+        self.protected = protected;
+        self
     }
 }
 
@@ -64,11 +114,48 @@ impl Drop for RpcImpl {
 }
 
 impl Rpc for RpcImpl {
-    fn get_status(&self, _inst: String) -> Result<String> {
+    fn get_status(&self, inst: String) -> Result<String> {
         info!("rpc get_status called");
-        match &*self.status.lock().unwrap() {
-            Ok(_) => Ok("ok".to_string()),
-            Err(e) => {
+        if inst == "replacer" {
+            return serde_json::to_string(&self.replacer_stats).map_err(|e| Error {
+                code: ErrorCode::InternalError,
+                message: e.to_string(),
+                data: None,
+            });
+        }
+        if inst == "unsupported" {
+            let unsupported = self
+                .hookfs
+                .as_ref()
+                .map(|hookfs| hookfs.unsupported_ops())
+                .unwrap_or_default();
+            return serde_json::to_string(&unsupported).map_err(|e| Error {
+                code: ErrorCode::InternalError,
+                message: e.to_string(),
+                data: None,
+            });
+        }
+        if inst == "handles" {
+            let handles = self.hookfs.as_ref().map(|hookfs| hookfs.handle_stats());
+            return serde_json::to_string(&handles).map_err(|e| Error {
+                code: ErrorCode::InternalError,
+                message: e.to_string(),
+                data: None,
+            });
+        }
+        let backend_lost = self
+            .hookfs
+            .as_ref()
+            .map_or(false, |hookfs| hookfs.backend_lost());
+        let session_error = self
+            .hookfs
+            .as_ref()
+            .and_then(|hookfs| hookfs.session_error());
+        match (&*self.status.lock().unwrap(), session_error) {
+            (Ok(_), Some(err)) => Ok(format!("session is lost: {}", err)),
+            (Ok(_), None) if backend_lost => Ok("backend is lost".to_string()),
+            (Ok(_), None) => Ok("ok".to_string()),
+            (Err(e), _) => {
                 let tx = &self.tx.lock().unwrap();
                 tx.send(Comm::Shutdown)
                     .expect("Send through channel failed");
@@ -76,20 +163,64 @@ impl Rpc for RpcImpl {
             }
         }
     }
-    fn update(&self, config: Vec<InjectorConfig>) -> Result<String> {
+    fn update(&self, mut config: Vec<InjectorConfig>) -> Result<String> {
         info!("rpc update called");
         if let Err(e) = &*self.status.lock().unwrap() {
             return Ok(e.to_string());
         }
+        config.extend(self.protected.clone());
         let injectors = MultiInjector::build(config);
         if let Err(e) = &injectors {
             return Ok(e.to_string());
         }
-        futures::executor::block_on(async {
-            let hookfs = self.hookfs.as_ref().unwrap();
-            let mut current_injectors = hookfs.injector.write().await;
-            *current_injectors = injectors.unwrap();
-        });
+        let hookfs = self.hookfs.as_ref().unwrap();
+        hookfs.set_injector(injectors.unwrap());
+        Ok("ok".to_string())
+    }
+    fn hot_files(&self, n: usize) -> Result<Vec<HotFile>> {
+        info!("rpc hot_files called");
+        Ok(self
+            .hookfs
+            .as_ref()
+            .map(|hookfs| hookfs.hot_files(n))
+            .unwrap_or_default())
+    }
+    fn inodes(&self, n: usize) -> Result<Option<InodeStats>> {
+        info!("rpc inodes called");
+        Ok(self
+            .hookfs
+            .as_ref()
+            .map(|hookfs| futures::executor::block_on(hookfs.inode_stats(n))))
+    }
+    fn pause(&self) -> Result<String> {
+        info!("rpc pause called");
+        match &self.hookfs {
+            Some(hookfs) => {
+                hookfs.disable_injection();
+                Ok("ok".to_string())
+            }
+            None => Ok("injection is not running".to_string()),
+        }
+    }
+    fn resume(&self) -> Result<String> {
+        info!("rpc resume called");
+        match &self.hookfs {
+            Some(hookfs) => {
+                hookfs.enable_injection();
+                Ok("ok".to_string())
+            }
+            None => Ok("injection is not running".to_string()),
+        }
+    }
+    fn set_log_level(&self, level: String) -> Result<String> {
+        info!("rpc set_log_level called");
+        let reloader = match &self.log_reloader {
+            Some(reloader) => reloader,
+            None => return Ok("log level is not reloadable".to_string()),
+        };
+        if let Err(e) = reloader(&level) {
+            return Ok(e.to_string());
+        }
         Ok("ok".to_string())
     }
 }
@@ -19,13 +19,24 @@
 #![allow(clippy::or_fun_call)]
 #![allow(clippy::too_many_arguments)]
 
+pub mod arch;
+pub mod embed;
+pub mod exec_gate;
 pub mod fuse_device;
 pub mod hookfs;
 pub mod injector;
+pub mod instance;
 pub mod jsonrpc;
 pub mod mount;
 pub mod mount_injector;
+pub mod namespace;
+pub mod privilege;
 pub mod ptrace;
+pub mod recorder;
 pub mod replacer;
+pub mod seccomp;
+pub mod shadow;
+pub mod snapshot;
 pub mod stop;
+pub mod telemetry;
 pub mod utils;
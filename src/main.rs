@@ -21,125 +21,379 @@
 
 extern crate derive_more;
 
+mod arch;
+// the API to embed toda, of which the binary only uses a part
+#[allow(dead_code)]
+mod embed;
+mod exec_gate;
 mod fuse_device;
 mod hookfs;
 mod injector;
+mod instance;
 mod jsonrpc;
 mod mount;
 mod mount_injector;
+mod namespace;
+mod privilege;
 mod ptrace;
+mod recorder;
 mod replacer;
+mod seccomp;
+mod shadow;
+mod snapshot;
 mod stop;
+mod telemetry;
 mod utils;
 
 use std::convert::TryFrom;
 use std::os::unix::io::RawFd;
 use std::path::PathBuf;
-use std::sync::{mpsc, Mutex};
-use std::{io, thread};
-
-use anyhow::Result;
-use injector::InjectorConfig;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use embed::{InjectionHandle, MountInjectorBuilder, Stage};
+use glob::Pattern;
+use hookfs::ownership::{Owner, OwnershipOptions};
+use hookfs::runtime::RuntimeOptions;
+use hookfs::HookFs;
+use injector::{InjectorConfig, Preset, ProtectedConfig, PRESETS};
 use jsonrpc::start_server;
-use mount_injector::{MountInjectionGuard, MountInjector};
+use mount_injector::{MountMode, PermissionCheck};
 use nix::sys::signal::{signal, SigHandler, Signal};
 use nix::unistd::{pipe, read, write};
-use replacer::{Replacer, UnionReplacer};
+use replacer::{ReplacerOptions, ReplacerStats, ReplacerStrategy};
+use seccomp::SeccompMode;
+use serde::Serialize;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use tokio::runtime::Runtime;
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 use tracing_subscriber::EnvFilter;
-use utils::encode_path;
 
 #[derive(StructOpt, Debug, Clone)]
-#[structopt(name = "basic")]
+#[structopt(name = "basic", setting = AppSettings::SubcommandsNegateReqs)]
 struct Options {
-    #[structopt(long)]
-    path: PathBuf,
+    /// the path to inject into, which is only optional to list the presets
+    #[structopt(long, required = true)]
+    path: Option<PathBuf>,
+
+    /// run inside the mount and pid namespaces of the process, where the path is resolved
+    #[structopt(long = "target-pid")]
+    target_pid: Option<i32>,
 
     #[structopt(long = "mount-only")]
     mount_only: bool,
 
+    #[structopt(long = "mount-mode", default_value = "move", possible_values = &["move", "bind"])]
+    mount_mode: MountMode,
+
+    /// check the permissions in the kernel with `default_permissions`, or in toda, which honors
+    /// the POSIX ACLs of the original files
+    #[structopt(
+        long = "permission-check",
+        default_value = "kernel",
+        possible_values = &["kernel", "daemon"]
+    )]
+    permission_check: PermissionCheck,
+
+    /// detach the FUSE lazily if it's still busy after all the umount retries
+    #[structopt(long = "lazy-umount")]
+    lazy_umount: bool,
+
+    /// mount at once, but only enable the injection after this delay, e.g. 30s
+    #[structopt(long = "delay", parse(try_from_str = humantime::parse_duration))]
+    delay: Option<Duration>,
+
+    /// stop the injection after it has been enabled for this long, e.g. 5m
+    #[structopt(long = "duration", parse(try_from_str = humantime::parse_duration))]
+    duration: Option<Duration>,
+
+    /// what to do when the --duration is over: disable the injection and keep the FUSE, or
+    /// recover the mount and exit
+    #[structopt(
+        long = "on-duration-end",
+        default_value = "disable",
+        possible_values = &["disable", "recover"]
+    )]
+    on_duration_end: DurationEnd,
+
+    /// never inject into the paths matching the pattern, whatever the filters of the injectors,
+    /// e.g. the binary of the application or its lock files
+    #[structopt(long = "protected-path", number_of_values = 1)]
+    protected_paths: Vec<Pattern>,
+
+    /// hold the execs of the files on the mount of this path, e.g. the root of the container,
+    /// until the FUSE is mounted, so that the workload started along with toda never runs on the
+    /// original directory. It's meant for `--replacer none`, and requires Linux 5.0
+    #[structopt(long = "wait-for-exec")]
+    wait_for_exec: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    replacer: ReplacerOptions,
+
+    #[structopt(flatten)]
+    runtime: RuntimeOptions,
+
+    #[structopt(flatten)]
+    ownership: OwnershipOptions,
+
     #[structopt(short = "v", long = "verbose", default_value = "trace")]
     verbose: String,
-}
 
-#[instrument(skip(option))]
-fn inject(option: Options, injector_config: Vec<InjectorConfig>) -> Result<MountInjectionGuard> {
-    info!("inject with config {:?}", injector_config);
+    /// record the operations through the FUSE to this file, which could be replayed with
+    /// `toda replay`
+    #[structopt(long = "record")]
+    record: Option<PathBuf>,
+
+    /// duplicate the data written through the FUSE to this directory, out of the path, before
+    /// it's corrupted by the injectors, so that the files could be checked with `toda verify`
+    #[structopt(long = "shadow-dir")]
+    shadow_dir: Option<PathBuf>,
+
+    /// keep the original directory in this directory during the injection, e.g. /run/toda,
+    /// instead of `__chaosfs__<name>__<id>` next to it, where the workload could walk into it and
+    /// bypass the injection. It's created accessible to root only
+    #[structopt(long = "staging-dir")]
+    staging_dir: Option<PathBuf>,
+
+    /// snapshot the path into this directory, out of the path, before the injection, with
+    /// reflinks if the filesystem supports them
+    #[structopt(long = "snapshot-dir")]
+    snapshot_dir: Option<PathBuf>,
+
+    /// put the contents in the --snapshot-dir back to the path after recovering the mount
+    #[structopt(long = "restore", requires = "snapshot-dir")]
+    restore: bool,
+
+    /// switch to this uid:gid once the FUSE is mounted and the processes are replaced, keeping
+    /// only the capabilities to serve the files of others and to recover (CAP_SYS_ADMIN,
+    /// CAP_SYS_PTRACE, CAP_DAC_OVERRIDE, etc.)
+    #[structopt(long = "run-as")]
+    run_as: Option<Owner>,
+
+    /// restrict the syscalls of toda to the ones serving the FUSE and recovering, once the setup
+    /// is done: "log" audits the others, "enforce" fails them with EPERM
+    #[structopt(
+        long = "seccomp",
+        default_value = "off",
+        possible_values = &["off", "log", "enforce"]
+    )]
+    seccomp: SeccompMode,
+
+    /// export the tracing spans to the OTLP collector at this endpoint
+    #[structopt(long = "otel-endpoint")]
+    otel_endpoint: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
 
-    let path = option.path.clone();
+#[derive(StructOpt, Debug, Clone)]
+enum Command {
+    /// the built-in experiments
+    Preset(PresetCommand),
+    /// execute the operations recorded with --record against a directory
+    Replay {
+        #[structopt(long)]
+        trace: PathBuf,
+        #[structopt(long)]
+        dir: PathBuf,
+        /// start the operations at their recorded time divided by this, or as fast as possible
+        /// if it's 0
+        #[structopt(long, default_value = "1")]
+        speed: f64,
+    },
+    /// report the files under a directory which differ from the data written to them, as kept
+    /// in the --shadow-dir
+    Verify {
+        #[structopt(long)]
+        shadow: PathBuf,
+        #[structopt(long)]
+        dir: PathBuf,
+    },
+    /// put the contents of a snapshot taken with --snapshot-dir back to a directory, which
+    /// should not be injected at the same time
+    Restore {
+        #[structopt(long)]
+        snapshot: PathBuf,
+        #[structopt(long)]
+        dir: PathBuf,
+    },
+    /// print the mount containing a path, with its propagation, parent and children, in the mount
+    /// namespace of the --target-pid
+    Mounts {
+        #[structopt(long)]
+        path: PathBuf,
+    },
+    /// list the processes holding fds, cwds or mappings under a path, which would be traced by
+    /// the replacer with the --replacer-* options, without injecting it
+    Check {
+        #[structopt(long)]
+        path: PathBuf,
+    },
+    /// put back the original directory left in a staging directory by a toda which crashed on a
+    /// path, with the --staging-dir it ran with, and remove the staging directories
+    Clean {
+        #[structopt(long)]
+        path: PathBuf,
+    },
+}
 
-    info!("canonicalizing path {}", path.display());
-    let path = path.canonicalize()?;
+#[derive(StructOpt, Debug, Clone)]
+enum PresetCommand {
+    /// list the presets
+    List,
+    /// inject with the preset once the path is mounted
+    Apply { name: String },
+}
 
-    let replacer = if !option.mount_only {
-        let mut replacer = UnionReplacer::default();
-        replacer.prepare(&path, &path)?;
+// DurationEnd decides what happens when the injection has lasted for the --duration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DurationEnd {
+    Disable,
+    Recover,
+}
 
-        Some(replacer)
-    } else {
-        None
-    };
+impl FromStr for DurationEnd {
+    type Err = anyhow::Error;
 
-    if let Err(err) = fuse_device::mkfuse_node() {
-        info!("fail to make /dev/fuse node: {}", err)
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "disable" => Ok(DurationEnd::Disable),
+            "recover" => Ok(DurationEnd::Recover),
+            _ => Err(anyhow!("unknown action at the end of duration: {}", s)),
+        }
     }
+}
 
-    let mut injection = MountInjector::create_injection(&option.path, injector_config)?;
-    let mount_guard = injection.mount()?;
-    info!("mount successfully");
-
-    if let Some(mut replacer) = replacer {
-        // At this time, `mount --move` has already been executed.
-        // Our FUSE are mounted on the "path", so we
-        replacer.run()?;
-        drop(replacer);
-        info!("replacer detached");
+impl Options {
+    fn use_replacer(&self) -> bool {
+        !self.mount_only && self.replacer.strategy == ReplacerStrategy::Ptrace
     }
 
-    info!("enable injection");
-    mount_guard.enable_injection();
-
-    Ok(mount_guard)
+    // protected_config returns the config of the --protected-path, which is kept along with the
+    // injectors, including the ones updated through the control API
+    fn protected_config(&self) -> Option<InjectorConfig> {
+        if self.protected_paths.is_empty() {
+            return None;
+        }
+        let paths = self
+            .protected_paths
+            .iter()
+            .map(|pattern| pattern.as_str().to_owned())
+            .collect();
+        Some(InjectorConfig::Protected(ProtectedConfig { paths }))
+    }
 }
 
-#[instrument(skip(option, mount_guard))]
-fn resume(option: Options, mount_guard: MountInjectionGuard) -> Result<()> {
-    info!("disable injection");
-    mount_guard.disable_injection();
-
-    let path = option.path.clone();
-
-    info!("canonicalizing path {}", path.display());
-    let path = path.canonicalize()?;
-    let (_, new_path) = encode_path(&path)?;
+#[instrument(skip(option))]
+fn inject(option: &Options, injector_config: Vec<InjectorConfig>) -> Result<InjectionHandle> {
+    info!("inject with config {:?}", injector_config);
 
-    let replacer = if !option.mount_only {
-        let mut replacer = UnionReplacer::default();
-        replacer.prepare(&path, &new_path)?;
-        info!("running replacer");
-        let result = replacer.run();
-        info!("replace result: {:?}", result);
+    let path = option
+        .path
+        .clone()
+        .ok_or(anyhow!("--path is required"))
+        .context(Stage::Config)?;
 
-        Some(replacer)
+    let replacer = if option.use_replacer() {
+        Some(option.replacer.clone())
     } else {
+        if option.replacer.strategy == ReplacerStrategy::None {
+            for user in mount::find_mount_users(&path).context(Stage::Mount)? {
+                warn!("{} will not be moved onto the FUSE", user);
+            }
+        }
         None
     };
 
-    info!("recovering mount");
-    mount_guard.recover_mount()?;
+    MountInjectorBuilder::new(&path)
+        .with_mount_mode(option.mount_mode)
+        .with_permission_check(option.permission_check)
+        .with_ownership(option.ownership.clone())
+        .with_config(injector_config)
+        .with_replacer(replacer)
+        .with_lazy_umount(option.lazy_umount)
+        .with_injection_enabled(option.delay.is_none())
+        .with_trace(option.record.clone())
+        .with_shadow_dir(option.shadow_dir.clone())
+        .with_staging_dir(option.staging_dir.clone())
+        .with_snapshot_dir(option.snapshot_dir.clone(), option.restore)
+        .with_wait_for_exec(option.wait_for_exec.clone())
+        .mount()
+}
 
-    info!("replacers detached");
-    info!("recover successfully");
+// confine drops the privileges, and applies the seccomp filter, once the injection is set up. If
+// either fails, the injection is recovered at once, instead of being served unconfined.
+fn confine(option: &Options, handle: InjectionHandle) -> Result<InjectionHandle> {
+    let result = option
+        .run_as
+        .map_or(Ok(()), privilege::drop_privileges)
+        .context("fail to drop the privileges")
+        .and_then(|_| seccomp::apply(option.seccomp).context("fail to apply the seccomp filter"));
+
+    if let Err(err) = result {
+        warn!("fail to confine toda, recover at once: {:#}", err);
+        handle.recover_blocking()?;
+        return Err(err);
+    }
+    Ok(handle)
+}
 
-    drop(replacer);
-    Ok(())
+// schedule enables the injection after the --delay, and stops it after the --duration. The
+// recovery is started in the same way as SIGTERM.
+fn schedule(option: &Options, hookfs: Arc<HookFs>) {
+    let (delay, duration, on_end) = (option.delay, option.duration, option.on_duration_end);
+    if delay.is_none() && duration.is_none() {
+        return;
+    }
+
+    thread::spawn(move || {
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+            info!("enable injection after {:?}", delay);
+            hookfs.enable_injection();
+        }
+
+        if let Some(duration) = duration {
+            thread::sleep(duration);
+            match on_end {
+                DurationEnd::Disable => {
+                    info!("disable injection after {:?}", duration);
+                    hookfs.disable_injection();
+                }
+                DurationEnd::Recover => {
+                    info!("recover after {:?}", duration);
+                    unsafe {
+                        write(SIGNAL_PIPE_WRITER, &SIGNAL_MSG).unwrap();
+                    }
+                }
+            }
+        }
+    });
+}
+
+// watch_session starts the recovery in the same way as SIGTERM once the FUSE session is lost, e.g.
+// the connection is aborted by the kernel, so that the original mount is restored at once
+fn watch_session(hookfs: Arc<HookFs>) {
+    thread::spawn(move || {
+        futures::executor::block_on(hookfs.session_ended());
+        if hookfs.session_error().is_some() {
+            warn!("FUSE session is lost, start to recover");
+            unsafe {
+                write(SIGNAL_PIPE_WRITER, &SIGNAL_MSG).unwrap();
+            }
+        }
+    });
 }
 
 static mut SIGNAL_PIPE_WRITER: RawFd = 0;
 
 const SIGNAL_MSG: [u8; 6] = *b"SIGNAL";
+const TOGGLE_MSG: [u8; 6] = *b"TOGGLE";
 
 extern "C" fn signal_handler(_: libc::c_int) {
     unsafe {
@@ -147,13 +401,155 @@ extern "C" fn signal_handler(_: libc::c_int) {
     }
 }
 
-fn wait_for_signal(chan: RawFd) -> Result<()> {
-    let mut buf = vec![0u8; 6];
-    read(chan, buf.as_mut_slice())?;
-    Ok(())
+extern "C" fn toggle_handler(_: libc::c_int) {
+    unsafe {
+        write(SIGNAL_PIPE_WRITER, &TOGGLE_MSG).unwrap();
+    }
+}
+
+// wait_for_signal waits until toda is asked to exit. SIGUSR1 pauses or resumes the injection in
+// the meantime, while the FUSE and the replaced fds are kept.
+fn wait_for_signal(chan: RawFd, handle: Option<&InjectionHandle>) -> Result<()> {
+    let mut buf = [0u8; 6];
+    loop {
+        read(chan, &mut buf)?;
+        if buf != TOGGLE_MSG {
+            return Ok(());
+        }
+
+        match handle.map(InjectionHandle::hookfs) {
+            Some(hookfs) if hookfs.injection_enabled() => {
+                info!("pause injection");
+                hookfs.disable_injection();
+            }
+            Some(hookfs) => {
+                info!("resume injection");
+                hookfs.enable_injection();
+            }
+            None => warn!("nothing to toggle, the injection has failed"),
+        }
+    }
 }
 
-fn main() -> Result<()> {
+fn list_presets() {
+    for preset in PRESETS {
+        println!("{:<12} {}", preset.name, preset.description);
+    }
+}
+
+// Outcome is printed to stderr in JSON as the last line before toda exits, for the controller
+// running it. The exit code is 0 on success, 2 if the config is invalid, 3 if the FUSE is not
+// mounted or its session is lost, 4 if the processes are not replaced, 5 if the recovery fails,
+// and 1 for the other errors.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Outcome {
+    exit_code: i32,
+    stage: Option<Stage>,
+    error: Option<String>,
+}
+
+impl Outcome {
+    fn of(result: &Result<()>) -> Outcome {
+        let err = match result {
+            Ok(_) => {
+                return Outcome {
+                    exit_code: 0,
+                    stage: None,
+                    error: None,
+                }
+            }
+            Err(err) => err,
+        };
+
+        let stage = err.downcast_ref::<Stage>().copied();
+        let exit_code = match stage {
+            Some(Stage::Config) => 2,
+            Some(Stage::Mount) => 3,
+            Some(Stage::Ptrace) => 4,
+            Some(Stage::Recovery) => 5,
+            None => 1,
+        };
+        Outcome {
+            exit_code,
+            stage,
+            error: Some(format!("{:#}", err)),
+        }
+    }
+}
+
+fn main() {
+    let outcome = Outcome::of(&run());
+    eprintln!("{}", serde_json::to_string(&outcome).unwrap());
+    std::process::exit(outcome.exit_code);
+}
+
+fn run() -> Result<()> {
+    let mut option = Options::from_args();
+    arch::check()?;
+    // the cgroups of the target are resolved in the host, before entering its namespaces
+    if let Some(pid) = option.target_pid {
+        option.replacer.restrict_to(pid)?;
+    }
+    let mut injector_config = match &option.command {
+        Some(Command::Preset(PresetCommand::List)) => {
+            list_presets();
+            return Ok(());
+        }
+        Some(Command::Preset(PresetCommand::Apply { .. })) if option.path.is_none() => {
+            return Err(anyhow!("--path is required to apply a preset")).context(Stage::Config);
+        }
+        Some(Command::Preset(PresetCommand::Apply { name })) => Preset::find(name)
+            .and_then(|preset| preset.config())
+            .context(Stage::Config)?,
+        Some(Command::Replay { trace, dir, speed }) => {
+            let stats = recorder::replay(trace, dir, Some(*speed))?;
+            println!("{}", serde_json::to_string(&stats)?);
+            return Ok(());
+        }
+        Some(Command::Verify { shadow, dir }) => {
+            let report = shadow::verify(shadow, dir)?;
+            println!("{}", serde_json::to_string(&report)?);
+            return Ok(());
+        }
+        Some(Command::Restore { snapshot, dir }) => {
+            let stats = snapshot::restore(snapshot, dir)?;
+            println!("{}", serde_json::to_string(&stats)?);
+            return Ok(());
+        }
+        Some(Command::Mounts { path }) => {
+            if let Some(pid) = option.target_pid {
+                namespace::enter(pid)?;
+            }
+            let analysis = mount::analyze_mounts(path)?;
+            println!("{}", serde_json::to_string(&analysis)?);
+            return Ok(());
+        }
+        Some(Command::Check { path }) => {
+            if let Some(pid) = option.target_pid {
+                namespace::enter(pid)?;
+            }
+            let report = replacer::analyze_references(path, &option.replacer)?;
+            println!("{}", serde_json::to_string(&report)?);
+            return Ok(());
+        }
+        Some(Command::Clean { path }) => {
+            if let Some(pid) = option.target_pid {
+                namespace::enter(pid)?;
+            }
+            let cleaned = instance::clean_stale_instances(path, option.staging_dir.as_deref())?;
+            println!("{}", serde_json::to_string(&cleaned)?);
+            return Ok(());
+        }
+        None => vec![],
+    };
+    injector_config.extend(option.protected_config());
+
+    // the namespaces must be entered before any thread is spawned
+    if let Some(pid) = option.target_pid {
+        namespace::enter(pid)?;
+    }
+
     let (reader, writer) = pipe()?;
     unsafe {
         SIGNAL_PIPE_WRITER = writer;
@@ -161,45 +557,65 @@ fn main() -> Result<()> {
 
     unsafe { signal(Signal::SIGINT, SigHandler::Handler(signal_handler))? };
     unsafe { signal(Signal::SIGTERM, SigHandler::Handler(signal_handler))? };
+    unsafe { signal(Signal::SIGUSR1, SigHandler::Handler(toggle_handler))? };
 
-    let option = Options::from_args();
     let env_filter = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_from(&option.verbose))
         .or_else(|_| EnvFilter::try_new("trace"))
         .unwrap();
-    tracing_subscriber::fmt()
-        .with_writer(io::stderr)
-        .with_env_filter(env_filter)
-        .init();
-    info!("start with option: {:?}", option);
-    let mount_injector = inject(option.clone(), vec![]);
+    let telemetry = telemetry::init(env_filter, option.otel_endpoint.as_deref())?;
+    info!(
+        "start instance {} with option: {:?}",
+        instance::instance_id(),
+        option
+    );
+    hookfs::runtime::configure(option.runtime.clone());
+    hookfs::runtime::apply_limits().context(Stage::Config)?;
+    let mount_injector =
+        inject(&option, injector_config).and_then(|handle| confine(&option, handle));
 
     let status = match &mount_injector {
         Ok(_) => Ok(()),
-        Err(e) => Err(anyhow::Error::msg(e.to_string())),
+        Err(e) => Err(anyhow::Error::msg(format!("{:#}", e))),
     };
 
+    if let Ok(handle) = &mount_injector {
+        schedule(&option, handle.hookfs().clone());
+        watch_session(handle.hookfs().clone());
+    }
+
     let (tx, _) = mpsc::channel();
     {
-        let hookfs = match &mount_injector {
-            Ok(e) => Some(e.hookfs.clone()),
-            Err(_) => None,
+        let (hookfs, stats) = match &mount_injector {
+            Ok(handle) => (Some(handle.hookfs().clone()), handle.replacer_stats()),
+            Err(_) => (None, ReplacerStats::default()),
         };
+        let log_reloader = telemetry.log_reloader();
+        let protected = option.protected_config();
         thread::spawn(|| {
             Runtime::new()
                 .expect("Failed to create Tokio runtime")
-                .block_on(start_server(jsonrpc::RpcImpl::new(
-                    Mutex::new(status),
-                    Mutex::new(tx),
-                    hookfs,
-                )));
+                .block_on(start_server(
+                    jsonrpc::RpcImpl::new(Mutex::new(status), Mutex::new(tx), hookfs)
+                        .with_replacer_stats(stats)
+                        .with_protected(protected)
+                        .with_log_reloader(log_reloader),
+                ));
         });
     }
     info!("waiting for signal to exit");
-    wait_for_signal(reader)?;
+    wait_for_signal(reader, mount_injector.as_ref().ok())?;
     info!("start to recover and exit");
-    if let Ok(v) = mount_injector {
-        resume(option, v)?;
+    let handle = match mount_injector {
+        Ok(handle) => handle,
+        // the error has been reported through get_status, and is reported again by the exit code
+        Err(err) => return Err(err),
+    };
+    let session_error = handle.hookfs().session_error();
+    handle.recover_blocking()?;
+    // exit with a failure, as the injection has ended before it's asked to
+    if let Some(err) = session_error {
+        return Err(anyhow!("FUSE session is lost: {}", err)).context(Stage::Mount);
     }
     Ok(())
 }
@@ -1,9 +1,64 @@
 use std::fs::create_dir_all;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
-use nix::mount::{mount, MsFlags};
-use procfs::process::{self, Process};
+use anyhow::{anyhow, Context, Result};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use procfs::process::{self, FDTarget, MountOptFields, Process};
+use serde::Serialize;
+
+// OverlayLayers are the directories merged by an overlayfs. The files are copied up from the
+// lower layers into the upper one when they're modified, and the removed ones are hidden by
+// whiteouts in the upper layer, so they should only be accessed through the merged directory.
+#[derive(Debug, Clone)]
+pub struct OverlayLayers {
+    pub mount_point: PathBuf,
+    // the lower layers, from the top-most one
+    pub lower_dirs: Vec<PathBuf>,
+    pub upper_dir: Option<PathBuf>,
+    pub work_dir: Option<PathBuf>,
+}
+
+impl OverlayLayers {
+    fn from_mount(item: &process::MountInfo) -> Option<OverlayLayers> {
+        if item.fs_type != "overlay" {
+            return None;
+        }
+        let option = |key: &str| {
+            item.super_options
+                .get(key)
+                .and_then(|value| value.as_deref())
+        };
+
+        Some(OverlayLayers {
+            mount_point: item.mount_point.clone(),
+            lower_dirs: option("lowerdir")
+                .map(|dirs| dirs.split(':').map(PathBuf::from).collect())
+                .unwrap_or_default(),
+            upper_dir: option("upperdir").map(PathBuf::from),
+            work_dir: option("workdir").map(PathBuf::from),
+        })
+    }
+
+    fn layers(&self) -> impl Iterator<Item = &PathBuf> {
+        self.upper_dir
+            .iter()
+            .chain(self.lower_dirs.iter())
+            .chain(self.work_dir.iter())
+    }
+
+    // merged_path returns the path in the merged directory of a file in one of the layers, which
+    // is how the mappings of the files on an overlayfs are shown in /proc/<pid>/maps by the
+    // kernels before 6.8
+    pub fn merged_path<P: AsRef<Path>>(&self, path: P) -> Option<PathBuf> {
+        self.upper_dir
+            .iter()
+            .chain(self.lower_dirs.iter())
+            .find_map(|layer| path.as_ref().strip_prefix(layer).ok())
+            .map(|relative| self.mount_point.join(relative))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct MountsInfo {
@@ -30,6 +85,101 @@ impl MountsInfo {
         Ok(false)
     }
 
+    // is_toda_mount returns true if the top-most mount on the path is a FUSE mounted by toda
+    pub fn is_toda_mount<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.mounts
+            .iter()
+            .rev()
+            .find(|item| item.mount_point == path.as_ref())
+            .map(|item| {
+                item.fs_type.starts_with("fuse") && item.mount_source.as_deref() == Some("toda")
+            })
+            .unwrap_or(false)
+    }
+
+    // containing_mount returns the top-most mount under which the path is, whose mount point is
+    // the path or one of its parents
+    pub fn containing_mount<P: AsRef<Path>>(&self, path: P) -> Option<&process::MountInfo> {
+        self.mounts
+            .iter()
+            .rev()
+            .find(|item| path.as_ref().starts_with(&item.mount_point))
+    }
+
+    // parent_mount returns the mount on which the mount point of the mount is
+    pub fn parent_mount(&self, mount: &process::MountInfo) -> Option<&process::MountInfo> {
+        self.mounts
+            .iter()
+            .find(|item| item.mnt_id == mount.pid && item.mnt_id != mount.mnt_id)
+    }
+
+    // child_mounts returns the mounts whose mount points are on the mount
+    pub fn child_mounts(&self, mount: &process::MountInfo) -> Vec<&process::MountInfo> {
+        self.mounts
+            .iter()
+            .filter(|item| item.pid == mount.mnt_id && item.mnt_id != mount.mnt_id)
+            .collect()
+    }
+
+    // covering_toda_mount returns the FUSE mounted by toda on the path or any of its parents, if
+    // it's the top-most mount under which the path is
+    pub fn covering_toda_mount<P: AsRef<Path>>(&self, path: P) -> Option<&Path> {
+        self.containing_mount(path)
+            .filter(|item| {
+                item.fs_type.starts_with("fuse") && item.mount_source.as_deref() == Some("toda")
+            })
+            .map(|item| item.mount_point.as_path())
+    }
+
+    // overlay returns the layers of the overlayfs, if it's the top-most mount under which the path
+    // is
+    pub fn overlay<P: AsRef<Path>>(&self, path: P) -> Option<OverlayLayers> {
+        self.containing_mount(path)
+            .and_then(OverlayLayers::from_mount)
+    }
+
+    // overlay_of_layer returns the overlayfs which has the path in one of its layers
+    pub fn overlay_of_layer<P: AsRef<Path>>(&self, path: P) -> Option<OverlayLayers> {
+        self.mounts
+            .iter()
+            .filter_map(OverlayLayers::from_mount)
+            .find(|overlay| {
+                overlay
+                    .layers()
+                    .any(|layer| path.as_ref().starts_with(layer))
+            })
+    }
+
+    // child_mount_points returns the mount points under the path, except the ones under another of
+    // them, which are moved along with it
+    pub fn child_mount_points<P: AsRef<Path>>(&self, path: P) -> Vec<PathBuf> {
+        let path = path.as_ref();
+        let mut mount_points: Vec<PathBuf> = Vec::new();
+        for item in self.mounts.iter() {
+            if item.mount_point == path || !item.mount_point.starts_with(path) {
+                continue;
+            }
+            mount_points.push(item.mount_point.clone());
+        }
+        mount_points.sort();
+        mount_points.dedup();
+
+        let mut children: Vec<PathBuf> = Vec::new();
+        for mount_point in mount_points {
+            if !children.iter().any(|child| mount_point.starts_with(child)) {
+                children.push(mount_point);
+            }
+        }
+        children
+    }
+
+    // is_mount_point returns true if anything is mounted on the path
+    pub fn is_mount_point<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.mounts
+            .iter()
+            .any(|item| item.mount_point == path.as_ref())
+    }
+
     pub fn move_mount<P1: AsRef<Path>, P2: AsRef<Path>>(
         &self,
         original_path: P1,
@@ -52,4 +202,232 @@ impl MountsInfo {
 
         Ok(())
     }
+
+    pub fn bind_mount<P1: AsRef<Path>, P2: AsRef<Path>>(
+        &self,
+        original_path: P1,
+        target_path: P2,
+    ) -> Result<()> {
+        create_dir_all(target_path.as_ref())?;
+
+        mount::<_, _, str, str>(
+            Some(original_path.as_ref()),
+            target_path.as_ref(),
+            None,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None,
+        )
+        .context(format!(
+            "source: {}, target: {}",
+            original_path.as_ref().display(),
+            target_path.as_ref().display()
+        ))?;
+
+        Ok(())
+    }
+
+    // detach_mount lazily unmounts the target, so the file descriptors which are still opened through it
+    // will keep working until they are closed.
+    pub fn detach_mount<P: AsRef<Path>>(&self, target_path: P) -> Result<()> {
+        umount2(target_path.as_ref(), MntFlags::MNT_DETACH)
+            .context(format!("target: {}", target_path.as_ref().display()))?;
+
+        Ok(())
+    }
+}
+
+// Propagation is how the mount and unmount events under a mount are propagated, as shown in the
+// optional fields of the mountinfo
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Propagation {
+    // the peer group, whose members share the events with each other
+    pub shared: Option<u32>,
+    // the peer group from which the events are received
+    pub master: Option<u32>,
+    // the nearest dominant peer group, if the master is not reachable from the process
+    pub propagate_from: Option<u32>,
+    pub unbindable: bool,
+}
+
+impl Propagation {
+    pub fn of(mount: &process::MountInfo) -> Propagation {
+        let mut propagation = Propagation::default();
+        for field in mount.opt_fields.iter() {
+            match field {
+                MountOptFields::Shared(group) => propagation.shared = Some(*group),
+                MountOptFields::Master(group) => propagation.master = Some(*group),
+                MountOptFields::PropagateFrom(group) => propagation.propagate_from = Some(*group),
+                MountOptFields::Unbindable => propagation.unbindable = true,
+            }
+        }
+        propagation
+    }
+}
+
+// MountSummary is a mount in the mountinfo, as printed by `toda mounts`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MountSummary {
+    pub mount_id: i32,
+    pub parent_id: i32,
+    pub mount_point: PathBuf,
+    // the directory in the filesystem mounted on the mount point
+    pub root: String,
+    pub fs_type: String,
+    pub source: Option<String>,
+    pub propagation: Propagation,
+}
+
+impl From<&process::MountInfo> for MountSummary {
+    fn from(mount: &process::MountInfo) -> Self {
+        MountSummary {
+            mount_id: mount.mnt_id,
+            parent_id: mount.pid,
+            mount_point: mount.mount_point.clone(),
+            root: mount.root.clone(),
+            fs_type: mount.fs_type.clone(),
+            source: mount.mount_source.clone(),
+            propagation: Propagation::of(mount),
+        }
+    }
+}
+
+// MountAnalysis describes the mounts around a path, and the problems they would cause to the
+// injection on it
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MountAnalysis {
+    pub path: PathBuf,
+    // whether the path is the mount point of the containing mount
+    pub is_mount_point: bool,
+    pub mount: MountSummary,
+    pub parent: Option<MountSummary>,
+    pub children: Vec<MountSummary>,
+    pub warnings: Vec<String>,
+}
+
+// analyze_mounts finds the mount containing the path, with its parent and children, in the mount
+// namespace of the current process
+pub fn analyze_mounts<P: AsRef<Path>>(path: P) -> Result<MountAnalysis> {
+    let path = path.as_ref().canonicalize()?;
+    let mounts = MountsInfo::parse_mounts()?;
+    let mount = mounts
+        .containing_mount(&path)
+        .ok_or_else(|| anyhow!("no mount contains {}", path.display()))?;
+    let parent = mounts.parent_mount(mount);
+    let children = mounts.child_mounts(mount);
+
+    let is_mount_point = mount.mount_point == path;
+    let mut warnings = Vec::new();
+    if !is_mount_point {
+        warnings.push(format!(
+            "{} is not a mount point, which could only be injected with --mount-mode bind",
+            path.display()
+        ));
+    } else if parent.map_or(false, |parent| Propagation::of(parent).shared.is_some()) {
+        warnings
+            .push("the parent mount is shared, from which the mount could not be moved".to_owned());
+    }
+    if Propagation::of(mount).unbindable {
+        warnings.push("the mount is unbindable, which could not be bind-mounted".to_owned());
+    }
+    if mount.fs_type.starts_with("fuse") && mount.mount_source.as_deref() == Some("toda") {
+        warnings.push("the path is already injected by toda".to_owned());
+    }
+    if mount.fs_type == "overlay" {
+        warnings
+            .push("the path is on an overlayfs, whose merged directory is bind-mounted".to_owned());
+    }
+    let covered: Vec<_> = children
+        .iter()
+        .filter(|child| child.mount_point.starts_with(&path))
+        .map(|child| child.mount_point.display().to_string())
+        .collect();
+    if !covered.is_empty() {
+        warnings.push(format!(
+            "the mounts under the path are moved with it, and injected too: {}",
+            covered.join(", ")
+        ));
+    }
+
+    Ok(MountAnalysis {
+        is_mount_point,
+        mount: mount.into(),
+        parent: parent.map(MountSummary::from),
+        children: children.into_iter().map(MountSummary::from).collect(),
+        warnings,
+        path,
+    })
+}
+
+// wait_for_fuse_mount polls the mountinfo until the FUSE mounted by toda shows up on the path.
+pub fn wait_for_fuse_mount<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<()> {
+    wait_for_session_mount(path, timeout, || false)
+}
+
+// wait_for_session_mount is like `wait_for_fuse_mount`, but gives up at once if `ended` returns
+// true, which means the session has ended before the FUSE shows up, e.g. the mount has failed.
+pub fn wait_for_session_mount<P, F>(path: P, timeout: Duration, ended: F) -> Result<()>
+where
+    P: AsRef<Path>,
+    F: Fn() -> bool,
+{
+    let start = Instant::now();
+    loop {
+        if MountsInfo::parse_mounts()?.is_toda_mount(path.as_ref()) {
+            return Ok(());
+        }
+
+        if ended() {
+            return Err(anyhow!(
+                "fuse session ended before it was mounted on {}",
+                path.as_ref().display()
+            ));
+        }
+
+        if start.elapsed() > timeout {
+            return Err(anyhow!(
+                "timeout waiting for fuse to be mounted on {}",
+                path.as_ref().display()
+            ));
+        }
+        sleep(Duration::from_millis(10));
+    }
+}
+
+// find_mount_users lists the processes which keep the mount busy by holding a fd or the cwd
+// inside it.
+pub fn find_mount_users<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    let mut users = Vec::new();
+
+    for process in process::all_processes()? {
+        let pid = process.pid;
+        let comm = &process.stat.comm;
+
+        if let Ok(cwd) = process.cwd() {
+            if cwd.starts_with(path) {
+                users.push(format!("pid {}({}) cwd: {}", pid, comm, cwd.display()));
+            }
+        }
+
+        if let Ok(fds) = process.fd() {
+            for fd in fds {
+                if let FDTarget::Path(target) = fd.target {
+                    if target.starts_with(path) {
+                        users.push(format!(
+                            "pid {}({}) fd {}: {}",
+                            pid,
+                            comm,
+                            fd.fd,
+                            target.display()
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(users)
 }
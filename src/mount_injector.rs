@@ -1,29 +1,131 @@
 use std::ffi::OsStr;
+use std::fs::DirBuilder;
+use std::os::unix::fs::DirBuilderExt;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
-use nix::mount::umount;
+use nix::errno::Errno;
+use nix::mount::{umount, umount2, MntFlags};
 use retry::delay::Fixed;
 use retry::{retry, OperationResult};
-use tracing::info;
+use tracing::{info, warn};
 
+use crate::hookfs::idmap::IdMap;
+use crate::hookfs::ownership::OwnershipOptions;
 use crate::injector::{InjectorConfig, MultiInjector};
+use crate::instance::find_stale_instances;
+use crate::recorder::Recorder;
+use crate::replacer::{CwdReplacer, ParallelReplacer, ReplacedCwd, Replacer, ReplacerOptions};
+use crate::shadow::Shadow;
+use crate::utils::{encode_path, encode_staging_path};
 use crate::{hookfs, mount, stop};
 
+const MOUNT_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// MountMode decides how the original directory is hidden behind the FUSE mount.
+//
+// `Move` moves the original mount point to the new path with `MS_MOVE`, which requires the
+// original path to be a mount point. `Bind` bind-mounts the original directory to the new path
+// and mounts the FUSE over the original one, which works in environments (rootless containers,
+// nested namespaces) where `MS_MOVE` is not permitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountMode {
+    Move,
+    Bind,
+}
+
+impl Default for MountMode {
+    fn default() -> Self {
+        MountMode::Move
+    }
+}
+
+impl FromStr for MountMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "move" => Ok(MountMode::Move),
+            "bind" => Ok(MountMode::Bind),
+            _ => Err(anyhow!("unknown mount mode: {}", s)),
+        }
+    }
+}
+
+// PermissionCheck decides where the permissions of the requests are checked.
+//
+// `Kernel` mounts the FUSE with `default_permissions`, and the kernel checks the mode bits, but
+// ignores the POSIX ACLs of the original files. `Daemon` mounts without it, and toda checks the
+// permissions of every request itself, honoring the ACLs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PermissionCheck {
+    Kernel,
+    Daemon,
+}
+
+impl Default for PermissionCheck {
+    fn default() -> Self {
+        PermissionCheck::Kernel
+    }
+}
+
+impl FromStr for PermissionCheck {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "kernel" => Ok(PermissionCheck::Kernel),
+            "daemon" => Ok(PermissionCheck::Daemon),
+            _ => Err(anyhow!("unknown permission check: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecoverOptions {
+    // replace the fds, cwd and mmaps of the workload from the FUSE back to the original mount
+    pub replacer: Option<ReplacerOptions>,
+    // the cwds replaced while injecting, which are moved back to their original directories if
+    // the reverse replacer doesn't find them by path
+    pub cwds: Vec<ReplacedCwd>,
+    // detach the FUSE lazily if it's still busy after all the umount retries
+    pub lazy_umount: bool,
+}
+
 #[derive(Debug)]
 pub struct MountInjector {
     original_path: PathBuf,
     new_path: PathBuf,
+    mount_mode: MountMode,
+    permission_check: PermissionCheck,
+    ownership: OwnershipOptions,
     injector_config: Vec<InjectorConfig>,
+    // the file to record the operations to
+    trace: Option<PathBuf>,
+    // the directory to duplicate the written data to
+    shadow_dir: Option<PathBuf>,
+    // the directory to keep the original path in, instead of next to it
+    staging_dir: Option<PathBuf>,
 }
 
 pub struct MountInjectionGuard {
     original_path: PathBuf,
     new_path: PathBuf,
+    mount_mode: MountMode,
     pub hookfs: Arc<hookfs::HookFs>,
     handler: Option<JoinHandle<Result<()>>>,
+    // the mounts under the original directory which are moved into the bind mount, relative to it
+    moved_children: Vec<PathBuf>,
+
+    // the steps of the recovery which have been done, so that a recovery failed halfway could be
+    // retried from where it stopped
+    unmounted: bool,
+    restored: bool,
 }
 
 impl MountInjectionGuard {
@@ -35,102 +137,397 @@ impl MountInjectionGuard {
         self.hookfs.disable_injection();
     }
 
-    pub fn recover_mount(mut self) -> Result<()> {
+    pub fn mount_mode(&self) -> MountMode {
+        self.mount_mode
+    }
+
+    // hidden_path returns where the original directory is kept behind the FUSE
+    pub fn hidden_path(&self) -> &Path {
+        &self.new_path
+    }
+
+    // recover_mount unmounts the FUSE and restores the original mount. Before every umount attempt
+    // failed with EBUSY, a reverse replacer pass is executed again, because the workload may have
+    // opened files through the FUSE after the last pass. If the FUSE is still busy after all the
+    // retries, it will be detached lazily when `lazy_umount` is set, or an error listing the
+    // processes keeping the mount busy will be returned.
+    //
+    // It could be called again after a failure. The steps which have been done, or are found done
+    // in the mountinfo, are skipped, so the replacers never run again once the FUSE is unmounted.
+    pub fn recover_mount(&mut self, options: RecoverOptions) -> Result<()> {
+        if self.restored {
+            info!("mount has already been recovered");
+            return Ok(());
+        }
+
+        let mount_point = self.original_path.clone();
+        let new_path = self.new_path.clone();
+
+        // the injection must not delay the requests closing the files from now on
+        self.hookfs.shutdown();
+
+        let backend_lost = self.hookfs.backend_lost();
+        if backend_lost {
+            warn!("backend is lost, the original mount may not be restored");
+        }
+
+        let mut replacers = Vec::new();
+        if !self.unmounted && !mount::MountsInfo::parse_mounts()?.is_toda_mount(&mount_point) {
+            info!("FUSE has already been unmounted");
+            self.unmounted = true;
+        }
+        if !self.unmounted {
+            let detached = self.unmount(&options, backend_lost, &mut replacers)?;
+            self.unmounted = true;
+
+            // the session of a detached FUSE only ends after the last fd on it is closed, which
+            // may never happen, so its thread is left behind instead of being joined
+            if detached {
+                warn!("FUSE is detached lazily, leave its session running");
+                self.handler.take();
+            }
+        }
+
+        // the original mount is still restored after the session is lost, of which the error has
+        // been recorded in the hookfs, or after its thread has panicked, which is returned at last
+        let mut session = Ok(());
+        if let Some(handler) = self.handler.take() {
+            match handler.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("FUSE session has failed: {}", err),
+                Err(_) => session = Err(anyhow!("FUSE session thread has panicked")),
+            }
+        }
+
+        let mounts = mount::MountsInfo::parse_mounts()?;
+
+        match self.mount_mode {
+            MountMode::Move if !mounts.is_mount_point(&new_path) => {
+                info!("original mount has already been moved back");
+            }
+            MountMode::Move => {
+                if mounts.non_root(&mount_point)? {
+                    // TODO: make the parent mount points private before move mount points
+                    tolerate(backend_lost, mounts.move_mount(&new_path, &mount_point))?;
+                } else {
+                    return Err(anyhow!("inject on a root mount"));
+                }
+            }
+            MountMode::Bind => {
+                // the moved mounts are put back before the bind mount is detached with them
+                for child in self.moved_children.iter() {
+                    if mounts.is_mount_point(new_path.join(child)) {
+                        tolerate(
+                            backend_lost,
+                            mounts.move_mount(new_path.join(child), mount_point.join(child)),
+                        )?;
+                    }
+                }
+
+                // The original directory is visible again after unmounting the FUSE. The bind
+                // mount is detached lazily because the replacers have pointed the fds of the
+                // workload to it, and they are still valid as they refer to the same files.
+                if mounts.is_mount_point(&new_path) {
+                    tolerate(backend_lost, mounts.detach_mount(&new_path))?;
+                }
+                if new_path.exists() {
+                    tolerate(
+                        backend_lost,
+                        std::fs::remove_dir(&new_path).map_err(Into::into),
+                    )?;
+                }
+            }
+        }
+        self.restored = true;
+
+        drop(replacers);
+        info!("replacers detached");
+
+        session
+    }
+
+    // unmount unmounts the FUSE, and keeps the replacers in `replacers` until the original mount
+    // is restored, so that the traced processes are not able to open new files through the FUSE.
+    // It returns true if the FUSE has been detached lazily.
+    fn unmount(
+        &self,
+        options: &RecoverOptions,
+        backend_lost: bool,
+        replacers: &mut Vec<ParallelReplacer>,
+    ) -> Result<bool> {
         let mount_point = self.original_path.clone();
+        let new_path = self.new_path.clone();
 
-        retry(Fixed::from_millis(500).take(20), || {
+        if let Some(replacer_options) = &options.replacer {
+            match reverse_replace(&mount_point, &new_path, replacer_options) {
+                Ok(replacer) => replacers.push(replacer),
+                Err(err) if backend_lost => warn!("fail to run reverse replacer: {:?}", err),
+                Err(err) => return Err(err),
+            }
+            if let Err(err) = restore_cwds(&mount_point, &new_path, &options.cwds, replacer_options)
+            {
+                warn!("fail to restore cwds: {:?}", err);
+            }
+        }
+
+        let result = retry(Fixed::from_millis(500).take(20), || {
             if let Err(err) = umount(mount_point.as_path()) {
                 info!("umount returns error: {:?}", err);
+                if let Some(replacer_options) = &options.replacer {
+                    if matches!(err, nix::Error::Sys(Errno::EBUSY)) {
+                        match reverse_replace(&mount_point, &new_path, replacer_options) {
+                            Ok(replacer) => replacers.push(replacer),
+                            Err(err) => warn!("fail to run reverse replacer: {:?}", err),
+                        }
+                    }
+                }
                 OperationResult::Retry(err)
             } else {
                 OperationResult::Ok(())
             }
-        })?;
+        });
+        if let Err(err) = result {
+            if options.lazy_umount {
+                warn!("fail to umount: {:?}, detach it lazily", err);
+                umount2(mount_point.as_path(), MntFlags::MNT_DETACH)?;
+                return Ok(true);
+            } else {
+                let users = match err {
+                    retry::Error::Operation {
+                        error: nix::Error::Sys(Errno::EBUSY),
+                        ..
+                    } => mount::find_mount_users(&mount_point)?,
+                    _ => Vec::new(),
+                };
+                return Err(anyhow!(
+                    "fail to umount {}: {:?}, mount is kept busy by: {:?}",
+                    mount_point.display(),
+                    err,
+                    users
+                ));
+            }
+        }
 
         info!("unmount successfully!");
-        self.handler
-            .take()
-            .ok_or(anyhow!("handler is empty"))?
-            .join()
-            .unwrap()?;
-
-        let new_path = self.new_path.clone();
-        let original_path = self.original_path;
-
-        let mounts = mount::MountsInfo::parse_mounts()?;
+        Ok(false)
+    }
+}
 
-        if mounts.non_root(&original_path)? {
-            // TODO: make the parent mount points private before move mount points
-            mounts.move_mount(new_path, original_path)?;
-        } else {
-            return Err(anyhow!("inject on a root mount"));
+// tolerate ignores the failure to restore the original mount once the backend is lost, which may
+// have been unmounted already, so that the FUSE is still cleaned up
+fn tolerate(backend_lost: bool, result: Result<()>) -> Result<()> {
+    match result {
+        Err(err) if backend_lost => {
+            warn!("backend is lost, ignore: {:?}", err);
+            Ok(())
         }
+        result => result,
+    }
+}
 
-        Ok(())
+// restore_cwds moves the processes which have been moved onto the FUSE while injecting, but are
+// still on it after the reverse replacer, back to their original cwds
+fn restore_cwds(
+    mount_path: &Path,
+    new_path: &Path,
+    cwds: &[ReplacedCwd],
+    options: &ReplacerOptions,
+) -> Result<()> {
+    if cwds.is_empty() {
+        return Ok(());
     }
+
+    let mut restorer = CwdReplacer::restore(cwds, mount_path, new_path, options)?;
+    restorer.run()
+}
+
+fn reverse_replace(
+    mount_path: &Path,
+    new_path: &Path,
+    options: &ReplacerOptions,
+) -> Result<ParallelReplacer> {
+    let mut replacer = ParallelReplacer::prepare(mount_path, new_path, options)?;
+    info!("running replacer");
+    let result = replacer.run();
+    info!("replace result: {:?}", result);
+
+    Ok(replacer)
 }
 
 impl MountInjector {
     pub fn create_injection<P: AsRef<Path>>(
         path: P,
+        mount_mode: MountMode,
+        permission_check: PermissionCheck,
         injector_config: Vec<InjectorConfig>,
     ) -> Result<MountInjector> {
-        let original_path: PathBuf = path.as_ref().to_owned();
-
-        let mut base_path: PathBuf = path.as_ref().to_owned();
-        if !base_path.pop() {
-            return Err(anyhow!("path is the root"));
+        let mounts = mount::MountsInfo::parse_mounts()?;
+        // a FUSE stacked on another one is unmounted before it, while the original mount is
+        // moved back under the other one, so the recovery of both would be broken
+        if let Some(mount_point) = mounts.covering_toda_mount(&path) {
+            return Err(anyhow!(
+                "{} is already injected by the toda mounted on {}",
+                path.as_ref().display(),
+                mount_point.display()
+            ));
+        }
+        // the files in a layer are not copied up when they're modified, and the whiteouts are
+        // listed as character devices, so the injection could only work on the merged directory
+        if let Some(overlay) = mounts.overlay_of_layer(&path) {
+            return Err(anyhow!(
+                "{} is in a layer of the overlayfs on {}, inject the merged directory instead",
+                path.as_ref().display(),
+                overlay.mount_point.display()
+            ));
         }
 
-        let mut new_path: PathBuf = base_path;
-        let original_filename = original_path
-            .file_name()
-            .ok_or(anyhow!("the path terminates in `..` or `/`"))?
-            .to_str()
-            .ok_or(anyhow!("path with non-UTF-8 character"))?;
-        let new_filename = format!("__chaosfs__{}__", original_filename);
-        new_path.push(new_filename.as_str());
+        let (original_path, new_path) = encode_path(path)?;
 
         Ok(MountInjector {
             original_path,
             new_path,
+            mount_mode,
+            permission_check,
+            ownership: OwnershipOptions::default(),
             injector_config,
+            trace: None,
+            shadow_dir: None,
+            staging_dir: None,
         })
     }
 
+    pub fn with_ownership(mut self, ownership: OwnershipOptions) -> MountInjector {
+        self.ownership = ownership;
+        self
+    }
+
+    pub fn with_trace(mut self, trace: Option<PathBuf>) -> MountInjector {
+        self.trace = trace;
+        self
+    }
+
+    pub fn with_shadow_dir(mut self, shadow_dir: Option<PathBuf>) -> MountInjector {
+        self.shadow_dir = shadow_dir;
+        self
+    }
+
+    // with_staging_dir keeps the original path in the staging directory during the injection,
+    // out of the tree the workload walks, instead of `__chaosfs__<name>__<id>` next to it
+    pub fn with_staging_dir(mut self, staging_dir: Option<PathBuf>) -> Result<MountInjector> {
+        if let Some(staging_dir) = &staging_dir {
+            self.new_path = encode_staging_path(&self.original_path, staging_dir)?;
+        }
+        self.staging_dir = staging_dir;
+        Ok(self)
+    }
+
     // This method should be called in host namespace
     pub fn mount(&mut self) -> Result<MountInjectionGuard> {
+        // the runtime is kept up until the session ends, and shut down after the last one
+        let session_runtime = hookfs::runtime::SessionRuntime::acquire();
+
         let original_path = self.original_path.clone();
         let new_path = self.new_path.clone();
 
+        // only root could look into the staging directory, and find the original path in it
+        if let Some(staging_dir) = &self.staging_dir {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(staging_dir)?;
+        }
+
+        // the staging directories left by the crashed toda are only reported, as the original
+        // directory may still be mounted on them
+        match find_stale_instances(&original_path, self.staging_dir.as_deref()) {
+            Ok(stale) if !stale.is_empty() => warn!(
+                "stale staging directories are found, clean them with `toda clean`: {:?}",
+                stale
+            ),
+            Ok(_) => {}
+            Err(err) => warn!("fail to find the stale staging directories: {}", err),
+        }
+
         let mounts = mount::MountsInfo::parse_mounts()?;
 
-        if mounts.non_root(&original_path)? {
-            // TODO: make the parent mount points private before move mount points
-            mounts.move_mount(original_path, new_path)?;
-        } else {
-            return Err(anyhow!("inject on a root mount"));
+        // a directory which is not a mount point could not be moved, so it's bind-mounted instead.
+        // Inside an overlayfs, the merged directory is bind-mounted, through which the copy-ups
+        // and whiteouts are still handled by the overlayfs.
+        if self.mount_mode == MountMode::Move && !mounts.is_mount_point(&original_path) {
+            info!(
+                "{} is not a mount point, bind mount it",
+                original_path.display()
+            );
+            self.mount_mode = MountMode::Bind;
+        }
+
+        // the mounts under the original mount are moved along with it
+        let mut moved_children = Vec::new();
+        match self.mount_mode {
+            MountMode::Move => {
+                if mounts.non_root(&original_path)? {
+                    // TODO: make the parent mount points private before move mount points
+                    mounts.move_mount(original_path, new_path)?;
+                } else {
+                    return Err(anyhow!("inject on a root mount"));
+                }
+            }
+            MountMode::Bind => {
+                mounts.bind_mount(&original_path, &new_path)?;
+
+                // the recursive bind mount skips the unbindable mounts under the directory, which
+                // would be hidden by the FUSE, so they are moved into the bind mount
+                let bound = mount::MountsInfo::parse_mounts()?;
+                for child in mounts.child_mount_points(&original_path) {
+                    let relative = child.strip_prefix(&original_path)?.to_owned();
+                    if bound.is_mount_point(new_path.join(&relative)) {
+                        continue;
+                    }
+                    info!("move mount {} into the bind mount", child.display());
+                    mounts.move_mount(&child, new_path.join(&relative))?;
+                    moved_children.push(relative);
+                }
+            }
         }
 
         let injectors = MultiInjector::build(self.injector_config.clone())?;
 
-        let hookfs = Arc::new(hookfs::HookFs::new(
-            &self.original_path,
-            &self.new_path,
-            injectors,
-        ));
+        let mut hookfs = hookfs::HookFs::new(&self.original_path, &self.new_path, injectors)?
+            .with_ownership(self.ownership.clone());
+        if let Some(pid) = self.ownership.id_map_pid {
+            hookfs = hookfs.with_id_map(IdMap::read(pid)?);
+        }
+        if let Some(trace) = &self.trace {
+            hookfs = hookfs.with_recorder(Recorder::create(trace, &self.new_path)?);
+        }
+        if let Some(shadow_dir) = &self.shadow_dir {
+            hookfs = hookfs.with_shadow(Shadow::create(shadow_dir, &self.new_path)?);
+        }
+        let hookfs = Arc::new(hookfs);
+        if self.permission_check == PermissionCheck::Daemon {
+            hookfs.enable_permission_check();
+        }
 
         let original_path = self.original_path.clone();
         let new_path = self.new_path.clone();
         let cloned_hookfs = hookfs.clone();
+        let session_hookfs = hookfs.clone();
+        let permission_check = self.permission_check;
+
+        let ended = Arc::new(AtomicBool::new(false));
+        let session_ended = ended.clone();
 
         let (before_mount_waiter, before_mount_guard) = stop::lock();
         let handler = std::thread::spawn(box move || {
+            let _ended = EndedGuard(session_ended);
             let fs = hookfs::AsyncFileSystem::from(cloned_hookfs);
 
             std::fs::create_dir_all(new_path.as_path())?;
 
-            let args = ["allow_other", "fsname=toda", "default_permissions", "nonempty"];
+            let mut args = vec!["allow_other", "fsname=toda", "nonempty"];
+            if permission_check == PermissionCheck::Kernel {
+                args.push("default_permissions");
+            }
             let flags: Vec<_> = args
                 .iter()
                 .flat_map(|item| vec![OsStr::new("-o"), OsStr::new(item)])
@@ -139,22 +536,66 @@ impl MountInjector {
             info!("mount with flags {:?}", flags);
 
             drop(before_mount_guard);
-            fuser::mount(fs, &original_path, &flags)?;
+            let result = fuser::mount(fs, &original_path, &flags);
+            session_hookfs.end_session(&result);
 
-            drop(hookfs::runtime::RUNTIME.write().unwrap().take().unwrap());
+            // the handles left open are never released once the session has ended, however it
+            // ended, and would keep the backend busy
+            futures::executor::block_on(session_hookfs.teardown());
+            drop(session_runtime);
 
-            Ok(())
+            Ok(result?)
         });
-        // TODO: remove this. But wait for FUSE gets up
+        // `fuser::mount` doesn't notify when the FUSE gets up, so wait until it appears in the
+        // mountinfo, or the session ends without it
         // Related Issue: https://github.com/zargony/fuse-rs/issues/9
         before_mount_waiter.wait();
-        std::thread::sleep(std::time::Duration::from_millis(200));
+        let ready = mount::wait_for_session_mount(&self.original_path, MOUNT_READY_TIMEOUT, || {
+            ended.load(Ordering::SeqCst)
+        });
 
-        Ok(MountInjectionGuard {
+        let mut guard = MountInjectionGuard {
             handler: Some(handler),
             hookfs,
             original_path: self.original_path.clone(),
             new_path: self.new_path.clone(),
-        })
+            mount_mode: self.mount_mode,
+            moved_children,
+            unmounted: false,
+            restored: false,
+        };
+        if let Err(err) = ready {
+            // the error of the session which has ended, e.g. the mount has failed, is returned
+            // instead. The thread of a session which never gets up is left behind, as it may never
+            // end either.
+            let err = match guard.handler.take() {
+                Some(handler) if ended.load(Ordering::SeqCst) => match handler.join() {
+                    Ok(Ok(())) => err,
+                    Ok(Err(session)) => session.context(err),
+                    Err(_) => anyhow!("FUSE session thread has panicked"),
+                },
+                _ => err,
+            };
+            let options = RecoverOptions {
+                replacer: None,
+                cwds: Vec::new(),
+                lazy_umount: true,
+            };
+            if let Err(rollback) = guard.recover_mount(options) {
+                warn!("fail to roll back the mount: {:?}", rollback);
+            }
+            return Err(err);
+        }
+
+        Ok(guard)
+    }
+}
+
+// EndedGuard marks the session as ended once its thread returns, however it returns
+struct EndedGuard(Arc<AtomicBool>);
+
+impl Drop for EndedGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
     }
 }
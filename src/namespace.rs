@@ -0,0 +1,121 @@
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use anyhow::{anyhow, Result};
+use nix::fcntl::{open, OFlag};
+use nix::sched::{setns, CloneFlags};
+use nix::sys::signal::{kill, signal, SigHandler, Signal};
+use nix::sys::stat::Mode;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{chdir, close, fork, ForkResult};
+use tracing::error;
+
+// Namespaces keeps the mount and pid namespaces of the target process opened
+#[derive(Debug)]
+pub struct Namespaces {
+    mnt: RawFd,
+    pid: RawFd,
+}
+
+impl Namespaces {
+    // open opens the namespaces of the process. They should be opened before entering any of
+    // them, as the `/proc` could be another one after entering the mount namespace
+    pub fn open(pid: i32) -> Result<Namespaces> {
+        let open_ns = |kind: &str| -> Result<RawFd> {
+            let path = format!("/proc/{}/ns/{}", pid, kind);
+            open(
+                path.as_str(),
+                OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )
+            .map_err(|err| anyhow!("fail to open {}: {}", path, err))
+        };
+
+        let mnt = open_ns("mnt")?;
+        let pid = match open_ns("pid") {
+            Ok(pid) => pid,
+            Err(err) => {
+                close(mnt)?;
+                return Err(err);
+            }
+        };
+
+        Ok(Namespaces { mnt, pid })
+    }
+
+    // enter moves the calling thread into the mount namespace, and its children into the pid
+    // namespace. The mount namespace could only be entered by a single-threaded process.
+    pub fn enter(&self) -> nix::Result<()> {
+        setns(self.pid, CloneFlags::CLONE_NEWPID)?;
+        setns(self.mnt, CloneFlags::CLONE_NEWNS)?;
+
+        // the working directory still refers to the original mount namespace
+        chdir("/")
+    }
+}
+
+impl Drop for Namespaces {
+    fn drop(&mut self) {
+        for fd in [self.mnt, self.pid].iter() {
+            if let Err(err) = close(*fd) {
+                error!("fail to close namespace fd {}: {:?}", fd, err);
+            }
+        }
+    }
+}
+
+static CHILD: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let child = CHILD.load(Ordering::SeqCst);
+    if child > 0 {
+        unsafe {
+            libc::kill(child, signal);
+        }
+    }
+}
+
+// enter runs the rest of toda inside the mount and pid namespaces of the target process, so that
+// the FUSE is mounted, and the processes are traced and recovered, all from the view of the
+// target. As the pid namespace only applies to the children, toda forks after entering the
+// namespaces, and the parent forwards the signals to the child and exits with its status.
+//
+// It must be called before any thread is spawned.
+pub fn enter(pid: i32) -> Result<()> {
+    let namespaces = Namespaces::open(pid)?;
+    namespaces
+        .enter()
+        .map_err(|err| anyhow!("fail to enter the namespaces of {}: {}", pid, err))?;
+    drop(namespaces);
+
+    match unsafe { fork()? } {
+        ForkResult::Child => {
+            // the child stops gracefully if the parent is killed
+            let ret = unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) };
+            if ret != 0 {
+                return Err(nix::Error::last().into());
+            }
+            Ok(())
+        }
+        ForkResult::Parent { child } => {
+            CHILD.store(child.as_raw(), Ordering::SeqCst);
+            for sig in [Signal::SIGINT, Signal::SIGTERM].iter() {
+                unsafe { signal(*sig, SigHandler::Handler(forward_signal))? };
+            }
+
+            let code = loop {
+                match waitpid(child, None) {
+                    Ok(WaitStatus::Exited(_, code)) => break code,
+                    Ok(WaitStatus::Signaled(_, signal, _)) => break 128 + signal as i32,
+                    Ok(_) => continue,
+                    Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                    Err(err) => {
+                        kill(child, Signal::SIGKILL).ok();
+                        return Err(err.into());
+                    }
+                }
+            };
+            std::process::exit(code);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use std::time::Duration;
+use std::{fs, thread};
+
+use anyhow::{anyhow, Result};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use tracing::info;
+
+use crate::hookfs::ownership::Owner;
+
+// the capabilities kept after dropping the privileges, to serve the FUSE with the files of other
+// users, and to recover the mount and the processes
+const CAP_CHOWN: u32 = 0;
+const CAP_DAC_OVERRIDE: u32 = 1;
+const CAP_DAC_READ_SEARCH: u32 = 2;
+const CAP_FOWNER: u32 = 3;
+const CAP_FSETID: u32 = 4;
+const CAP_KILL: u32 = 5;
+const CAP_SYS_PTRACE: u32 = 19;
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_MKNOD: u32 = 27;
+
+const RETAINED_CAPS: u64 = 1 << CAP_CHOWN
+    | 1 << CAP_DAC_OVERRIDE
+    | 1 << CAP_DAC_READ_SEARCH
+    | 1 << CAP_FOWNER
+    | 1 << CAP_FSETID
+    | 1 << CAP_KILL
+    | 1 << CAP_SYS_PTRACE
+    | 1 << CAP_SYS_ADMIN
+    | 1 << CAP_MKNOD;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+// how long to wait for the threads to drop their privileges
+const DROP_ROUNDS: usize = 100;
+const DROP_INTERVAL: Duration = Duration::from_millis(10);
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+static UID: AtomicU32 = AtomicU32::new(0);
+static GID: AtomicU32 = AtomicU32::new(0);
+// the errno of the first thread failed to drop its privileges
+static ERRNO: AtomicI32 = AtomicI32::new(0);
+
+// drop_thread switches the calling thread to the uid and gid, keeping the retained capabilities.
+// The credentials belong to every thread, rather than the process, so the raw syscalls are used
+// instead of the wrappers of libc, which would broadcast them to the other threads. It's called
+// in a signal handler, so it must be async-signal-safe.
+fn drop_thread() -> bool {
+    let (uid, gid) = (UID.load(Ordering::SeqCst), GID.load(Ordering::SeqCst));
+    let header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapData {
+            effective: RETAINED_CAPS as u32,
+            permitted: RETAINED_CAPS as u32,
+            inheritable: 0,
+        },
+        CapData {
+            effective: (RETAINED_CAPS >> 32) as u32,
+            permitted: (RETAINED_CAPS >> 32) as u32,
+            inheritable: 0,
+        },
+    ];
+
+    // the permitted capabilities are kept through the setresuid, and raised again by the capset
+    unsafe {
+        libc::syscall(libc::SYS_prctl, libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) == 0
+            && libc::syscall(libc::SYS_setgroups, 0, std::ptr::null::<libc::gid_t>()) == 0
+            && libc::syscall(libc::SYS_setresgid, gid, gid, gid) == 0
+            && libc::syscall(libc::SYS_setresuid, uid, uid, uid) == 0
+            && libc::syscall(libc::SYS_capset, &header, data.as_ptr()) == 0
+    }
+}
+
+extern "C" fn drop_handler(_: libc::c_int) {
+    if !drop_thread() {
+        let errno = unsafe { *libc::__errno_location() };
+        let _ = ERRNO.compare_exchange(0, errno, Ordering::SeqCst, Ordering::SeqCst);
+    }
+}
+
+// dropped returns true if the thread has switched to the uid and gid with the retained
+// capabilities, or has exited
+fn dropped(tid: &str, owner: Owner) -> bool {
+    let status = match fs::read_to_string(format!("/proc/self/task/{}/status", tid)) {
+        Ok(status) => status,
+        Err(_) => return true,
+    };
+
+    let ids = |field: &str, id: u32| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(field))
+            .map_or(false, |ids| {
+                ids.split_whitespace().all(|item| item == id.to_string())
+            })
+    };
+    let permitted = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapPrm:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok());
+
+    ids("Uid:", owner.uid) && ids("Gid:", owner.gid) && permitted == Some(RETAINED_CAPS)
+}
+
+// drop_privileges switches every thread of toda to the uid and gid, and keeps only the capabilities
+// to serve the FUSE and to recover. The other threads are signaled to drop their own credentials,
+// until all of them, including the ones spawned meanwhile, have dropped them.
+pub fn drop_privileges(owner: Owner) -> Result<()> {
+    UID.store(owner.uid, Ordering::SeqCst);
+    GID.store(owner.gid, Ordering::SeqCst);
+
+    // the parent death signal is cleared once the credentials change
+    let mut pdeath_signal: libc::c_int = 0;
+    unsafe { libc::prctl(libc::PR_GET_PDEATHSIG, &mut pdeath_signal) };
+
+    let signal = Signal::SIGUSR2;
+    let action = SigAction::new(
+        SigHandler::Handler(drop_handler),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(signal, &action)? };
+
+    let (pid, own) = unsafe { (libc::getpid(), libc::syscall(libc::SYS_gettid)) };
+    let mut result = Err(anyhow!("threads have not dropped the privileges in time"));
+    for _ in 0..DROP_ROUNDS {
+        let pending: Vec<String> = fs::read_dir("/proc/self/task")?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|tid| !dropped(tid, owner))
+            .collect();
+        let errno = ERRNO.load(Ordering::SeqCst);
+        if errno != 0 {
+            result = Err(nix::Error::from_errno(nix::errno::from_i32(errno)).into());
+            break;
+        }
+        if pending.is_empty() {
+            result = Ok(());
+            break;
+        }
+
+        for tid in pending
+            .iter()
+            .filter_map(|tid| tid.parse::<libc::c_long>().ok())
+        {
+            if tid == own {
+                drop_handler(0);
+            } else {
+                unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, signal as libc::c_int) };
+            }
+        }
+        thread::sleep(DROP_INTERVAL);
+    }
+
+    // the signals sent to a thread twice may still be pending, so the signal is ignored from now
+    // on, instead of terminating toda by default
+    let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+    unsafe { sigaction(signal, &ignore)? };
+    if pdeath_signal != 0 {
+        unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, pdeath_signal) };
+    }
+
+    result?;
+    info!(
+        "run as {}:{} with capabilities {:#x}",
+        owner.uid, owner.gid, RETAINED_CAPS
+    );
+    Ok(())
+}
@@ -1,17 +1,23 @@
-use std::cell::RefCell;
+use std::cell::Cell;
 use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread::{self, sleep, ThreadId};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use nix::errno::Errno;
 use nix::sys::mman::{MapFlags, ProtFlags};
 use nix::sys::signal::Signal;
 use nix::sys::uio::{process_vm_writev, IoVec, RemoteIoVec};
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
 use nix::sys::{ptrace, wait};
 use nix::unistd::Pid;
 use nix::Error::Sys;
+use once_cell::sync::Lazy;
 use procfs::process::Task;
 use procfs::ProcError;
 use retry::delay::Fixed;
@@ -20,33 +26,210 @@ use retry::OperationResult;
 use tracing::{error, info, instrument, trace, warn};
 use Error::Internal;
 
-// There should be only one PtraceManager in one thread. But as we don't implement TLS
-// , we cannot use thread-local variables safely.
+use crate::arch;
+
+mod permission;
+
+pub use permission::check_permission;
+
+// PtraceManager keeps the reference counts and the attached tasks of all traced processes. It's
+// shared by all threads, but a process can only be operated and detached by the thread which
+// attached it, as the kernel only accepts the ptrace requests from the tracer thread.
 #[derive(Debug, Default)]
 pub struct PtraceManager {
-    counter: RefCell<HashMap<i32, i32>>,
+    processes: Mutex<HashMap<i32, TracedEntry>>,
+}
+
+#[derive(Debug)]
+struct TracedEntry {
+    owner: ThreadId,
+    count: i32,
+    // the attached tasks, which is empty while attaching
+    tasks: HashSet<i32>,
 }
 
+// ThreadState is the ptrace settings of a thread. It detaches the processes still owned by the
+// thread when the thread exits.
+#[derive(Debug)]
+struct ThreadState {
+    id: ThreadId,
+
+    // the longest time to wait for a task to stop, `None` means waiting forever
+    timeout: Cell<Option<Duration>>,
+
+    trap_strategy: Cell<TrapStrategy>,
+}
+
+// TrapStrategy decides how to detect the completion of the codes injected by `run_codes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapStrategy {
+    // the codes end with an int3, which can collide with the tracee's own SIGTRAP usage
+    Breakpoint,
+    // the final int3 is replaced with a marker syscall, whose exit is caught by PTRACE_SYSCALL
+    Syscall,
+}
+
+impl Default for TrapStrategy {
+    fn default() -> Self {
+        TrapStrategy::Breakpoint
+    }
+}
+
+impl FromStr for TrapStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "breakpoint" => Ok(TrapStrategy::Breakpoint),
+            "syscall" => Ok(TrapStrategy::Syscall),
+            _ => Err(anyhow!("unknown trap strategy: {}", s)),
+        }
+    }
+}
+
+static PTRACE_MANAGER: Lazy<PtraceManager> = Lazy::new(PtraceManager::default);
+
 thread_local! {
-    static PTRACE_MANAGER: PtraceManager = PtraceManager::default()
+    static THREAD_STATE: ThreadState = ThreadState {
+        id: thread::current().id(),
+        timeout: Cell::new(None),
+        trap_strategy: Cell::new(TrapStrategy::default()),
+    }
+}
+
+impl Drop for ThreadState {
+    fn drop(&mut self) {
+        PTRACE_MANAGER.sweep(self.id);
+    }
+}
+
+fn current_thread() -> ThreadId {
+    THREAD_STATE.with(|state| state.id)
 }
 
 pub fn trace(pid: i32) -> Result<TracedProcess> {
-    PTRACE_MANAGER.with(|pm| pm.trace(pid))
+    PTRACE_MANAGER.trace(pid)
+}
+
+// set_timeout sets the timeout of the ptrace operations in the current thread
+pub fn set_timeout(timeout: Option<Duration>) {
+    THREAD_STATE.with(|state| state.timeout.set(timeout))
+}
+
+// set_trap_strategy sets how `run_codes` detects the completion of codes in the current thread
+pub fn set_trap_strategy(strategy: TrapStrategy) {
+    THREAD_STATE.with(|state| state.trap_strategy.set(strategy))
+}
+
+// wait_timeout waits for the state change of a task like `waitpid`, but returns `None` if it
+// doesn't happen before the timeout of the current thread.
+fn wait_timeout(pid: Pid, flags: Option<WaitPidFlag>) -> Result<Option<WaitStatus>> {
+    let timeout = match THREAD_STATE.with(|state| state.timeout.get()) {
+        Some(timeout) => timeout,
+        None => return Ok(Some(wait::waitpid(pid, flags)?)),
+    };
+
+    let deadline = Instant::now() + timeout;
+    let flags = flags.unwrap_or_else(WaitPidFlag::empty) | WaitPidFlag::WNOHANG;
+    loop {
+        match wait::waitpid(pid, Some(flags))? {
+            WaitStatus::StillAlive => {}
+            status => return Ok(Some(status)),
+        }
+
+        if Instant::now() >= deadline {
+            warn!("task {} didn't stop in {:?}", pid, timeout);
+            return Ok(None);
+        }
+        sleep(Duration::from_millis(1));
+    }
+}
+
+// interrupt stops a running task with SIGSTOP, so that its registers can be restored. The other
+// signals received in the meantime are deferred into `pending`.
+fn interrupt(pid: Pid, pending: &mut Vec<Signal>) -> Result<()> {
+    let ret = unsafe { libc::syscall(libc::SYS_tgkill, pid.as_raw(), pid.as_raw(), libc::SIGSTOP) };
+    Errno::result(ret)?;
+
+    loop {
+        match wait_timeout(pid, None)? {
+            Some(WaitStatus::Stopped(_, Signal::SIGSTOP)) => return Ok(()),
+            Some(WaitStatus::Stopped(_, sig)) if !pending.contains(&sig) => pending.push(sig),
+            Some(status) => info!("wait status: {:?}", status),
+            None => return Err(anyhow!("fail to interrupt task {}", pid)),
+        }
+        ptrace::cont(pid, None)?;
+    }
 }
 
-fn thread_is_gone(state: char) -> bool {
-    // return true if the process is Zombie or Dead
-    state == 'Z' || state == 'x' || state == 'X'
+// is_trapped checks the stop of a task which is running the injected codes, and returns whether
+// it has reached the final trap. A SIGTRAP is only treated as the final trap if `breakpoint` is
+// true, otherwise it's a normal signal. The signals delivered to the task in the meantime are deferred
+// into `pending`, so that neither the handlers run in the middle of the codes, nor the signals
+// (e.g. SIGCHLD, or the job-control ones which cause a group-stop) are swallowed.
+fn is_trapped(
+    pid: Pid,
+    status: WaitStatus,
+    breakpoint: bool,
+    pending: &mut Vec<Signal>,
+) -> Result<bool> {
+    match status {
+        WaitStatus::Stopped(_, Signal::SIGTRAP) if breakpoint => Ok(true),
+        WaitStatus::Stopped(_, sig) => {
+            // a group-stop has no siginfo, and the task only needs to be continued
+            match ptrace::getsiginfo(pid) {
+                Err(Sys(Errno::EINVAL)) => info!("task {} is in group-stop", pid),
+                _ => {
+                    info!("defer signal {:?} of task {}", sig, pid);
+                    if !pending.contains(&sig) {
+                        pending.push(sig);
+                    }
+                }
+            }
+            Ok(false)
+        }
+        WaitStatus::Exited(_, _) | WaitStatus::Signaled(_, _, _) => Err(anyhow!(
+            "task {} exited while running codes: {:?}",
+            pid,
+            status
+        )),
+        _ => Ok(false),
+    }
+}
+
+// requeue_signals sends the deferred signals to the task again, they will be delivered once the
+// task is continued or detached
+fn requeue_signals(pid: Pid, pending: &[Signal]) -> Result<()> {
+    for sig in pending {
+        trace!("requeue signal {:?} of task {}", sig, pid);
+        let ret =
+            unsafe { libc::syscall(libc::SYS_tgkill, pid.as_raw(), pid.as_raw(), *sig as i32) };
+        Errno::result(ret)?;
+    }
+
+    Ok(())
 }
 
+// attach_task seizes a task and stops it with PTRACE_INTERRUPT. Unlike PTRACE_ATTACH, no SIGSTOP
+// is queued, so a task which doesn't stop in time never stops on its own after it's given up.
 #[instrument]
-fn attach_task(task: &Task) -> Result<()> {
+fn attach_task(task: &Task) -> Result<bool> {
     let pid = Pid::from_raw(task.tid);
     let process = procfs::process::Process::new(task.tid)?;
 
     trace!("attach task: {}", task.tid);
-    match ptrace::attach(pid) {
+    let seized = ptrace::seize(pid, ptrace::Options::empty()).and_then(|()| {
+        let ret = unsafe {
+            libc::ptrace(
+                libc::PTRACE_INTERRUPT,
+                pid.as_raw(),
+                std::ptr::null_mut::<libc::c_void>(),
+                std::ptr::null_mut::<libc::c_void>(),
+            )
+        };
+        Errno::result(ret).map(drop)
+    });
+    match seized {
         Err(Sys(errno))
             if errno == Errno::ESRCH
                 || (errno == Errno::EPERM && thread_is_gone(process.stat.state)) =>
@@ -62,142 +245,314 @@ fn attach_task(task: &Task) -> Result<()> {
     info!("attach task: {} successfully", task.tid);
 
     // TODO: check wait result
-    match wait::waitpid(pid, Some(wait::WaitPidFlag::__WALL)) {
-        Ok(status) => {
+    match wait_timeout(pid, Some(wait::WaitPidFlag::__WALL)) {
+        Ok(Some(status)) => {
             info!("wait status: {:?}", status);
         }
+        Ok(None) => return Ok(false),
         Err(err) => warn!("fail to wait for process({}): {:?}", pid, err),
     };
 
+    Ok(true)
+}
+
+// attach_all_tasks attaches all tasks of a process, including the ones created during attaching.
+// The attached tasks are recorded in `traced_tasks` even if it fails, so that they can be
+// detached by the caller.
+fn attach_all_tasks(pid: i32, traced_tasks: &mut HashSet<i32>) -> Result<()> {
+    let mut iterations = 2;
+    let mut skipped_tasks = HashSet::<i32>::new();
+
+    while iterations > 0 {
+        let mut new_threads_found = false;
+        let process = procfs::process::Process::new(pid)?;
+        for task in process.tasks()?.flatten() {
+            if traced_tasks.contains(&task.tid) || skipped_tasks.contains(&task.tid) {
+                continue;
+            }
+
+            match attach_task(&task) {
+                Ok(true) => {
+                    trace!("newly traced task: {}", task.tid);
+                    new_threads_found = true;
+                    traced_tasks.insert(task.tid);
+                }
+                Ok(false) => {
+                    skipped_tasks.insert(task.tid);
+                }
+                Err(_) => {}
+            }
+        }
+
+        if !new_threads_found {
+            iterations -= 1;
+        }
+    }
+
+    if !skipped_tasks.is_empty() {
+        // a process cannot be modified safely while some of its threads are running
+        warn!(
+            "skip process {}, as tasks {:?} didn't stop in time",
+            pid, skipped_tasks
+        );
+        // the tasks are busy rather than untraceable, so the caller could retry later
+        let err = anyhow::Error::from(Sys(Errno::EBUSY)).context(format!(
+            "tasks {:?} of process {} didn't stop in time",
+            skipped_tasks, pid
+        ));
+        traced_tasks.extend(skipped_tasks);
+        return Err(err);
+    }
+
     Ok(())
 }
 
+// detach_task detaches a task, which can only be done while it's stopped. A task which was still
+// running toward the interrupt of `attach_task` is waited for first, as it would stay in the
+// interrupt stop until it's detached.
+fn detach_task(tid: i32) {
+    let pid = Pid::from_raw(tid);
+    match ptrace::detach(pid, None) {
+        Ok(()) => {
+            info!("successfully detached task: {}", tid);
+            return;
+        }
+        Err(Sys(Errno::ESRCH)) => {}
+        Err(err) => {
+            warn!("fail to detach: {:?}", err);
+            return;
+        }
+    }
+
+    match wait_timeout(pid, Some(WaitPidFlag::__WALL)) {
+        Ok(Some(WaitStatus::Exited(_, _))) | Ok(Some(WaitStatus::Signaled(_, _, _))) => {
+            trace!("task {} has exited", tid)
+        }
+        Ok(Some(status)) => {
+            trace!("task {} stopped before detaching: {:?}", tid, status);
+            match ptrace::detach(pid, None) {
+                Ok(()) => info!("successfully detached task: {}", tid),
+                Err(err) => warn!("fail to detach task {}: {:?}", tid, err),
+            }
+        }
+        Ok(None) => warn!(
+            "task {} is still running, and is detached once the thread exits",
+            tid
+        ),
+        Err(err) => trace!(
+            "task {} doesn't exist, maybe has stopped or not traced: {:?}",
+            tid,
+            err
+        ),
+    }
+}
+
 impl PtraceManager {
     #[instrument(skip(self))]
     pub fn trace(&self, pid: i32) -> Result<TracedProcess> {
         let raw_pid = pid;
         let pid = Pid::from_raw(pid);
+        let owner = current_thread();
 
-        let mut counter_ref = self.counter.borrow_mut();
-        match counter_ref.get_mut(&raw_pid) {
-            Some(count) => *count += 1,
-            None => {
-                trace!("stop {} successfully", pid);
-
-                let mut iterations = 2;
-                let mut traced_tasks = HashSet::<i32>::new();
-
-                while iterations > 0 {
-                    let mut new_threads_found = false;
-                    let process = procfs::process::Process::new(raw_pid)?;
-                    for task in process.tasks()?.flatten() {
-                        if traced_tasks.contains(&task.tid) {
-                            continue;
-                        }
-
-                        if let Ok(()) = attach_task(&task) {
-                            trace!("newly traced task: {}", task.tid);
-                            new_threads_found = true;
-                            traced_tasks.insert(task.tid);
-                        }
-                    }
-
-                    if !new_threads_found {
-                        iterations -= 1;
-                    }
+        {
+            let mut processes = self.processes.lock().unwrap();
+            match processes.get_mut(&raw_pid) {
+                Some(entry) if entry.owner == owner => {
+                    entry.count += 1;
+                    return Ok(TracedProcess {
+                        pid: raw_pid,
+                        owner,
+                    });
+                }
+                Some(entry) => {
+                    return Err(anyhow!(
+                        "process {} is traced by another thread {:?}",
+                        pid,
+                        entry.owner
+                    ))
+                }
+                None => {
+                    // reserve the process, so that the lock isn't held while attaching
+                    processes.insert(
+                        raw_pid,
+                        TracedEntry {
+                            owner,
+                            count: 0,
+                            tasks: HashSet::new(),
+                        },
+                    );
                 }
+            }
+        }
+
+        trace!("stop {} successfully", pid);
 
-                info!("trace process: {} successfully", pid);
-                counter_ref.insert(raw_pid, 1);
+        let mut traced_tasks = HashSet::<i32>::new();
+        if let Err(err) = attach_all_tasks(raw_pid, &mut traced_tasks) {
+            for tid in traced_tasks {
+                detach_task(tid);
             }
+            self.processes.lock().unwrap().remove(&raw_pid);
+            return Err(err);
+        }
+
+        info!("trace process: {} successfully", pid);
+        let mut processes = self.processes.lock().unwrap();
+        if let Some(entry) = processes.get_mut(&raw_pid) {
+            entry.count += 1;
+            entry.tasks = traced_tasks;
+        }
+
+        Ok(TracedProcess {
+            pid: raw_pid,
+            owner,
+        })
+    }
+
+    // retain increases the counter of a traced process
+    fn retain(&self, process: &TracedProcess) -> TracedProcess {
+        if let Some(entry) = self.processes.lock().unwrap().get_mut(&process.pid) {
+            entry.count += 1;
         }
 
-        Ok(TracedProcess { pid: raw_pid })
+        TracedProcess {
+            pid: process.pid,
+            owner: process.owner,
+        }
     }
 
     #[instrument(skip(self))]
     pub fn detach(&self, pid: i32) -> Result<()> {
-        let mut counter_ref = self.counter.borrow_mut();
-        match counter_ref.get_mut(&pid) {
-            Some(count) => {
-                *count -= 1;
-                trace!("decrease counter to {}", *count);
-                if *count < 1 {
-                    counter_ref.remove(&pid);
-
-                    info!("detach process: {}", pid);
-                    if let Err(err) = retry::retry::<_, _, _, anyhow::Error, _>(
-                        Fixed::from_millis(500).take(20),
-                        || match procfs::process::Process::new(pid) {
-                            Err(ProcError::NotFound(_)) => {
-                                info!("process {} not found", pid);
-                                OperationResult::Ok(())
-                            }
-                            Err(err) => {
-                                warn!("fail to detach task: {}, retry", pid);
-                                OperationResult::Retry(err.into())
+        let mut traced_tasks = {
+            let mut processes = self.processes.lock().unwrap();
+            let entry = match processes.get_mut(&pid) {
+                Some(entry) => entry,
+                None => return Err(anyhow::anyhow!("haven't traced this process")),
+            };
+
+            entry.count -= 1;
+            trace!("decrease counter to {}", entry.count);
+            if entry.count >= 1 {
+                return Ok(());
+            }
+            if entry.owner != current_thread() {
+                // the process is left to the final sweep of the owner thread
+                return Err(anyhow!(
+                    "process {} can only be detached by thread {:?}",
+                    pid,
+                    entry.owner
+                ));
+            }
+
+            processes
+                .remove(&pid)
+                .map(|entry| entry.tasks)
+                .unwrap_or_default()
+        };
+
+        info!("detach process: {}", pid);
+        if let Err(err) =
+            retry::retry::<_, _, _, anyhow::Error, _>(Fixed::from_millis(500).take(20), || {
+                match procfs::process::Process::new(pid) {
+                    Err(ProcError::NotFound(_)) => {
+                        info!("process {} not found", pid);
+                        OperationResult::Ok(())
+                    }
+                    Err(err) => {
+                        warn!("fail to detach task: {}, retry", pid);
+                        OperationResult::Retry(err.into())
+                    }
+                    Ok(process) => match process.tasks() {
+                        Err(err) => OperationResult::Retry(err.into()),
+                        Ok(tasks) => {
+                            for task in tasks.flatten() {
+                                detach_task(task.tid);
+                                traced_tasks.remove(&task.tid);
+                                trace!("detach task: {} successfully", task.tid);
                             }
-                            Ok(process) => match process.tasks() {
-                                Err(err) => OperationResult::Retry(err.into()),
-                                Ok(tasks) => {
-                                    for task in tasks.flatten() {
-                                        match ptrace::detach(Pid::from_raw(task.tid), None) {
-                                                Ok(()) => {
-                                                    info!("successfully detached task: {}", task.tid);
-                                                }
-                                                Err(Sys(Errno::ESRCH)) => trace!(
-                                                    "task {} doesn't exist, maybe has stopped or not traced",
-                                                    task.tid
-                                                ),
-                                                Err(err) => {
-                                                    warn!("fail to detach: {:?}", err)
-                                                },
-                                            }
-                                        trace!("detach task: {} successfully", task.tid);
-                                    }
-                                    info!("detach process: {} successfully", pid);
-                                    OperationResult::Ok(())
-                                }
-                            },
-                        },
-                    ) {
-                        warn!("fail to detach: {:?}", err);
-                        match err {
-                            Operation {
-                                error: e,
-                                total_delay: _,
-                                tries: _,
-                            } => return Err(e),
-                            Internal(err) => error!("internal error: {:?}", err),
+                            info!("detach process: {} successfully", pid);
+                            OperationResult::Ok(())
                         }
-                    };
+                    },
                 }
+            })
+        {
+            warn!("fail to detach: {:?}", err);
+            match err {
+                Operation {
+                    error: e,
+                    total_delay: _,
+                    tries: _,
+                } => return Err(e),
+                Internal(err) => error!("internal error: {:?}", err),
+            }
+        };
 
-                Ok(())
+        // the attached tasks which are not listed by procfs any more
+        for tid in traced_tasks {
+            detach_task(tid);
+        }
+
+        Ok(())
+    }
+
+    // sweep detaches all processes owned by the thread, so that none of their tasks is left
+    // stopped after the thread exits
+    fn sweep(&self, owner: ThreadId) {
+        let entries: Vec<_> = {
+            let mut processes = self.processes.lock().unwrap();
+            let pids: Vec<_> = processes
+                .iter()
+                .filter(|(_, entry)| entry.owner == owner)
+                .map(|(pid, _)| *pid)
+                .collect();
+            pids.into_iter()
+                .filter_map(|pid| processes.remove(&pid).map(|entry| (pid, entry)))
+                .collect()
+        };
+
+        for (pid, entry) in entries {
+            warn!("process {} is still traced, detaching", pid);
+            for tid in entry.tasks {
+                detach_task(tid);
             }
-            None => Err(anyhow::anyhow!("haven't traced this process")),
         }
     }
 }
 
+// TracedProcess is a handle of a traced process. It can be moved across threads, but can only
+// be operated by the thread which attached the process.
 #[derive(Debug)]
 pub struct TracedProcess {
     pub pid: i32,
+    owner: ThreadId,
 }
 
 impl Clone for TracedProcess {
     fn clone(&self) -> Self {
-        // TODO: handler error here
-        PTRACE_MANAGER.with(|pm| pm.trace(self.pid)).unwrap()
+        PTRACE_MANAGER.retain(self)
     }
 }
 
 impl TracedProcess {
+    fn check_affinity(&self) -> Result<()> {
+        if self.owner != current_thread() {
+            return Err(anyhow!(
+                "process {} can only be operated by thread {:?}",
+                self.pid,
+                self.owner
+            ));
+        }
+
+        Ok(())
+    }
+
     #[instrument]
     fn protect(&self) -> Result<ThreadGuard> {
-        let regs = ptrace::getregs(Pid::from_raw(self.pid))?;
+        self.check_affinity()?;
+        let regs = arch::getregs(Pid::from_raw(self.pid))?;
 
-        let rip = regs.rip;
+        let rip = arch::instruction_pointer(&regs);
         trace!("protecting regs: {:?}", regs);
         let rip_ins = ptrace::read(Pid::from_raw(self.pid), rip as *mut libc::c_void)?;
 
@@ -227,55 +582,47 @@ impl TracedProcess {
         self.with_protect(|thread| -> Result<u64> {
             let pid = Pid::from_raw(thread.pid);
 
-            let mut regs = ptrace::getregs(pid)?;
-            let cur_ins_ptr = regs.rip;
-
-            regs.rax = id;
-            for (index, arg) in args.iter().enumerate() {
-                // All these registers are hard coded for x86 platform
-                if index == 0 {
-                    regs.rdi = *arg
-                } else if index == 1 {
-                    regs.rsi = *arg
-                } else if index == 2 {
-                    regs.rdx = *arg
-                } else if index == 3 {
-                    regs.r10 = *arg
-                } else if index == 4 {
-                    regs.r8 = *arg
-                } else if index == 5 {
-                    regs.r9 = *arg
-                } else {
-                    return Err(anyhow!("too many arguments for a syscall"));
-                }
-            }
+            let mut regs = arch::getregs(pid)?;
+            let cur_ins_ptr = arch::instruction_pointer(&regs);
+
+            arch::set_syscall(&mut regs, id, args)?;
             trace!("setting regs for pid: {:?}, regs: {:?}", pid, regs);
-            ptrace::setregs(pid, regs)?;
+            arch::setregs(pid, regs)?;
 
-            // We only support x86-64 platform now, so using hard coded `LittleEndian` here is ok.
+            // both x86_64 and aarch64 are little endian, so the instruction is the lowest bytes
             unsafe {
                 ptrace::write(
                     pid,
                     cur_ins_ptr as *mut libc::c_void,
-                    0x050f as *mut libc::c_void,
+                    arch::SYSCALL_INSTRUCTION as *mut libc::c_void,
                 )?
             };
             ptrace::step(pid, None)?;
 
+            let mut pending = Vec::new();
             loop {
-                let status = wait::waitpid(pid, None)?;
+                let status = match wait_timeout(pid, None)? {
+                    Some(status) => status,
+                    None => {
+                        interrupt(pid, &mut pending)?;
+                        requeue_signals(pid, &pending)?;
+                        return Err(anyhow!("syscall {} in task {} timed out", id, pid));
+                    }
+                };
                 info!("wait status: {:?}", status);
-                match status {
-                    wait::WaitStatus::Stopped(_, Signal::SIGTRAP) => break,
-                    _ => ptrace::step(pid, None)?,
+                if is_trapped(pid, status, true, &mut pending)? {
+                    break;
                 }
+                ptrace::step(pid, None)?;
             }
+            requeue_signals(pid, &pending)?;
 
-            let regs = ptrace::getregs(pid)?;
+            let regs = arch::getregs(pid)?;
+            let ret = arch::syscall_result(&regs);
 
-            trace!("returned: {:?}", regs.rax);
+            trace!("returned: {:?}", ret);
 
-            Ok(regs.rax)
+            Ok(ret)
         })
     }
 
@@ -285,14 +632,14 @@ impl TracedProcess {
         let flags = MapFlags::MAP_PRIVATE | MapFlags::MAP_ANON;
 
         self.syscall(
-            9,
+            libc::SYS_mmap as u64,
             &[0, length, prot.bits() as u64, flags.bits() as u64, fd, 0],
         )
     }
 
     #[instrument]
     pub fn munmap(&self, addr: u64, len: u64) -> Result<u64> {
-        self.syscall(11, &[addr, len])
+        self.syscall(libc::SYS_munmap as u64, &[addr, len])
     }
 
     #[instrument(skip(f))]
@@ -314,7 +661,7 @@ impl TracedProcess {
         self.with_mmap(path.len() as u64, |process, addr| {
             process.write_mem(addr, path)?;
 
-            self.syscall(80, &[addr])?;
+            self.syscall(libc::SYS_chdir as u64, &[addr])?;
             Ok(())
         })
     }
@@ -337,47 +684,81 @@ impl TracedProcess {
 
     #[instrument(skip(codes))]
     pub fn run_codes<F: Fn(u64) -> Result<(u64, Vec<u8>)>>(&self, codes: F) -> Result<()> {
+        self.check_affinity()?;
         let pid = Pid::from_raw(self.pid);
 
-        let regs = ptrace::getregs(pid)?;
-        let (_, ins) = codes(regs.rip)?; // generate codes to get length
+        let regs = arch::getregs(pid)?;
+        let (_, ins) = codes(arch::instruction_pointer(&regs))?; // generate codes to get length
+
+        let strategy = THREAD_STATE.with(|state| state.trap_strategy.get());
+        // the final int3 is replaced with `mov eax, getpid; syscall` in the syscall strategy
+        let marker_len = match strategy {
+            TrapStrategy::Breakpoint => 0,
+            TrapStrategy::Syscall => 6,
+        };
 
-        self.with_mmap(ins.len() as u64 + 16, |_, addr| {
+        self.with_mmap(ins.len() as u64 + marker_len + 16, |_, addr| {
             self.with_protect(|_| {
-                let (offset, ins) = codes(addr)?; // generate codes
+                let (offset, mut ins) = codes(addr)?; // generate codes
+
+                if strategy == TrapStrategy::Syscall {
+                    if ins.pop() != Some(0xcc) {
+                        return Err(anyhow!("the codes don't end with int3"));
+                    }
+                    ins.push(0xb8);
+                    ins.extend_from_slice(&(libc::SYS_getpid as u32).to_le_bytes());
+                    ins.extend_from_slice(&[0x0f, 0x05]);
+
+                    ptrace::setoptions(pid, ptrace::Options::PTRACE_O_TRACESYSGOOD)?;
+                }
 
                 let end_addr = addr + ins.len() as u64;
                 trace!("write instructions to addr: {:X}-{:X}", addr, end_addr);
                 self.write_mem(addr, &ins)?;
 
-                let mut regs = ptrace::getregs(pid)?;
+                let mut regs = arch::getregs(pid)?;
                 trace!("modify rip to addr: {:X}", addr + offset);
-                regs.rip = addr + offset;
-                ptrace::setregs(pid, regs)?;
+                arch::set_instruction_pointer(&mut regs, addr + offset);
+                arch::setregs(pid, regs)?;
 
-                let regs = ptrace::getregs(pid)?;
+                let regs = arch::getregs(pid)?;
                 info!("current registers: {:?}", regs);
 
+                let mut pending = Vec::new();
                 loop {
                     info!("run instructions");
-                    ptrace::cont(pid, None)?;
+                    match strategy {
+                        TrapStrategy::Breakpoint => ptrace::cont(pid, None)?,
+                        TrapStrategy::Syscall => ptrace::syscall(pid, None)?,
+                    }
 
                     info!("wait for pid: {:?}", pid);
-                    let status = wait::waitpid(pid, None)?;
+                    let status = match wait_timeout(pid, None)? {
+                        Some(status) => status,
+                        None => {
+                            interrupt(pid, &mut pending)?;
+                            requeue_signals(pid, &pending)?;
+                            return Err(anyhow!("running codes in task {} timed out", pid));
+                        }
+                    };
                     info!("wait status: {:?}", status);
 
-                    use nix::sys::signal::SIGTRAP;
-                    let regs = ptrace::getregs(pid)?;
+                    let breakpoint = strategy == TrapStrategy::Breakpoint;
+                    let trapped = is_trapped(pid, status, breakpoint, &mut pending)?;
+                    let regs = arch::getregs(pid)?;
 
                     info!("current registers: {:?}", regs);
-                    match status {
-                        wait::WaitStatus::Stopped(_, SIGTRAP) => {
-                            break;
-                        }
-                        _ => info!("continue running replacers"),
+                    // the syscall-entry-stop of the marker has -ENOSYS in rax
+                    let trapped = trapped
+                        || (status == WaitStatus::PtraceSyscall(pid)
+                            && arch::instruction_pointer(&regs) == end_addr
+                            && arch::syscall_result(&regs) != -libc::ENOSYS as u64);
+                    if trapped {
+                        break;
                     }
+                    info!("continue running replacers");
                 }
-                Ok(())
+                requeue_signals(pid, &pending)
             })
         })
     }
@@ -387,7 +768,7 @@ impl Drop for TracedProcess {
     fn drop(&mut self) {
         trace!("dropping traced process: {}", self.pid);
 
-        if let Err(err) = PTRACE_MANAGER.with(|pm| pm.detach(self.pid)) {
+        if let Err(err) = PTRACE_MANAGER.detach(self.pid) {
             info!(
                 "detaching process {} failed with error: {:?}",
                 self.pid, err
@@ -399,21 +780,27 @@ impl Drop for TracedProcess {
 #[derive(Debug)]
 struct ThreadGuard {
     tid: i32,
-    regs: libc::user_regs_struct,
+    regs: arch::Regs,
     rip_ins: i64,
 }
 
 impl Drop for ThreadGuard {
     fn drop(&mut self) {
         let pid = Pid::from_raw(self.tid);
-        unsafe {
+        if let Err(err) = unsafe {
             ptrace::write(
                 pid,
-                self.regs.rip as *mut libc::c_void,
+                arch::instruction_pointer(&self.regs) as *mut libc::c_void,
                 self.rip_ins as *mut libc::c_void,
             )
-            .unwrap();
+        } {
+            error!(
+                "fail to restore instruction of task {}: {:?}",
+                self.tid, err
+            );
+        }
+        if let Err(err) = arch::setregs(pid, self.regs) {
+            error!("fail to restore registers of task {}: {:?}", self.tid, err);
         }
-        ptrace::setregs(pid, self.regs).unwrap();
     }
 }
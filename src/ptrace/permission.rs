@@ -0,0 +1,96 @@
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use anyhow::{anyhow, Result};
+use nix::unistd::geteuid;
+use once_cell::sync::Lazy;
+use procfs::process::Process;
+
+// the bit of CAP_SYS_PTRACE in the capability sets of /proc/<pid>/status
+const CAP_SYS_PTRACE: u64 = 1 << 19;
+
+// `_IO(0xb7, 0x2)`, which opens the parent of a user namespace
+const NS_GET_PARENT: libc::c_ulong = 0xb702;
+
+// the value of kernel.yama.ptrace_scope, or `None` without the YAMA LSM
+static YAMA_SCOPE: Lazy<Option<u32>> = Lazy::new(|| {
+    fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope")
+        .ok()
+        .and_then(|scope| scope.trim().parse().ok())
+});
+
+static HAS_CAP_SYS_PTRACE: Lazy<bool> = Lazy::new(|| {
+    Process::myself()
+        .and_then(|process| process.status())
+        .map_or(false, |status| status.capeff & CAP_SYS_PTRACE != 0)
+});
+
+// check_permission finds the reasons for which the process could never be traced, before
+// attaching to it, so that they're reported with how to fix them instead of a bare EPERM
+pub fn check_permission(pid: i32) -> Result<()> {
+    let scope = *YAMA_SCOPE;
+    if scope == Some(3) {
+        return Err(anyhow!(
+            "ptrace is disabled by kernel.yama.ptrace_scope=3 until reboot"
+        ));
+    }
+
+    if !*HAS_CAP_SYS_PTRACE {
+        match scope {
+            Some(2) => {
+                return Err(anyhow!(
+                    "kernel.yama.ptrace_scope=2 requires CAP_SYS_PTRACE, which toda doesn't have"
+                ))
+            }
+            Some(1) => {
+                return Err(anyhow!(
+                    "kernel.yama.ptrace_scope=1 only permits tracing the descendants without \
+                     CAP_SYS_PTRACE, run toda with it or set the scope to 0"
+                ))
+            }
+            _ => {}
+        }
+
+        let status = Process::new(pid)?.status()?;
+        let uid = geteuid().as_raw();
+        if status.ruid != uid || status.euid != uid || status.suid != uid {
+            return Err(anyhow!(
+                "process {} is owned by uid {}, which could only be traced by uid {} with \
+                 CAP_SYS_PTRACE",
+                pid,
+                status.ruid,
+                uid
+            ));
+        }
+    }
+
+    if !in_owned_user_ns(pid)? {
+        return Err(anyhow!(
+            "process {} is in a user namespace out of the one of toda, where CAP_SYS_PTRACE \
+             doesn't apply, run toda in an ancestor user namespace of the target",
+            pid
+        ));
+    }
+
+    Ok(())
+}
+
+// in_owned_user_ns returns true if the user namespace of the process is the one of toda, or a
+// descendant of it, in which the capabilities of toda apply
+fn in_owned_user_ns(pid: i32) -> Result<bool> {
+    let own = fs::metadata("/proc/self/ns/user")?.ino();
+    let mut ns = File::open(format!("/proc/{}/ns/user", pid))?;
+    loop {
+        if ns.metadata()?.ino() == own {
+            return Ok(true);
+        }
+
+        // the parent out of the user namespace of toda is not permitted to be opened
+        let parent = unsafe { libc::ioctl(ns.as_raw_fd(), NS_GET_PARENT) };
+        if parent < 0 {
+            return Ok(false);
+        }
+        ns = unsafe { File::from_raw_fd(parent) };
+    }
+}
@@ -0,0 +1,295 @@
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tracing::{trace, warn};
+
+use crate::injector::Method;
+
+// the magic number at the beginning of a trace, with the version of the format in the last byte
+const TRACE_MAGIC: [u8; 8] = *b"TODATRC\x01";
+
+// Operation is an operation done through the FUSE, which is recorded with the path relative to
+// the mount point, so that it could be replayed against any directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operation {
+    pub method: Method,
+    pub path: PathBuf,
+    // the offset of a read or write, which is `None` for the other operations
+    pub offset: Option<i64>,
+    // the bytes read or written, or the new length of a truncated file
+    pub size: u64,
+    // the destination of a rename
+    pub new_path: Option<PathBuf>,
+    // when the operation was received, since the recording started
+    pub start: Duration,
+    // how long the operation took, including the injected latency
+    pub latency: Duration,
+}
+
+impl Operation {
+    pub fn new<P: AsRef<Path>>(method: Method, path: P) -> Operation {
+        Operation {
+            method,
+            path: path.as_ref().to_owned(),
+            offset: None,
+            size: 0,
+            new_path: None,
+            start: Duration::default(),
+            latency: Duration::default(),
+        }
+    }
+
+    pub fn io(mut self, offset: i64, size: u64) -> Operation {
+        self.offset = Some(offset);
+        self.size = size;
+        self
+    }
+
+    pub fn size(mut self, size: u64) -> Operation {
+        self.size = size;
+        self
+    }
+
+    pub fn new_path<P: AsRef<Path>>(mut self, path: P) -> Operation {
+        self.new_path = Some(path.as_ref().to_owned());
+        self
+    }
+
+    // the layout of a record, in little endian:
+    // method (u64) | start (u64, us) | latency (u64, us) | offset (i64, -1 for none) | size (u64)
+    // | path length (u16) | path | new path length (u16, 0 for none) | new path
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.method.bits().to_le_bytes())?;
+        writer.write_all(&(self.start.as_micros() as u64).to_le_bytes())?;
+        writer.write_all(&(self.latency.as_micros() as u64).to_le_bytes())?;
+        writer.write_all(&self.offset.unwrap_or(-1).to_le_bytes())?;
+        writer.write_all(&self.size.to_le_bytes())?;
+        encode_path(writer, Some(&self.path))?;
+        encode_path(writer, self.new_path.as_deref())
+    }
+
+    // decode reads the next record, or returns `None` at the end of the trace
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Option<Operation>> {
+        let mut method = [0u8; 8];
+        match reader.read_exact(&mut method) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+
+        let start = Duration::from_micros(read_u64(reader)?);
+        let latency = Duration::from_micros(read_u64(reader)?);
+        let offset = read_u64(reader)? as i64;
+        let size = read_u64(reader)?;
+        let path = decode_path(reader)?;
+        let new_path = decode_path(reader)?;
+
+        Ok(Some(Operation {
+            method: Method::from_bits_truncate(u64::from_le_bytes(method)),
+            path,
+            offset: if offset < 0 { None } else { Some(offset) },
+            size,
+            new_path: if new_path.as_os_str().is_empty() {
+                None
+            } else {
+                Some(new_path)
+            },
+            start,
+            latency,
+        }))
+    }
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn encode_path<W: Write>(writer: &mut W, path: Option<&Path>) -> io::Result<()> {
+    let bytes = path.map(|path| path.as_os_str().as_bytes()).unwrap_or(&[]);
+    if bytes.len() > u16::MAX as usize {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "path is too long"));
+    }
+    writer.write_all(&(bytes.len() as u16).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn decode_path<R: Read>(reader: &mut R) -> io::Result<PathBuf> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0u8; u16::from_le_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(PathBuf::from(OsStr::from_bytes(&bytes)))
+}
+
+// Recorder appends the operations done through the FUSE to a trace file
+#[derive(Debug)]
+pub struct Recorder {
+    root: PathBuf,
+    started: Instant,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    // create starts a new trace, in which the operations under the root are recorded
+    pub fn create<P1: AsRef<Path>, P2: AsRef<Path>>(trace: P1, root: P2) -> Result<Recorder> {
+        let mut writer = BufWriter::new(File::create(trace.as_ref())?);
+        writer.write_all(&TRACE_MAGIC)?;
+
+        Ok(Recorder {
+            root: root.as_ref().to_owned(),
+            started: Instant::now(),
+            writer: Mutex::new(writer),
+        })
+    }
+
+    // record appends the operation which was received at `received`. The paths outside the root
+    // are ignored, and a failure to write the trace never fails the operation itself.
+    pub fn record(&self, mut operation: Operation, received: Instant) {
+        operation.path = match operation.path.strip_prefix(&self.root) {
+            Ok(path) => path.to_owned(),
+            Err(_) => return,
+        };
+        if let Some(new_path) = &operation.new_path {
+            operation.new_path = new_path.strip_prefix(&self.root).ok().map(Path::to_owned);
+        }
+        operation.start = received.saturating_duration_since(self.started);
+        operation.latency = received.elapsed();
+
+        if let Err(err) = operation.encode(&mut *self.writer.lock().unwrap()) {
+            warn!("fail to record {:?}: {}", operation, err);
+        }
+    }
+
+    pub fn flush(&self) {
+        if let Err(err) = self.writer.lock().unwrap().flush() {
+            warn!("fail to flush the trace: {}", err);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+// read_trace reads all the operations in the trace
+pub fn read_trace<P: AsRef<Path>>(trace: P) -> Result<Vec<Operation>> {
+    let mut reader = BufReader::new(File::open(trace.as_ref())?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != TRACE_MAGIC {
+        return Err(anyhow!("{} is not a trace", trace.as_ref().display()));
+    }
+
+    let mut operations = Vec::new();
+    while let Some(operation) = Operation::decode(&mut reader)? {
+        operations.push(operation);
+    }
+    Ok(operations)
+}
+
+// ReplayStats is the summary of a replay
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayStats {
+    pub operations: usize,
+    pub errors: usize,
+    pub skipped: usize,
+    pub elapsed: Duration,
+}
+
+// replay executes the operations of the trace against the directory one by one. With a speed,
+// the operations are started at their recorded time divided by it, or as fast as possible
+// otherwise. The data written is zeroed, as only the sizes are recorded.
+pub fn replay<P1: AsRef<Path>, P2: AsRef<Path>>(
+    trace: P1,
+    dir: P2,
+    speed: Option<f64>,
+) -> Result<ReplayStats> {
+    let operations = read_trace(trace)?;
+    let mut stats = ReplayStats::default();
+    let started = Instant::now();
+
+    for operation in operations.iter() {
+        if let Some(speed) = speed.filter(|speed| *speed > 0.0) {
+            let due = operation.start.div_f64(speed);
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                sleep(wait);
+            }
+        }
+
+        stats.operations += 1;
+        match replay_operation(dir.as_ref(), operation) {
+            Ok(true) => {}
+            Ok(false) => stats.skipped += 1,
+            Err(err) => {
+                trace!("fail to replay {:?}: {}", operation, err);
+                stats.errors += 1;
+            }
+        }
+    }
+
+    stats.elapsed = started.elapsed();
+    Ok(stats)
+}
+
+// replay_operation returns whether the operation is replayed, as some of them (e.g. xattrs) are
+// not recorded in enough detail to be replayed
+fn replay_operation(dir: &Path, operation: &Operation) -> io::Result<bool> {
+    let path = dir.join(&operation.path);
+    let offset = operation.offset.unwrap_or(0) as u64;
+
+    match operation.method {
+        Method::LOOKUP | Method::GETATTR => {
+            fs::symlink_metadata(&path)?;
+        }
+        Method::SETATTR => {
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(operation.size)?;
+        }
+        Method::MKNOD | Method::CREATE => {
+            OpenOptions::new().write(true).create(true).open(&path)?;
+        }
+        Method::MKDIR => fs::create_dir(&path)?,
+        Method::UNLINK => fs::remove_file(&path)?,
+        Method::RMDIR => fs::remove_dir(&path)?,
+        Method::RENAME => match &operation.new_path {
+            Some(new_path) => fs::rename(&path, dir.join(new_path))?,
+            None => return Ok(false),
+        },
+        Method::OPEN => {
+            File::open(&path)?;
+        }
+        Method::READ => {
+            let mut buf = vec![0u8; operation.size as usize];
+            File::open(&path)?.read_at(&mut buf, offset)?;
+        }
+        Method::WRITE => {
+            let buf = vec![0u8; operation.size as usize];
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .write_all_at(&buf, offset)?;
+        }
+        Method::FSYNC => File::open(&path)?.sync_all()?,
+        Method::READDIR => {
+            fs::read_dir(&path)?.count();
+        }
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
@@ -1,25 +1,31 @@
 use std::fmt::Debug;
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 
 use anyhow::Result;
-use tracing::{error, info, trace};
+use tracing::{info, trace};
 
-use super::utils::all_processes;
-use super::{ptrace, Replacer};
+use super::utils::{all_processes, trace_with_retry, AttachFailures};
+use super::{ptrace, ReplacedCwd, Replacer, ReplacerOptions, ReplacerStats};
 
 #[derive(Debug)]
 pub struct CwdReplacer {
-    processes: Vec<(ptrace::TracedProcess, PathBuf)>,
+    pub(super) processes: Vec<(ptrace::TracedProcess, ReplacedCwd)>,
+    pub(super) stats: ReplacerStats,
 }
 
 impl CwdReplacer {
     pub fn prepare<P1: AsRef<Path>, P2: AsRef<Path>>(
         detect_path: P1,
         new_path: P2,
+        options: &ReplacerOptions,
     ) -> Result<CwdReplacer> {
         info!("preparing cmdreplacer");
 
-        let processes = all_processes()?
+        let mut stats = ReplacerStats::default();
+        let mut failures = AttachFailures::default();
+        let processes = all_processes(options)?
             .filter_map(|process| -> Option<_> {
                 let pid = process.pid;
                 trace!("itering proc: {}", pid);
@@ -33,30 +39,80 @@ impl CwdReplacer {
                 }
             })
             .filter(|(_, path)| path.starts_with(detect_path.as_ref()))
-            .filter_map(|(pid, path)| match ptrace::trace(pid) {
+            .filter_map(|(pid, path)| match trace_with_retry(pid, options) {
                 Ok(process) => {
                     let mut new_path = new_path.as_ref().to_path_buf();
 
                     new_path.push(path.strip_prefix(detect_path.as_ref()).unwrap());
-                    Some((process, new_path))
+                    let cwd = ReplacedCwd {
+                        pid,
+                        original: path,
+                        replaced: new_path,
+                    };
+                    Some((process, cwd))
                 }
                 Err(err) => {
-                    error!("fail to ptrace process: pid({}) with error: {:?}", pid, err);
+                    failures.push(pid, path.display(), err);
                     None
                 }
             })
             .collect();
+        failures.resolve(options.attach_failure, &mut stats)?;
 
-        Ok(CwdReplacer { processes })
+        Ok(CwdReplacer { processes, stats })
+    }
+
+    // restore prepares to move the cwds replaced by a former pass back to their original
+    // directories under the new path. It's for the processes whose cwd is still on the
+    // filesystem of the detect path, but not found under it by path, as they've moved to a
+    // directory which has been removed or renamed since then.
+    pub fn restore<P1: AsRef<Path>, P2: AsRef<Path>>(
+        cwds: &[ReplacedCwd],
+        detect_path: P1,
+        new_path: P2,
+        options: &ReplacerOptions,
+    ) -> Result<CwdReplacer> {
+        info!("preparing cwd restorer");
+
+        let dev = fs::metadata(detect_path.as_ref())?.dev();
+        let mut stats = ReplacerStats::default();
+        let mut failures = AttachFailures::default();
+        let processes = cwds
+            .iter()
+            .filter(|cwd| {
+                // the processes which have exited are skipped
+                fs::metadata(format!("/proc/{}/cwd", cwd.pid))
+                    .map_or(false, |meta| meta.dev() == dev)
+            })
+            .filter_map(|cwd| {
+                // the replaced cwd is the original one seen through the detect path
+                let relative = cwd.replaced.strip_prefix(detect_path.as_ref()).ok()?;
+                let restored = ReplacedCwd {
+                    pid: cwd.pid,
+                    original: cwd.replaced.clone(),
+                    replaced: new_path.as_ref().join(relative),
+                };
+                match trace_with_retry(cwd.pid, options) {
+                    Ok(process) => Some((process, restored)),
+                    Err(err) => {
+                        failures.push(cwd.pid, cwd.replaced.display(), err);
+                        None
+                    }
+                }
+            })
+            .collect();
+        failures.resolve(options.attach_failure, &mut stats)?;
+
+        Ok(CwdReplacer { processes, stats })
     }
 }
 
 impl Replacer for CwdReplacer {
     fn run(&mut self) -> Result<()> {
         info!("running cwd replacer");
-        for (process, new_path) in self.processes.iter() {
-            trace!("replacing cwd: {} to {:?}", process.pid, new_path);
-            process.chdir(new_path)?;
+        for (process, cwd) in self.processes.iter() {
+            trace!("replacing cwd: {} to {:?}", process.pid, cwd.replaced);
+            process.chdir(&cwd.replaced)?;
         }
 
         Ok(())
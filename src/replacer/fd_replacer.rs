@@ -1,17 +1,19 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::io::{Cursor, Read, Write};
+use std::io::{Cursor, Write};
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
 use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
-use itertools::Itertools;
-use procfs::process::FDTarget;
-use tracing::{error, info, trace};
+use nix::sys::stat::{self, SFlag};
+use procfs::process::{FDInfo, FDTarget};
+use tracing::{error, info, trace, warn};
 
-use super::utils::all_processes;
-use super::{ptrace, Replacer};
+use super::fdinfo::read_fdinfo;
+use super::process_patcher::Assembler;
+use super::utils::{all_processes, trace_with_retry, AttachFailures};
+use super::{ptrace, Replacer, ReplacerOptions, ReplacerStats};
 
 #[derive(Clone, Copy)]
 #[repr(packed)]
@@ -19,20 +21,53 @@ use super::{ptrace, Replacer};
 struct ReplaceCase {
     fd: u64,
     new_path_offset: u64,
+    // the file status flags read from fdinfo
+    flags: u64,
 }
 
 impl ReplaceCase {
-    pub fn new(fd: u64, new_path_offset: u64) -> ReplaceCase {
+    pub fn new(fd: u64, new_path_offset: u64, flags: i32) -> ReplaceCase {
         ReplaceCase {
             fd,
             new_path_offset,
+            flags: flags as u64,
         }
     }
 }
 
+// EpollCase re-registers a replaced fd into the interest list of an epoll fd, as the
+// registration is removed with the old file.
+#[derive(Clone, Copy)]
+#[repr(packed)]
+#[repr(C)]
+struct EpollCase {
+    epfd: u64,
+    fd: u64,
+    // the layout of `struct epoll_event` on x86_64
+    events: u32,
+    data: u64,
+}
+
+// UringCase updates a fixed file of an io_uring fd with the replaced fd. The `fds` field is the
+// pointer to `fd`, which will be filled by the injected codes, so that `offset`, `resv` and `fds`
+// form a `struct io_uring_files_update`.
+#[derive(Clone, Copy)]
+#[repr(packed)]
+#[repr(C)]
+struct UringCase {
+    ring_fd: u64,
+    offset: u32,
+    resv: u32,
+    fds: u64,
+    fd: u64,
+}
+
 struct ProcessAccessorBuilder {
     cases: Vec<ReplaceCase>,
     new_paths: Cursor<Vec<u8>>,
+
+    epoll_cases: Vec<EpollCase>,
+    uring_cases: Vec<UringCase>,
 }
 
 impl ProcessAccessorBuilder {
@@ -40,6 +75,9 @@ impl ProcessAccessorBuilder {
         ProcessAccessorBuilder {
             cases: Vec::new(),
             new_paths: Cursor::new(Vec::new()),
+
+            epoll_cases: Vec::new(),
+            uring_cases: Vec::new(),
         }
     }
 
@@ -49,11 +87,82 @@ impl ProcessAccessorBuilder {
 
             cases: self.cases,
             new_paths: self.new_paths,
+
+            epoll_cases: self.epoll_cases,
+            uring_cases: self.uring_cases,
         })
     }
 
-    pub fn push_case(&mut self, fd: u64, new_path: PathBuf) -> anyhow::Result<()> {
-        info!("push case fd: {}, new_path: {}", fd, new_path.display());
+    // push_registered_fds finds the replaced fds which are registered in epoll interest lists or
+    // io_uring fixed file tables of the process, and pushes the cases to register them again.
+    pub fn push_registered_fds(
+        &mut self,
+        pid: i32,
+        fds: &[FDInfo],
+        replaced_fds: &[(u64, PathBuf, i32)],
+        detect_path: &Path,
+    ) {
+        for entry in fds {
+            let kind = match &entry.target {
+                FDTarget::AnonInode(kind) if kind == "[eventpoll]" || kind == "[io_uring]" => kind,
+                _ => continue,
+            };
+            let info = match read_fdinfo(pid, entry.fd as u64) {
+                Ok(info) => info,
+                Err(err) => {
+                    warn!("fail to read fdinfo of {} fd({}): {:?}", pid, entry.fd, err);
+                    continue;
+                }
+            };
+
+            for target in info.epoll_targets {
+                if replaced_fds.iter().any(|(fd, _, _)| *fd == target.fd) {
+                    info!("push epoll case epfd: {}, fd: {}", entry.fd, target.fd);
+                    self.epoll_cases.push(EpollCase {
+                        epfd: entry.fd as u64,
+                        fd: target.fd,
+                        events: target.events,
+                        data: target.data,
+                    });
+                }
+            }
+
+            for (offset, path) in info.uring_files {
+                if !path.starts_with(detect_path) {
+                    continue;
+                }
+                match replaced_fds
+                    .iter()
+                    .find(|(_, replaced, _)| *replaced == path)
+                {
+                    Some((fd, _, _)) => {
+                        info!("push io_uring case ring: {}, fd: {}", entry.fd, fd);
+                        self.uring_cases.push(UringCase {
+                            ring_fd: entry.fd as u64,
+                            offset,
+                            resv: 0,
+                            fds: 0,
+                            fd: *fd,
+                        });
+                    }
+                    None => warn!(
+                        "cannot find opened fd for {} fixed file {}: {}",
+                        kind,
+                        offset,
+                        path.display()
+                    ),
+                }
+            }
+        }
+    }
+
+    pub fn push_case(&mut self, fd: u64, new_path: PathBuf, flags: i32) -> anyhow::Result<()> {
+        info!(
+            "push case fd: {}, new_path: {}, flags: {:o}",
+            fd,
+            new_path.display(),
+            flags
+        );
 
         let mut new_path = new_path
             .to_str()
@@ -66,17 +175,17 @@ impl ProcessAccessorBuilder {
         let offset = self.new_paths.position();
         self.new_paths.write_all(new_path.as_slice())?;
 
-        self.cases.push(ReplaceCase::new(fd, offset));
+        self.cases.push(ReplaceCase::new(fd, offset, flags));
 
         Ok(())
     }
 }
 
-impl FromIterator<(u64, PathBuf)> for ProcessAccessorBuilder {
-    fn from_iter<T: IntoIterator<Item = (u64, PathBuf)>>(iter: T) -> Self {
+impl FromIterator<(u64, PathBuf, i32)> for ProcessAccessorBuilder {
+    fn from_iter<T: IntoIterator<Item = (u64, PathBuf, i32)>>(iter: T) -> Self {
         let mut builder = Self::new();
-        for (fd, path) in iter {
-            if let Err(err) = builder.push_case(fd, path) {
+        for (fd, path, flags) in iter {
+            if let Err(err) = builder.push_case(fd, path, flags) {
                 error!("fail to write to AccessorBuilder. Error: {:?}", err)
             }
         }
@@ -85,11 +194,19 @@ impl FromIterator<(u64, PathBuf)> for ProcessAccessorBuilder {
     }
 }
 
-struct ProcessAccessor {
-    process: ptrace::TracedProcess,
+pub(super) struct ProcessAccessor {
+    pub(super) process: ptrace::TracedProcess,
 
     cases: Vec<ReplaceCase>,
     new_paths: Cursor<Vec<u8>>,
+
+    epoll_cases: Vec<EpollCase>,
+    uring_cases: Vec<UringCase>,
+}
+
+fn as_bytes<T: Copy>(cases: &[T]) -> &[u8] {
+    let size = cases.len() * std::mem::size_of::<T>();
+    unsafe { std::slice::from_raw_parts(cases.as_ptr() as *const u8, size) }
 }
 
 impl Debug for ProcessAccessor {
@@ -99,89 +216,176 @@ impl Debug for ProcessAccessor {
 }
 
 impl ProcessAccessor {
-    pub fn run(&mut self) -> anyhow::Result<()> {
-        self.new_paths.set_position(0);
+    pub(super) fn case_count(&self) -> usize {
+        self.cases.len()
+    }
 
-        let mut new_paths = Vec::new();
-        self.new_paths.read_to_end(&mut new_paths)?;
+    // emit writes the codes replacing the fds into `vec_rt`, without the final trap, so that
+    // they can be combined with the codes of the other replacers
+    pub(super) fn emit(&self, vec_rt: &mut Assembler) -> Result<()> {
+        let new_paths = self.new_paths.get_ref();
+        let cases = as_bytes(&self.cases);
+        let epoll_cases = as_bytes(&self.epoll_cases);
+        let uring_cases = as_bytes(&self.uring_cases);
+
+        dynasm!(vec_rt
+            ; .arch x64
+            ; jmp ->fd_codes
+            ; ->fd_cases:
+            ; .bytes cases
+            ; ->fd_cases_length:
+            ; .qword cases.len() as i64
+            ; ->epoll_cases:
+            ; .bytes epoll_cases
+            ; ->epoll_cases_length:
+            ; .qword epoll_cases.len() as i64
+            ; ->uring_cases:
+            ; .bytes uring_cases
+            ; ->uring_cases_length:
+            ; .qword uring_cases.len() as i64
+            ; ->fd_new_paths:
+            ; .bytes new_paths.as_slice()
+            ; nop
+            ; nop
+        );
+
+        trace!("static bytes placed");
+        dynasm!(vec_rt
+            ; .arch x64
+            ; ->fd_codes:
+            // set r15 to 0
+            ; xor r15, r15
+            ; lea r14, [-> fd_cases]
+
+            ; jmp ->fd_end
+            ; ->fd_start:
+            ; mov rbx, QWORD [r14+r15+16] // store file status flags in rbx
+            // fcntl F_GETFD
+            ; mov rax, 0x48
+            ; mov rdi, QWORD [r14+r15] // fd
+            ; mov rsi, libc::F_GETFD
+            ; mov rdx, 0x0
+            ; syscall
+            ; mov rbp, rax // store fd flags (FD_CLOEXEC) in rbp
+            // open
+            ; mov rax, 0x2
+            ; lea rdi, [-> fd_new_paths]
+            ; add rdi, QWORD [r14+r15+8] // path
+            ; mov rsi, rbx
+            ; mov rdx, 0x0
+            ; syscall
+            ; mov r12, rax // store newly opened fd in r12
+            // an O_PATH fd has neither status flags to set nor an offset to seek
+            ; test rbx, libc::O_PATH
+            ; jnz >dup
+            // fcntl F_SETFL, as some status flags (e.g. O_NONBLOCK) may be ignored by open
+            ; mov rax, 0x48
+            ; mov rdi, r12
+            ; mov rsi, libc::F_SETFL
+            ; mov rdx, rbx
+            ; syscall
+            // the offset of a directory is a cookie of the original filesystem, which is
+            // meaningless for the new one
+            ; test rbx, libc::O_DIRECTORY
+            ; jnz >dup
+            // lseek, which returns the full 64-bit offset in rax, and takes it in rsi
+            ; mov rax, 0x8
+            ; mov rdi, QWORD [r14+r15] // fd
+            ; xor rsi, rsi
+            ; mov rdx, libc::SEEK_CUR
+            ; syscall
+            // an unseekable fd returns a negative errno, which is not an offset to restore
+            ; test rax, rax
+            ; js >dup
+            ; mov rdi, r12
+            ; mov rsi, rax
+            // lseek
+            ; mov rax, 0x8
+            ; mov rdx, libc::SEEK_SET
+            ; syscall
+            ; dup:
+            // dup3 keeps FD_CLOEXEC in the same syscall, so a concurrent fork and exec never
+            // inherits the fd, while dup2 clears it until another fcntl
+            ; xor rdx, rdx
+            ; test rbp, libc::FD_CLOEXEC
+            ; jz >dup3
+            ; mov rdx, libc::O_CLOEXEC
+            ; dup3:
+            ; mov rax, 0x124
+            ; mov rdi, r12
+            ; mov rsi, QWORD [r14+r15] // fd
+            ; syscall
+            // EBUSY is returned while the fd is being installed by a concurrent open, which
+            // finishes soon
+            ; cmp rax, -libc::EBUSY
+            ; je <dup3
+            // close
+            ; mov rax, 0x3
+            ; mov rdi, r12
+            ; syscall
+
+            ; add r15, std::mem::size_of::<ReplaceCase>() as i32
+            ; ->fd_end:
+            ; mov r13, QWORD [->fd_cases_length]
+            ; cmp r15, r13
+            ; jb ->fd_start
+
+            // register the replaced fds into epoll again
+            ; xor r15, r15
+            ; lea r14, [-> epoll_cases]
+
+            ; jmp ->epoll_end
+            ; ->epoll_start:
+            // epoll_ctl
+            ; mov rax, 0xE9
+            ; mov rdi, QWORD [r14+r15] // epfd
+            ; mov rsi, libc::EPOLL_CTL_ADD
+            ; mov rdx, QWORD [r14+r15+8] // fd
+            ; lea r10, [r14+r15+16] // event
+            ; syscall
+
+            ; add r15, std::mem::size_of::<EpollCase>() as i32
+            ; ->epoll_end:
+            ; mov r13, QWORD [->epoll_cases_length]
+            ; cmp r15, r13
+            ; jb ->epoll_start
+
+            // update the fixed files of io_uring
+            ; xor r15, r15
+            ; lea r14, [-> uring_cases]
+
+            ; jmp ->uring_end
+            ; ->uring_start:
+            // io_uring_register
+            ; mov rax, 0x1AB
+            ; mov rdi, QWORD [r14+r15] // ring fd
+            ; mov rsi, 0x6 // IORING_REGISTER_FILES_UPDATE
+            ; lea rdx, [r14+r15+24] // fd
+            ; mov QWORD [r14+r15+16], rdx // fds
+            ; lea rdx, [r14+r15+8] // io_uring_files_update
+            ; mov r10, 0x1
+            ; syscall
+
+            ; add r15, std::mem::size_of::<UringCase>() as i32
+            ; ->uring_end:
+            ; mov r13, QWORD [->uring_cases_length]
+            ; cmp r15, r13
+            ; jb ->uring_start
+        );
 
-        let (cases_ptr, length, _) = self.cases.clone().into_raw_parts();
-        let size = length * std::mem::size_of::<ReplaceCase>();
-        let cases = unsafe { std::slice::from_raw_parts(cases_ptr as *mut u8, size) };
+        Ok(())
+    }
 
+    pub fn run(&mut self) -> Result<()> {
         self.process.run_codes(|addr| {
-            let mut vec_rt =
-                dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(addr as usize);
+            let mut vec_rt = Assembler::new(addr as usize);
+            self.emit(&mut vec_rt)?;
             dynasm!(vec_rt
                 ; .arch x64
-                ; ->cases:
-                ; .bytes cases
-                ; ->cases_length:
-                ; .qword cases.len() as i64
-                ; ->new_paths:
-                ; .bytes new_paths.as_slice()
-                ; nop
-                ; nop
-            );
-
-            trace!("static bytes placed");
-            let replace = vec_rt.offset();
-            dynasm!(vec_rt
-                ; .arch x64
-                // set r15 to 0
-                ; xor r15, r15
-                ; lea r14, [-> cases]
-
-                ; jmp ->end
-                ; ->start:
-                // fcntl
-                ; mov rax, 0x48
-                ; mov rdi, QWORD [r14+r15] // fd
-                ; mov rsi, 0x3
-                ; mov rdx, 0x0
-                ; syscall
-                ; mov rsi, rax
-                // open
-                ; mov rax, 0x2
-                ; lea rdi, [-> new_paths]
-                ; add rdi, QWORD [r14+r15+8] // path
-                ; mov rdx, 0x0
-                ; syscall
-                ; mov r12, rax // store newly opened fd in r12
-                // lseek
-                ; mov rax, 0x8
-                ; mov rdi, QWORD [r14+r15] // fd
-                ; mov rsi, 0
-                ; mov rdx, libc::SEEK_CUR
-                ; syscall
-                ; mov rdi, r12
-                ; mov rsi, rax
-                // lseek
-                ; mov rax, 0x8
-                ; mov rdx, libc::SEEK_SET
-                ; syscall
-                // dup2
-                ; mov rax, 0x21
-                ; mov rdi, r12
-                ; mov rsi, QWORD [r14+r15] // fd
-                ; syscall
-                // close
-                ; mov rax, 0x3
-                ; mov rdi, r12
-                ; syscall
-
-                ; add r15, std::mem::size_of::<ReplaceCase>() as i32
-                ; ->end:
-                ; mov r13, QWORD [->cases_length]
-                ; cmp r15, r13
-                ; jb ->start
-
                 ; int3
             );
 
-            let instructions = vec_rt.finalize()?;
-
-            Ok((replace.0 as u64, instructions))
+            Ok((0, vec_rt.finalize()?))
         })?;
 
         trace!("reopen successfully");
@@ -189,55 +393,166 @@ impl ProcessAccessor {
     }
 }
 
+// FdOutcome is the decision made for a fd of the traced process
+#[derive(Debug)]
+enum FdOutcome {
+    // replace the fd with the path, and reopen it with the flags
+    Replace(PathBuf, i32),
+    SkipDeleted(PathBuf),
+    SkipSpecialFile(PathBuf, &'static str),
+    SkipUnknownFlags(PathBuf),
+}
+
+impl FdOutcome {
+    // detect returns `None` if the fd is irrelevant with the detect path
+    fn detect(pid: i32, entry: &FDInfo, detect_path: &Path) -> Option<FdOutcome> {
+        let path = match &entry.target {
+            FDTarget::Path(path) => path,
+            target => {
+                trace!("skip fd({}) of process {}: {:?}", entry.fd, pid, target);
+                return None;
+            }
+        };
+
+        // the target of an unlinked file is shown as "<path> (deleted)"
+        let path_str = path.to_string_lossy();
+        if let Some(deleted_path) = path_str.strip_suffix(" (deleted)") {
+            let deleted_path = PathBuf::from(deleted_path);
+            if deleted_path.starts_with(detect_path) {
+                return Some(FdOutcome::SkipDeleted(deleted_path));
+            }
+            return None;
+        }
+
+        if !path.starts_with(detect_path) {
+            return None;
+        }
+
+        let flags = match read_fdinfo(pid, entry.fd as u64) {
+            Ok(info) => info.flags,
+            Err(err) => {
+                warn!(
+                    "fail to read fdinfo of fd({}) of process {}: {:?}",
+                    entry.fd, pid, err
+                );
+                return Some(FdOutcome::SkipUnknownFlags(path.clone()));
+            }
+        };
+
+        // an O_PATH fd doesn't open the file actually, so it can always be reopened safely
+        if flags & libc::O_PATH != 0 {
+            return Some(FdOutcome::Replace(path.clone(), flags));
+        }
+
+        // reopening a fifo, socket or device through the path may block or open a different
+        // object, so they are skipped. A directory is reopened with O_DIRECTORY, so that
+        // `openat` relative to it keeps resolving into a directory, even if the fd was opened
+        // without the flag.
+        let mut flags = flags;
+        let kind = match stat::stat(format!("/proc/{}/fd/{}", pid, entry.fd).as_str()) {
+            Ok(stat) => match SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT {
+                SFlag::S_IFIFO => Some("fifo"),
+                SFlag::S_IFSOCK => Some("socket"),
+                SFlag::S_IFCHR | SFlag::S_IFBLK => Some("device"),
+                SFlag::S_IFDIR => {
+                    flags |= libc::O_DIRECTORY;
+                    None
+                }
+                _ => None,
+            },
+            Err(err) => {
+                warn!(
+                    "fail to stat fd({}) of process {}: {:?}",
+                    entry.fd, pid, err
+                );
+                None
+            }
+        };
+
+        match kind {
+            Some(kind) => Some(FdOutcome::SkipSpecialFile(path.clone(), kind)),
+            None => Some(FdOutcome::Replace(path.clone(), flags)),
+        }
+    }
+}
+
 pub struct FdReplacer {
-    processes: HashMap<i32, ProcessAccessor>,
+    pub(super) processes: HashMap<i32, ProcessAccessor>,
+    pub(super) stats: ReplacerStats,
 }
 
 impl FdReplacer {
     pub fn prepare<P1: AsRef<Path>, P2: AsRef<Path>>(
         detect_path: P1,
         new_path: P2,
+        options: &ReplacerOptions,
     ) -> Result<FdReplacer> {
         info!("preparing fd replacer");
 
         let detect_path = detect_path.as_ref();
         let new_path = new_path.as_ref();
 
-        let processes = all_processes()?
+        let mut stats = ReplacerStats::default();
+        let mut failures = AttachFailures::default();
+        let processes = all_processes(options)?
             .filter_map(|process| -> Option<_> {
                 let pid = process.pid;
 
-                let traced_process = match ptrace::trace(pid) {
-                    Ok(p) => p,
-                    Err(err) => {
-                        error!("fail to trace process: {} {}", pid, err);
-                        return None;
-                    }
-                };
+                // only the processes with fds under the path are traced
                 let fd = process.fd().ok()?;
+                let used = fd.iter().find_map(|entry| match &entry.target {
+                    FDTarget::Path(path) if path.starts_with(detect_path) => Some(path.clone()),
+                    _ => None,
+                })?;
 
-                Some((traced_process, fd))
+                match trace_with_retry(pid, options) {
+                    Ok(traced_process) => Some((traced_process, fd)),
+                    Err(err) => {
+                        failures.push(pid, used.display(), err);
+                        None
+                    }
+                }
             })
-            .flat_map(|(process, fd)| {
-                fd.into_iter()
-                    .filter_map(|entry| match entry.target {
-                        FDTarget::Path(path) => Some((entry.fd as u64, path)),
-                        _ => None,
+            .filter_map(|(process, fd)| {
+                let pid = process.pid;
+
+                let replaced_fds: Vec<_> = fd
+                    .iter()
+                    .filter_map(|entry| {
+                        let outcome = FdOutcome::detect(pid, entry, detect_path)?;
+                        info!("fd({}) of process {}: {:?}", entry.fd, pid, outcome);
+                        match outcome {
+                            FdOutcome::Replace(path, flags) => Some((entry.fd as u64, path, flags)),
+                            FdOutcome::SkipDeleted(path) => {
+                                stats.skip(pid, path.display(), "deleted file");
+                                None
+                            }
+                            FdOutcome::SkipSpecialFile(path, kind) => {
+                                stats.skip(pid, path.display(), kind);
+                                None
+                            }
+                            FdOutcome::SkipUnknownFlags(path) => {
+                                stats.skip(pid, path.display(), "unknown flags");
+                                None
+                            }
+                        }
                     })
-                    .filter(|(_, path)| path.starts_with(detect_path))
-                    .filter_map(move |(fd, path)| {
+                    .collect();
+                if replaced_fds.is_empty() {
+                    return None;
+                }
+
+                let mut builder = replaced_fds
+                    .iter()
+                    .filter_map(|(fd, path, flags)| {
                         trace!("replace fd({}): {}", fd, path.display());
                         let stripped_path = path.strip_prefix(&detect_path).ok()?;
-                        Some((process.clone(), (fd, new_path.join(stripped_path))))
+                        Some((*fd, new_path.join(stripped_path), *flags))
                     })
-            })
-            .group_by(|(process, _)| process.pid)
-            .into_iter()
-            .filter_map(|(pid, group)| Some((ptrace::trace(pid).ok()?, group)))
-            .map(|(process, group)| (process, group.map(|(_, group)| group)))
-            .filter_map(|(process, group)| {
-                let pid = process.pid;
-                match group.collect::<ProcessAccessorBuilder>().build(process) {
+                    .collect::<ProcessAccessorBuilder>();
+                builder.push_registered_fds(pid, &fd, &replaced_fds, detect_path);
+
+                match builder.build(process) {
                     Ok(accessor) => Some((pid, accessor)),
                     Err(err) => {
                         error!("fail to build accessor: {:?}", err);
@@ -246,8 +561,9 @@ impl FdReplacer {
                 }
             })
             .collect();
+        failures.resolve(options.attach_failure, &mut stats)?;
 
-        Ok(FdReplacer { processes })
+        Ok(FdReplacer { processes, stats })
     }
 }
 
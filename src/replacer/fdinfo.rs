@@ -0,0 +1,82 @@
+use std::fs::read_to_string;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone)]
+pub struct EpollTarget {
+    pub fd: u64,
+    pub events: u32,
+    pub data: u64,
+}
+
+// FdInfo is the parsed content of `/proc/<pid>/fdinfo/<fd>`
+#[derive(Debug, Clone, Default)]
+pub struct FdInfo {
+    pub pos: u64,
+    pub flags: i32,
+
+    // the interest list of an epoll fd
+    pub epoll_targets: Vec<EpollTarget>,
+    // the registered fixed files of an io_uring fd, in the form of (index, path)
+    pub uring_files: Vec<(u32, PathBuf)>,
+}
+
+pub fn read_fdinfo(pid: i32, fd: u64) -> Result<FdInfo> {
+    let content = read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd))?;
+
+    parse_fdinfo(&content)
+}
+
+pub fn parse_fdinfo(content: &str) -> Result<FdInfo> {
+    let mut info = FdInfo::default();
+    let mut in_uring_files = false;
+
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let key = match fields.next() {
+            Some(key) => key,
+            None => continue,
+        };
+
+        match key {
+            "pos:" => info.pos = fields.next().ok_or(anyhow!("missing pos"))?.parse()?,
+            "flags:" => {
+                let flags = fields.next().ok_or(anyhow!("missing flags"))?;
+                info.flags = i32::from_str_radix(flags, 8)?;
+            }
+            "tfd:" => info.epoll_targets.push(parse_epoll_target(line)?),
+            "UserFiles:" => in_uring_files = true,
+            _ if in_uring_files => match key.strip_suffix(':').map(|index| index.parse::<u32>()) {
+                Some(Ok(index)) => {
+                    let path = line.splitn(2, ": ").nth(1).unwrap_or_default();
+                    info.uring_files.push((index, PathBuf::from(path)));
+                }
+                _ => in_uring_files = false,
+            },
+            _ => {}
+        }
+    }
+
+    Ok(info)
+}
+
+// parse_epoll_target parses a line like
+// `tfd:        5 events:       19 data:   7fff00000005  pos:0 ino:61af sdev:7`
+fn parse_epoll_target(line: &str) -> Result<EpollTarget> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    let value = |key: &str| -> Result<&str> {
+        fields
+            .iter()
+            .position(|field| *field == key)
+            .and_then(|index| fields.get(index + 1))
+            .copied()
+            .ok_or(anyhow!("missing {} in epoll fdinfo: {}", key, line))
+    };
+
+    Ok(EpollTarget {
+        fd: value("tfd:")?.parse()?,
+        events: u32::from_str_radix(value("events:")?, 16)?,
+        data: u64::from_str_radix(value("data:")?, 16)?,
+    })
+}
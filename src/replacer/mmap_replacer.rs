@@ -1,18 +1,80 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::io::{Cursor, Read, Write};
+use std::fs;
+use std::io::{Cursor, Write};
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use anyhow::{anyhow, Result};
 use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
 use itertools::Itertools;
 use nix::sys::mman::{MapFlags, ProtFlags};
 use procfs::process::MMapPath;
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn};
+
+use super::process_patcher::Assembler;
+use super::utils::{all_processes, trace_with_retry, AttachFailures};
+use super::{ptrace, Replacer, ReplacerOptions, ReplacerStats};
+use crate::mount::{MountsInfo, OverlayLayers};
+
+// SharedMmapStrategy decides how to handle the writable shared mappings, whose dirty pages would
+// be discarded silently by munmap if they are not flushed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharedMmapStrategy {
+    // msync the mapping inside the traced process before remapping it
+    Sync,
+    // leave the mapping untouched, it will keep pointing to the original file
+    Skip,
+}
+
+impl Default for SharedMmapStrategy {
+    fn default() -> Self {
+        SharedMmapStrategy::Sync
+    }
+}
 
-use super::utils::all_processes;
-use super::{ptrace, Replacer};
+impl FromStr for SharedMmapStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "sync" => Ok(SharedMmapStrategy::Sync),
+            "skip" => Ok(SharedMmapStrategy::Skip),
+            _ => Err(anyhow!("unknown shared mmap strategy: {}", s)),
+        }
+    }
+}
+
+// ElfMmapStrategy decides how to handle the mappings of the main executable and the loaded shared
+// objects, as replacing them carelessly can crash the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfMmapStrategy {
+    // leave all the mappings of the ELF objects untouched
+    Skip,
+    // remap the read-only and executable mappings at the same address, and skip the private
+    // writable ones, which contain relocated data
+    Replace,
+}
+
+impl Default for ElfMmapStrategy {
+    fn default() -> Self {
+        ElfMmapStrategy::Skip
+    }
+}
+
+impl FromStr for ElfMmapStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(ElfMmapStrategy::Skip),
+            "replace" => Ok(ElfMmapStrategy::Replace),
+            _ => Err(anyhow!("unknown elf mmap strategy: {}", s)),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 struct ReplaceCase {
@@ -24,6 +86,14 @@ struct ReplaceCase {
     pub offset: u64,
 }
 
+impl ReplaceCase {
+    // is_shared_writable returns true if the mapping may contain dirty pages
+    fn is_shared_writable(&self) -> bool {
+        self.flags & MapFlags::MAP_SHARED.bits() as u64 != 0
+            && self.prot & ProtFlags::PROT_WRITE.bits() as u64 != 0
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(packed)]
 #[repr(C)]
@@ -34,6 +104,10 @@ struct RawReplaceCase {
     flags: u64,
     new_path_offset: u64,
     offset: u64,
+    sync: u64,
+    // the flags to open the new file with, which is only writable for a shared writable mapping,
+    // as the executables could never be opened for writing while they're run
+    open_flags: u64,
 }
 
 impl RawReplaceCase {
@@ -44,7 +118,16 @@ impl RawReplaceCase {
         flags: u64,
         new_path_offset: u64,
         offset: u64,
+        sync: bool,
     ) -> RawReplaceCase {
+        let shared_writable = flags & MapFlags::MAP_SHARED.bits() as u64 != 0
+            && prot & ProtFlags::PROT_WRITE.bits() as u64 != 0;
+        let open_flags = if shared_writable {
+            libc::O_RDWR
+        } else {
+            libc::O_RDONLY
+        };
+
         RawReplaceCase {
             memory_addr,
             length,
@@ -52,6 +135,8 @@ impl RawReplaceCase {
             flags,
             new_path_offset,
             offset,
+            sync: sync as u64,
+            open_flags: open_flags as u64,
         }
     }
 }
@@ -87,6 +172,7 @@ impl ProcessAccessorBuilder {
         flags: u64,
         new_path: PathBuf,
         offset: u64,
+        sync: bool,
     ) -> anyhow::Result<()> {
         info!("push case");
 
@@ -108,6 +194,7 @@ impl ProcessAccessorBuilder {
             flags,
             new_path_offset,
             offset,
+            sync,
         ));
 
         Ok(())
@@ -118,6 +205,7 @@ impl FromIterator<ReplaceCase> for ProcessAccessorBuilder {
     fn from_iter<T: IntoIterator<Item = ReplaceCase>>(iter: T) -> Self {
         let mut builder = Self::new();
         for case in iter {
+            let sync = case.is_shared_writable();
             if let Err(err) = builder.push_case(
                 case.memory_addr,
                 case.length,
@@ -125,6 +213,7 @@ impl FromIterator<ReplaceCase> for ProcessAccessorBuilder {
                 case.flags,
                 case.path,
                 case.offset,
+                sync,
             ) {
                 error!("fail to write to AccessorBuilder. Error: {:?}", err)
             }
@@ -134,8 +223,8 @@ impl FromIterator<ReplaceCase> for ProcessAccessorBuilder {
     }
 }
 
-struct ProcessAccessor {
-    process: ptrace::TracedProcess,
+pub(super) struct ProcessAccessor {
+    pub(super) process: ptrace::TracedProcess,
 
     cases: Vec<RawReplaceCase>,
     new_paths: Cursor<Vec<u8>>,
@@ -148,91 +237,102 @@ impl Debug for ProcessAccessor {
 }
 
 impl ProcessAccessor {
-    pub fn run(&mut self) -> anyhow::Result<()> {
-        self.new_paths.set_position(0);
+    pub(super) fn case_count(&self) -> usize {
+        self.cases.len()
+    }
 
-        let mut new_paths = Vec::new();
-        self.new_paths.read_to_end(&mut new_paths)?;
+    // emit writes the codes replacing the mappings into `vec_rt`, without the final trap, so
+    // that they can be combined with the codes of the other replacers
+    pub(super) fn emit(&self, vec_rt: &mut Assembler) -> Result<()> {
+        let new_paths = self.new_paths.get_ref();
+        let size = self.cases.len() * std::mem::size_of::<RawReplaceCase>();
+        let cases = unsafe { std::slice::from_raw_parts(self.cases.as_ptr() as *const u8, size) };
+
+        dynasm!(vec_rt
+            ; .arch x64
+            ; jmp ->mmap_codes
+            ; ->mmap_cases:
+            ; .bytes cases
+            ; ->mmap_cases_length:
+            ; .qword cases.len() as i64
+            ; ->mmap_new_paths:
+            ; .bytes new_paths.as_slice()
+            ; nop
+            ; nop
+        );
+
+        trace!("static bytes placed");
+        dynasm!(vec_rt
+            ; .arch x64
+            ; ->mmap_codes:
+            // set r15 to 0
+            ; xor r15, r15
+            ; lea r14, [-> mmap_cases]
+
+            ; jmp ->mmap_end
+            ; ->mmap_start:
+            // open, before anything is unmapped, so that the original mapping is kept intact if
+            // the new file couldn't be opened
+            ; mov rax, 0x2
+            ; lea rdi, [-> mmap_new_paths]
+            ; add rdi, QWORD [r14+r15+32] // path
+            ; mov rsi, QWORD [r14+r15+56] // open flags
+            ; mov rdx, 0x0
+            ; syscall
+            ; test rax, rax
+            ; js >next
+            ; push rax // fd
+            // msync, to flush the dirty pages of a shared mapping
+            ; mov rax, QWORD [r14+r15+48] // sync
+            ; test rax, rax
+            ; jz >munmap
+            ; mov rax, 0x1A
+            ; mov rdi, QWORD [r14+r15] // addr
+            ; mov rsi, QWORD [r14+r15+8] // length
+            ; mov rdx, libc::MS_SYNC
+            ; syscall
+            ; munmap:
+            // munmap
+            ; mov rax, 0x0B
+            ; mov rdi, QWORD [r14+r15] // addr
+            ; mov rsi, QWORD [r14+r15+8] // length
+            ; mov rdx, 0x0
+            ; syscall
+            // mmap
+            ; mov rax, 0x9
+            ; mov rdi, QWORD [r14+r15] // addr
+            ; mov rsi, QWORD [r14+r15+8] // length
+            ; mov rdx, QWORD [r14+r15+16] // prot
+            ; mov r10, QWORD [r14+r15+24] // flags
+            ; mov r8, QWORD [rsp] // fd
+            ; mov r9, QWORD [r14+r15+40] // offset
+            ; syscall
+            // close
+            ; mov rax, 0x3
+            ; pop rdi
+            ; syscall
+            ; next:
+
+            ; add r15, std::mem::size_of::<RawReplaceCase>() as i32
+            ; ->mmap_end:
+            ; mov r13, QWORD [->mmap_cases_length]
+            ; cmp r15, r13
+            ; jb ->mmap_start
+        );
 
-        let (cases_ptr, length, _) = self.cases.clone().into_raw_parts();
-        let size = length * std::mem::size_of::<RawReplaceCase>();
-        let cases = unsafe { std::slice::from_raw_parts(cases_ptr as *mut u8, size) };
+        Ok(())
+    }
 
+    pub fn run(&mut self) -> Result<()> {
         self.process.run_codes(|addr| {
-            let mut vec_rt =
-                dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(addr as usize);
+            let mut vec_rt = Assembler::new(addr as usize);
+            self.emit(&mut vec_rt)?;
             dynasm!(vec_rt
                 ; .arch x64
-                ; ->cases:
-                ; .bytes cases
-                ; ->cases_length:
-                ; .qword cases.len() as i64
-                ; ->new_paths:
-                ; .bytes new_paths.as_slice()
-                ; nop
-                ; nop
-            );
-
-            trace!("static bytes placed");
-            let replace = vec_rt.offset();
-            dynasm!(vec_rt
-                ; .arch x64
-                // set r15 to 0
-                ; xor r15, r15
-                ; lea r14, [-> cases]
-
-                ; jmp ->end
-                ; ->start:
-                // munmap
-                ; mov rax, 0x0B
-                ; mov rdi, QWORD [r14+r15] // addr
-                ; mov rsi, QWORD [r14+r15+8] // length
-                ; mov rdx, 0x0
-                ; push rdi
-                ; syscall
-                // open
-                ; mov rax, 0x2
-
-                ; lea rdi, [-> new_paths]
-                ; add r15, 8 * 4 // set r15 to point to path
-                ; add rdi, QWORD [r14+r15] // path
-                ; sub r15, 8 * 4
-
-                ; mov rsi, libc::O_RDWR
-                ; mov rdx, 0x0
-                ; syscall
-                ; pop rdi // addr
-                ; push rax
-                ; mov r8, rax // fd
-                // mmap
-                ; mov rax, 0x9
-                ; add r15, 8
-                ; mov rsi, QWORD [r14+r15] // length
-                ; add r15, 8
-                ; mov rdx, QWORD [r14+r15] // prot
-                ; add r15, 8
-                ; mov r10, QWORD [r14+r15] // flags
-                ; add r15, 16
-                ; mov r9, QWORD [r14+r15] // offset
-                ; syscall
-                ; sub r15, 8 * 5
-                // close
-                ; mov rax, 0x3
-                ; pop rdi
-                ; syscall
-
-                ; add r15, std::mem::size_of::<RawReplaceCase>() as i32
-                ; ->end:
-                ; mov r13, QWORD [->cases_length]
-                ; cmp r15, r13
-                ; jb ->start
-
                 ; int3
             );
 
-            let instructions = vec_rt.finalize()?;
-
-            Ok((replace.0 as u64, instructions))
+            Ok((0, vec_rt.finalize()?))
         })?;
 
         trace!("reopen successfully");
@@ -268,33 +368,97 @@ fn get_prot_and_flags_from_perms<S: AsRef<str>>(perms: S) -> (u64, u64) {
 }
 
 pub struct MmapReplacer {
-    processes: HashMap<i32, ProcessAccessor>,
+    pub(super) processes: HashMap<i32, ProcessAccessor>,
+    pub(super) stats: ReplacerStats,
+}
+
+// merged_path translates the path of a file in a layer of the overlayfs to the merged directory
+fn merged_path(overlay: Option<&OverlayLayers>, path: PathBuf) -> PathBuf {
+    overlay
+        .and_then(|overlay| overlay.merged_path(&path))
+        .unwrap_or(path)
 }
 
 impl MmapReplacer {
     pub fn prepare<P1: AsRef<Path>, P2: AsRef<Path>>(
         detect_path: P1,
         new_path: P2,
+        options: &ReplacerOptions,
     ) -> Result<MmapReplacer> {
         info!("preparing mmap replacer");
 
         let detect_path = detect_path.as_ref();
         let new_path = new_path.as_ref();
-
-        let processes = all_processes()?
+        let shared_mmap = options.shared_mmap;
+        let elf_mmap = options.elf_mmap;
+
+        // the files on an overlayfs may be mapped with their paths in the layers
+        let overlay = MountsInfo::parse_mounts()
+            .ok()
+            .and_then(|mounts| mounts.overlay(detect_path));
+        let overlay = overlay.as_ref();
+
+        let stats = RefCell::new(ReplacerStats::default());
+        let stats_ref = &stats;
+        let mut failures = AttachFailures::default();
+        let processes = all_processes(options)?
             .filter_map(|process| -> Option<_> {
                 let pid = process.pid;
 
-                let traced_process = ptrace::trace(pid).ok()?;
+                // only the processes with mappings under the path are traced
                 let maps = process.maps().ok()?;
-
-                Some((traced_process, maps))
+                let used = maps.iter().find_map(|entry| match &entry.pathname {
+                    MMapPath::Path(path) => Some(merged_path(overlay, path.clone()))
+                        .filter(|path| path.starts_with(detect_path)),
+                    _ => None,
+                })?;
+
+                match trace_with_retry(pid, options) {
+                    Ok(traced_process) => Some((traced_process, process.exe().ok(), maps)),
+                    Err(err) => {
+                        failures.push(pid, used.display(), err);
+                        None
+                    }
+                }
             })
-            .flat_map(|(process, maps)| {
+            .flat_map(|(process, exe, maps)| {
+                // the main executable and the shared objects which have executable mappings
+                let elf_paths: HashSet<PathBuf> = maps
+                    .iter()
+                    .filter(|entry| entry.perms.contains('x'))
+                    .filter_map(|entry| match &entry.pathname {
+                        MMapPath::Path(path) => Some(merged_path(overlay, path.clone())),
+                        _ => None,
+                    })
+                    .chain(exe.map(|exe| merged_path(overlay, exe)))
+                    .collect();
+
                 maps.into_iter()
                     .filter_map(move |entry| {
                         match entry.pathname {
                             MMapPath::Path(path) => {
+                                let merged = merged_path(overlay, path.clone());
+                                // the file removed from the merged directory after being mapped
+                                // is hidden by a whiteout, and couldn't be reopened through it
+                                if merged != path
+                                    && merged.starts_with(detect_path)
+                                    && fs::symlink_metadata(&merged).is_err()
+                                {
+                                    warn!(
+                                        "skip mapping {:x} of process {} removed from the overlayfs: {}",
+                                        entry.address.0,
+                                        process.pid,
+                                        path.display()
+                                    );
+                                    stats_ref.borrow_mut().skip(
+                                        process.pid,
+                                        merged.display(),
+                                        "removed from the overlayfs",
+                                    );
+                                    return None;
+                                }
+                                let path = merged;
+
                                 let (start_address, end_address) = entry.address;
                                 let length = end_address - start_address;
                                 let (prot, flags) = get_prot_and_flags_from_perms(entry.perms);
@@ -314,6 +478,47 @@ impl MmapReplacer {
                         }
                     })
                     .filter(|(_, case)| case.path.starts_with(detect_path))
+                    .filter_map(move |(process, mut case)| {
+                        if shared_mmap == SharedMmapStrategy::Skip && case.is_shared_writable() {
+                            warn!(
+                                "skip shared writable mapping {:x} of process {}: {}",
+                                case.memory_addr,
+                                process.pid,
+                                case.path.display()
+                            );
+                            stats_ref.borrow_mut().skip(
+                                process.pid,
+                                case.path.display(),
+                                "shared writable mapping",
+                            );
+                            return None;
+                        }
+
+                        if elf_paths.contains(&case.path) {
+                            let private_writable = case.flags & MapFlags::MAP_PRIVATE.bits() as u64
+                                != 0
+                                && case.prot & ProtFlags::PROT_WRITE.bits() as u64 != 0;
+                            if elf_mmap == ElfMmapStrategy::Skip || private_writable {
+                                warn!(
+                                    "skip elf mapping {:x} of process {}: {}",
+                                    case.memory_addr,
+                                    process.pid,
+                                    case.path.display()
+                                );
+                                stats_ref.borrow_mut().skip(
+                                    process.pid,
+                                    case.path.display(),
+                                    "elf mapping",
+                                );
+                                return None;
+                            }
+
+                            // the codes may be referenced by absolute addresses
+                            case.flags |= MapFlags::MAP_FIXED.bits() as u64;
+                        }
+
+                        Some((process, case))
+                    })
                     .filter_map(|(process, mut case)| {
                         let stripped_path = case.path.strip_prefix(&detect_path).ok()?;
                         case.path = new_path.join(stripped_path);
@@ -336,8 +541,10 @@ impl MmapReplacer {
                 }
             })
             .collect();
+        let mut stats = stats.into_inner();
+        failures.resolve(options.attach_failure, &mut stats)?;
 
-        Ok(MmapReplacer { processes })
+        Ok(MmapReplacer { processes, stats })
     }
 }
 
@@ -1,23 +1,232 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use structopt::StructOpt;
 
 use crate::ptrace;
 
 mod cwd_replacer;
 mod fd_replacer;
+mod fdinfo;
 mod mmap_replacer;
+mod parallel_replacer;
+mod process_patcher;
+mod references;
+mod stats;
 mod utils;
+mod verify;
+mod watcher;
 
 use tracing::error;
 
 pub trait Replacer {
     fn run(&mut self) -> Result<()>;
+
+    fn stats(&self) -> ReplacerStats {
+        ReplacerStats::default()
+    }
+}
+
+// ReplacerStrategy decides how the running processes are moved onto the FUSE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplacerStrategy {
+    // replace the fds, cwd and mmaps of the running processes through ptrace
+    Ptrace,
+    // never touch the running processes, for the environments without CAP_SYS_PTRACE. The FUSE
+    // should be mounted before the workload starts, as the processes which have already opened
+    // the path keep using the original filesystem.
+    None,
+}
+
+impl Default for ReplacerStrategy {
+    fn default() -> Self {
+        ReplacerStrategy::Ptrace
+    }
+}
+
+impl FromStr for ReplacerStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "ptrace" => Ok(ReplacerStrategy::Ptrace),
+            "none" => Ok(ReplacerStrategy::None),
+            _ => Err(anyhow!("unknown replacer strategy: {}", s)),
+        }
+    }
+}
+
+// ReplacerScope decides which processes may be traced, by their cgroups relative to the cgroup of
+// the --target-pid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplacerScope {
+    // the processes in the same container as the target
+    Container,
+    // the processes in the same pod as the target, except the pause container. The pod is the
+    // parent cgroup of the container if its name contains "pod", or the container itself
+    // otherwise.
+    Pod,
+    // all the processes, including the management processes on the node
+    All,
+}
+
+impl Default for ReplacerScope {
+    fn default() -> Self {
+        ReplacerScope::Pod
+    }
+}
+
+impl FromStr for ReplacerScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "container" => Ok(ReplacerScope::Container),
+            "pod" => Ok(ReplacerScope::Pod),
+            "all" => Ok(ReplacerScope::All),
+            _ => Err(anyhow!("unknown replacer scope: {}", s)),
+        }
+    }
+}
+
+// AttachFailurePolicy decides what happens when a process using the path could not be traced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachFailurePolicy {
+    // leave the process on the original mount, and report it in the skipped cases
+    Skip,
+    // fail the replacer, so that the injection is not left half done
+    Abort,
+}
+
+impl Default for AttachFailurePolicy {
+    fn default() -> Self {
+        AttachFailurePolicy::Skip
+    }
+}
+
+impl FromStr for AttachFailurePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(AttachFailurePolicy::Skip),
+            "abort" => Ok(AttachFailurePolicy::Abort),
+            _ => Err(anyhow!("unknown attach failure policy: {}", s)),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone, Default)]
+pub struct ReplacerOptions {
+    /// how to move the running processes onto the FUSE: "ptrace" replaces their fds, cwd and
+    /// mmaps, "none" leaves them untouched and requires injecting before the workload starts
+    #[structopt(long = "replacer", default_value = "ptrace", possible_values = &["ptrace", "none"])]
+    pub strategy: ReplacerStrategy,
+
+    /// how to handle writable shared mappings: "sync" flushes them before remapping, "skip"
+    /// leaves them untouched
+    #[structopt(long = "shared-mmap", default_value = "sync", possible_values = &["sync", "skip"])]
+    pub shared_mmap: SharedMmapStrategy,
+
+    /// how to handle the mappings of executables and shared objects: "skip" leaves them
+    /// untouched, "replace" remaps the read-only and executable ones at the same address
+    #[structopt(long = "elf-mmap", default_value = "skip", possible_values = &["skip", "replace"])]
+    pub elf_mmap: ElfMmapStrategy,
+
+    /// only trace the processes with these pids (or the names in --replacer-include-name)
+    #[structopt(long = "replacer-include-pid", number_of_values = 1)]
+    pub include_pid: Vec<i32>,
+
+    /// never trace the processes with these pids
+    #[structopt(long = "replacer-exclude-pid", number_of_values = 1)]
+    pub exclude_pid: Vec<i32>,
+
+    /// only trace the processes with these names (or the pids in --replacer-include-pid)
+    #[structopt(long = "replacer-include-name", number_of_values = 1)]
+    pub include_name: Vec<String>,
+
+    /// never trace the processes with these names
+    #[structopt(long = "replacer-exclude-name", number_of_values = 1)]
+    pub exclude_name: Vec<String>,
+
+    /// the number of threads which attach and replace the processes concurrently
+    #[structopt(long = "replacer-workers", default_value = "4")]
+    pub workers: usize,
+
+    /// the longest time in milliseconds to wait for a traced task to stop, 0 means waiting forever
+    #[structopt(long = "ptrace-timeout", default_value = "10000")]
+    pub ptrace_timeout_ms: u64,
+
+    /// how many times to retry attaching to a process which fails to be traced
+    #[structopt(long = "ptrace-attach-retries", default_value = "3")]
+    pub attach_retries: usize,
+
+    /// the delay in milliseconds before the first attach retry, which doubles after every retry
+    #[structopt(long = "ptrace-attach-backoff", default_value = "100")]
+    pub attach_backoff_ms: u64,
+
+    /// what to do with a process using the path which still fails to be traced after the
+    /// retries: "skip" leaves it on the original mount and reports it, "abort" fails the injection
+    #[structopt(long = "ptrace-attach-failure", default_value = "skip", possible_values = &["skip", "abort"])]
+    pub attach_failure: AttachFailurePolicy,
+
+    /// how to detect the completion of the injected codes: "breakpoint" ends them with an int3,
+    /// "syscall" ends them with a marker syscall caught by PTRACE_SYSCALL
+    #[structopt(long = "trap", default_value = "breakpoint", possible_values = &["breakpoint", "syscall"])]
+    pub trap_strategy: ptrace::TrapStrategy,
+
+    /// rescan the processes at this interval in milliseconds during the injection, and replace
+    /// the ones still using the original mount, which is only detected with --mount-mode move. 0
+    /// disables the rescans
+    #[structopt(long = "replacer-watch-interval", default_value = "0")]
+    pub watch_interval_ms: u64,
+
+    /// how many times to replace again the processes whose fds, cwd or mmaps are still out of
+    /// the FUSE after the replacer pass, which are reported in the status either way
+    #[structopt(long = "replacer-verify-retries", default_value = "1")]
+    pub verify_retries: usize,
+
+    /// which processes may be traced with --target-pid: "container" or "pod" only traces the
+    /// ones in the cgroup of the container or the pod of the target, "all" traces any of them
+    #[structopt(long = "replacer-scope", default_value = "pod", possible_values = &["container", "pod", "all"])]
+    pub scope: ReplacerScope,
+
+    /// only discover the processes in this cgroup and its descendants, from their cgroup.procs,
+    /// instead of scanning all the processes
+    #[structopt(long = "target-cgroup")]
+    pub target_cgroup: Option<PathBuf>,
+
+    /// only discover the processes in the pid namespace of this process
+    #[structopt(long = "target-pidns")]
+    pub target_pidns: Option<utils::PidNamespace>,
+
+    #[structopt(skip)]
+    pub shard: Option<utils::Shard>,
+
+    // the cgroups resolved from the scope, under which the traced processes should be
+    #[structopt(skip)]
+    pub cgroups: Vec<utils::CgroupScope>,
+}
+
+impl ReplacerOptions {
+    // restrict_to resolves the scope into the cgroups around the target process. It should be
+    // called before entering the namespaces of the target.
+    pub fn restrict_to(&mut self, pid: i32) -> Result<()> {
+        self.cgroups = utils::resolve_scope(self.scope, pid)?;
+        if self.scope == ReplacerScope::Pod {
+            self.exclude_name.push("pause".to_owned());
+        }
+        Ok(())
+    }
 }
 
 #[derive(Default)]
 pub struct UnionReplacer<'a> {
     replacers: Vec<Box<dyn Replacer + 'a>>,
+
+    // the cases skipped while preparing
+    stats: ReplacerStats,
 }
 
 impl<'a> UnionReplacer<'a> {
@@ -25,18 +234,47 @@ impl<'a> UnionReplacer<'a> {
         &mut self,
         detect_path: P1,
         new_path: P2,
+        options: &ReplacerOptions,
     ) -> Result<()> {
-        match FdReplacer::prepare(&detect_path, &new_path) {
-            Err(err) => error!("Error while preparing fd replacer: {:?}", err),
-            Ok(replacer) => self.replacers.push(Box::new(replacer)),
+        // the failures are only ignored if the processes could be skipped
+        let abort = options.attach_failure == AttachFailurePolicy::Abort;
+        let fd = match FdReplacer::prepare(&detect_path, &new_path, options) {
+            Err(err) if abort => return Err(err),
+            Err(err) => {
+                error!("Error while preparing fd replacer: {:?}", err);
+                None
+            }
+            Ok(replacer) => Some(replacer),
+        };
+        let cwd = match CwdReplacer::prepare(&detect_path, &new_path, options) {
+            Err(err) if abort => return Err(err),
+            Err(err) => {
+                error!("Error while preparing cwd replacer: {:?}", err);
+                None
+            }
+            Ok(replacer) => Some(replacer),
+        };
+        let mmap = match MmapReplacer::prepare(&detect_path, &new_path, options) {
+            Err(err) if abort => return Err(err),
+            Err(err) => {
+                error!("Error while preparing mmap replacer: {:?}", err);
+                None
+            }
+            Ok(replacer) => Some(replacer),
+        };
+
+        for replacer in fd.iter() {
+            self.stats.merge(replacer.stats.clone());
         }
-        match CwdReplacer::prepare(&detect_path, &new_path) {
-            Err(err) => error!("Error while preparing cwd replacer: {:?}", err),
-            Ok(replacer) => self.replacers.push(Box::new(replacer)),
+        for replacer in cwd.iter() {
+            self.stats.merge(replacer.stats.clone());
         }
-        match MmapReplacer::prepare(&detect_path, &new_path) {
-            Err(err) => error!("Error while preparing mmap replacer: {:?}", err),
-            Ok(replacer) => self.replacers.push(Box::new(replacer)),
+        for replacer in mmap.iter() {
+            self.stats.merge(replacer.stats.clone());
+        }
+
+        for patcher in ProcessPatcher::batch(fd, cwd, mmap) {
+            self.replacers.push(Box::new(patcher));
         }
         Ok(())
     }
@@ -50,8 +288,24 @@ impl<'a> Replacer for UnionReplacer<'a> {
 
         Ok(())
     }
+
+    fn stats(&self) -> ReplacerStats {
+        let mut stats = self.stats.clone();
+        for replacer in self.replacers.iter() {
+            stats.merge(replacer.stats());
+        }
+
+        stats
+    }
 }
 
 pub use cwd_replacer::CwdReplacer;
 pub use fd_replacer::FdReplacer;
-pub use mmap_replacer::MmapReplacer;
+pub use fdinfo::{parse_fdinfo, EpollTarget, FdInfo};
+pub use mmap_replacer::{ElfMmapStrategy, MmapReplacer, SharedMmapStrategy};
+pub use parallel_replacer::ParallelReplacer;
+pub use process_patcher::ProcessPatcher;
+pub use references::{analyze_references, ProcessReferences, ReferencesReport};
+pub use stats::{ReplacedCwd, ReplacerStats, SkippedCase};
+pub use verify::verify;
+pub use watcher::ReplacerWatcher;
@@ -0,0 +1,184 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tracing::{error, info};
+
+use super::utils::Shard;
+use super::{ptrace, Replacer, ReplacerOptions, ReplacerStats, UnionReplacer};
+use crate::arch;
+
+// ParallelReplacer prepares and runs the replacers from a pool of worker threads. The processes
+// are sharded by pid, and every worker keeps the processes of its shard traced until the
+// ParallelReplacer is dropped, because the ptrace requests are only accepted from the thread
+// which attached the tracee.
+pub struct ParallelReplacer {
+    workers: Vec<Worker>,
+    stats: ReplacerStats,
+}
+
+// the reply of a worker after running its replacers
+type RunReply = (Result<()>, ReplacerStats);
+
+struct Worker {
+    index: usize,
+    sender: Option<Sender<Sender<RunReply>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ParallelReplacer {
+    pub fn prepare<P1: AsRef<Path>, P2: AsRef<Path>>(
+        detect_path: P1,
+        new_path: P2,
+        options: &ReplacerOptions,
+    ) -> Result<ParallelReplacer> {
+        arch::check_replacer()?;
+
+        let count = options.workers.max(1);
+        info!("preparing replacers with {} workers", count);
+
+        let mut workers = Vec::with_capacity(count);
+        let mut prepared = Vec::with_capacity(count);
+        for index in 0..count {
+            let detect_path = detect_path.as_ref().to_path_buf();
+            let new_path = new_path.as_ref().to_path_buf();
+            let mut options = options.clone();
+            options.shard = Some(Shard { index, count });
+
+            let (prepared_tx, prepared_rx) = channel();
+            let (sender, receiver) = channel();
+            let handle = thread::Builder::new()
+                .name(format!("replacer-{}", index))
+                .spawn(move || work(detect_path, new_path, options, prepared_tx, receiver))?;
+
+            workers.push(Worker {
+                index,
+                sender: Some(sender),
+                handle: Some(handle),
+            });
+            prepared.push(prepared_rx);
+        }
+
+        // the workers are kept even if some of them failed, so that the others are joined
+        // before returning the error
+        let replacer = ParallelReplacer {
+            workers,
+            stats: ReplacerStats::default(),
+        };
+        for rx in prepared {
+            rx.recv()
+                .map_err(|_| anyhow!("replacer worker exited before preparing"))??;
+        }
+
+        Ok(replacer)
+    }
+}
+
+fn work(
+    detect_path: PathBuf,
+    new_path: PathBuf,
+    options: ReplacerOptions,
+    prepared: Sender<Result<()>>,
+    receiver: Receiver<Sender<RunReply>>,
+) {
+    if options.ptrace_timeout_ms > 0 {
+        ptrace::set_timeout(Some(Duration::from_millis(options.ptrace_timeout_ms)));
+    }
+
+    ptrace::set_trap_strategy(options.trap_strategy);
+
+    let mut replacer = UnionReplacer::default();
+    let result = replacer.prepare(&detect_path, &new_path, &options);
+    let failed = result.is_err();
+    if prepared.send(result).is_err() || failed {
+        return;
+    }
+
+    for reply in receiver {
+        let result = replacer.run();
+        if reply.send((result, replacer.stats())).is_err() {
+            break;
+        }
+    }
+
+    // the traced processes are detached here, in the same thread which attached them
+    drop(replacer);
+}
+
+impl Replacer for ParallelReplacer {
+    fn run(&mut self) -> Result<()> {
+        info!("running replacers");
+
+        // send the requests to all workers before waiting for any of them, so that they run
+        // concurrently
+        let mut replies = Vec::with_capacity(self.workers.len());
+        for worker in self.workers.iter() {
+            let (tx, rx) = channel();
+            if let Some(sender) = &worker.sender {
+                sender
+                    .send(tx)
+                    .map_err(|_| anyhow!("replacer worker {} has exited", worker.index))?;
+            }
+            replies.push((worker.index, rx));
+        }
+
+        let mut result = Ok(());
+        let mut stats = ReplacerStats::default();
+        for (index, rx) in replies {
+            let reply = rx
+                .recv()
+                .map_err(|_| anyhow!("replacer worker {} has exited", index))
+                .and_then(|(reply, worker_stats)| {
+                    stats.merge(worker_stats);
+                    reply
+                });
+            if let Err(err) = reply {
+                error!("replacer worker {} failed: {:?}", index, err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+
+        info!(
+            "replacers patched {} processes: {} fds, {} cwds and {} mmaps, skipped {} cases",
+            stats.processes_patched,
+            stats.fds_replaced,
+            stats.cwds_replaced,
+            stats.mmaps_remapped,
+            stats.skipped.len()
+        );
+        for case in stats.skipped.iter() {
+            info!(
+                "skipped {} of process {}: {}",
+                case.target, case.pid, case.reason
+            );
+        }
+        self.stats = stats;
+
+        result
+    }
+
+    fn stats(&self) -> ReplacerStats {
+        self.stats.clone()
+    }
+}
+
+impl Drop for ParallelReplacer {
+    fn drop(&mut self) {
+        // closing the channels stops the workers, which then detach their processes concurrently
+        for worker in self.workers.iter_mut() {
+            drop(worker.sender.take());
+        }
+
+        for worker in self.workers.iter_mut() {
+            if let Some(handle) = worker.handle.take() {
+                if handle.join().is_err() {
+                    error!("replacer worker {} panicked", worker.index);
+                }
+            }
+        }
+    }
+}
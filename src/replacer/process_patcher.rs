@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+
+use anyhow::Result;
+use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
+use tracing::info;
+
+use super::{
+    fd_replacer, mmap_replacer, ptrace, CwdReplacer, FdReplacer, MmapReplacer, ReplacedCwd,
+    Replacer, ReplacerStats,
+};
+
+pub(super) type Assembler = dynasmrt::VecAssembler<dynasmrt::x64::X64Relocation>;
+
+// ProcessPatcher accumulates the fd, cwd and mmap cases of one process, and runs all of them in
+// a single injected code blob, so that the process is only stopped once.
+pub struct ProcessPatcher {
+    process: ptrace::TracedProcess,
+
+    fd: Option<fd_replacer::ProcessAccessor>,
+    cwd: Option<ReplacedCwd>,
+    mmap: Option<mmap_replacer::ProcessAccessor>,
+
+    patched: bool,
+}
+
+impl ProcessPatcher {
+    // batch merges the cases of the prepared replacers by process
+    pub fn batch(
+        fd: Option<FdReplacer>,
+        cwd: Option<CwdReplacer>,
+        mmap: Option<MmapReplacer>,
+    ) -> Vec<ProcessPatcher> {
+        let mut patchers = HashMap::new();
+
+        for (_, accessor) in fd.into_iter().flat_map(|replacer| replacer.processes) {
+            let patcher = entry(&mut patchers, &accessor.process);
+            patcher.fd = Some(accessor);
+        }
+        for (process, cwd) in cwd.into_iter().flat_map(|replacer| replacer.processes) {
+            entry(&mut patchers, &process).cwd = Some(cwd);
+        }
+        for (_, accessor) in mmap.into_iter().flat_map(|replacer| replacer.processes) {
+            let patcher = entry(&mut patchers, &accessor.process);
+            patcher.mmap = Some(accessor);
+        }
+
+        patchers.into_iter().map(|(_, patcher)| patcher).collect()
+    }
+}
+
+fn entry<'a>(
+    patchers: &'a mut HashMap<i32, ProcessPatcher>,
+    process: &ptrace::TracedProcess,
+) -> &'a mut ProcessPatcher {
+    patchers
+        .entry(process.pid)
+        .or_insert_with(|| ProcessPatcher {
+            process: process.clone(),
+            fd: None,
+            cwd: None,
+            mmap: None,
+            patched: false,
+        })
+}
+
+fn emit_chdir(vec_rt: &mut Assembler, path: &[u8]) {
+    dynasm!(vec_rt
+        ; .arch x64
+        ; jmp ->cwd_codes
+        ; ->cwd_path:
+        ; .bytes path
+        ; ->cwd_codes:
+        // chdir
+        ; mov rax, 0x50
+        ; lea rdi, [-> cwd_path]
+        ; syscall
+    );
+}
+
+impl Replacer for ProcessPatcher {
+    fn run(&mut self) -> Result<()> {
+        info!("patching process {}", self.process.pid);
+
+        let cwd = match &self.cwd {
+            Some(cwd) => Some(CString::new(cwd.replaced.as_os_str().as_bytes())?),
+            None => None,
+        };
+
+        self.process.run_codes(|addr| {
+            let mut vec_rt = Assembler::new(addr as usize);
+            if let Some(fd) = &self.fd {
+                fd.emit(&mut vec_rt)?;
+            }
+            if let Some(cwd) = &cwd {
+                emit_chdir(&mut vec_rt, cwd.as_bytes_with_nul());
+            }
+            if let Some(mmap) = &self.mmap {
+                mmap.emit(&mut vec_rt)?;
+            }
+            dynasm!(vec_rt
+                ; .arch x64
+                ; int3
+            );
+
+            Ok((0, vec_rt.finalize()?))
+        })?;
+        self.patched = true;
+
+        Ok(())
+    }
+
+    fn stats(&self) -> ReplacerStats {
+        if !self.patched {
+            return ReplacerStats::default();
+        }
+
+        ReplacerStats {
+            processes_patched: 1,
+            fds_replaced: self.fd.as_ref().map_or(0, |fd| fd.case_count()),
+            cwds_replaced: self.cwd.is_some() as usize,
+            mmaps_remapped: self.mmap.as_ref().map_or(0, |mmap| mmap.case_count()),
+            skipped: Vec::new(),
+            remaining: Vec::new(),
+            cwds: self.cwd.iter().cloned().collect(),
+        }
+    }
+}
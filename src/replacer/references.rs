@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use procfs::process::{FDTarget, MMapPath, Process};
+use serde::Serialize;
+
+use super::utils::{candidate_processes, is_selected};
+use super::ReplacerOptions;
+
+// the processes which manage the containers, and would stall all of them while being traced
+const RISKY_PROCESSES: &[&str] = &[
+    "containerd",
+    "containerd-shim",
+    "dockerd",
+    "runc",
+    "crio",
+    "conmon",
+    "kubelet",
+    "systemd",
+];
+
+// ProcessReferences are the fds, the cwd and the mappings of a process under a path
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessReferences {
+    pub pid: i32,
+    pub name: String,
+    pub cwd: Option<PathBuf>,
+    pub fds: Vec<PathBuf>,
+    pub mmaps: Vec<PathBuf>,
+    // whether the process would be traced by the replacer with the options
+    pub traced: bool,
+    // whether tracing the process may stall the init or the containers on the node
+    pub risky: bool,
+}
+
+impl ProcessReferences {
+    pub fn collect<P: AsRef<Path>>(process: &Process, path: P) -> ProcessReferences {
+        let path = path.as_ref();
+        let mut references = ProcessReferences {
+            pid: process.pid,
+            name: process.stat.comm.clone(),
+            risky: process.pid == 1 || RISKY_PROCESSES.contains(&process.stat.comm.as_str()),
+            ..Default::default()
+        };
+
+        references.cwd = process.cwd().ok().filter(|cwd| cwd.starts_with(path));
+        if let Ok(fds) = process.fd() {
+            for fd in fds {
+                if let FDTarget::Path(target) = fd.target {
+                    if target.starts_with(path) {
+                        references.fds.push(target);
+                    }
+                }
+            }
+        }
+        if let Ok(maps) = process.maps() {
+            for map in maps {
+                if let MMapPath::Path(target) = map.pathname {
+                    if target.starts_with(path) && !references.mmaps.contains(&target) {
+                        references.mmaps.push(target);
+                    }
+                }
+            }
+        }
+
+        references
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cwd.is_none() && self.fds.is_empty() && self.mmaps.is_empty()
+    }
+}
+
+// ReferencesReport lists the processes using a path before injecting it, which are the ones
+// traced and patched by the replacer
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReferencesReport {
+    pub path: PathBuf,
+    pub processes: Vec<ProcessReferences>,
+}
+
+// analyze_references finds the processes holding fds, the cwd or mappings under the path, among
+// the ones discovered with the options. The toda processes are never traced, so they're not
+// listed.
+pub fn analyze_references<P: AsRef<Path>>(
+    path: P,
+    options: &ReplacerOptions,
+) -> Result<ReferencesReport> {
+    let path = path.as_ref().canonicalize()?;
+    let processes = candidate_processes(options)?
+        .map(|process| {
+            let mut references = ProcessReferences::collect(&process, &path);
+            references.traced = is_selected(options, &process);
+            references
+        })
+        .filter(|references| !references.is_empty())
+        .collect();
+
+    Ok(ReferencesReport { path, processes })
+}
@@ -0,0 +1,58 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+// ReplacerStats is the statistics of a replacer pass, which is logged and reported through the
+// control API
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplacerStats {
+    pub processes_patched: usize,
+    pub fds_replaced: usize,
+    pub cwds_replaced: usize,
+    pub mmaps_remapped: usize,
+    pub skipped: Vec<SkippedCase>,
+    // the references found out of the FUSE by the verification after the pass
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub remaining: Vec<SkippedCase>,
+    // the cwds moved onto the FUSE, which are moved back exactly on recovery
+    #[serde(skip)]
+    pub cwds: Vec<ReplacedCwd>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SkippedCase {
+    pub pid: i32,
+    pub target: String,
+    pub reason: String,
+}
+
+// ReplacedCwd is the cwd of a process before and after the replacer pass
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplacedCwd {
+    pub pid: i32,
+    pub original: PathBuf,
+    pub replaced: PathBuf,
+}
+
+impl ReplacerStats {
+    pub fn skip<T: Display, R: Into<String>>(&mut self, pid: i32, target: T, reason: R) {
+        self.skipped.push(SkippedCase {
+            pid,
+            target: target.to_string(),
+            reason: reason.into(),
+        });
+    }
+
+    pub fn merge(&mut self, other: ReplacerStats) {
+        self.processes_patched += other.processes_patched;
+        self.fds_replaced += other.fds_replaced;
+        self.cwds_replaced += other.cwds_replaced;
+        self.mmaps_remapped += other.mmaps_remapped;
+        self.skipped.extend(other.skipped);
+        self.remaining.extend(other.remaining);
+        self.cwds.extend(other.cwds);
+    }
+}
@@ -1,14 +1,272 @@
-use anyhow::Result;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::Duration;
+use std::{fs, io};
+
+use anyhow::{anyhow, Result};
+use nix::errno::Errno;
 use procfs::process::{self, Process};
+use tracing::{error, info, warn};
 
-pub fn all_processes() -> Result<impl Iterator<Item = Process>> {
-    Ok(process::all_processes()?
-        .into_iter()
-        .filter(|process| -> bool {
-            if let Ok(cmdline) = process.cmdline() {
-                !cmdline.iter().map(|stat| stat.contains("toda")).any(|x| x)
-            } else {
-                true
+use super::{ptrace, AttachFailurePolicy, ReplacerOptions, ReplacerScope, ReplacerStats};
+
+// Shard selects the processes handled by one of the replacer workers
+#[derive(Debug, Clone, Copy)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+// the mount points of the cgroup hierarchies, under which the cgroup of --target-cgroup is looked
+// up, for the unified hierarchy and the hybrid or legacy ones
+const CGROUP_ROOTS: &[&str] = &[
+    "/sys/fs/cgroup",
+    "/sys/fs/cgroup/unified",
+    "/sys/fs/cgroup/pids",
+    "/sys/fs/cgroup/memory",
+];
+
+// PidNamespace is the pid namespace of a process, which is parsed from the pid of the process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PidNamespace(u64);
+
+impl PidNamespace {
+    fn of(pid: i32) -> io::Result<PidNamespace> {
+        let meta = fs::metadata(format!("/proc/{}/ns/pid", pid))?;
+        Ok(PidNamespace(meta.ino()))
+    }
+}
+
+impl FromStr for PidNamespace {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let pid = s.parse()?;
+        PidNamespace::of(pid)
+            .map_err(|err| anyhow!("fail to read the pid namespace of {}: {}", pid, err))
+    }
+}
+
+// CgroupScope is a cgroup in one of the hierarchies, under which the traced processes should be
+#[derive(Debug, Clone)]
+pub struct CgroupScope {
+    pub hierarchy: u32,
+    pub path: PathBuf,
+}
+
+// resolve_scope returns the cgroups of the container or the pod of the process. The hierarchies
+// in which the process is in the root cgroup are skipped, as they don't restrict anything.
+pub fn resolve_scope(scope: ReplacerScope, pid: i32) -> Result<Vec<CgroupScope>> {
+    if scope == ReplacerScope::All {
+        return Ok(Vec::new());
+    }
+
+    let mut cgroups = Vec::new();
+    for cgroup in Process::new(pid)?.cgroups()? {
+        let mut path = PathBuf::from(&cgroup.pathname);
+        if scope == ReplacerScope::Pod {
+            let pod = path
+                .parent()
+                .filter(|parent| {
+                    parent
+                        .file_name()
+                        .map_or(false, |name| name.to_string_lossy().contains("pod"))
+                })
+                .map(Path::to_path_buf);
+            if let Some(pod) = pod {
+                path = pod;
+            }
+        }
+        if path != Path::new("/") {
+            cgroups.push(CgroupScope {
+                hierarchy: cgroup.hierarchy,
+                path,
+            });
+        }
+    }
+
+    if cgroups.is_empty() {
+        warn!("process {} is in the root cgroups, trace any process", pid);
+    } else {
+        info!("only trace the processes under the cgroups {:?}", cgroups);
+    }
+    Ok(cgroups)
+}
+
+// in_scope returns true if the process is under all the cgroups of the scope
+fn in_scope(scope: &[CgroupScope], process: &Process) -> bool {
+    if scope.is_empty() {
+        return true;
+    }
+
+    let cgroups = match process.cgroups() {
+        Ok(cgroups) => cgroups,
+        Err(_) => return false,
+    };
+    scope.iter().all(|scope| {
+        cgroups.iter().any(|cgroup| {
+            cgroup.hierarchy == scope.hierarchy
+                && Path::new(&cgroup.pathname).starts_with(&scope.path)
+        })
+    })
+}
+
+// the flag of the kernel threads in the stat of a process, which could never be traced
+const PF_KTHREAD: u32 = 0x0020_0000;
+
+// is_transient returns true if tracing the process could succeed later: its tasks are still
+// stopping, or the process is still there while a task is gone. The other failures, e.g. EPERM,
+// or the process being traced by another thread, are never retried.
+fn is_transient(pid: i32, err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<nix::Error>())
+        .any(|err| match err {
+            nix::Error::Sys(Errno::EBUSY) => true,
+            nix::Error::Sys(Errno::ESRCH) => Process::new(pid).is_ok(),
+            _ => false,
+        })
+}
+
+// trace_with_retry attaches to the process, and retries with a doubling backoff if it fails
+// transiently. It should only be called for the processes using the path, as the backoff is paid
+// for every process failing to be traced.
+pub fn trace_with_retry(pid: i32, options: &ReplacerOptions) -> Result<ptrace::TracedProcess> {
+    ptrace::check_permission(pid)?;
+
+    let mut backoff = Duration::from_millis(options.attach_backoff_ms);
+    let mut retries = 0;
+    loop {
+        match ptrace::trace(pid) {
+            Ok(process) => return Ok(process),
+            Err(err) if retries < options.attach_retries && is_transient(pid, &err) => {
+                warn!(
+                    "fail to trace process {}: {:?}, retry in {:?}",
+                    pid, err, backoff
+                );
+                sleep(backoff);
+                backoff *= 2;
+                retries += 1;
             }
-        }))
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// AttachFailures collects the processes using the path which could not be traced while
+// preparing a replacer
+#[derive(Debug, Default)]
+pub struct AttachFailures(Vec<(i32, String, String)>);
+
+impl AttachFailures {
+    pub fn push<T: ToString>(&mut self, pid: i32, target: T, err: anyhow::Error) {
+        error!("fail to trace process {}: {:?}", pid, err);
+        self.0.push((pid, target.to_string(), err.to_string()));
+    }
+
+    // resolve reports the processes as skipped, or fails if the policy aborts
+    pub fn resolve(self, policy: AttachFailurePolicy, stats: &mut ReplacerStats) -> Result<()> {
+        if policy == AttachFailurePolicy::Abort && !self.0.is_empty() {
+            let pids: Vec<_> = self.0.iter().map(|(pid, _, _)| pid).collect();
+            return Err(anyhow!("fail to trace the processes {:?}", pids));
+        }
+        for (pid, target, err) in self.0 {
+            stats.skip(pid, target, format!("fail to trace: {}", err));
+        }
+        Ok(())
+    }
+}
+
+// candidate_processes lists the processes in the --target-cgroup and the --target-pidns, or all
+// the processes on the host without them. The toda processes and the kernel threads are always
+// skipped.
+pub fn candidate_processes(options: &ReplacerOptions) -> Result<impl Iterator<Item = Process>> {
+    let processes = match &options.target_cgroup {
+        Some(cgroup) => cgroup_processes(cgroup)?,
+        None => process::all_processes()?,
+    };
+
+    let pidns = options.target_pidns;
+    Ok(processes
+        .into_iter()
+        .filter(move |process| {
+            pidns.map_or(true, |pidns| {
+                PidNamespace::of(process.pid).map_or(false, |ns| ns == pidns)
+            })
+        })
+        .filter(|process| process.stat.flags & PF_KTHREAD == 0)
+        .filter(|process| !is_toda(process)))
+}
+
+// all_processes lists the processes which could be traced by the replacers. The candidates are
+// filtered with the include/exclude lists and the cgroups in options.
+pub fn all_processes(options: &ReplacerOptions) -> Result<impl Iterator<Item = Process> + '_> {
+    Ok(candidate_processes(options)?.filter(move |process| is_selected(options, process)))
+}
+
+// cgroup_processes lists the processes in the cgroup and its descendants from their cgroup.procs,
+// without scanning all the processes
+fn cgroup_processes(cgroup: &Path) -> Result<Vec<Process>> {
+    let relative = cgroup.strip_prefix("/").unwrap_or(cgroup);
+    let dir = CGROUP_ROOTS
+        .iter()
+        .map(|root| Path::new(root).join(relative))
+        .find(|dir| dir.join("cgroup.procs").exists())
+        .ok_or_else(|| anyhow!("cgroup {} is not found", cgroup.display()))?;
+
+    let mut pids = Vec::new();
+    collect_pids(&dir, &mut pids)?;
+    Ok(pids
+        .into_iter()
+        .filter_map(|pid| Process::new(pid).ok())
+        .collect())
+}
+
+fn collect_pids(dir: &Path, pids: &mut Vec<i32>) -> io::Result<()> {
+    for line in fs::read_to_string(dir.join("cgroup.procs"))?.lines() {
+        if let Ok(pid) = line.trim().parse() {
+            pids.push(pid);
+        }
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            collect_pids(&entry.path(), pids)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_toda(process: &Process) -> bool {
+    if let Ok(cmdline) = process.cmdline() {
+        cmdline.iter().map(|stat| stat.contains("toda")).any(|x| x)
+    } else {
+        false
+    }
+}
+
+pub fn is_selected(options: &ReplacerOptions, process: &Process) -> bool {
+    let pid = process.pid;
+    let name = &process.stat.comm;
+
+    if let Some(shard) = options.shard {
+        if pid as usize % shard.count != shard.index {
+            return false;
+        }
+    }
+
+    if options.exclude_pid.contains(&pid) || options.exclude_name.contains(name) {
+        return false;
+    }
+
+    if !in_scope(&options.cgroups, process) {
+        return false;
+    }
+
+    if options.include_pid.is_empty() && options.include_name.is_empty() {
+        return true;
+    }
+
+    options.include_pid.contains(&pid) || options.include_name.contains(name)
 }
@@ -0,0 +1,83 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use anyhow::Result;
+use nix::sys::stat::{major, minor};
+use procfs::process::{FDTarget, MMapPath, Process};
+use tracing::{info, warn};
+
+use super::utils::all_processes;
+use super::{ReplacerOptions, ReplacerStats, SkippedCase};
+
+// verify re-reads the fds, cwds and mappings of the processes after a replacer pass, and returns
+// the ones under the mount path or the hidden path which are still not on the FUSE. They are
+// told apart by the device, as the original directory may be covered at the same path. The
+// cases skipped by the pass in `stats` are not reported again.
+pub fn verify<P1: AsRef<Path>, P2: AsRef<Path>>(
+    mount_path: P1,
+    hidden_path: P2,
+    options: &ReplacerOptions,
+    stats: &ReplacerStats,
+) -> Result<Vec<SkippedCase>> {
+    let (mount_path, hidden_path) = (mount_path.as_ref(), hidden_path.as_ref());
+    let fuse_dev = fs::metadata(mount_path)?.dev();
+    let under = |path: &Path| path.starts_with(mount_path) || path.starts_with(hidden_path);
+
+    let mut remaining = Vec::new();
+    for process in all_processes(options)? {
+        let mut stale = |target: &Path, kind: &str| {
+            let target = target.display().to_string();
+            let skipped = stats
+                .skipped
+                .iter()
+                .any(|case| case.pid == process.pid && case.target == target);
+            if !skipped {
+                remaining.push(SkippedCase {
+                    pid: process.pid,
+                    target,
+                    reason: format!("{} is not on the FUSE", kind),
+                });
+            }
+        };
+
+        if let Ok(cwd) = process.cwd() {
+            if under(&cwd) && !on_device(&process, "cwd", fuse_dev) {
+                stale(&cwd, "cwd");
+            }
+        }
+        if let Ok(fds) = process.fd() {
+            for fd in fds {
+                if let FDTarget::Path(target) = &fd.target {
+                    let link = format!("fd/{}", fd.fd);
+                    if under(target) && !on_device(&process, &link, fuse_dev) {
+                        stale(target, "fd");
+                    }
+                }
+            }
+        }
+        if let Ok(maps) = process.maps() {
+            let fuse = (major(fuse_dev) as i32, minor(fuse_dev) as i32);
+            for map in maps {
+                if let MMapPath::Path(target) = &map.pathname {
+                    if under(target) && map.dev != fuse {
+                        stale(target, "mapping");
+                    }
+                }
+            }
+        }
+    }
+
+    if remaining.is_empty() {
+        info!("all the references are replaced onto the FUSE");
+    } else {
+        warn!("references are not replaced onto the FUSE: {:?}", remaining);
+    }
+    Ok(remaining)
+}
+
+// on_device returns true if the file behind the link in /proc/<pid> is on the device, or the
+// link is gone with its process
+fn on_device(process: &Process, link: &str, dev: u64) -> bool {
+    fs::metadata(format!("/proc/{}/{}", process.pid, link)).map_or(true, |meta| meta.dev() == dev)
+}
@@ -0,0 +1,113 @@
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Result;
+use tracing::{error, info};
+
+use super::utils::all_processes;
+use super::{ParallelReplacer, ProcessReferences, Replacer, ReplacerOptions, ReplacerStats};
+
+// ReplacerWatcher rescans the processes periodically after the replacer pass, and replaces the
+// references to the hidden path held by the ones which bypass the FUSE, like the children forked
+// with the fds inherited before the pass. Only the processes holding such references are traced,
+// so an idle scan never stops any process.
+//
+// The references are detected by their paths, which are only moved to the hidden path by the
+// move mount mode. With the bind mode, the original directory is covered at the same path.
+pub struct ReplacerWatcher {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+    stats: Arc<Mutex<ReplacerStats>>,
+}
+
+impl ReplacerWatcher {
+    // start scans the processes every interval, and moves the references to the hidden path onto
+    // the mount path
+    pub fn start<P1: AsRef<Path>, P2: AsRef<Path>>(
+        hidden_path: P1,
+        mount_path: P2,
+        options: ReplacerOptions,
+        interval: Duration,
+    ) -> Result<ReplacerWatcher> {
+        let hidden_path = hidden_path.as_ref().to_path_buf();
+        let mount_path = mount_path.as_ref().to_path_buf();
+        let stats = Arc::new(Mutex::new(ReplacerStats::default()));
+        let watcher_stats = stats.clone();
+
+        let (stop, stopped) = channel();
+        let handle = thread::Builder::new()
+            .name("replacer-watcher".to_owned())
+            .spawn(move || loop {
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+
+                match rescan(&hidden_path, &mount_path, &options) {
+                    Ok(Some(stats)) => watcher_stats.lock().unwrap().merge(stats),
+                    Ok(None) => {}
+                    Err(err) => error!("fail to rescan the processes: {:?}", err),
+                }
+            })?;
+
+        Ok(ReplacerWatcher {
+            stop: Some(stop),
+            handle: Some(handle),
+            stats,
+        })
+    }
+
+    // stats returns what the rescans have replaced so far
+    pub fn stats(&self) -> ReplacerStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    // stop waits for the running rescan, and stops the watcher. It must be stopped before the
+    // recovery, which moves the references back to the hidden path.
+    pub fn stop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("replacer watcher panicked");
+            }
+        }
+    }
+}
+
+impl Drop for ReplacerWatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// rescan runs an incremental replacer pass on the processes holding references to the hidden
+// path, and returns `None` if there isn't any
+fn rescan(
+    hidden_path: &Path,
+    mount_path: &Path,
+    options: &ReplacerOptions,
+) -> Result<Option<ReplacerStats>> {
+    let pids: Vec<i32> = all_processes(options)?
+        .filter(|process| !ProcessReferences::collect(process, hidden_path).is_empty())
+        .map(|process| process.pid)
+        .collect();
+    if pids.is_empty() {
+        return Ok(None);
+    }
+
+    info!("processes {:?} bypass the FUSE, replace them", pids);
+    let mut options = options.clone();
+    options.include_pid = pids;
+    options.include_name.clear();
+
+    let mut replacer = ParallelReplacer::prepare(hidden_path, mount_path, &options)?;
+    let result = replacer.run();
+    let stats = replacer.stats();
+    drop(replacer);
+    result?;
+
+    Ok(Some(stats))
+}
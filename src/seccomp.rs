@@ -0,0 +1,307 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use nix::errno::Errno;
+use tracing::info;
+
+use crate::arch::{AUDIT_ARCH, LEGACY_SYSCALLS};
+
+// the instructions of the classic BPF, which the seccomp filters are written in
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+// the offsets of the fields of `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+const SECCOMP_SET_MODE_FILTER: libc::c_long = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_long = 1;
+
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+
+// the syscalls numbered in the table shared by all the architectures, which may be missing in libc
+const SYS_CLONE3: libc::c_long = 435;
+const SYS_OPENAT2: libc::c_long = 437;
+const SYS_FACCESSAT2: libc::c_long = 439;
+
+// the syscalls made by toda once it's serving: the operations on the backend, the runtime and the
+// threads, the control API on the stdio, the OTLP exporter, and the recovery of the mount and the
+// processes
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_lseek,
+    libc::SYS_openat,
+    // the backend opens the files beneath its root with openat2, and falls back to openat only if
+    // it's not implemented
+    SYS_OPENAT2,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_close,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_getdents64,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_syncfs,
+    libc::SYS_ftruncate,
+    libc::SYS_truncate,
+    libc::SYS_fallocate,
+    libc::SYS_fadvise64,
+    libc::SYS_copy_file_range,
+    libc::SYS_sendfile,
+    libc::SYS_splice,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    libc::SYS_mkdirat,
+    libc::SYS_mknodat,
+    libc::SYS_symlinkat,
+    libc::SYS_linkat,
+    libc::SYS_readlinkat,
+    libc::SYS_fchownat,
+    libc::SYS_fchown,
+    libc::SYS_fchmodat,
+    libc::SYS_fchmod,
+    libc::SYS_utimensat,
+    libc::SYS_faccessat,
+    SYS_FACCESSAT2,
+    libc::SYS_getxattr,
+    libc::SYS_lgetxattr,
+    libc::SYS_fgetxattr,
+    libc::SYS_setxattr,
+    libc::SYS_lsetxattr,
+    libc::SYS_fsetxattr,
+    libc::SYS_listxattr,
+    libc::SYS_llistxattr,
+    libc::SYS_flistxattr,
+    libc::SYS_removexattr,
+    libc::SYS_lremovexattr,
+    libc::SYS_fremovexattr,
+    libc::SYS_flock,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_getcwd,
+    libc::SYS_chdir,
+    libc::SYS_fchdir,
+    libc::SYS_umask,
+    // memory
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    // threads and signals
+    libc::SYS_clone,
+    SYS_CLONE3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_kill,
+    libc::SYS_tgkill,
+    libc::SYS_tkill,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_prctl,
+    // events and time
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    // identities and limits
+    libc::SYS_uname,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getgroups,
+    libc::SYS_capget,
+    libc::SYS_prlimit64,
+    // the OTLP exporter
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_shutdown,
+    // the recovery
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+];
+
+// SeccompMode decides what happens to the syscalls out of the ones toda makes while serving the
+// FUSE, once the filter is applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompMode {
+    // no filter is applied
+    Off,
+    // the syscalls are allowed, but logged by the audit, to find the ones missing in the list
+    Log,
+    // the syscalls fail with EPERM
+    Enforce,
+}
+
+impl Default for SeccompMode {
+    fn default() -> Self {
+        SeccompMode::Off
+    }
+}
+
+impl FromStr for SeccompMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(SeccompMode::Off),
+            "log" => Ok(SeccompMode::Log),
+            "enforce" => Ok(SeccompMode::Enforce),
+            _ => Err(anyhow!("unknown seccomp mode: {}", s)),
+        }
+    }
+}
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+fn statement(code: u16, k: u32) -> SockFilter {
+    SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter {
+        code: BPF_JMP_JEQ_K,
+        jt,
+        jf,
+        k,
+    }
+}
+
+// build_filter allows the syscalls in the list, and returns `default` for the others, or the ones
+// of another architecture
+fn build_filter(allowed: &[libc::c_long], default: u32) -> Vec<SockFilter> {
+    // every syscall jumps over the ones after it to the allowing return
+    assert!(allowed.len() <= u8::MAX as usize);
+
+    let mut filter = vec![
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(AUDIT_ARCH, 1, 0),
+        statement(BPF_RET_K, default),
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+    for (index, nr) in allowed.iter().enumerate() {
+        filter.push(jump(*nr as u32, (allowed.len() - index) as u8, 0));
+    }
+    filter.push(statement(BPF_RET_K, default));
+    filter.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+    filter
+}
+
+// apply restricts the syscalls of every thread of toda to the ones needed to serve the FUSE and
+// to recover. It should be called once the injection is set up, after which the namespaces and
+// the credentials are never changed again.
+pub fn apply(mode: SeccompMode) -> Result<()> {
+    let default = match mode {
+        SeccompMode::Off => return Ok(()),
+        SeccompMode::Log => SECCOMP_RET_LOG,
+        SeccompMode::Enforce => SECCOMP_RET_ERRNO | libc::EPERM as u32,
+    };
+
+    let mut allowed = ALLOWED_SYSCALLS.to_vec();
+    allowed.extend_from_slice(LEGACY_SYSCALLS);
+    let filter = build_filter(&allowed, default);
+    let program = SockFprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr(),
+    };
+
+    // the threads synchronized to the filter inherit the no_new_privs of the caller, which allows
+    // the filter without CAP_SYS_ADMIN
+    let ret = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    Errno::result(ret)?;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &program as *const SockFprog,
+        )
+    };
+    if ret > 0 {
+        return Err(anyhow!(
+            "thread {} could not be synchronized to the filter",
+            ret
+        ));
+    }
+    Errno::result(ret)?;
+
+    info!(
+        "seccomp filter applied with {} syscalls in {:?} mode",
+        allowed.len(),
+        mode
+    );
+    Ok(())
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+// the index of the ranges written to every file, which is saved in the shadow directory
+const INDEX_FILE: &str = "index.json";
+// the directory under the shadow directory, which keeps the written data at the same paths and
+// offsets as the files under the mount point
+const DATA_DIR: &str = "data";
+
+// the size of the chunks compared by verify
+const VERIFY_CHUNK: u64 = 64 * 1024;
+
+// Ranges is a set of disjoint ranges of bytes `[start, end)`, sorted by their start
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Ranges(Vec<(u64, u64)>);
+
+impl Ranges {
+    fn insert(&mut self, start: u64, end: u64) {
+        let (mut start, mut end) = (start, end);
+        let mut ranges = Vec::with_capacity(self.0.len() + 1);
+        for &(s, e) in self.0.iter() {
+            if e < start || s > end {
+                ranges.push((s, e));
+            } else {
+                start = start.min(s);
+                end = end.max(e);
+            }
+        }
+        ranges.push((start, end));
+        ranges.sort_unstable();
+        self.0 = ranges;
+    }
+
+    fn truncate(&mut self, len: u64) {
+        self.0.retain(|&(start, _)| start < len);
+        for range in self.0.iter_mut() {
+            range.1 = range.1.min(len);
+        }
+    }
+}
+
+// Shadow duplicates the data written through the FUSE into a shadow directory, before any
+// injector corrupts it, so that the files could be verified against what the application
+// intended to write after the experiment. The renames, unlinks and truncations are followed, so
+// that the shadow keeps matching the files.
+#[derive(Debug)]
+pub struct Shadow {
+    dir: PathBuf,
+    root: PathBuf,
+    index: Mutex<HashMap<PathBuf, Ranges>>,
+}
+
+impl Shadow {
+    // create starts a new shadow of the files under the root
+    pub fn create<P1: AsRef<Path>, P2: AsRef<Path>>(dir: P1, root: P2) -> Result<Shadow> {
+        let data = dir.as_ref().join(DATA_DIR);
+        if data.exists() {
+            fs::remove_dir_all(&data)?;
+        }
+        fs::create_dir_all(&data)?;
+
+        Ok(Shadow {
+            dir: dir.as_ref().to_owned(),
+            root: root.as_ref().to_owned(),
+            index: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn relative(&self, path: &Path) -> Option<PathBuf> {
+        path.strip_prefix(&self.root).ok().map(Path::to_owned)
+    }
+
+    fn data_path(&self, relative: &Path) -> PathBuf {
+        self.dir.join(DATA_DIR).join(relative)
+    }
+
+    // write records the data intended to be written to the file at the offset
+    pub fn write(&self, path: &Path, offset: u64, data: &[u8]) {
+        let relative = match self.relative(path) {
+            Some(relative) => relative,
+            None => return,
+        };
+        let shadow = self.data_path(&relative);
+        let result = shadow
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| OpenOptions::new().write(true).create(true).open(&shadow))
+            .and_then(|file| file.write_all_at(data, offset));
+        if let Err(err) = result {
+            warn!("fail to write the shadow of {}: {}", path.display(), err);
+            return;
+        }
+
+        self.index
+            .lock()
+            .unwrap()
+            .entry(relative)
+            .or_default()
+            .insert(offset, offset + data.len() as u64);
+    }
+
+    // rename moves the shadows of the file, or all the files under the directory
+    pub fn rename(&self, old_path: &Path, new_path: &Path) {
+        let (old, new) = match (self.relative(old_path), self.relative(new_path)) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return,
+        };
+
+        let mut index = self.index.lock().unwrap();
+        // the replaced file is not shadowed anymore
+        let replaced = index.remove(&new).is_some();
+        let moved: Vec<_> = index
+            .keys()
+            .filter(|path| path.starts_with(&old))
+            .cloned()
+            .collect();
+        if moved.is_empty() {
+            if replaced {
+                if let Err(err) = fs::remove_file(self.data_path(&new)) {
+                    warn!(
+                        "fail to remove the shadow of {}: {}",
+                        new_path.display(),
+                        err
+                    );
+                }
+            }
+            return;
+        }
+        for path in moved {
+            if let Some(ranges) = index.remove(&path) {
+                let renamed = new.join(path.strip_prefix(&old).unwrap());
+                index.insert(renamed, ranges);
+            }
+        }
+
+        let (old_shadow, new_shadow) = (self.data_path(&old), self.data_path(&new));
+        let result = new_shadow
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::rename(&old_shadow, &new_shadow));
+        if let Err(err) = result {
+            warn!(
+                "fail to rename the shadow of {}: {}",
+                old_path.display(),
+                err
+            );
+        }
+    }
+
+    // remove forgets the shadow of the removed file
+    pub fn remove(&self, path: &Path) {
+        let relative = match self.relative(path) {
+            Some(relative) => relative,
+            None => return,
+        };
+        if self.index.lock().unwrap().remove(&relative).is_some() {
+            if let Err(err) = fs::remove_file(self.data_path(&relative)) {
+                warn!("fail to remove the shadow of {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    // truncate forgets the written data beyond the new length of the file
+    pub fn truncate(&self, path: &Path, len: u64) {
+        if let Some(relative) = self.relative(path) {
+            if let Some(ranges) = self.index.lock().unwrap().get_mut(&relative) {
+                ranges.truncate(len);
+            }
+        }
+    }
+
+    // save writes the index into the shadow directory, which is read by verify
+    pub fn save(&self) {
+        let index = self.index.lock().unwrap();
+        let result = serde_json::to_vec(&*index)
+            .map_err(io::Error::from)
+            .and_then(|data| fs::write(self.dir.join(INDEX_FILE), data));
+        if let Err(err) = result {
+            warn!("fail to save the index of the shadow: {}", err);
+        }
+    }
+}
+
+impl Drop for Shadow {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+// DivergedFile is a file whose content differs from the data written to it
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DivergedFile {
+    pub path: PathBuf,
+    // the offset of the first byte which differs
+    pub offset: u64,
+    // the count of the bytes which differ
+    pub bytes: u64,
+}
+
+// VerifyReport is the result of verifying the files against their shadows
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyReport {
+    pub files: usize,
+    pub diverged: Vec<DivergedFile>,
+    // the files which were written, but are gone from the directory
+    pub missing: Vec<PathBuf>,
+}
+
+// verify compares the ranges written to every file under the directory with the data kept in the
+// shadow. As the shadow keeps the data before any injector corrupts it, the files diverged by
+// the injection are reported, while the changes made by the application itself are not.
+pub fn verify<P1: AsRef<Path>, P2: AsRef<Path>>(shadow: P1, dir: P2) -> Result<VerifyReport> {
+    let shadow = shadow.as_ref();
+    let index: HashMap<PathBuf, Ranges> =
+        serde_json::from_slice(&fs::read(shadow.join(INDEX_FILE))?)?;
+
+    let mut report = VerifyReport {
+        files: index.len(),
+        ..Default::default()
+    };
+    for (path, ranges) in index.iter() {
+        let file = match File::open(dir.as_ref().join(path)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                report.missing.push(path.clone());
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let expected = File::open(shadow.join(DATA_DIR).join(path))?;
+
+        if let Some(diverged) = compare(&expected, &file, ranges)? {
+            report.diverged.push(DivergedFile {
+                path: path.clone(),
+                offset: diverged.0,
+                bytes: diverged.1,
+            });
+        }
+    }
+
+    report.diverged.sort_by(|a, b| a.path.cmp(&b.path));
+    report.missing.sort();
+    Ok(report)
+}
+
+// compare returns the first offset and the count of the bytes which differ in the ranges. The
+// bytes beyond the end of the file are counted as different.
+fn compare(expected: &File, actual: &File, ranges: &Ranges) -> io::Result<Option<(u64, u64)>> {
+    let mut first = None;
+    let mut count = 0;
+    let mut expected_buf = vec![0u8; VERIFY_CHUNK as usize];
+    let mut actual_buf = vec![0u8; VERIFY_CHUNK as usize];
+
+    for &(start, end) in ranges.0.iter() {
+        let mut offset = start;
+        while offset < end {
+            let len = (end - offset).min(VERIFY_CHUNK) as usize;
+            expected.read_exact_at(&mut expected_buf[..len], offset)?;
+            let read = read_at_most(actual, &mut actual_buf[..len], offset)?;
+
+            for (i, byte) in expected_buf[..len].iter().enumerate() {
+                if i >= read || *byte != actual_buf[i] {
+                    first.get_or_insert(offset + i as u64);
+                    count += 1;
+                }
+            }
+            offset += len as u64;
+        }
+    }
+
+    Ok(first.map(|first| (first, count)))
+}
+
+fn read_at_most(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64)? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
@@ -0,0 +1,174 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+// `_IOW(0x94, 9, int)`, which shares the extents of a file with another one on the filesystems
+// supporting reflinks (btrfs, xfs, etc.)
+const FICLONE: libc::c_ulong = 0x4004_9409;
+
+// SnapshotStats counts the entries copied by a snapshot or a restore
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotStats {
+    pub files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    // the files whose extents are shared instead of copied
+    pub reflinked: usize,
+    // the entries removed from the target by a restore
+    pub removed: usize,
+    // the special files (FIFOs, sockets and devices), which are not kept
+    pub skipped: usize,
+}
+
+// take snapshots the tree under the path into the snapshot directory, which should not exist or
+// be empty. The files are reflinked if possible, or copied otherwise. Hard links would not keep
+// the contents, as the files are modified in place through them.
+pub fn take<P1: AsRef<Path>, P2: AsRef<Path>>(path: P1, snapshot: P2) -> Result<SnapshotStats> {
+    let snapshot = std::env::current_dir()?.join(snapshot.as_ref());
+    let snapshot = snapshot.as_path();
+    if snapshot.starts_with(path.as_ref()) {
+        return Err(anyhow!("snapshot {} is under the path", snapshot.display()));
+    }
+    if snapshot.exists() && fs::read_dir(snapshot)?.next().is_some() {
+        return Err(anyhow!("snapshot {} is not empty", snapshot.display()));
+    }
+
+    info!(
+        "snapshot {} into {}",
+        path.as_ref().display(),
+        snapshot.display()
+    );
+    let mut stats = SnapshotStats::default();
+    copy_tree(path.as_ref(), snapshot, &mut stats)?;
+    Ok(stats)
+}
+
+// restore puts the contents in the snapshot back to the path. The entries created after the
+// snapshot are removed, and the files are rewritten in place, so that the opened fds see the
+// original contents too.
+pub fn restore<P1: AsRef<Path>, P2: AsRef<Path>>(snapshot: P1, path: P2) -> Result<SnapshotStats> {
+    let snapshot = snapshot.as_ref();
+    if !snapshot.is_dir() {
+        return Err(anyhow!("snapshot {} doesn't exist", snapshot.display()));
+    }
+
+    info!(
+        "restore {} from {}",
+        path.as_ref().display(),
+        snapshot.display()
+    );
+    let mut stats = SnapshotStats::default();
+    remove_extra(snapshot, path.as_ref(), &mut stats)?;
+    copy_tree(snapshot, path.as_ref(), &mut stats)?;
+    Ok(stats)
+}
+
+// remove_extra removes the entries under the target which are not in the source, or have another
+// type
+fn remove_extra(src: &Path, dst: &Path, stats: &mut SnapshotStats) -> io::Result<()> {
+    for entry in fs::read_dir(dst)? {
+        let entry = entry?;
+        let dst_type = entry.file_type()?;
+        let src_path = src.join(entry.file_name());
+
+        match fs::symlink_metadata(&src_path) {
+            Ok(src_meta) if src_meta.file_type() == dst_type => {
+                if dst_type.is_dir() {
+                    remove_extra(&src_path, &entry.path(), stats)?;
+                }
+            }
+            _ => {
+                if dst_type.is_dir() {
+                    fs::remove_dir_all(entry.path())?;
+                } else {
+                    fs::remove_file(entry.path())?;
+                }
+                stats.removed += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_tree(src: &Path, dst: &Path, stats: &mut SnapshotStats) -> io::Result<()> {
+    let meta = fs::symlink_metadata(src)?;
+    if !dst.is_dir() {
+        fs::create_dir_all(dst)?;
+    }
+    copy_attrs(&meta, dst)?;
+    stats.dirs += 1;
+
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let (src_path, dst_path) = (entry.path(), dst.join(entry.file_name()));
+        let meta = fs::symlink_metadata(&src_path)?;
+        let file_type = meta.file_type();
+
+        if file_type.is_dir() {
+            copy_tree(&src_path, &dst_path, stats)?;
+        } else if file_type.is_symlink() {
+            if fs::symlink_metadata(&dst_path).is_ok() {
+                fs::remove_file(&dst_path)?;
+            }
+            symlink(fs::read_link(&src_path)?, &dst_path)?;
+            chown(&meta, &dst_path);
+            stats.symlinks += 1;
+        } else if file_type.is_file() {
+            if clone_file(&src_path, &dst_path)? {
+                stats.reflinked += 1;
+            }
+            copy_attrs(&meta, &dst_path)?;
+            stats.files += 1;
+        } else {
+            warn!("skip the special file {}", src_path.display());
+            stats.skipped += 1;
+        }
+    }
+
+    Ok(())
+}
+
+// clone_file rewrites the target with the contents of the source in place, and returns whether
+// the extents are shared
+fn clone_file(src: &Path, dst: &Path) -> io::Result<bool> {
+    let mut src = File::open(src)?;
+    let mut dst = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dst)?;
+
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE, src.as_raw_fd()) };
+    if ret == 0 {
+        return Ok(true);
+    }
+
+    io::copy(&mut src, &mut dst)?;
+    Ok(false)
+}
+
+fn copy_attrs(meta: &fs::Metadata, dst: &Path) -> io::Result<()> {
+    fs::set_permissions(dst, fs::Permissions::from_mode(meta.mode() & 0o7777))?;
+    chown(meta, dst);
+    Ok(())
+}
+
+// chown keeps the owner of the entry, which is only permitted to root
+fn chown(meta: &fs::Metadata, dst: &Path) {
+    let owner = (
+        Some(Uid::from_raw(meta.uid())),
+        Some(Gid::from_raw(meta.gid())),
+    );
+    if let Err(err) = fchownat(None, dst, owner.0, owner.1, FchownatFlags::NoFollowSymlink) {
+        debug!("fail to change the owner of {}: {}", dst.display(), err);
+    }
+}
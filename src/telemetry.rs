@@ -0,0 +1,96 @@
+use std::io;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tracing_subscriber::EnvFilter;
+
+// LogReloader replaces the filter of the global subscriber with the given directives
+pub type LogReloader = Arc<dyn Fn(&str) -> Result<()> + Send + Sync>;
+
+// TelemetryGuard keeps the span exporter running, and flushes the pending spans when it's
+// dropped
+pub struct TelemetryGuard {
+    reloader: LogReloader,
+
+    #[cfg(feature = "otel")]
+    _uninstall: Option<opentelemetry_otlp::Uninstall>,
+}
+
+impl TelemetryGuard {
+    pub fn log_reloader(&self) -> LogReloader {
+        self.reloader.clone()
+    }
+}
+
+// init installs the global tracing subscriber. The spans are also exported to the OTLP collector
+// at `otel_endpoint` if it's given, so that every FUSE request can be viewed as a span alongside
+// the traces of the applications
+pub fn init(env_filter: EnvFilter, otel_endpoint: Option<&str>) -> Result<TelemetryGuard> {
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_env_filter(env_filter)
+        .with_filter_reloading();
+
+    let handle = subscriber.reload_handle();
+    let reloader: LogReloader = Arc::new(move |directives| {
+        let filter = EnvFilter::try_new(directives)
+            .map_err(|err| anyhow!("invalid log level {}: {}", directives, err))?;
+        handle
+            .reload(filter)
+            .map_err(|err| anyhow!("fail to reload log level: {}", err))
+    });
+
+    #[cfg(feature = "otel")]
+    {
+        use tracing_subscriber::layer::SubscriberExt;
+        use tracing_subscriber::util::SubscriberInitExt;
+
+        let (layer, uninstall) = match otel_endpoint {
+            Some(endpoint) => {
+                let (tracer, uninstall) = otlp::install(endpoint)?;
+                (
+                    Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+                    Some(uninstall),
+                )
+            }
+            None => (None, None),
+        };
+        subscriber.finish().with(layer).init();
+
+        Ok(TelemetryGuard {
+            reloader,
+            _uninstall: uninstall,
+        })
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        if otel_endpoint.is_some() {
+            return Err(anyhow!(
+                "toda is built without the otel feature, and cannot export spans"
+            ));
+        }
+        subscriber.init();
+
+        Ok(TelemetryGuard { reloader })
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otlp {
+    use anyhow::{anyhow, Result};
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::Uninstall;
+
+    pub fn install(endpoint: &str) -> Result<(trace::Tracer, Uninstall)> {
+        opentelemetry_otlp::new_pipeline()
+            .with_endpoint(endpoint)
+            .with_trace_config(
+                trace::config()
+                    .with_resource(Resource::new(vec![KeyValue::new("service.name", "toda")])),
+            )
+            .install()
+            .map_err(|err| anyhow!("fail to install otlp exporter: {}", err))
+    }
+}
@@ -2,22 +2,65 @@ use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
 
-pub fn encode_path<P: AsRef<Path>>(original_path: P) -> Result<(PathBuf, PathBuf)> {
-    let original_path: PathBuf = original_path.as_ref().to_owned();
+use crate::instance::instance_id;
+
+// staging_location returns the directory in which the original path is kept during the
+// injection, and the prefix of its name, which is followed by the id of the toda instance
+pub fn staging_location<P: AsRef<Path>>(
+    original_path: P,
+    staging_dir: Option<&Path>,
+) -> Result<(PathBuf, String)> {
+    let original_path = original_path.as_ref();
+    let staging_dir = match staging_dir {
+        Some(staging_dir) => staging_dir,
+        None => {
+            let base_path = original_path.parent().ok_or(anyhow!("path is the root"))?;
+            let original_filename = original_path
+                .file_name()
+                .ok_or(anyhow!("the path terminates in `..` or `/`"))?
+                .to_str()
+                .ok_or(anyhow!("path with non-UTF-8 character"))?;
+            return Ok((
+                base_path.to_owned(),
+                format!("__chaosfs__{}__", original_filename),
+            ));
+        }
+    };
 
-    let mut base_path: PathBuf = original_path.clone();
-    if !base_path.pop() {
-        return Err(anyhow!("path is the root"));
+    if !staging_dir.is_absolute() {
+        return Err(anyhow!("the staging directory is not an absolute path"));
+    }
+    // the staging directory would be covered by the FUSE, and served by itself
+    if staging_dir.starts_with(original_path) {
+        return Err(anyhow!("the staging directory is under the path"));
     }
 
-    let mut new_path: PathBuf = base_path;
-    let original_filename = original_path
-        .file_name()
-        .ok_or(anyhow!("the path terminates in `..` or `/`"))?
+    // the whole path is escaped into the name, so that the paths injected by several toda never
+    // collide
+    let escaped = original_path
         .to_str()
-        .ok_or(anyhow!("path with non-UTF-8 character"))?;
-    let new_filename = format!("__chaosfs__{}__", original_filename);
-    new_path.push(new_filename.as_str());
+        .ok_or(anyhow!("path with non-UTF-8 character"))?
+        .replace('%', "%25")
+        .replace('/', "%2F");
+    Ok((staging_dir.to_owned(), format!("__chaosfs__{}__", escaped)))
+}
+
+// encode_path returns the original path, and where it's kept next to itself during the injection
+// by this toda instance
+pub fn encode_path<P: AsRef<Path>>(original_path: P) -> Result<(PathBuf, PathBuf)> {
+    let original_path = original_path.as_ref().to_owned();
+    let (base_path, prefix) = staging_location(&original_path, None)?;
+    let new_path = base_path.join(format!("{}{}", prefix, instance_id()));
 
     Ok((original_path, new_path))
 }
+
+// encode_staging_path returns where the original path is kept in the staging directory by this
+// toda instance
+pub fn encode_staging_path<P1: AsRef<Path>, P2: AsRef<Path>>(
+    original_path: P1,
+    staging_dir: P2,
+) -> Result<PathBuf> {
+    let (staging_dir, prefix) = staging_location(original_path, Some(staging_dir.as_ref()))?;
+    Ok(staging_dir.join(format!("{}{}", prefix, instance_id())))
+}
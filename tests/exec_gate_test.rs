@@ -0,0 +1,55 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use std::{fs, thread};
+
+use nix::mount::{mount, umount, MsFlags};
+use toda::exec_gate::ExecGate;
+
+#[test]
+fn hold_execs() {
+    // the execs are held on a mount of its own, so that the other tests are never held
+    let dir = Path::new("/tmp/test_exec_gate");
+    fs::create_dir_all(dir).unwrap();
+    mount(
+        Some("tmpfs"),
+        dir,
+        Some("tmpfs"),
+        MsFlags::empty(),
+        None::<&str>,
+    )
+    .unwrap();
+    fs::copy("/bin/true", dir.join("true")).unwrap();
+
+    let gate = ExecGate::hold(dir).unwrap();
+
+    // the children of toda are let through, but the exec of the grandchild is held
+    let script = format!("{} && echo done", dir.join("true").display());
+    let mut child = Command::new("sh")
+        .args(&["-c", &script])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(child.try_wait().unwrap().is_none());
+
+    drop(gate);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"done\n");
+
+    umount(dir).unwrap();
+}
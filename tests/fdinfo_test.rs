@@ -0,0 +1,94 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::PathBuf;
+
+use toda::replacer::parse_fdinfo;
+
+#[test]
+fn parse_regular_file() {
+    let info = parse_fdinfo("pos:\t42\nflags:\t0102002\nmnt_id:\t27\nino:\t1057\n").unwrap();
+    assert_eq!(info.pos, 42);
+    assert_eq!(info.flags, 0o102002);
+    assert!(info.epoll_targets.is_empty());
+    assert!(info.uring_files.is_empty());
+}
+
+#[test]
+fn parse_epoll() {
+    let content = "pos:\t0\n\
+                   flags:\t02\n\
+                   mnt_id:\t15\n\
+                   ino:\t1057\n\
+                   tfd:        5 events:       19 data:   7fff00000005  pos:0 ino:61af sdev:7\n\
+                   tfd:       12 events:        1 data:              c  pos:0 ino:61b0 sdev:7\n";
+    let info = parse_fdinfo(content).unwrap();
+    assert_eq!(info.flags, 0o2);
+
+    let targets: Vec<_> = info
+        .epoll_targets
+        .iter()
+        .map(|target| (target.fd, target.events, target.data))
+        .collect();
+    assert_eq!(targets, vec![(5, 0x19, 0x7fff00000005), (12, 0x1, 0xc)]);
+}
+
+#[test]
+fn parse_io_uring() {
+    let content = "pos:\t0\n\
+                   flags:\t02000002\n\
+                   mnt_id:\t15\n\
+                   SqMask:\t0x3\n\
+                   UserFiles:\t2\n\
+                   \x20   0: /tmp/a\n\
+                   \x20   1: /tmp/dir with space\n\
+                   UserBufs:\t0\n\
+                   \x20   0: 0x7f0000000000/4096\n";
+    let info = parse_fdinfo(content).unwrap();
+    assert_eq!(
+        info.uring_files,
+        vec![
+            (0, PathBuf::from("/tmp/a")),
+            (1, PathBuf::from("/tmp/dir with space"))
+        ]
+    );
+}
+
+#[test]
+fn missing_fields() {
+    // the lines which are never read are not required
+    let info = parse_fdinfo("mnt_id:\t27\n\n").unwrap();
+    assert_eq!(info.pos, 0);
+    assert_eq!(info.flags, 0);
+
+    // but a line without its value is rejected
+    assert!(parse_fdinfo("pos:\nflags:\t02\n").is_err());
+    assert!(parse_fdinfo("pos:\t0\nflags:\n").is_err());
+    assert!(parse_fdinfo("tfd:        5 events:       19  pos:0 ino:61af sdev:7\n").is_err());
+    assert!(parse_fdinfo("tfd:        5 data:   7fff00000005  pos:0 ino:61af sdev:7\n").is_err());
+}
+
+#[test]
+fn malformed_input() {
+    assert!(parse_fdinfo("pos:\tabc\n").is_err());
+    assert!(parse_fdinfo("pos:\t-1\n").is_err());
+
+    // the flags are in octal
+    assert!(parse_fdinfo("flags:\t0x02\n").is_err());
+    assert!(parse_fdinfo("flags:\t0109\n").is_err());
+
+    // the events and data of epoll targets are in hex, and the fd is in decimal
+    assert!(parse_fdinfo("tfd:      0x5 events:       19 data:   7f\n").is_err());
+    assert!(parse_fdinfo("tfd:        5 events:       zz data:   7f\n").is_err());
+    assert!(parse_fdinfo("tfd:        5 events:       19 data:   xyz\n").is_err());
+}
@@ -0,0 +1,322 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::{symlink, FileExt};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::sys::stat::Mode;
+use nix::unistd::{lseek, mkfifo, Whence};
+use toda::hookfs::testing::TestMount;
+
+#[test]
+fn fault_rate() {
+    let mount = TestMount::mount(
+        "fault_rate",
+        r#"[{
+            "type": "fault",
+            "methods": ["open"],
+            "path": "/tmp/test_mnt/fault_rate/*",
+            "percent": 50,
+            "faults": [{"errno": 5, "weight": 1}],
+            "seed": 1
+        }]"#,
+    )
+    .unwrap();
+    fs::write(mount.backend.join("file"), b"hello world").unwrap();
+
+    let mut failed = 0;
+    for _ in 0..200 {
+        match File::open(mount.path.join("file")) {
+            Ok(_) => {}
+            Err(err) => {
+                assert_eq!(err.raw_os_error(), Some(libc::EIO));
+                failed += 1;
+            }
+        }
+    }
+    assert!(failed > 60 && failed < 140, "{}", failed);
+
+    // the injection stops with the injectors
+    mount.set_config("[]").unwrap();
+    File::open(mount.path.join("file")).unwrap();
+}
+
+#[test]
+fn read_latency() {
+    let mount = TestMount::mount(
+        "read_latency",
+        r#"[{"type": "latency", "methods": ["read"], "percent": 100, "latency": "100ms"}]"#,
+    )
+    .unwrap();
+    fs::write(mount.backend.join("file"), b"hello world").unwrap();
+
+    let mut file = File::open(mount.path.join("file")).unwrap();
+    let start = Instant::now();
+    let mut content = String::new();
+    file.read_to_string(&mut content).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(content, "hello world");
+}
+
+#[test]
+fn write_mistake() {
+    let mount = TestMount::mount(
+        "write_mistake",
+        r#"[{
+            "type": "mistake",
+            "methods": ["write"],
+            "percent": 100,
+            "mistake": {"filling": "zero", "maxLength": 16, "maxOccurrences": 1}
+        }]"#,
+    )
+    .unwrap();
+
+    fs::write(mount.path.join("file"), vec![0xffu8; 4096]).unwrap();
+
+    // between 1 and 16 bytes are zeroed on the backend, in a single range
+    let written = fs::read(mount.backend.join("file")).unwrap();
+    assert_eq!(written.len(), 4096);
+    let zeroed: Vec<_> = written
+        .iter()
+        .enumerate()
+        .filter(|(_, byte)| **byte == 0)
+        .map(|(i, _)| i)
+        .collect();
+    assert!(!zeroed.is_empty() && zeroed.len() <= 16);
+    assert_eq!(zeroed.last().unwrap() - zeroed[0] + 1, zeroed.len());
+}
+
+// create_sparse creates a file on the backend with a hole between two blocks of data
+fn create_sparse(mount: &TestMount) -> File {
+    let file = File::create(mount.backend.join("sparse")).unwrap();
+    file.write_all_at(&[b'a'; 4096], 0).unwrap();
+    file.write_all_at(&[b'b'; 4096], 1 << 20).unwrap();
+    file
+}
+
+#[test]
+fn sparse_seek() {
+    let mount = TestMount::mount("sparse_seek", "[]").unwrap();
+    let backend = create_sparse(&mount);
+    let mut file = File::open(mount.path.join("sparse")).unwrap();
+
+    // the holes are found through the FUSE at the same offsets as on the backend
+    for &(offset, whence) in &[
+        (0, Whence::SeekData),
+        (0, Whence::SeekHole),
+        (4096, Whence::SeekData),
+        (1 << 20, Whence::SeekHole),
+    ] {
+        assert_eq!(
+            lseek(file.as_raw_fd(), offset, whence).unwrap(),
+            lseek(backend.as_raw_fd(), offset, whence).unwrap()
+        );
+    }
+    assert_eq!(lseek(file.as_raw_fd(), 0, Whence::SeekHole).unwrap(), 4096);
+    assert_eq!(
+        lseek(file.as_raw_fd(), 4096, Whence::SeekData).unwrap(),
+        1 << 20
+    );
+
+    // the file is read on from the offset sought
+    let mut tail = String::new();
+    file.read_to_string(&mut tail).unwrap();
+    assert_eq!(tail, "b".repeat(4096));
+}
+
+#[test]
+fn sparse_mistake() {
+    let mount = TestMount::mount(
+        "sparse_mistake",
+        r#"[{
+            "type": "mistake",
+            "methods": ["read"],
+            "percent": 100,
+            "mistake": {
+                "filling": "random",
+                "maxLength": 65536,
+                "maxOccurrences": 64,
+                "preserveHoles": true
+            }
+        }]"#,
+    )
+    .unwrap();
+    create_sparse(&mount);
+
+    // the data read through the FUSE is corrupted, but the hole is still read as zero
+    let data = fs::read(mount.path.join("sparse")).unwrap();
+    assert_eq!(data.len(), (1 << 20) + 4096);
+    assert!(data[4096..1 << 20].iter().all(|byte| *byte == 0));
+}
+
+#[test]
+fn fsync_on_shutdown() {
+    let mount = TestMount::mount(
+        "fsync_on_shutdown",
+        r#"[{"type": "latency", "methods": ["fsync"], "percent": 100, "latency": "10s"}]"#,
+    )
+    .unwrap();
+    let file = File::create(mount.path.join("file")).unwrap();
+
+    // the delayed fsync is let through once the FUSE is shutting down
+    let start = Instant::now();
+    let fsync = std::thread::spawn(move || file.sync_all());
+    std::thread::sleep(Duration::from_millis(100));
+    mount.hookfs.shutdown();
+    fsync.join().unwrap().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(10));
+
+    // and the injection is never enabled again
+    mount.hookfs.enable_injection();
+    assert!(!mount.hookfs.injection_enabled());
+}
+
+#[test]
+fn teardown() {
+    let mount = TestMount::mount("teardown", "[]").unwrap();
+    let backend_file = mount.backend.join("file");
+    fs::write(&backend_file, b"hello world").unwrap();
+    let backend_fds = || {
+        fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| fs::read_link(entry.unwrap().path()).ok())
+            .filter(|target| target == &backend_file)
+            .count()
+    };
+
+    // the file opened through the FUSE is opened on the backend by the daemon in this process
+    let _file = File::open(mount.path.join("file")).unwrap();
+    assert_eq!(backend_fds(), 1);
+
+    futures::executor::block_on(mount.hookfs.teardown());
+    assert_eq!(backend_fds(), 0);
+}
+
+#[test]
+fn link_target() {
+    let mount = TestMount::mount("link_target", "[]").unwrap();
+
+    // the link created on the backend is read with the mount path
+    symlink(mount.backend.join("file"), mount.backend.join("hidden")).unwrap();
+    let target = fs::read_link(mount.path.join("hidden")).unwrap();
+    assert_eq!(target, mount.path.join("file"));
+
+    // the link created through the FUSE is stored with the mount path
+    symlink(mount.backend.join("file"), mount.path.join("created")).unwrap();
+    let target = fs::read_link(mount.backend.join("created")).unwrap();
+    assert_eq!(target, mount.path.join("file"));
+
+    // the other links are kept as they are
+    symlink("file", mount.path.join("relative")).unwrap();
+    let target = fs::read_link(mount.path.join("relative")).unwrap();
+    assert_eq!(target, PathBuf::from("file"));
+}
+
+#[test]
+fn session_lost() {
+    let mount = TestMount::mount("session_lost", "[]").unwrap();
+
+    // the session ended by the recovery is not lost
+    mount.hookfs.shutdown();
+    mount.hookfs.end_session(&Ok(()));
+    assert_eq!(mount.hookfs.session_error(), None);
+    drop(mount);
+
+    let mount = TestMount::mount("session_lost", "[]").unwrap();
+    let err = std::io::Error::from_raw_os_error(libc::ENODEV);
+    mount.hookfs.end_session(&Err(err));
+    futures::executor::block_on(mount.hookfs.session_ended());
+    assert!(mount.hookfs.session_error().is_some());
+    assert!(!mount.hookfs.injection_enabled());
+}
+
+// mount_fifo mounts the FUSE with a FIFO on the backend, which is shown as a regular file, so that
+// it's opened, read and written through the daemon rather than served by the kernel
+fn mount_fifo(name: &str) -> TestMount {
+    let config = format!(
+        r#"[{{"type": "attrOverride", "path": "/tmp/test_mnt/{}/fifo", "percent": 100, "kind": "regularFile"}}]"#,
+        name
+    );
+    let mount = TestMount::mount(name, &config).unwrap();
+    mkfifo(&mount.backend.join("fifo"), Mode::S_IRWXU).unwrap();
+    mount
+}
+
+// read_fifo reads the FIFO through the FUSE in another thread, until all the writers close it
+fn read_fifo(mount: &TestMount) -> Receiver<String> {
+    let path = mount.path.join("fifo");
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut content = String::new();
+        File::open(path)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        tx.send(content).unwrap();
+    });
+    rx
+}
+
+#[test]
+fn fifo_reader_blocks() {
+    let mount = mount_fifo("fifo_reader_blocks");
+
+    // the reader waits for a writer, instead of reading EOF at once
+    let reader = read_fifo(&mount);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(reader.try_recv(), Err(TryRecvError::Empty));
+
+    fs::write(mount.backend.join("fifo"), b"hello").unwrap();
+    assert_eq!(
+        reader.recv_timeout(Duration::from_secs(5)).unwrap(),
+        "hello"
+    );
+}
+
+#[test]
+fn fifo_shard() {
+    let mount = mount_fifo("fifo_shard");
+    for i in 0..32 {
+        fs::write(mount.backend.join(format!("file{}", i)), i.to_string()).unwrap();
+    }
+
+    // the files opened and read while the reader is waiting are spread over all the shards,
+    // including the one of the reader
+    let reader = read_fifo(&mount);
+    thread::sleep(Duration::from_millis(200));
+    let path = mount.path.clone();
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for i in 0..32 {
+            let content = fs::read_to_string(path.join(format!("file{}", i))).unwrap();
+            assert_eq!(content, i.to_string());
+        }
+        tx.send(()).unwrap();
+    });
+    rx.recv_timeout(Duration::from_secs(10)).unwrap();
+
+    // the writer is closed without writing, after which the reader reads EOF
+    drop(
+        OpenOptions::new()
+            .write(true)
+            .open(mount.backend.join("fifo"))
+            .unwrap(),
+    );
+    assert_eq!(reader.recv_timeout(Duration::from_secs(5)).unwrap(), "");
+}
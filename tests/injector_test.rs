@@ -0,0 +1,534 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use fuser::{consts, FileAttr, FileType};
+use futures::executor::block_on;
+use toda::hookfs::{Entry, Open, Poll, Reply, StatFs};
+use toda::injector::{
+    Injector, InjectorConfig, IoRange, Method, MultiInjector, Preset, IO_RANGE, PRESETS,
+};
+
+fn build(config: &str) -> MultiInjector {
+    let config: Vec<InjectorConfig> = serde_json::from_str(config).unwrap();
+    MultiInjector::build(config).unwrap()
+}
+
+fn faults(injector: &MultiInjector) -> Vec<bool> {
+    (0..256)
+        .map(|i| {
+            let path = format!("/mnt/file-{}", i);
+            block_on(injector.inject(&Method::READ, Path::new(&path))).is_err()
+        })
+        .collect()
+}
+
+#[test]
+fn seeded_faults() {
+    let config = r#"[{
+        "type": "fault",
+        "percent": 50,
+        "faults": [{"errno": 5, "weight": 1}],
+        "seed": 42
+    }]"#;
+
+    let injected = faults(&build(config));
+    assert_eq!(injected, faults(&build(config)));
+    assert!(injected.iter().any(|injected| *injected));
+    assert!(injected.iter().any(|injected| !*injected));
+}
+
+#[test]
+fn seeded_mistakes() {
+    let config = r#"[{
+        "type": "mistake",
+        "percent": 100,
+        "mistake": {"filling": "random", "maxLength": 16, "maxOccurrences": 4},
+        "seed": 7
+    }]"#;
+
+    let sabotage = |injector: &MultiInjector| {
+        let mut data = vec![0u8; 1024];
+        injector
+            .inject_write_data(Path::new("/mnt/file"), &mut data)
+            .unwrap();
+        data
+    };
+
+    let (first, second) = (build(config), build(config));
+    for _ in 0..16 {
+        assert_eq!(sabotage(&first), sabotage(&second));
+    }
+}
+
+#[test]
+fn mistake_density() {
+    let injector = build(
+        r#"[{
+            "type": "mistake",
+            "percent": 100,
+            "mistake": {"filling": "zero", "maxLength": 1, "density": 8},
+            "seed": 3
+        }]"#,
+    );
+
+    // the corrupted bytes of 1MiB written at once, or in 4KiB requests
+    let corrupted = |size: usize| {
+        let mut corrupted = 0;
+        for offset in (0..1 << 20).step_by(size) {
+            let range = IoRange {
+                offset: offset as u64,
+                size: size as u64,
+            };
+            let mut data = vec![1u8; size];
+            let path = Path::new("/mnt/file");
+            block_on(IO_RANGE.scope(range, async {
+                injector.inject_write_data(path, &mut data).unwrap()
+            }));
+            corrupted += data.iter().filter(|byte| **byte == 0).count();
+        }
+        corrupted
+    };
+
+    // about 8 bytes per KiB, whatever the size of the requests
+    for size in [1 << 20, 4096, 1000].iter() {
+        let corrupted = corrupted(*size);
+        assert!(corrupted > 7800 && corrupted < 8600, "{}", corrupted);
+    }
+}
+
+#[test]
+fn offset_range_faults() {
+    let injector = build(
+        r#"[{
+            "type": "fault",
+            "percent": 100,
+            "faults": [{"errno": 5, "weight": 1}],
+            "offsetRange": {"start": 0, "end": 4096}
+        }]"#,
+    );
+
+    let read = |offset, size| {
+        let range = IoRange { offset, size };
+        let inject = injector.inject(&Method::READ, Path::new("/mnt/db"));
+        block_on(IO_RANGE.scope(range, inject)).is_err()
+    };
+
+    assert!(read(0, 512));
+    assert!(read(4000, 512));
+    assert!(!read(4096, 512));
+    assert!(!read(8192, 4096));
+
+    // the requests without a range, e.g. getattr, are never injected
+    assert!(block_on(injector.inject(&Method::GETATTR, Path::new("/mnt/db"))).is_ok());
+}
+
+#[test]
+fn composite_conditions() {
+    let injector = build(
+        r#"[{
+            "type": "composite",
+            "when": {"all": [
+                {"path": "/mnt/data/**/*"},
+                {"methods": ["write"]},
+                {"not": {"path": "/mnt/data/log/*"}}
+            ]},
+            "injectors": [{
+                "type": "fault",
+                "percent": 100,
+                "faults": [{"errno": 5, "weight": 1}]
+            }]
+        }]"#,
+    );
+    let inject = |method, path| block_on(injector.inject(&method, Path::new(path))).is_err();
+
+    assert!(inject(Method::WRITE, "/mnt/data/db/file"));
+    assert!(!inject(Method::READ, "/mnt/data/db/file"));
+    assert!(!inject(Method::WRITE, "/mnt/other/file"));
+    assert!(!inject(Method::WRITE, "/mnt/data/log/file"));
+
+    // the requests without a caller never match the conditions on it
+    let injector = build(
+        r#"[{
+            "type": "composite",
+            "when": {"pids": [1]},
+            "injectors": [{
+                "type": "fault",
+                "percent": 100,
+                "faults": [{"errno": 5, "weight": 1}]
+            }]
+        }]"#,
+    );
+    assert!(block_on(injector.inject(&Method::WRITE, Path::new("/mnt/file"))).is_ok());
+}
+
+// inject_timed injects a write on the runtime of tokio, which drives the latencies
+fn inject_timed(config: &str) -> (Option<i32>, Duration) {
+    let injector = build(config);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let start = Instant::now();
+    let result = runtime.block_on(injector.inject(&Method::WRITE, Path::new("/mnt/file")));
+    (result.err().map(|err| err.into()), start.elapsed())
+}
+
+#[test]
+fn chained_faults() {
+    // all the latencies are applied, even after the fault
+    let (errno, elapsed) = inject_timed(
+        r#"[
+            {"type": "fault", "percent": 100, "faults": [{"errno": 5, "weight": 1}]},
+            {"type": "latency", "percent": 100, "latency": "50ms"}
+        ]"#,
+    );
+    assert_eq!(errno, Some(libc::EIO));
+    assert!(elapsed >= Duration::from_millis(50));
+
+    // the following injectors are skipped if the fault doesn't continue
+    let (errno, elapsed) = inject_timed(
+        r#"[
+            {
+                "type": "fault",
+                "percent": 100,
+                "faults": [{"errno": 5, "weight": 1}],
+                "continueOnFault": false
+            },
+            {"type": "latency", "percent": 100, "latency": "10s"}
+        ]"#,
+    );
+    assert_eq!(errno, Some(libc::EIO));
+    assert!(elapsed < Duration::from_secs(10));
+
+    // the first fault in the order of priorities wins
+    let (errno, _) = inject_timed(
+        r#"[
+            {"type": "fault", "percent": 100, "faults": [{"errno": 5, "weight": 1}]},
+            {
+                "type": "fault",
+                "percent": 100,
+                "faults": [{"errno": 28, "weight": 1}],
+                "priority": 10
+            }
+        ]"#,
+    );
+    assert_eq!(errno, Some(libc::ENOSPC));
+}
+
+// inject_concurrently injects two writes concurrently, and returns the time to finish each of them
+fn inject_concurrently(config: &str) -> (Duration, Duration) {
+    let injector = build(config);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let start = Instant::now();
+    let timed = || async {
+        let path = Path::new("/mnt/file");
+        injector.inject(&Method::WRITE, path).await.unwrap();
+        start.elapsed()
+    };
+    runtime.block_on(async { futures::join!(timed(), timed()) })
+}
+
+#[test]
+fn latency_queue_depth() {
+    let (first, second) = inject_concurrently(
+        r#"[{"type": "latency", "percent": 100, "latency": "100ms", "maxConcurrent": 1}]"#,
+    );
+    assert!(first.max(second) >= Duration::from_millis(200));
+
+    let (first, second) = inject_concurrently(
+        r#"[{
+            "type": "latency",
+            "percent": 100,
+            "latency": "100ms",
+            "maxConcurrent": 1,
+            "overflow": "pass"
+        }]"#,
+    );
+    assert!(first.min(second) < Duration::from_millis(100));
+    assert!(first.max(second) >= Duration::from_millis(100));
+}
+
+#[test]
+fn latency_phase() {
+    let injector =
+        build(r#"[{"type": "latency", "percent": 100, "latency": "100ms", "phase": "after"}]"#);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let path = Path::new("/mnt/file");
+
+    let start = Instant::now();
+    runtime
+        .block_on(injector.inject(&Method::FSYNC, path))
+        .unwrap();
+    assert!(start.elapsed() < Duration::from_millis(100));
+
+    let start = Instant::now();
+    runtime
+        .block_on(injector.inject_after(&Method::FSYNC, path))
+        .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn latency_bandwidth() {
+    let injector =
+        build(r#"[{"type": "latency", "percent": 100, "bandwidth": 1048576, "phase": "after"}]"#);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut write = |size| {
+        let range = IoRange { offset: 0, size };
+        let start = Instant::now();
+        let inject = injector.inject_after(&Method::WRITE, Path::new("/mnt/file"));
+        runtime.block_on(IO_RANGE.scope(range, inject)).unwrap();
+        start.elapsed()
+    };
+
+    // 200KiB take 200ms at 1MiB/s, while a single byte takes as long as a chunk of 4KiB
+    let elapsed = write(200 * 1024);
+    assert!(elapsed >= Duration::from_millis(190));
+    assert!(write(1) < Duration::from_millis(100));
+}
+
+#[test]
+fn latency_interrupt() {
+    let injector = build(r#"[{"type": "latency", "percent": 100, "latency": "10s"}]"#);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let path = Path::new("/mnt/file");
+
+    // the delay in flight is cancelled by the interrupt
+    let start = Instant::now();
+    runtime.block_on(async {
+        let delayed = injector.inject(&Method::WRITE, path);
+        let interrupt = async {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            injector.interrupt();
+        };
+        futures::join!(delayed, interrupt).0.unwrap();
+    });
+    assert!(start.elapsed() < Duration::from_secs(10));
+
+    // while the following requests are still delayed
+    let result = runtime.block_on(async {
+        tokio::time::timeout(
+            Duration::from_millis(100),
+            injector.inject(&Method::WRITE, path),
+        )
+        .await
+    });
+    assert!(result.is_err());
+}
+
+#[test]
+fn poll_mistakes() {
+    let ready = (libc::POLLIN | libc::POLLOUT) as u32;
+    let poll = |injector: &MultiInjector| {
+        let mut poll = Poll::new(ready);
+        injector
+            .inject_reply(
+                &Method::POLL,
+                Path::new("/mnt/file"),
+                &mut Reply::Poll(&mut poll),
+            )
+            .unwrap();
+        poll.revents
+    };
+
+    // the mistakes without methods leave the polls alone
+    let injector = build(
+        r#"[{
+            "type": "mistake",
+            "percent": 100,
+            "mistake": {"filling": "zero", "maxLength": 1, "maxOccurrences": 1}
+        }]"#,
+    );
+    assert_eq!(poll(&injector), ready);
+
+    let injector = build(
+        r#"[{
+            "type": "mistake",
+            "percent": 100,
+            "methods": ["read", "poll"],
+            "mistake": {"filling": "zero", "maxLength": 1, "maxOccurrences": 1}
+        }]"#,
+    );
+    assert_eq!(poll(&injector), 0);
+}
+
+#[test]
+fn statfs_override() {
+    let injector = build(
+        r#"[{
+            "type": "statFsOverride",
+            "percent": 100,
+            "bfree": "5%",
+            "bavail": "5%",
+            "files": 1000
+        }]"#,
+    );
+
+    let mut statfs = StatFs::new(10000, 8000, 7000, 500, 400, 4096, 255, 4096);
+    injector
+        .inject_reply(
+            &Method::STATFS,
+            Path::new("/mnt"),
+            &mut Reply::StatFs(&mut statfs),
+        )
+        .unwrap();
+
+    assert_eq!(statfs.blocks, 10000);
+    assert_eq!(statfs.bfree, 500);
+    assert_eq!(statfs.bavail, 500);
+    assert_eq!(statfs.files, 1000);
+    assert_eq!(statfs.ffree, 400);
+}
+
+#[test]
+fn not_found() {
+    let injector = build(r#"[{"type": "notFound", "path": "/mnt/data/*", "percent": 100}]"#);
+    let lookup = |path| block_on(injector.inject(&Method::LOOKUP, Path::new(path)));
+
+    assert_eq!(
+        lookup("/mnt/data/file").err().map(i32::from),
+        Some(libc::ENOENT)
+    );
+    assert!(lookup("/mnt/other").is_ok());
+    assert!(block_on(injector.inject(&Method::GETATTR, Path::new("/mnt/data/file"))).is_ok());
+
+    // the negative entry is replied instead, to be cached by the kernel
+    let injector = build(
+        r#"[{"type": "notFound", "path": "/mnt/data/*", "percent": 100, "entryTimeout": "5s"}]"#,
+    );
+    assert!(block_on(injector.inject(&Method::LOOKUP, Path::new("/mnt/data/file"))).is_ok());
+
+    let attr = file_attr(42);
+    let mut entry = Entry::new(attr, 0);
+    injector
+        .inject_reply(
+            &Method::LOOKUP,
+            Path::new("/mnt/data/file"),
+            &mut Reply::Entry(&mut entry),
+        )
+        .unwrap();
+    assert_eq!(entry.stat.ino, 0);
+    assert_eq!(entry.ttl, Duration::from_secs(5));
+}
+
+fn file_attr(ino: u64) -> FileAttr {
+    FileAttr {
+        ino,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0o644,
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 4096,
+        padding: 0,
+        flags: 0,
+    }
+}
+
+#[test]
+fn cache_timeout() {
+    let injector = build(
+        r#"[{"type": "cacheTimeout", "path": "/mnt/cached/*", "percent": 100, "entryTimeout": "1m"}]"#,
+    );
+
+    let lookup = |path| {
+        let mut entry = Entry::new(file_attr(42), 0);
+        let mut reply = Reply::Entry(&mut entry);
+        injector
+            .inject_reply(&Method::LOOKUP, Path::new(path), &mut reply)
+            .unwrap();
+        entry.ttl
+    };
+
+    assert_eq!(lookup("/mnt/cached/file"), Duration::from_secs(60));
+    assert_eq!(lookup("/mnt/other"), Duration::from_secs(0));
+}
+
+#[test]
+fn presets() {
+    for preset in PRESETS {
+        MultiInjector::build(preset.config().unwrap()).unwrap();
+    }
+    assert!(Preset::find("slow-disk").is_ok());
+    assert!(Preset::find("no-such-preset").is_err());
+
+    let config = Preset::find("disk-full").unwrap().config().unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+    let result = block_on(injector.inject(&Method::WRITE, Path::new("/mnt/file")));
+    assert_eq!(result.err().map(i32::from), Some(libc::ENOSPC));
+}
+
+#[test]
+fn protected_paths() {
+    let injector = build(
+        r#"[{
+            "type": "protected",
+            "paths": ["/protected/**/*.lock"]
+        }, {
+            "type": "fault",
+            "percent": 100,
+            "faults": [{"errno": 5, "weight": 1}]
+        }, {
+            "type": "mistake",
+            "percent": 100,
+            "mistake": {"filling": "zero", "maxLength": 4, "maxOccurrences": 1}
+        }]"#,
+    );
+
+    let lock = Path::new("/protected/dir/app.lock");
+    assert!(block_on(injector.inject(&Method::READ, lock)).is_ok());
+    let mut data = vec![1u8; 16];
+    assert!(injector.inject_write_data(lock, &mut data).is_ok());
+    assert_eq!(data, vec![1u8; 16]);
+
+    let data_file = Path::new("/protected/dir/data");
+    assert!(block_on(injector.inject(&Method::READ, data_file)).is_err());
+
+    // the paths are only protected from the injectors built with them
+    let unprotected =
+        build(r#"[{"type": "fault", "percent": 100, "faults": [{"errno": 5, "weight": 1}]}]"#);
+    assert!(block_on(unprotected.inject(&Method::READ, lock)).is_err());
+
+    // an invalid glob is rejected
+    let config = r#"[{"type": "protected", "paths": ["/protected/[a"]}]"#;
+    let config: Vec<InjectorConfig> = serde_json::from_str(config).unwrap();
+    assert!(MultiInjector::build(config).is_err());
+}
+
+#[test]
+fn cache_mode() {
+    let injector = build(
+        r#"[{"type": "cacheMode", "path": "/mnt/db/*.wal", "directIo": true, "keepCache": false}]"#,
+    );
+    let open = |path, flags| {
+        let mut open = Open::new(1, flags);
+        injector
+            .inject_reply(&Method::OPEN, Path::new(path), &mut Reply::Open(&mut open))
+            .unwrap();
+        open.flags
+    };
+
+    let keep_cache = consts::FOPEN_KEEP_CACHE as i32;
+    assert_eq!(
+        open("/mnt/db/000001.wal", keep_cache),
+        consts::FOPEN_DIRECT_IO as i32
+    );
+    assert_eq!(open("/mnt/db/000001.sst", keep_cache), keep_cache);
+}
@@ -0,0 +1,129 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::executor::block_on;
+use structopt::StructOpt;
+use toda::hookfs::runtime::{configure, RuntimeOptions};
+use toda::hookfs::testing::TestMount;
+use toda::hookfs::InodeStats;
+
+// the runtime options apply to the whole test binary, so every test in it caps the inode map
+fn mount(name: &str) -> TestMount {
+    configure(RuntimeOptions::from_iter(&["toda", "--max-inodes", "8"]));
+    TestMount::mount(name, "[]").unwrap()
+}
+
+// forget_files creates and removes the files through the FUSE, which are forgotten by the kernel
+// once they're removed
+fn forget_files(mount: &TestMount, count: usize) {
+    for i in 0..count {
+        let path = mount.path.join(format!("forgotten-{}", i));
+        File::create(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+// wait_stats waits until the forgets sent by the kernel asynchronously are handled
+fn wait_stats<F: Fn(&InodeStats) -> bool>(mount: &TestMount, done: F) -> InodeStats {
+    let start = Instant::now();
+    loop {
+        let stats = block_on(mount.hookfs.inode_stats(0));
+        if done(&stats) || start.elapsed() > Duration::from_secs(10) {
+            return stats;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn inode_cap() {
+    let mount = mount("inode_cap");
+
+    // the forgotten inodes are kept until the map grows over its capacity
+    forget_files(&mount, 32);
+    let stats = wait_stats(&mount, |stats| stats.evicted >= 24);
+    assert_eq!(stats.capacity, Some(8));
+    assert_eq!(stats.inodes, 8);
+    assert_eq!(stats.unreferenced, 7);
+    assert_eq!(stats.evicted, 24);
+
+    // the root is never evicted
+    assert_eq!(fs::read_dir(&mount.path).unwrap().count(), 0);
+}
+
+#[test]
+fn referenced_inodes() {
+    let mount = mount("referenced_inodes");
+
+    // the files kept open are referenced by the kernel all the time
+    let mut files: Vec<_> = (0..16)
+        .map(|i| {
+            let path = mount.path.join(format!("referenced-{}", i));
+            fs::write(&path, format!("file {}", i)).unwrap();
+            File::open(&path).unwrap()
+        })
+        .collect();
+
+    forget_files(&mount, 32);
+    let stats = wait_stats(&mount, |stats| stats.evicted >= 32);
+    assert_eq!(stats.inodes, 17);
+    assert_eq!(stats.unreferenced, 0);
+    assert_eq!(stats.evicted, 32);
+
+    // the map is over its capacity, but all the referenced inodes are still served
+    for (i, file) in files.iter_mut().enumerate() {
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, format!("file {}", i));
+
+        let path = mount.path.join(format!("referenced-{}", i));
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+    }
+}
+
+#[test]
+fn resolve_renamed() {
+    let mount = mount("resolve_renamed");
+    fs::write(mount.backend.join("file"), b"hello").unwrap();
+    let file = File::open(mount.path.join("file")).unwrap();
+
+    // the file is moved on the backend directly, behind the FUSE
+    fs::create_dir(mount.backend.join("dir")).unwrap();
+    fs::rename(mount.backend.join("file"), mount.backend.join("dir/moved")).unwrap();
+
+    // the inode is found again by its handle, and opened again without being looked up
+    assert_eq!(file.metadata().unwrap().len(), 5);
+    let reopened = format!("/proc/self/fd/{}", file.as_raw_fd());
+    assert_eq!(fs::read_to_string(&reopened).unwrap(), "hello");
+}
+
+#[test]
+fn resolve_unlinked() {
+    let mount = mount("resolve_unlinked");
+    fs::write(mount.backend.join("file"), b"hello").unwrap();
+    let file = File::open(mount.path.join("file")).unwrap();
+
+    // the file is still open by the FUSE after it's removed on the backend, and is found by its
+    // handle, but it's never taken for the file at the path of a deleted one
+    fs::remove_file(mount.backend.join("file")).unwrap();
+    fs::write(mount.backend.join("file (deleted)"), b"decoy!").unwrap();
+
+    let err = file.metadata().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+}
@@ -1,8 +1,15 @@
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::AsRawFd;
 use std::sync::mpsc::channel;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use anyhow::anyhow;
+use structopt::StructOpt;
+use toda::hookfs::runtime::{configure, RuntimeOptions};
+use toda::hookfs::testing::TestMount;
 use toda::jsonrpc::{self, new_handler, Comm};
+use toda::replacer::ReplacerStats;
+use toda::telemetry::LogReloader;
 #[test]
 fn test_status_good() {
     let (tx, _rx) = channel();
@@ -30,6 +37,110 @@ fn test_status_bad() {
     assert_eq!(rx.recv().unwrap(), Comm::Shutdown);
 }
 
+#[test]
+fn test_status_replacer_stats() {
+    let (tx, _rx) = channel();
+    let mut stats = ReplacerStats {
+        processes_patched: 1,
+        fds_replaced: 2,
+        ..Default::default()
+    };
+    stats.skip(3, "/mnt/fifo", "fifo");
+    let io = new_handler(
+        jsonrpc::RpcImpl::new(Mutex::new(Ok(())), Mutex::new(tx), None).with_replacer_stats(stats),
+    );
+    let request = r#"{"jsonrpc": "2.0","method":"get_status","params":["replacer"],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"{\"processesPatched\":1,\"fdsReplaced\":2,\"cwdsReplaced\":0,\"mmapsRemapped\":0,\"skipped\":[{\"pid\":3,\"target\":\"/mnt/fifo\",\"reason\":\"fifo\"}]}","id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+// the limits of the handles apply to the whole test binary, so it's the only test mounting a FUSE
+#[test]
+fn test_status_unsupported_and_handles() {
+    configure(RuntimeOptions::from_iter(&[
+        "toda",
+        "--max-open-files",
+        "4",
+    ]));
+    let mount = TestMount::mount("jsonrpc_status", "[]").unwrap();
+    fs::write(mount.backend.join("file"), b"content").unwrap();
+
+    let (tx, _rx) = channel();
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        Some(mount.hookfs.clone()),
+    ));
+    let status = |inst: &str| {
+        let request = format!(
+            r#"{{"jsonrpc": "2.0","method":"get_status","params":["{}"],"id":1}}"#,
+            inst
+        );
+        io.handle_request_sync(&request).unwrap()
+    };
+
+    let file = OpenOptions::new()
+        .write(true)
+        .open(mount.path.join("file"))
+        .unwrap();
+    // the kernel turns the ENOSYS of the FUSE into EOPNOTSUPP
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, 4096) };
+    assert_eq!(ret, -1);
+    assert_eq!(
+        std::io::Error::last_os_error().raw_os_error(),
+        Some(libc::EOPNOTSUPP)
+    );
+    assert_eq!(
+        status("unsupported"),
+        r#"{"jsonrpc":"2.0","result":"{\"fallocate\":1}","id":1}"#
+    );
+
+    // the opens beyond --max-open-files fail with EMFILE, and are counted as rejected
+    let mut files = vec![file];
+    for _ in 1..4 {
+        files.push(File::open(mount.path.join("file")).unwrap());
+    }
+    let err = File::open(mount.path.join("file")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+    assert_eq!(
+        status("handles"),
+        r#"{"jsonrpc":"2.0","result":"{\"files\":4,\"dirs\":0,\"maxFiles\":4,\"maxDirs\":null,\"rejected\":1}","id":1}"#
+    );
+}
+
+#[test]
+fn test_hot_files_without_hookfs() {
+    let (tx, _rx) = channel();
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        None,
+    ));
+    let request = r#"{"jsonrpc": "2.0","method":"hot_files","params":[10],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":[],"id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_set_log_level() {
+    let (tx, _rx) = channel();
+    let level = Arc::new(Mutex::new(String::new()));
+    let reloader: LogReloader = {
+        let level = level.clone();
+        Arc::new(move |directives: &str| {
+            *level.lock().unwrap() = directives.to_string();
+            Ok(())
+        })
+    };
+    let io = new_handler(
+        jsonrpc::RpcImpl::new(Mutex::new(Ok(())), Mutex::new(tx), None).with_log_reloader(reloader),
+    );
+    let request = r#"{"jsonrpc": "2.0","method":"set_log_level","params":["info"],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"ok","id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+    assert_eq!(*level.lock().unwrap(), "info");
+}
+
 #[test]
 fn test_should_not_update_config_if_status_is_failed() {
     let (tx, _rx) = channel();
@@ -0,0 +1,39 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+
+use structopt::StructOpt;
+use toda::hookfs::runtime::{apply_limits, configure, RuntimeOptions};
+
+// the limits apply to the whole test binary, so it's the only test in it
+#[test]
+fn max_fds() {
+    let options = RuntimeOptions::from_iter(&["toda", "--max-fds", "64", "--max-memory", "65536"]);
+    configure(options);
+    apply_limits().unwrap();
+
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    assert_eq!((limit.rlim_cur, limit.rlim_max), (64, 64));
+    unsafe { libc::getrlimit(libc::RLIMIT_AS, &mut limit) };
+    assert_eq!(limit.rlim_cur, 65536 << 20);
+
+    // the opens beyond the limit fail, instead of growing the fds of toda
+    let files: Vec<_> = (0..64).map(|_| fs::File::open("/dev/null")).collect();
+    let err = files.into_iter().find_map(|file| file.err()).unwrap();
+    assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+}
@@ -0,0 +1,120 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toda::instance::{clean_stale_instances, find_stale_instances};
+use toda::mount::{analyze_mounts, MountsInfo};
+use toda::mount_injector::{MountInjector, MountMode, PermissionCheck, RecoverOptions};
+
+#[test]
+fn analyze_root_mount() {
+    let analysis = analyze_mounts("/").unwrap();
+    assert_eq!(analysis.path, Path::new("/"));
+    assert!(analysis.is_mount_point);
+    assert_eq!(analysis.mount.mount_point, Path::new("/"));
+
+    // every child is mounted on the root mount, under the path
+    for child in analysis.children.iter() {
+        assert_eq!(child.parent_id, analysis.mount.mount_id);
+    }
+
+    let mounts = MountsInfo::parse_mounts().unwrap();
+    let proc_mount = mounts.containing_mount("/proc/self").unwrap();
+    assert_eq!(proc_mount.fs_type, "proc");
+    assert!(!mounts.is_toda_mount("/proc"));
+
+    // the nested mounts are moved along with their parents
+    let children = mounts.child_mount_points("/");
+    assert!(children.iter().any(|child| child == Path::new("/proc")));
+    for child in children.iter() {
+        assert!(!children
+            .iter()
+            .any(|other| other != child && child.starts_with(other)));
+    }
+}
+
+#[test]
+fn staging_dir() {
+    let path = PathBuf::from("/tmp/test_mount/staging_dir/path");
+    let staging_dir = PathBuf::from("/tmp/test_mount/staging");
+    fs::remove_dir_all("/tmp/test_mount").ok();
+    fs::create_dir_all(&path).unwrap();
+    fs::write(path.join("file"), b"hello").unwrap();
+
+    let mut injection =
+        MountInjector::create_injection(&path, MountMode::Bind, PermissionCheck::Kernel, vec![])
+            .unwrap()
+            .with_staging_dir(Some(staging_dir.clone()))
+            .unwrap();
+    let mut guard = injection.mount().unwrap();
+
+    // the original directory is kept out of the parent of the path
+    let hidden_path = guard.hidden_path().to_owned();
+    let siblings: Vec<_> = fs::read_dir(path.parent().unwrap())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    let content = fs::read(path.join("file")).unwrap();
+    let hidden_content = fs::read(hidden_path.join("file")).unwrap();
+
+    guard.recover_mount(RecoverOptions::default()).unwrap();
+
+    assert!(hidden_path.starts_with(&staging_dir));
+    assert_eq!(siblings, vec!["path"]);
+    assert_eq!(content, b"hello");
+    assert_eq!(hidden_content, b"hello");
+    assert!(!hidden_path.exists());
+    fs::remove_dir_all("/tmp/test_mount").unwrap();
+}
+
+#[test]
+fn staging_dir_under_path() {
+    let result =
+        MountInjector::create_injection("/tmp", MountMode::Bind, PermissionCheck::Kernel, vec![])
+            .unwrap()
+            .with_staging_dir(Some(PathBuf::from("/tmp/staging")));
+    assert!(result.is_err());
+}
+
+#[test]
+fn clean_stale() {
+    let path = PathBuf::from("/tmp/test_mount_clean/path");
+    let parent = path.parent().unwrap();
+    fs::remove_dir_all(parent).ok();
+    fs::create_dir_all(&path).unwrap();
+    fs::write(path.join("file"), b"hello").unwrap();
+
+    // a crashed toda left the original directory bind-mounted, and an empty staging directory
+    let mounts = MountsInfo::parse_mounts().unwrap();
+    mounts
+        .bind_mount(&path, parent.join("__chaosfs__path__0000beef"))
+        .unwrap();
+    fs::create_dir(parent.join("__chaosfs__path__")).unwrap();
+    // the staging directory of another path
+    fs::create_dir(parent.join("__chaosfs__path__x__0000beef")).unwrap();
+
+    let mut stale = find_stale_instances(&path, None).unwrap();
+    stale.sort_by(|a, b| a.id.cmp(&b.id));
+    assert_eq!(stale.len(), 2);
+    assert_eq!((stale[0].id.as_str(), stale[0].mounted), ("", false));
+    assert_eq!((stale[1].id.as_str(), stale[1].mounted), ("0000beef", true));
+
+    let cleaned = clean_stale_instances(&path, None).unwrap();
+    assert_eq!(cleaned.len(), 2);
+    assert!(find_stale_instances(&path, None).unwrap().is_empty());
+    assert!(parent.join("__chaosfs__path__x__0000beef").exists());
+    assert_eq!(fs::read(path.join("file")).unwrap(), b"hello");
+    fs::remove_dir_all(parent).unwrap();
+}
@@ -0,0 +1,69 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::read_link;
+use std::io::Error;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use toda::namespace::Namespaces;
+
+#[test]
+fn enter_target_namespaces() {
+    // `unshare` forks the `sleep` into the new namespaces
+    let mut unshare = Command::new("unshare")
+        .args(&["--mount", "--pid", "--fork", "sleep", "100"])
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let target = std::fs::read_to_string(format!(
+        "/proc/{}/task/{}/children",
+        unshare.id(),
+        unshare.id()
+    ))
+    .unwrap()
+    .trim()
+    .parse::<i32>()
+    .unwrap();
+
+    let expected_mnt = read_link(format!("/proc/{}/ns/mnt", target)).unwrap();
+    let expected_pid = read_link(format!("/proc/{}/ns/pid", target)).unwrap();
+    assert_ne!(expected_mnt, read_link("/proc/self/ns/mnt").unwrap());
+
+    // the namespaces are entered by a forked child, as the test process is multi-threaded
+    let namespaces = Namespaces::open(target).unwrap();
+    let output = unsafe {
+        Command::new("readlink")
+            .args(&["/proc/self/ns/mnt", "/proc/self/ns/pid_for_children"])
+            .pre_exec(move || namespaces.enter().map_err(|_| Error::last_os_error()))
+            .output()
+            .unwrap()
+    };
+    let output = String::from_utf8(output.stdout).unwrap();
+    let links: Vec<_> = output.lines().collect();
+
+    assert_eq!(
+        links,
+        vec![
+            expected_mnt.to_str().unwrap(),
+            expected_pid.to_str().unwrap()
+        ]
+    );
+
+    kill(Pid::from_raw(target), Signal::SIGKILL).unwrap();
+    unshare.wait().unwrap();
+}
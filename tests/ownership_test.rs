@@ -0,0 +1,109 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use toda::hookfs::idmap::IdMap;
+use toda::hookfs::ownership::{Owner, OwnerMapping, OwnershipOptions};
+use toda::hookfs::testing::TestMount;
+
+#[test]
+fn parse_owner() {
+    let owner: Owner = "1000:100".parse().unwrap();
+    assert_eq!(
+        owner,
+        Owner {
+            uid: 1000,
+            gid: 100
+        }
+    );
+
+    assert!("1000".parse::<Owner>().is_err());
+    assert!("root:root".parse::<Owner>().is_err());
+}
+
+#[test]
+fn owner_precedence() {
+    let caller = Owner { uid: 1, gid: 1 };
+
+    let options = OwnershipOptions::default();
+    assert_eq!(options.owner(Path::new("/mnt/a"), caller), caller);
+
+    let options = OwnershipOptions {
+        squash: Some(Owner { uid: 2, gid: 2 }),
+        mappings: vec!["/mnt/data/**/*=3:3".parse::<OwnerMapping>().unwrap()],
+        ..Default::default()
+    };
+    assert_eq!(
+        options.owner(Path::new("/mnt/a"), caller),
+        Owner { uid: 2, gid: 2 }
+    );
+    assert_eq!(
+        options.owner(Path::new("/mnt/data/dir/file"), caller),
+        Owner { uid: 3, gid: 3 }
+    );
+}
+
+#[test]
+fn id_map_shift() {
+    let id_map = IdMap::parse("0 100000 65536\n", "0 100000 1000\n1000 0 1\n").unwrap();
+
+    assert_eq!(id_map.uid_to_backend(0), 100000);
+    assert_eq!(id_map.uid_to_backend(1000), 101000);
+    assert_eq!(id_map.uid_to_backend(70000), 65534);
+    assert_eq!(id_map.uid_to_workload(101000), 1000);
+    assert_eq!(id_map.uid_to_workload(0), 65534);
+    assert_eq!(id_map.gid_to_workload(0), 1000);
+
+    assert!(IdMap::parse("0 100000\n", "").is_err());
+}
+
+#[test]
+fn id_map_overflow() {
+    let id_map = IdMap::parse("0 4294967000 1000\n", "0 0 4294967295\n").unwrap();
+
+    assert_eq!(id_map.uid_to_backend(200), 4294967200);
+    assert_eq!(id_map.uid_to_backend(500), 65534);
+    assert_eq!(id_map.gid_to_backend(500), 500);
+}
+
+#[test]
+fn id_map_mounted() {
+    let mount = TestMount::mount_with("id_map_mounted", "[]", |hookfs| {
+        hookfs
+            .with_ownership(OwnershipOptions {
+                mappings: vec!["/tmp/test_mnt/id_map_mounted/squashed*=1000:1000"
+                    .parse::<OwnerMapping>()
+                    .unwrap()],
+                ..Default::default()
+            })
+            .with_id_map(IdMap::parse("0 100000 65536\n", "0 100000 65536\n").unwrap())
+    })
+    .unwrap();
+
+    // the callers and the replies are shifted by the kernel, never again by toda
+    File::create(mount.path.join("file")).unwrap();
+    let backend = fs::metadata(mount.backend.join("file")).unwrap();
+    assert_eq!((backend.uid(), backend.gid()), (0, 0));
+    let mounted = fs::metadata(mount.path.join("file")).unwrap();
+    assert_eq!((mounted.uid(), mounted.gid()), (0, 0));
+
+    // while the configured owners are given in the ids of the workload
+    File::create(mount.path.join("squashed")).unwrap();
+    let backend = fs::metadata(mount.backend.join("squashed")).unwrap();
+    assert_eq!((backend.uid(), backend.gid()), (101000, 101000));
+    let mounted = fs::metadata(mount.path.join("squashed")).unwrap();
+    assert_eq!((mounted.uid(), mounted.gid()), (101000, 101000));
+}
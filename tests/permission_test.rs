@@ -0,0 +1,326 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{self, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+use fuser::{FileAttr, FileType};
+use nix::errno::Errno;
+use nix::unistd::{chown, Gid, Uid};
+use toda::hookfs::permission::{
+    check_access, check_chown, check_owner, check_sticky, check_xattr, Acl, Requester,
+};
+use toda::hookfs::testing::TestMount;
+use toda::hookfs::Error;
+
+// the tags of the ACL entries, and the id of the entries without one
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+const UNDEFINED_ID: u32 = u32::MAX;
+
+const R: u32 = libc::R_OK as u32;
+const W: u32 = libc::W_OK as u32;
+const X: u32 = libc::X_OK as u32;
+
+// acl_xattr encodes the entries of (tag, perm, id) as the xattr of an access ACL
+fn acl_xattr(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+    let mut data = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in entries.iter() {
+        data.extend_from_slice(&tag.to_le_bytes());
+        data.extend_from_slice(&perm.to_le_bytes());
+        data.extend_from_slice(&id.to_le_bytes());
+    }
+    data
+}
+
+fn file_attr(perm: u16, uid: u32, gid: u32) -> FileAttr {
+    FileAttr {
+        ino: 2,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm,
+        nlink: 1,
+        uid,
+        gid,
+        rdev: 0,
+        blksize: 4096,
+        padding: 0,
+        flags: 0,
+    }
+}
+
+// the requesters are never found in /proc, so they have no supplementary groups
+fn requester(uid: u32, gid: u32) -> Requester {
+    Requester { uid, gid, pid: 0 }
+}
+
+fn errno<T>(result: Result<T, Error>) -> Option<Errno> {
+    match result {
+        Err(Error::Sys(errno)) => Some(errno),
+        _ => None,
+    }
+}
+
+#[test]
+fn parse_acl() {
+    // a short or truncated xattr is rejected
+    assert_eq!(errno(Acl::parse(&[])), Some(Errno::EINVAL));
+    assert_eq!(errno(Acl::parse(&[2, 0, 0])), Some(Errno::EINVAL));
+    let mut truncated = acl_xattr(&[(USER_OBJ, 6, UNDEFINED_ID)]);
+    truncated.pop();
+    assert_eq!(errno(Acl::parse(&truncated)), Some(Errno::EINVAL));
+
+    // so is the xattr of another version
+    let mut corrupt = acl_xattr(&[(USER_OBJ, 6, UNDEFINED_ID)]);
+    corrupt[0] = 1;
+    assert_eq!(errno(Acl::parse(&corrupt)), Some(Errno::EOPNOTSUPP));
+
+    // an empty ACL falls back to the mode bits
+    let empty = Acl::parse(&acl_xattr(&[])).unwrap();
+    let attr = file_attr(0o604, 1000, 1000);
+    assert!(check_access(&requester(2000, 2000), &attr, Some(&empty), R).is_ok());
+
+    // the named users and groups, and the mask, are parsed with their ids and permissions
+    let acl = Acl::parse(&acl_xattr(&[
+        (USER_OBJ, 6, UNDEFINED_ID),
+        (USER, 7, 2000),
+        (GROUP_OBJ, 0, UNDEFINED_ID),
+        (GROUP, 6, 3000),
+        (MASK, 5, UNDEFINED_ID),
+        (OTHER, 0, UNDEFINED_ID),
+    ]))
+    .unwrap();
+    let attr = file_attr(0o650, 1000, 1000);
+    assert!(check_access(&requester(2000, 2000), &attr, Some(&acl), R | X).is_ok());
+    assert!(check_access(&requester(2001, 3000), &attr, Some(&acl), R).is_ok());
+    assert!(check_access(&requester(2001, 2001), &attr, Some(&acl), R).is_err());
+}
+
+#[test]
+fn access_precedence() {
+    let acl = Acl::parse(&acl_xattr(&[
+        (USER_OBJ, 6, UNDEFINED_ID),
+        (USER, 0, 1000),
+        (USER, 7, 2000),
+        (USER, 0, 2001),
+        (GROUP_OBJ, 4, UNDEFINED_ID),
+        (GROUP, 6, 3000),
+        (MASK, 5, UNDEFINED_ID),
+        (OTHER, 0, UNDEFINED_ID),
+    ]))
+    .unwrap();
+    let attr = file_attr(0o650, 1000, 1000);
+    let check = |uid, gid, mask| errno(check_access(&requester(uid, gid), &attr, Some(&acl), mask));
+
+    // the owner is checked against the owner bits, even if it's a named user
+    assert_eq!(check(1000, 1000, R | W), None);
+    assert_eq!(check(1000, 1000, X), Some(Errno::EACCES));
+
+    // a named user is checked against its entry limited by the mask, instead of its groups
+    assert_eq!(check(2000, 2000, R | X), None);
+    assert_eq!(check(2000, 2000, W), Some(Errno::EACCES));
+    assert_eq!(check(2001, 1000, R), Some(Errno::EACCES));
+
+    // the owning group and the named groups are limited by the mask
+    assert_eq!(check(4000, 1000, R), None);
+    assert_eq!(check(4000, 1000, W), Some(Errno::EACCES));
+    assert_eq!(check(4000, 3000, R), None);
+    assert_eq!(check(4000, 3000, W), Some(Errno::EACCES));
+
+    // the others get the other entry only
+    assert_eq!(check(4000, 4000, R), Some(Errno::EACCES));
+
+    // without a mask, the named groups get their whole entries
+    let unmasked = Acl::parse(&acl_xattr(&[
+        (USER_OBJ, 6, UNDEFINED_ID),
+        (GROUP_OBJ, 4, UNDEFINED_ID),
+        (GROUP, 6, 3000),
+        (OTHER, 4, UNDEFINED_ID),
+    ]))
+    .unwrap();
+    let check = |uid, gid, mask| {
+        errno(check_access(
+            &requester(uid, gid),
+            &attr,
+            Some(&unmasked),
+            mask,
+        ))
+    };
+    assert_eq!(check(4000, 3000, R | W), None);
+    assert_eq!(check(4000, 4000, W), Some(Errno::EACCES));
+
+    // the other entry is never limited by the mask
+    let closed = Acl::parse(&acl_xattr(&[
+        (USER_OBJ, 6, UNDEFINED_ID),
+        (GROUP_OBJ, 4, UNDEFINED_ID),
+        (MASK, 0, UNDEFINED_ID),
+        (OTHER, 4, UNDEFINED_ID),
+    ]))
+    .unwrap();
+    let check = |uid, gid, mask| {
+        errno(check_access(
+            &requester(uid, gid),
+            &attr,
+            Some(&closed),
+            mask,
+        ))
+    };
+    assert_eq!(check(4000, 1000, R), Some(Errno::EACCES));
+    assert_eq!(check(4000, 4000, R), None);
+
+    // root is permitted to do anything but executing a file which is executable for no one
+    let root = requester(0, 0);
+    assert!(check_access(&root, &attr, Some(&acl), R | W).is_ok());
+    let attr = file_attr(0o640, 1000, 1000);
+    assert_eq!(
+        errno(check_access(&root, &attr, Some(&acl), X)),
+        Some(Errno::EACCES)
+    );
+}
+
+#[test]
+fn owner() {
+    let attr = file_attr(0o666, 1000, 1000);
+    assert!(check_owner(&requester(1000, 2000), &attr).is_ok());
+    assert!(check_owner(&requester(0, 0), &attr).is_ok());
+    // the permissions to write the file are not enough
+    assert_eq!(
+        errno(check_owner(&requester(2000, 1000), &attr)),
+        Some(Errno::EPERM)
+    );
+}
+
+#[test]
+fn chown_permission() {
+    let attr = file_attr(0o644, 1000, 1000);
+    let check =
+        |uid, gid, owner, group| errno(check_chown(&requester(uid, gid), &attr, owner, group));
+
+    // root could give the file away
+    assert_eq!(check(0, 0, Some(2000), Some(2000)), None);
+
+    // the owner could only change the group to its own one
+    assert_eq!(check(1000, 2000, None, Some(2000)), None);
+    assert_eq!(check(1000, 1000, None, Some(3000)), Some(Errno::EPERM));
+    assert_eq!(check(1000, 1000, Some(2000), None), Some(Errno::EPERM));
+    assert_eq!(check(1000, 1000, Some(1000), Some(1000)), None);
+
+    // the others could change nothing, even to their own ids
+    assert_eq!(check(2000, 2000, Some(2000), None), Some(Errno::EPERM));
+    assert_eq!(check(2000, 2000, None, Some(2000)), Some(Errno::EPERM));
+    assert_eq!(check(2000, 1000, Some(1000), Some(1000)), None);
+}
+
+#[test]
+fn sticky_directory() {
+    let sticky = file_attr(0o1777, 3000, 3000);
+    let attr = file_attr(0o644, 1000, 1000);
+
+    // only the owners of the file and the directory, and root, could remove the file
+    assert!(check_sticky(&requester(1000, 1000), &sticky, &attr).is_ok());
+    assert!(check_sticky(&requester(3000, 3000), &sticky, &attr).is_ok());
+    assert!(check_sticky(&requester(0, 0), &sticky, &attr).is_ok());
+    assert_eq!(
+        errno(check_sticky(&requester(2000, 1000), &sticky, &attr)),
+        Some(Errno::EPERM)
+    );
+
+    let dir = file_attr(0o777, 3000, 3000);
+    assert!(check_sticky(&requester(2000, 2000), &dir, &attr).is_ok());
+}
+
+#[test]
+fn xattr_permission() {
+    let attr = file_attr(0o646, 1000, 1000);
+    let check = |uid, name| errno(check_xattr(&requester(uid, uid), &attr, None, name));
+
+    // the ACLs could only be changed by the owner
+    assert_eq!(check(1000, "system.posix_acl_access"), None);
+    assert_eq!(check(2000, "system.posix_acl_access"), Some(Errno::EPERM));
+
+    // the security and trusted xattrs could only be changed by root
+    assert_eq!(check(1000, "trusted.overlay.opaque"), Some(Errno::EPERM));
+    assert_eq!(check(1000, "security.capability"), Some(Errno::EPERM));
+    assert_eq!(check(0, "trusted.overlay.opaque"), None);
+
+    // the user xattrs could be changed by the ones permitted to write the file
+    assert_eq!(check(2000, "user.comment"), None);
+    let attr = file_attr(0o644, 1000, 1000);
+    assert_eq!(
+        errno(check_xattr(
+            &requester(2000, 2000),
+            &attr,
+            None,
+            "user.comment"
+        )),
+        Some(Errno::EACCES)
+    );
+}
+
+#[test]
+fn mounted_permission_check() {
+    let mount = TestMount::mount_with("mounted_permission_check", "[]", |hookfs| {
+        hookfs.enable_permission_check();
+        hookfs
+    })
+    .unwrap();
+
+    let sticky = mount.backend.join("sticky");
+    fs::create_dir(&sticky).unwrap();
+    fs::set_permissions(&sticky, Permissions::from_mode(0o1777)).unwrap();
+    let file = sticky.join("file");
+    fs::write(&file, b"hello").unwrap();
+    chown(&file, Some(Uid::from_raw(1000)), Some(Gid::from_raw(1000))).unwrap();
+
+    // run runs the command in the sticky directory through the FUSE as the user
+    let run = |uid: u32, args: &[&str]| {
+        Command::new(args[0])
+            .args(&args[1..])
+            .current_dir(mount.path.join("sticky"))
+            .uid(uid)
+            .gid(uid)
+            .status()
+            .unwrap()
+            .success()
+    };
+
+    // the others are not permitted to remove, rename or give away the file in the sticky
+    // directory, though they could write the directory
+    assert!(!run(2000, &["rm", "-f", "file"]));
+    assert!(!run(2000, &["mv", "file", "moved"]));
+    assert!(!run(2000, &["chown", "2000", "file"]));
+    assert!(!run(2000, &["chmod", "777", "file"]));
+    assert!(file.exists());
+    assert_eq!(
+        fs::metadata(&file).unwrap().permissions().mode() & 0o777,
+        0o644
+    );
+
+    // but the owner is
+    assert!(run(1000, &["chmod", "600", "file"]));
+    assert!(run(1000, &["mv", "file", "moved"]));
+    assert!(run(1000, &["rm", "-f", "moved"]));
+    assert!(!sticky.join("moved").exists());
+}
@@ -17,11 +17,13 @@ use std::io::{Read, Write};
 use std::os::unix::fs::symlink;
 use std::path::PathBuf;
 use std::sync::{Arc, Once};
+use std::time::Duration;
 
 use nix::sys::stat;
 use nix::{fcntl, unistd};
 use toda::hookfs;
 use toda::injector::MultiInjector;
+use toda::mount::wait_for_fuse_mount;
 
 // These tests are port from go-fuse test
 
@@ -41,11 +43,14 @@ fn init(name: &str) -> (PathBuf, fuser::BackgroundSession) {
     std::fs::create_dir_all(&test_path_backend).ok();
     std::fs::create_dir_all(&test_path).ok();
 
-    let hookfs = Arc::new(hookfs::HookFs::new(
-        &test_path,
-        &test_path_backend,
-        MultiInjector::build(Vec::new()).unwrap(),
-    ));
+    let hookfs = Arc::new(
+        hookfs::HookFs::new(
+            &test_path,
+            &test_path_backend,
+            MultiInjector::build(Vec::new()).unwrap(),
+        )
+        .unwrap(),
+    );
 
     let fs = hookfs::AsyncFileSystem::from(hookfs);
 
@@ -61,7 +66,7 @@ fn init(name: &str) -> (PathBuf, fuser::BackgroundSession) {
         .collect();
 
     let session = fuser::spawn_mount(fs, &test_path, &flags).unwrap();
-    std::thread::sleep(std::time::Duration::from_secs(1));
+    wait_for_fuse_mount(&test_path, Duration::from_secs(10)).unwrap();
     (test_path, session)
 }
 
@@ -348,6 +353,40 @@ fn append_unlink_write() {
     assert_eq!(&output, "hello world");
 }
 
+#[test]
+fn errno_passthrough() {
+    let (test_path, _) = init("errno_passthrough");
+
+    let dir: PathBuf = test_path.join("dir");
+    let file: PathBuf = test_path.join("file");
+    std::fs::create_dir(&dir).unwrap();
+    write(dir.join("child"), "child").unwrap();
+    write(&file, "file").unwrap();
+
+    let errno = |result: std::io::Result<()>| result.unwrap_err().raw_os_error().unwrap();
+
+    assert_eq!(
+        errno(File::open(test_path.join("missing")).map(drop)),
+        libc::ENOENT
+    );
+    assert_eq!(
+        errno(write(test_path.join("n".repeat(300)), "name")),
+        libc::ENAMETOOLONG
+    );
+    assert_eq!(errno(std::fs::create_dir(&dir)), libc::EEXIST);
+    assert_eq!(errno(std::fs::remove_dir(&dir)), libc::ENOTEMPTY);
+    assert_eq!(errno(std::fs::remove_dir(&file)), libc::ENOTDIR);
+    assert_eq!(
+        errno(File::open(file.join("child")).map(drop)),
+        libc::ENOTDIR
+    );
+    assert_eq!(errno(std::fs::remove_file(&dir)), libc::EISDIR);
+    assert_eq!(
+        errno(OpenOptions::new().write(true).open(&dir).map(drop)),
+        libc::EISDIR
+    );
+}
+
 // func RenameOpenDir(t *testing.T, mnt string) {
 // 	if err := os.Mkdir(mnt+"/dir1", 0755); err != nil {
 // 		t.Fatalf("Mkdir: %v", err)
@@ -0,0 +1,44 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::os::unix::fs::PermissionsExt;
+use std::sync::mpsc::channel;
+use std::{fs, thread};
+
+use toda::hookfs::ownership::Owner;
+use toda::privilege::drop_privileges;
+
+// the credentials of the whole test binary are changed, so it's the only test in it
+#[test]
+fn drop_every_thread() {
+    let path = "/tmp/test_privilege";
+    fs::write(path, b"hello").unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).unwrap();
+
+    // the thread is spawned before the privileges are dropped, and reads the file after
+    let (tx, rx) = channel::<()>();
+    let reader = thread::spawn(move || {
+        rx.recv().unwrap();
+        (unsafe { libc::geteuid() }, fs::read(path).unwrap())
+    });
+
+    drop_privileges("65534:65534".parse::<Owner>().unwrap()).unwrap();
+    tx.send(()).unwrap();
+    let (euid, content) = reader.join().unwrap();
+
+    assert_eq!(unsafe { libc::geteuid() }, 65534);
+    assert_eq!(euid, 65534);
+    // the file of root is still served with CAP_DAC_OVERRIDE
+    assert_eq!(content, b"hello");
+    fs::remove_file(path).unwrap();
+}
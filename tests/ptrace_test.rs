@@ -0,0 +1,141 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::read_link;
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::Pid;
+use toda::ptrace;
+
+// the SIGUSR1 received by the signal target
+static USR1: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn count_usr1(_: libc::c_int) {
+    USR1.fetch_add(1, Ordering::SeqCst);
+}
+
+// signal_target is the workload traced by run_codes_keeps_signals, which runs the test binary
+// again to get a process of its own. It runs a threaded tokio runtime, whose workers keep
+// starting children and receive a SIGCHLD from every one of them, and reports every SIGUSR1.
+#[test]
+fn signal_target() {
+    if std::env::var_os("TODA_SIGNAL_TARGET").is_none() {
+        return;
+    }
+
+    let action = SigAction::new(
+        SigHandler::Handler(count_usr1),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGUSR1, &action).unwrap() };
+
+    let mut runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .core_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        for _ in 0..4 {
+            tokio::spawn(async {
+                loop {
+                    tokio::process::Command::new("true").status().await.unwrap();
+                }
+            });
+        }
+
+        println!("ready");
+        let mut reported = 0;
+        loop {
+            let received = USR1.load(Ordering::SeqCst);
+            if received > reported {
+                println!("usr1");
+                reported = received;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+    });
+}
+
+#[test]
+fn run_codes_keeps_signals() {
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .args(&["signal_target", "--exact", "--nocapture"])
+        .env("TODA_SIGNAL_TARGET", "1")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id() as i32;
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    while line.trim() != "ready" {
+        line.clear();
+        assert_ne!(stdout.read_line(&mut line).unwrap(), 0);
+    }
+
+    let process = ptrace::trace(pid).unwrap();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let sender = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                kill(Pid::from_raw(pid), Signal::SIGUSR1).unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+        })
+    };
+
+    for _ in 0..100 {
+        process.run_codes(|_| Ok((0, vec![0xcc]))).unwrap();
+        process.chdir("/tmp").unwrap();
+    }
+
+    // the last pending SIGUSR1 is received while running the codes, and should be delivered
+    // after detaching
+    stop.store(true, Ordering::SeqCst);
+    sender.join().unwrap();
+    process.run_codes(|_| Ok((0, vec![0xcc]))).unwrap();
+    drop(process);
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        read_link(format!("/proc/{}/cwd", pid)).unwrap(),
+        PathBuf::from("/tmp")
+    );
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let mut output = String::new();
+    stdout.read_to_string(&mut output).unwrap();
+    assert!(output.contains("usr1"));
+}
+
+#[test]
+fn check_permission() {
+    let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+
+    // the child is owned by the same user, in the same user namespace
+    ptrace::check_permission(child.id() as i32).unwrap();
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
@@ -0,0 +1,68 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use toda::injector::Method;
+use toda::recorder::{read_trace, replay, Operation, Recorder};
+
+#[test]
+fn record_and_replay() {
+    let base = PathBuf::from("/tmp/test_recorder");
+    let _ = fs::remove_dir_all(&base);
+    let dir = base.join("replay");
+    fs::create_dir_all(&dir).unwrap();
+    let trace = base.join("trace");
+
+    let root = Path::new("/mnt/backend");
+    {
+        let recorder = Recorder::create(&trace, root).unwrap();
+        let received = Instant::now();
+        recorder.record(Operation::new(Method::MKDIR, root.join("dir")), received);
+        recorder.record(Operation::new(Method::CREATE, root.join("dir/a")), received);
+        recorder.record(
+            Operation::new(Method::WRITE, root.join("dir/a")).io(4096, 100),
+            received,
+        );
+        recorder.record(
+            Operation::new(Method::RENAME, root.join("dir/a")).new_path(root.join("dir/b")),
+            received,
+        );
+        recorder.record(
+            Operation::new(Method::GETXATTR, root.join("dir/b")),
+            received,
+        );
+        // the paths out of the root are not recorded
+        recorder.record(Operation::new(Method::READ, "/etc/passwd"), received);
+    }
+
+    let operations = read_trace(&trace).unwrap();
+    assert_eq!(operations.len(), 5);
+    assert_eq!(operations[0].path, Path::new("dir"));
+    assert_eq!(operations[2].method, Method::WRITE);
+    assert_eq!(operations[2].offset, Some(4096));
+    assert_eq!(operations[2].size, 100);
+    assert_eq!(operations[1].offset, None);
+    assert_eq!(operations[3].new_path.as_deref(), Some(Path::new("dir/b")));
+
+    let stats = replay(&trace, &dir, None).unwrap();
+    assert_eq!(stats.operations, 5);
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.skipped, 1);
+    assert_eq!(fs::metadata(dir.join("dir/b")).unwrap().len(), 4196);
+    assert!(!dir.join("dir/a").exists());
+
+    fs::remove_dir_all(&base).unwrap();
+}
@@ -0,0 +1,331 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use toda::injector::InjectorConfig;
+use toda::mount::MountsInfo;
+use toda::mount_injector::{MountInjector, MountMode, PermissionCheck, RecoverOptions};
+use toda::replacer::{
+    analyze_references, verify, ElfMmapStrategy, Replacer, ReplacerOptions, ReplacerScope,
+    ReplacerStats, UnionReplacer,
+};
+
+#[test]
+fn openat_dirfd() {
+    let path = PathBuf::from("/tmp/test_replacer/openat_dirfd");
+    let dir = path.join("dir");
+    std::fs::remove_dir_all(&path).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // the workload holds a dirfd opened without O_DIRECTORY, and creates a file relative to it
+    // after the injection
+    let mut workload = Command::new("sh")
+        .arg("-c")
+        .arg("exec 3<\"$1\"; read line; echo hello > /proc/self/fd/3/file")
+        .arg("sh")
+        .arg(&dir)
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let config: InjectorConfig = serde_json::from_str(
+        r#"{"type": "fault", "percent": 100, "faults": [{"errno": 5, "weight": 1}]}"#,
+    )
+    .unwrap();
+    let mut injection = MountInjector::create_injection(
+        &path,
+        MountMode::Bind,
+        PermissionCheck::Kernel,
+        vec![config],
+    )
+    .unwrap();
+
+    let mut replacer = UnionReplacer::default();
+    replacer
+        .prepare(&path, &path, &ReplacerOptions::default())
+        .unwrap();
+    let mut guard = injection.mount().unwrap();
+    replacer.run().unwrap();
+    drop(replacer);
+    guard.enable_injection();
+
+    // the file is created through the FUSE, so it fails with the injected fault
+    writeln!(workload.stdin.take().unwrap()).unwrap();
+    let status = workload.wait().unwrap();
+
+    guard.disable_injection();
+    guard.recover_mount(RecoverOptions::default()).unwrap();
+
+    assert!(!status.success());
+    assert!(!dir.join("file").exists());
+}
+
+// fdinfo_field reads a field of the fdinfo of a fd
+fn fdinfo_field(pid: i32, fd: i32, field: &str) -> String {
+    std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd))
+        .unwrap()
+        .lines()
+        .find_map(|line| {
+            line.strip_prefix(field)
+                .map(|value| value.trim().to_owned())
+        })
+        .unwrap()
+}
+
+#[test]
+fn replace_busy_fd() {
+    let path = PathBuf::from("/tmp/test_replacer/replace_busy_fd");
+    std::fs::remove_dir_all(&path).ok();
+    std::fs::create_dir_all(&path).unwrap();
+
+    // the workload writes to the fd without pause, and exits once a write fails
+    let mut workload = Command::new("sh")
+        .arg("-c")
+        .arg("exec 3>>file; while echo x >&3; do :; done")
+        .current_dir(&path)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let pid = workload.id() as i32;
+    let flags = fdinfo_field(pid, 3, "flags:");
+
+    let mut injection =
+        MountInjector::create_injection(&path, MountMode::Bind, PermissionCheck::Kernel, vec![])
+            .unwrap();
+    let mut replacer = UnionReplacer::default();
+    replacer
+        .prepare(&path, &path, &ReplacerOptions::default())
+        .unwrap();
+    let mut guard = injection.mount().unwrap();
+    replacer.run().unwrap();
+    drop(replacer);
+
+    thread::sleep(Duration::from_millis(200));
+    let fuse = MountsInfo::parse_mounts()
+        .unwrap()
+        .containing_mount(&path)
+        .unwrap()
+        .mnt_id;
+    let replaced = (
+        fdinfo_field(pid, 3, "flags:"),
+        fdinfo_field(pid, 3, "mnt_id:"),
+    );
+    let running = workload.try_wait().unwrap().is_none();
+
+    workload.kill().unwrap();
+    workload.wait().unwrap();
+    guard.recover_mount(RecoverOptions::default()).unwrap();
+
+    assert!(running);
+    assert_eq!(replaced, (flags, fuse.to_string()));
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn replace_large_offset() {
+    let path = PathBuf::from("/tmp/test_replacer/replace_large_offset");
+    std::fs::remove_dir_all(&path).ok();
+    std::fs::create_dir_all(&path).unwrap();
+
+    // the offset doesn't fit in 32 bits, while the sparse file takes no space
+    let offset: u64 = 5 << 30;
+    let mut file = std::fs::File::create(path.join("file")).unwrap();
+    file.set_len(offset * 2).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    let mut workload = Command::new("sleep")
+        .arg("10")
+        .stdin(Stdio::from(file))
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let pid = workload.id() as i32;
+
+    let mut injection =
+        MountInjector::create_injection(&path, MountMode::Bind, PermissionCheck::Kernel, vec![])
+            .unwrap();
+    let mut replacer = UnionReplacer::default();
+    replacer
+        .prepare(&path, &path, &ReplacerOptions::default())
+        .unwrap();
+    let mut guard = injection.mount().unwrap();
+    replacer.run().unwrap();
+    drop(replacer);
+
+    let fuse = MountsInfo::parse_mounts()
+        .unwrap()
+        .containing_mount(&path)
+        .unwrap()
+        .mnt_id;
+    let replaced = (
+        fdinfo_field(pid, 0, "pos:"),
+        fdinfo_field(pid, 0, "mnt_id:"),
+    );
+
+    workload.kill().unwrap();
+    workload.wait().unwrap();
+    guard.recover_mount(RecoverOptions::default()).unwrap();
+
+    assert_eq!(replaced, (offset.to_string(), fuse.to_string()));
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn replace_elf_mapping() {
+    let path = PathBuf::from("/tmp/test_replacer/replace_elf_mapping");
+    std::fs::remove_dir_all(&path).ok();
+    std::fs::create_dir_all(&path).unwrap();
+
+    // the executable of the workload is under the path, which could never be opened for writing
+    std::fs::copy("/bin/sleep", path.join("sleep")).unwrap();
+    let mut workload = Command::new(path.join("sleep")).arg("10").spawn().unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let pid = workload.id() as i32;
+
+    let mut injection =
+        MountInjector::create_injection(&path, MountMode::Bind, PermissionCheck::Kernel, vec![])
+            .unwrap();
+    let options = ReplacerOptions {
+        elf_mmap: ElfMmapStrategy::Replace,
+        ..Default::default()
+    };
+    let mut replacer = UnionReplacer::default();
+    replacer.prepare(&path, &path, &options).unwrap();
+    let mut guard = injection.mount().unwrap();
+    replacer.run().unwrap();
+    drop(replacer);
+
+    // the mappings are reopened read-only, and the workload keeps running on them
+    thread::sleep(Duration::from_millis(100));
+    let running = workload.try_wait().unwrap().is_none();
+    let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid)).unwrap();
+
+    workload.kill().unwrap();
+    workload.wait().unwrap();
+    guard.recover_mount(RecoverOptions::default()).unwrap();
+
+    assert!(running);
+    assert!(maps.contains(path.join("sleep").to_str().unwrap()));
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn verify_replaced() {
+    let path = PathBuf::from("/tmp/test_replacer/verify_replaced");
+    std::fs::remove_dir_all(&path).ok();
+    std::fs::create_dir_all(&path).unwrap();
+
+    let mut workload = Command::new("sh")
+        .arg("-c")
+        .arg("exec 3>>file; sleep 10")
+        .current_dir(&path)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let pid = workload.id() as i32;
+
+    let mut injection =
+        MountInjector::create_injection(&path, MountMode::Bind, PermissionCheck::Kernel, vec![])
+            .unwrap();
+    let mut guard = injection.mount().unwrap();
+    let options = ReplacerOptions::default();
+    let stats = ReplacerStats::default();
+
+    // the workload is left on the original directory until it's replaced
+    let before = verify(&path, guard.hidden_path(), &options, &stats).unwrap();
+    let mut replacer = UnionReplacer::default();
+    replacer.prepare(&path, &path, &options).unwrap();
+    replacer.run().unwrap();
+    drop(replacer);
+    let after = verify(&path, guard.hidden_path(), &options, &stats).unwrap();
+
+    workload.kill().unwrap();
+    workload.wait().unwrap();
+    guard.recover_mount(RecoverOptions::default()).unwrap();
+
+    assert!(before.iter().any(|case| case.pid == pid));
+    assert!(!after.iter().any(|case| case.pid == pid));
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn references_report() {
+    let path = PathBuf::from("/tmp/test_replacer/references_report");
+    std::fs::remove_dir_all(&path).ok();
+    std::fs::create_dir_all(&path).unwrap();
+    std::fs::write(path.join("file"), b"hello").unwrap();
+
+    let mut workload = Command::new("sh")
+        .arg("-c")
+        .arg("exec 3<file; read line")
+        .current_dir(&path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let pid = workload.id() as i32;
+
+    let report = analyze_references(&path, &ReplacerOptions::default()).unwrap();
+    let references = report
+        .processes
+        .iter()
+        .find(|references| references.pid == pid)
+        .unwrap();
+    assert_eq!(references.cwd.as_ref(), Some(&path));
+    assert_eq!(references.fds, vec![path.join("file")]);
+    assert!(references.traced);
+    assert!(!references.risky);
+
+    // the excluded processes are listed, but not traced
+    let options = ReplacerOptions {
+        exclude_pid: vec![pid],
+        ..Default::default()
+    };
+    let report = analyze_references(&path, &options).unwrap();
+    assert!(report
+        .processes
+        .iter()
+        .any(|references| references.pid == pid && !references.traced));
+
+    // the workload is in the same container as the test
+    let mut options = ReplacerOptions {
+        scope: ReplacerScope::Container,
+        ..Default::default()
+    };
+    options.restrict_to(std::process::id() as i32).unwrap();
+    let report = analyze_references(&path, &options).unwrap();
+    assert!(report
+        .processes
+        .iter()
+        .any(|references| references.pid == pid && references.traced));
+
+    // the workload is in the same pid namespace as the test
+    let options = ReplacerOptions {
+        target_pidns: Some(std::process::id().to_string().parse().unwrap()),
+        ..Default::default()
+    };
+    let report = analyze_references(&path, &options).unwrap();
+    assert!(report
+        .processes
+        .iter()
+        .any(|references| references.pid == pid));
+
+    workload.stdin.take().unwrap().write_all(b"\n").unwrap();
+    workload.wait().unwrap();
+    std::fs::remove_dir_all(&path).unwrap();
+}
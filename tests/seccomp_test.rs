@@ -0,0 +1,75 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+
+use toda::hookfs::testing::TestMount;
+use toda::seccomp::{apply, SeccompMode};
+
+// the filter applies to the whole test binary, so it's the only test in it
+#[test]
+fn enforce() {
+    let mount = TestMount::mount("seccomp_enforce", "[]").unwrap();
+    fs::create_dir(mount.backend.join("dir")).unwrap();
+    fs::write(mount.backend.join("dir/file"), b"hello").unwrap();
+
+    apply(SeccompMode::Enforce).unwrap();
+
+    // the requests are still served by the FUSE, whose threads are filtered as well
+    let mut content = String::new();
+    File::open(mount.path.join("dir/file"))
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "hello");
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(mount.path.join("dir/created"))
+        .unwrap();
+    file.write_all(b"world").unwrap();
+    file.sync_all().unwrap();
+    drop(file);
+    assert_eq!(
+        fs::read(mount.backend.join("dir/created")).unwrap(),
+        b"world"
+    );
+
+    let file = OpenOptions::new()
+        .write(true)
+        .open(mount.path.join("dir/file"))
+        .unwrap();
+    file.set_len(2).unwrap();
+    assert_eq!(fs::read(mount.path.join("dir/file")).unwrap(), b"he");
+
+    let mut names: Vec<_> = fs::read_dir(mount.path.join("dir"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["created", "file"]);
+    File::open(mount.path.join("dir"))
+        .unwrap()
+        .sync_all()
+        .unwrap();
+
+    // the namespaces are never changed once the injection is set up
+    let ret = unsafe { libc::unshare(libc::CLONE_NEWNS) };
+    assert_eq!(ret, -1);
+    assert_eq!(
+        std::io::Error::last_os_error().raw_os_error(),
+        Some(libc::EPERM)
+    );
+}
@@ -0,0 +1,60 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toda::shadow::{verify, Shadow};
+
+#[test]
+fn verify_shadow() {
+    let base = PathBuf::from("/tmp/test_shadow");
+    let _ = fs::remove_dir_all(&base);
+    let root = base.join("root");
+    let shadow_dir = base.join("shadow");
+    fs::create_dir_all(root.join("dir")).unwrap();
+
+    {
+        let shadow = Shadow::create(&shadow_dir, &root).unwrap();
+
+        // the application writes through the FUSE, while the second file is corrupted
+        fs::write(root.join("dir/tmp"), b"hello world").unwrap();
+        shadow.write(&root.join("dir/tmp"), 0, b"hello world");
+        fs::write(root.join("b"), b"abcdef").unwrap();
+        shadow.write(&root.join("b"), 0, b"abcxyz");
+        fs::write(root.join("c"), b"gone").unwrap();
+        shadow.write(&root.join("c"), 0, b"gone");
+
+        fs::rename(root.join("dir/tmp"), root.join("a")).unwrap();
+        shadow.rename(&root.join("dir/tmp"), &root.join("a"));
+        fs::remove_file(root.join("c")).unwrap();
+        shadow.remove(&root.join("c"));
+    }
+
+    // the data which is not written through the FUSE is never checked
+    fs::write(root.join("untouched"), b"anything").unwrap();
+
+    let report = verify(&shadow_dir, &root).unwrap();
+    assert_eq!(report.files, 2);
+    assert!(report.missing.is_empty());
+    assert_eq!(report.diverged.len(), 1);
+    assert_eq!(report.diverged[0].path, Path::new("b"));
+    assert_eq!(report.diverged[0].offset, 3);
+    assert_eq!(report.diverged[0].bytes, 3);
+
+    fs::remove_file(root.join("a")).unwrap();
+    let report = verify(&shadow_dir, &root).unwrap();
+    assert_eq!(report.missing, vec![PathBuf::from("a")]);
+
+    fs::remove_dir_all(&base).unwrap();
+}
@@ -0,0 +1,56 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::PathBuf;
+
+use toda::snapshot::{restore, take};
+
+#[test]
+fn take_and_restore() {
+    let base = PathBuf::from("/tmp/test_snapshot");
+    let _ = fs::remove_dir_all(&base);
+    let root = base.join("root");
+    let snapshot_dir = base.join("snapshot");
+    fs::create_dir_all(root.join("dir")).unwrap();
+    fs::write(root.join("a"), b"hello world").unwrap();
+    fs::write(root.join("dir/b"), b"abcdef").unwrap();
+    symlink("a", root.join("link")).unwrap();
+
+    let stats = take(&root, &snapshot_dir).unwrap();
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.dirs, 2);
+    assert_eq!(stats.symlinks, 1);
+    // the snapshot should not be taken into a non-empty directory
+    assert!(take(&root, &snapshot_dir).is_err());
+
+    // the injection corrupts a file, while the application adds and removes some
+    fs::write(root.join("a"), b"hello").unwrap();
+    fs::write(root.join("dir/c"), b"new").unwrap();
+    fs::remove_file(root.join("dir/b")).unwrap();
+    fs::remove_file(root.join("link")).unwrap();
+    fs::create_dir(root.join("link")).unwrap();
+
+    let stats = restore(&snapshot_dir, &root).unwrap();
+    assert_eq!(stats.removed, 2);
+    assert_eq!(fs::read(root.join("a")).unwrap(), b"hello world");
+    assert_eq!(fs::read(root.join("dir/b")).unwrap(), b"abcdef");
+    assert!(!root.join("dir/c").exists());
+    assert_eq!(
+        fs::read_link(root.join("link")).unwrap(),
+        PathBuf::from("a")
+    );
+
+    fs::remove_dir_all(&base).unwrap();
+}